@@ -0,0 +1,120 @@
+//! READ over loopback TCP from a MemFS, and the bytes the server allocates
+//! for each READ. The data is written from the pooled read buffer, so a
+//! READ of a MemFS, which reads into that buffer, should not allocate in
+//! proportion to its count. A file system with only read (the default
+//! read_ext) allocates the bytes it returns, and no more.
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{forward_to_memfs, serve_with, Client};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::NFSFileSystem;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The system allocator, counting the bytes allocated by the server thread
+struct Counting;
+
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static SERVER_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count(size: usize) {
+    if SERVER_THREAD.try_with(Cell::get).unwrap_or(false) {
+        ALLOCATED.fetch_add(size as u64, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A MemFS without its read_ext
+struct ReadOnlyFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! { ReadOnlyFS, }
+
+const FILE_SIZE: usize = 1 << 20;
+
+/// A client of fs, served holding a file of FILE_SIZE bytes
+fn setup<T: NFSFileSystem + Send + Sync + 'static>(fs: T) -> (Client, nfs_fh3) {
+    // the listener runs on a current thread runtime, on the thread which
+    // configures it
+    let port = serve_with(fs, |_| SERVER_THREAD.with(|s| s.set(true)));
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    for (i, chunk) in data.chunks(1 << 16).enumerate() {
+        client.write(&file, (i << 16) as u64, chunk).unwrap();
+    }
+    (client, file)
+}
+
+/// The bytes the server allocates for each READ of count bytes, once the
+/// read buffers are pooled
+fn allocated_per_read(client: &mut Client, file: &nfs_fh3, count: u32) -> u64 {
+    const READS: u64 = 100;
+    client.read(file, 0, count).unwrap();
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    for _ in 0..READS {
+        client.read(file, 0, count).unwrap();
+    }
+    (ALLOCATED.load(Ordering::Relaxed) - before) / READS
+}
+
+fn bench_reads(c: &mut Criterion, name: &str, (mut client, file): (Client, nfs_fh3)) {
+    let mut group = c.benchmark_group(name);
+    for count in [4 << 10, FILE_SIZE as u32] {
+        println!(
+            "{name}/{count}: {} bytes allocated by the server per READ",
+            allocated_per_read(&mut client, &file, count)
+        );
+        group.throughput(Throughput::Bytes(count as u64));
+        group.bench_function(count.to_string(), |b| {
+            b.iter(|| client.read(&file, 0, count).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    bench_reads(c, "read", setup(MemFS::new()));
+}
+
+fn read_default(c: &mut Criterion) {
+    bench_reads(
+        c,
+        "read_default",
+        setup(ReadOnlyFS {
+            inner: MemFS::new(),
+        }),
+    );
+}
+
+criterion_group!(benches, read, read_default);
+criterion_main!(benches);
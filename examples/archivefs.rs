@@ -0,0 +1,594 @@
+//! Serves the contents of a tar archive as a read-only file system
+//! without unpacking it. The archive is scanned once on startup to build
+//! an index of its entries, and file contents are read directly from the
+//! archive at the offsets recorded in the index.
+use std::collections::BTreeMap;
+use std::io::{Read, SeekFrom};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::debug;
+
+use nfsserve::nfs::*;
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities};
+
+const BLOCK_SIZE: u64 = 512;
+
+#[derive(Debug, Clone)]
+enum ArchiveContents {
+    /// A regular file stored at offset in the archive
+    File { offset: u64 },
+    /// A directory and its children by name
+    Directory(BTreeMap<Vec<u8>, fileid3>),
+    /// A symlink and its target
+    Symlink(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct ArchiveEntry {
+    name: filename3,
+    parent: fileid3,
+    attr: fattr3,
+    contents: ArchiveContents,
+}
+
+/// The fields of a tar header we care about
+struct TarHeader {
+    path: Vec<u8>,
+    link: Vec<u8>,
+    typeflag: u8,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u32,
+}
+
+/// Parses a NUL (or space) terminated octal field. GNU tar stores large
+/// numeric fields in base-256 with the high bit of the first byte set.
+fn parse_numeric(field: &[u8]) -> u64 {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        let mut ret: u64 = (field[0] & 0x7f) as u64;
+        for b in &field[1..] {
+            ret = (ret << 8) | *b as u64;
+        }
+        return ret;
+    }
+    let mut ret: u64 = 0;
+    for b in field.iter().skip_while(|b| **b == b' ') {
+        if !(b'0'..=b'7').contains(b) {
+            break;
+        }
+        ret = (ret << 3) | (b - b'0') as u64;
+    }
+    ret
+}
+
+fn parse_string(field: &[u8]) -> Vec<u8> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    field[..end].to_vec()
+}
+
+fn parse_header(block: &[u8; BLOCK_SIZE as usize]) -> TarHeader {
+    let mut path = parse_string(&block[0..100]);
+    // ustar splits long names into a prefix and a name
+    if &block[257..263] == b"ustar\0" {
+        let prefix = parse_string(&block[345..500]);
+        if !prefix.is_empty() {
+            path = [prefix, b"/".to_vec(), path].concat();
+        }
+    }
+    TarHeader {
+        path,
+        link: parse_string(&block[157..257]),
+        typeflag: block[156],
+        mode: parse_numeric(&block[100..108]) as u32,
+        uid: parse_numeric(&block[108..116]) as u32,
+        gid: parse_numeric(&block[116..124]) as u32,
+        size: parse_numeric(&block[124..136]),
+        mtime: parse_numeric(&block[136..148]) as u32,
+    }
+}
+
+/// Extracts the path and linkpath records from a pax extended header
+fn parse_pax(data: &[u8]) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let mut path = None;
+    let mut link = None;
+    let mut rest = data;
+    // each record is "<len> <key>=<value>\n" where len includes itself
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let len = parse_decimal(&rest[..space]);
+        // room for the separator and the newline, which must end it
+        if len < space + 2 || len > rest.len() || rest[len - 1] != b'\n' {
+            break;
+        }
+        let record = &rest[space + 1..len - 1];
+        if let Some(eq) = record.iter().position(|b| *b == b'=') {
+            match &record[..eq] {
+                b"path" => path = Some(record[eq + 1..].to_vec()),
+                b"linkpath" => link = Some(record[eq + 1..].to_vec()),
+                _ => {}
+            }
+        }
+        rest = &rest[len..];
+    }
+    (path, link)
+}
+
+fn parse_decimal(field: &[u8]) -> usize {
+    field
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .fold(0, |acc, b| acc * 10 + (b - b'0') as usize)
+}
+
+/// Rounds up to a multiple of the tar block size
+fn pad_to_block(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+fn make_attr(id: fileid3, ftype: ftype3, hdr: Option<&TarHeader>) -> fattr3 {
+    let (mode, uid, gid, size, mtime) = match hdr {
+        Some(hdr) => (hdr.mode & 0o7777, hdr.uid, hdr.gid, hdr.size, hdr.mtime),
+        // synthesized directories
+        None => (0o755, 0, 0, 0, 0),
+    };
+    let size = match ftype {
+        ftype3::NF3REG => size,
+        ftype3::NF3LNK => hdr.map_or(0, |h| h.link.len() as u64),
+        _ => 0,
+    };
+    let time = nfstime3 {
+        seconds: mtime,
+        nseconds: 0,
+    };
+    fattr3 {
+        ftype,
+        mode,
+        nlink: if matches!(ftype, ftype3::NF3DIR) {
+            2
+        } else {
+            1
+        },
+        uid,
+        gid,
+        size,
+        used: size,
+        rdev: specdata3::default(),
+        fsid: 0,
+        fileid: id,
+        atime: time,
+        mtime: time,
+        ctime: time,
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveFS {
+    archive: PathBuf,
+    /// entries indexed by fileid. fileid 0 is unused.
+    entries: Vec<ArchiveEntry>,
+}
+
+impl ArchiveFS {
+    const ROOT: fileid3 = fileid3(1);
+
+    /// Scans the archive and builds the index. Fileids are assigned in
+    /// the order in which paths first appear in the archive, so the same
+    /// archive always produces the same ids.
+    pub fn new(archive: &Path) -> std::io::Result<ArchiveFS> {
+        let placeholder = ArchiveEntry {
+            name: filename3::default(),
+            parent: fileid3(0),
+            attr: fattr3::default(),
+            contents: ArchiveContents::Directory(BTreeMap::new()),
+        };
+        let root = ArchiveEntry {
+            name: b"/".as_slice().into(),
+            parent: Self::ROOT,
+            attr: make_attr(Self::ROOT, ftype3::NF3DIR, None),
+            contents: ArchiveContents::Directory(BTreeMap::new()),
+        };
+        let mut fs = ArchiveFS {
+            archive: archive.to_path_buf(),
+            entries: vec![placeholder, root],
+        };
+
+        let mut f = std::io::BufReader::new(std::fs::File::open(archive)?);
+        let mut block = [0_u8; BLOCK_SIZE as usize];
+        let mut offset: u64 = 0;
+        // hardlinks are resolved once the whole archive is indexed
+        let mut hardlinks: Vec<(fileid3, Vec<u8>)> = Vec::new();
+        let mut long_path: Option<Vec<u8>> = None;
+        let mut long_link: Option<Vec<u8>> = None;
+        loop {
+            if f.read_exact(&mut block).is_err() {
+                break;
+            }
+            offset += BLOCK_SIZE;
+            // the archive ends with zero blocks
+            if block.iter().all(|b| *b == 0) {
+                break;
+            }
+            let mut hdr = parse_header(&block);
+            let data_offset = offset;
+            let data_len = pad_to_block(hdr.size);
+            match hdr.typeflag {
+                // GNU long names and pax extended headers apply to the next entry
+                b'L' | b'K' | b'x' => {
+                    let mut data = vec![0_u8; hdr.size as usize];
+                    f.read_exact(&mut data)?;
+                    std::io::copy(
+                        &mut (&mut f).take(data_len - hdr.size),
+                        &mut std::io::sink(),
+                    )?;
+                    offset += data_len;
+                    match hdr.typeflag {
+                        b'L' => long_path = Some(parse_string(&data)),
+                        b'K' => long_link = Some(parse_string(&data)),
+                        _ => {
+                            let (path, link) = parse_pax(&data);
+                            long_path = path.or(long_path);
+                            long_link = link.or(long_link);
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(path) = long_path.take() {
+                hdr.path = path;
+            }
+            if let Some(link) = long_link.take() {
+                hdr.link = link;
+            }
+            std::io::copy(&mut (&mut f).take(data_len), &mut std::io::sink())?;
+            offset += data_len;
+
+            let (ftype, contents) = match hdr.typeflag {
+                b'0' | 0 | b'7' => (
+                    ftype3::NF3REG,
+                    ArchiveContents::File {
+                        offset: data_offset,
+                    },
+                ),
+                b'1' => (ftype3::NF3REG, ArchiveContents::File { offset: 0 }),
+                b'2' => (ftype3::NF3LNK, ArchiveContents::Symlink(hdr.link.clone())),
+                b'5' => (ftype3::NF3DIR, ArchiveContents::Directory(BTreeMap::new())),
+                other => {
+                    debug!("Skipping {:?} of type {:?}", hdr.path, other);
+                    continue;
+                }
+            };
+            if let Some(id) = fs.insert_path(&hdr.path, ftype, &hdr, contents) {
+                if hdr.typeflag == b'1' {
+                    hardlinks.push((id, hdr.link.clone()));
+                }
+            }
+        }
+        for (id, target) in hardlinks {
+            if let Some(target) = fs.find_path(&target) {
+                let target = fs.entries[target.0 as usize].clone();
+                let entry = &mut fs.entries[id.0 as usize];
+                entry.contents = target.contents;
+                entry.attr.size = target.attr.size;
+                entry.attr.used = target.attr.used;
+            }
+        }
+        debug!("Indexed {:?} entries", fs.entries.len() - 1);
+        Ok(fs)
+    }
+
+    /// Splits an archive path into its non-empty components
+    fn components(path: &[u8]) -> Vec<&[u8]> {
+        path.split(|b| *b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .collect()
+    }
+
+    fn find_path(&self, path: &[u8]) -> Option<fileid3> {
+        let mut cur = Self::ROOT;
+        for component in Self::components(path) {
+            match &self.entries[cur.0 as usize].contents {
+                ArchiveContents::Directory(children) => cur = *children.get(component)?,
+                _ => return None,
+            }
+        }
+        Some(cur)
+    }
+
+    /// Adds a new entry to a directory, returning its fileid
+    fn add_child(&mut self, dirid: fileid3, name: &[u8], entry: ArchiveEntry) -> fileid3 {
+        let id = fileid3(self.entries.len() as u64);
+        let mut entry = entry;
+        entry.attr.fileid = id;
+        self.entries.push(entry);
+        if let ArchiveContents::Directory(children) = &mut self.entries[dirid.0 as usize].contents {
+            children.insert(name.to_vec(), id);
+        }
+        id
+    }
+
+    /// Inserts an archive entry, synthesizing any missing parent
+    /// directories. A path which appears more than once in the archive
+    /// keeps its fileid and takes the contents of the last occurrence.
+    fn insert_path(
+        &mut self,
+        path: &[u8],
+        ftype: ftype3,
+        hdr: &TarHeader,
+        contents: ArchiveContents,
+    ) -> Option<fileid3> {
+        let components = Self::components(path);
+        let (name, parents) = components.split_last()?;
+        let mut cur = Self::ROOT;
+        for component in parents {
+            let existing = match &self.entries[cur.0 as usize].contents {
+                ArchiveContents::Directory(children) => children.get(*component).copied(),
+                // a path component which is not a directory
+                _ => return None,
+            };
+            cur = match existing {
+                Some(id) => id,
+                None => {
+                    let dir = ArchiveEntry {
+                        name: (*component).into(),
+                        parent: cur,
+                        attr: make_attr(fileid3(0), ftype3::NF3DIR, None),
+                        contents: ArchiveContents::Directory(BTreeMap::new()),
+                    };
+                    self.add_child(cur, component, dir)
+                }
+            };
+        }
+        let existing = match &self.entries[cur.0 as usize].contents {
+            ArchiveContents::Directory(children) => children.get(*name).copied(),
+            _ => return None,
+        };
+        match existing {
+            Some(id) => {
+                let entry = &mut self.entries[id.0 as usize];
+                entry.attr = make_attr(id, ftype, Some(hdr));
+                // keep the children of a directory listed again
+                if !matches!(
+                    (&entry.contents, &contents),
+                    (ArchiveContents::Directory(_), ArchiveContents::Directory(_))
+                ) {
+                    entry.contents = contents;
+                }
+                Some(id)
+            }
+            None => {
+                let entry = ArchiveEntry {
+                    name: (*name).into(),
+                    parent: cur,
+                    attr: make_attr(fileid3(0), ftype, Some(hdr)),
+                    contents,
+                };
+                Some(self.add_child(cur, name, entry))
+            }
+        }
+    }
+
+    fn find_entry(&self, id: fileid3) -> Result<&ArchiveEntry, nfsstat3> {
+        if id.0 == 0 {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        self.entries
+            .get(id.0 as usize)
+            .ok_or(nfsstat3::NFS3ERR_NOENT)
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for ArchiveFS {
+    fn root_dir(&self) -> fileid3 {
+        Self::ROOT
+    }
+    fn capabilities(&self) -> VFSCapabilities {
+        VFSCapabilities::ReadOnly
+    }
+
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        let entry = self.find_entry(dirid)?;
+        if let ArchiveContents::Directory(children) = &entry.contents {
+            // if looking for dir/. its the current directory
+            if filename[..] == [b'.'] {
+                return Ok(dirid);
+            }
+            // if looking for dir/.. its the parent directory
+            if filename[..] == [b'.', b'.'] {
+                return Ok(entry.parent);
+            }
+            children
+                .get(&filename[..])
+                .copied()
+                .ok_or(nfsstat3::NFS3ERR_NOENT)
+        } else {
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        }
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        Ok(self.find_entry(id)?.attr)
+    }
+
+    async fn setattr(&self, _id: fileid3, _setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let entry = self.find_entry(id)?;
+        let data_offset = match entry.contents {
+            ArchiveContents::File { offset } => offset,
+            ArchiveContents::Directory(_) => return Err(nfsstat3::NFS3ERR_ISDIR),
+            ArchiveContents::Symlink(_) => return Err(nfsstat3::NFS3ERR_INVAL),
+        };
+        let len = entry.attr.size;
+        let start = offset.min(len);
+        let end = offset.saturating_add(count as u64);
+        let eof = end >= len;
+        let end = end.min(len);
+        let mut f = File::open(&self.archive)
+            .await
+            .or(Err(nfsstat3::NFS3ERR_IO))?;
+        f.seek(SeekFrom::Start(data_offset + start))
+            .await
+            .or(Err(nfsstat3::NFS3ERR_IO))?;
+        let mut buf = vec![0; (end - start) as usize];
+        f.read_exact(&mut buf).await.or(Err(nfsstat3::NFS3ERR_IO))?;
+        Ok((buf, eof))
+    }
+
+    async fn write(&self, _id: fileid3, _offset: u64, _data: &[u8]) -> Result<fattr3, nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn create(
+        &self,
+        _dirid: fileid3,
+        _filename: &filename3,
+        _attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn create_exclusive(
+        &self,
+        _dirid: fileid3,
+        _filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn mkdir(
+        &self,
+        _dirid: fileid3,
+        _dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn remove(&self, _dirid: fileid3, _filename: &filename3) -> Result<(), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn rename(
+        &self,
+        _from_dirid: fileid3,
+        _from_filename: &filename3,
+        _to_dirid: fileid3,
+        _to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        let entry = self.find_entry(dirid)?;
+        let children = match &entry.contents {
+            ArchiveContents::Directory(children) => children,
+            _ => return Err(nfsstat3::NFS3ERR_NOTDIR),
+        };
+        // entries are listed in name order. Resume after the name of the
+        // start_after entry, whose cookie is its fileid.
+        let range_start = if start_after > cookie3(0) {
+            let after = self
+                .find_entry(fileid3(start_after.0))
+                .or(Err(nfsstat3::NFS3ERR_BAD_COOKIE))?;
+            if after.parent != dirid {
+                return Err(nfsstat3::NFS3ERR_BAD_COOKIE);
+            }
+            Bound::Excluded(after.name.0.clone())
+        } else {
+            Bound::Unbounded
+        };
+        let mut ret = ReadDirResult {
+            entries: Vec::new(),
+            end: false,
+        };
+        let mut remaining = children.range((range_start, Bound::Unbounded));
+        for (name, id) in remaining.by_ref() {
+            ret.entries.push(DirEntry {
+                fileid: *id,
+                name: name.as_slice().into(),
+                attr: self.find_entry(*id)?.attr,
+                cookie: cookie3(id.0),
+            });
+            if ret.entries.len() >= max_entries {
+                break;
+            }
+        }
+        ret.end = remaining.next().is_none();
+        Ok(ret)
+    }
+
+    async fn symlink(
+        &self,
+        _dirid: fileid3,
+        _linkname: &filename3,
+        _symlink: &nfspath3,
+        _attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        match &self.find_entry(id)?.contents {
+            ArchiveContents::Symlink(target) => Ok(target.as_slice().into()),
+            _ => Err(nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    async fn is_immutable_dir(&self, _dirid: fileid3) -> bool {
+        // archives are never modified while served
+        true
+    }
+
+    async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        // walk up to the root to rebuild the path
+        let mut names = Vec::new();
+        let mut cur = id;
+        while cur != Self::ROOT {
+            let entry = self.find_entry(cur).ok()?;
+            names.push(String::from_utf8_lossy(&entry.name).into_owned());
+            cur = entry.parent;
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+}
+
+const HOSTPORT: u32 = 11111;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let path = std::env::args()
+        .nth(1)
+        .expect("must supply a tar archive to serve");
+    let fs = ArchiveFS::new(Path::new(&path)).expect("unable to read archive");
+    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
+        .await
+        .unwrap();
+    listener.handle_forever().await.unwrap();
+}
+// Test with
+// mount -t nfs -o nolocks,vers=3,tcp,port=11111,mountport=11111,soft 127.0.0.1:/ mnt/
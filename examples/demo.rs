@@ -5,10 +5,11 @@ use async_trait::async_trait;
 
 use nfsserve::{
     nfs::{
-        self, fattr3, fileid3, filename3, ftype3, nfspath3, nfsstat3, nfstime3, sattr3, specdata3,
+        self, cookie3, fattr3, fileid3, filename3, ftype3, nfspath3, nfsstat3, nfstime3, sattr3,
+        specdata3,
     },
     tcp::*,
-    vfs::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities},
+    vfs::{DirEntry, NFSFileSystem, ReadDirResult, ReadReply, VFSCapabilities},
 };
 
 #[derive(Debug, Clone)]
@@ -92,27 +93,37 @@ impl Default for DemoFS {
         //      |-thisworks.txt
         //
         let entries = vec![
-            make_file("", 0, 0, &[]), // fileid 0 is special
+            make_file("", fileid3(0), fileid3(0), &[]), // fileid 0 is special
             make_dir(
                 "/",
-                1,             // current id. Must match position in entries
-                1,             // parent id
-                vec![2, 3, 4], // children
+                fileid3(1), // current id. Must match position in entries
+                fileid3(1), // parent id
+                vec![fileid3(2), fileid3(3), fileid3(4)], // children
             ),
             make_file(
                 "a.txt",
-                2, // current id
-                1, // parent id
+                fileid3(2), // current id
+                fileid3(1), // parent id
                 "hello world\n".as_bytes(),
             ),
-            make_file("b.txt", 3, 1, "Greetings to xet data\n".as_bytes()),
-            make_dir("another_dir", 4, 1, vec![5]),
-            make_file("thisworks.txt", 5, 4, "i hope\n".as_bytes()),
+            make_file(
+                "b.txt",
+                fileid3(3),
+                fileid3(1),
+                "Greetings to xet data\n".as_bytes(),
+            ),
+            make_dir("another_dir", fileid3(4), fileid3(1), vec![fileid3(5)]),
+            make_file(
+                "thisworks.txt",
+                fileid3(5),
+                fileid3(4),
+                "i hope\n".as_bytes(),
+            ),
         ];
 
         DemoFS {
             fs: Mutex::new(entries),
-            rootdir: 1,
+            rootdir: fileid3(1),
         }
     }
 }
@@ -132,17 +143,19 @@ impl NFSFileSystem for DemoFS {
     async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
         {
             let mut fs = self.fs.lock().unwrap();
-            let mut fssize = fs[id as usize].attr.size;
-            if let FSContents::File(bytes) = &mut fs[id as usize].contents {
+            let mut fssize = fs[id.0 as usize].attr.size;
+            if let FSContents::File(bytes) = &mut fs[id.0 as usize].contents {
                 let offset = offset as usize;
+                // a gap between the old end of the file and offset
+                // reads as zeros
                 if offset + data.len() > bytes.len() {
                     bytes.resize(offset + data.len(), 0);
-                    bytes[offset..].copy_from_slice(data);
                     fssize = bytes.len() as u64;
                 }
+                bytes[offset..offset + data.len()].copy_from_slice(data);
             }
-            fs[id as usize].attr.size = fssize;
-            fs[id as usize].attr.used = fssize;
+            fs[id.0 as usize].attr.size = fssize;
+            fs[id.0 as usize].attr.used = fssize;
         }
         self.getattr(id).await
     }
@@ -156,14 +169,14 @@ impl NFSFileSystem for DemoFS {
         let newid: fileid3;
         {
             let mut fs = self.fs.lock().unwrap();
-            newid = fs.len() as fileid3;
+            newid = fileid3(fs.len() as u64);
             fs.push(make_file(
                 std::str::from_utf8(filename).unwrap(),
                 newid,
                 dirid,
                 "".as_bytes(),
             ));
-            if let FSContents::Directory(dir) = &mut fs[dirid as usize].contents {
+            if let FSContents::Directory(dir) = &mut fs[dirid.0 as usize].contents {
                 dir.push(newid);
             }
         }
@@ -180,7 +193,7 @@ impl NFSFileSystem for DemoFS {
 
     async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
         let fs = self.fs.lock().unwrap();
-        let entry = fs.get(dirid as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        let entry = fs.get(dirid.0 as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
         if let FSContents::File(_) = entry.contents {
             return Err(nfsstat3::NFS3ERR_NOTDIR);
         } else if let FSContents::Directory(dir) = &entry.contents {
@@ -193,7 +206,7 @@ impl NFSFileSystem for DemoFS {
                 return Ok(entry.parent);
             }
             for i in dir {
-                if let Some(f) = fs.get(*i as usize) {
+                if let Some(f) = fs.get(i.0 as usize) {
                     if f.name[..] == filename[..] {
                         return Ok(*i);
                     }
@@ -204,12 +217,12 @@ impl NFSFileSystem for DemoFS {
     }
     async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
         let fs = self.fs.lock().unwrap();
-        let entry = fs.get(id as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        let entry = fs.get(id.0 as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
         Ok(entry.attr)
     }
     async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
         let mut fs = self.fs.lock().unwrap();
-        let entry = fs.get_mut(id as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        let entry = fs.get_mut(id.0 as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
         match setattr.atime {
             nfs::set_atime::DONT_CHANGE => {}
             nfs::set_atime::SET_TO_CLIENT_TIME(c) => {
@@ -267,21 +280,32 @@ impl NFSFileSystem for DemoFS {
         offset: u64,
         count: u32,
     ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let mut buf = Vec::new();
+        let reply = self.read_ext(id, offset, count, &mut buf).await?;
+        Ok((buf, reply.eof))
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
         let fs = self.fs.lock().unwrap();
-        let entry = fs.get(id as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        let entry = fs.get(id.0 as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
         if let FSContents::Directory(_) = entry.contents {
             return Err(nfsstat3::NFS3ERR_ISDIR);
         } else if let FSContents::File(bytes) = &entry.contents {
-            let mut start = offset as usize;
-            let mut end = offset as usize + count as usize;
+            let start = (offset as usize).min(bytes.len());
+            let end = (offset as usize).saturating_add(count as usize);
             let eof = end >= bytes.len();
-            if start >= bytes.len() {
-                start = bytes.len();
-            }
-            if end > bytes.len() {
-                end = bytes.len();
-            }
-            return Ok((bytes[start..end].to_vec(), eof));
+            let end = end.min(bytes.len());
+            buf.extend_from_slice(&bytes[start..end]);
+            return Ok(ReadReply {
+                eof,
+                attr: Some(entry.attr),
+            });
         }
         Err(nfsstat3::NFS3ERR_NOENT)
     }
@@ -289,11 +313,11 @@ impl NFSFileSystem for DemoFS {
     async fn readdir(
         &self,
         dirid: fileid3,
-        start_after: fileid3,
+        start_after: cookie3,
         max_entries: usize,
     ) -> Result<ReadDirResult, nfsstat3> {
         let fs = self.fs.lock().unwrap();
-        let entry = fs.get(dirid as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        let entry = fs.get(dirid.0 as usize).ok_or(nfsstat3::NFS3ERR_NOENT)?;
         if let FSContents::File(_) = entry.contents {
             return Err(nfsstat3::NFS3ERR_NOTDIR);
         } else if let FSContents::Directory(dir) = &entry.contents {
@@ -302,8 +326,9 @@ impl NFSFileSystem for DemoFS {
                 end: false,
             };
             let mut start_index = 0;
-            if start_after > 0 {
-                if let Some(pos) = dir.iter().position(|&r| r == start_after) {
+            // the cookie of an entry is its fileid
+            if start_after > cookie3(0) {
+                if let Some(pos) = dir.iter().position(|&r| r.0 == start_after.0) {
                     start_index = pos + 1;
                 } else {
                     return Err(nfsstat3::NFS3ERR_BAD_COOKIE);
@@ -314,8 +339,9 @@ impl NFSFileSystem for DemoFS {
             for i in dir[start_index..].iter() {
                 ret.entries.push(DirEntry {
                     fileid: *i,
-                    name: fs[(*i) as usize].name.clone(),
-                    attr: fs[(*i) as usize].attr,
+                    name: fs[i.0 as usize].name.clone(),
+                    attr: fs[i.0 as usize].attr,
+                    cookie: cookie3(i.0),
                 });
                 if ret.entries.len() >= max_entries {
                     break;
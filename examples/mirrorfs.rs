@@ -1,11 +1,14 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::fs::Metadata;
 use std::io::SeekFrom;
 use std::ops::Bound;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use intaglio::osstr::SymbolTable;
@@ -14,10 +17,16 @@ use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tracing::debug;
 
+use nfsserve::fileid_alloc::path_hash;
 use nfsserve::fs_util::*;
 use nfsserve::nfs::*;
+use nfsserve::sync_batch::{SyncBatchConfig, SyncBatcher};
 use nfsserve::tcp::{NFSTcp, NFSTcpListener};
-use nfsserve::vfs::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities};
+use nfsserve::vfs::{
+    current_user, discard_unstable_writes, DirEntry, DirEntryPlus, FsStat, NFSFileSystem,
+    ReadDirPlusPage, ReadDirPlusResult, ReadDirResult, ReadReply, StableFh, VFSCapabilities,
+    WriteReply,
+};
 
 #[derive(Debug, Clone)]
 struct FSEntry {
@@ -26,17 +35,214 @@ struct FSEntry {
     /// metadata when building the children list
     children_meta: fattr3,
     children: Option<BTreeSet<fileid3>>,
+    /// set when the directory has too many entries to hold a children
+    /// set for. Such directories are listed directly from the backing
+    /// filesystem on every readdir, until a relisting (on a change of
+    /// children_meta) finds them under the cap again.
+    children_capped: bool,
+    /// The identity of the backing object. If the path comes to name
+    /// another object (deleted and recreated, or replaced by a rename
+    /// outside of NFS) the fileid is retired rather than reused, so old
+    /// handles go STALE instead of reaching the new object.
+    identity: Identity,
+}
+
+/// Syncs the data and metadata of the file at path
+async fn sync_file(path: &Path) -> std::io::Result<()> {
+    File::open(path).await?.sync_all().await
+}
+
+/// The (device, inode, birth time in nanoseconds) of a backing object.
+/// The birth time tells apart objects which reuse the inode of a deleted
+/// one; it is 0 where the filesystem does not record it.
+type Identity = (u64, u64, u64);
+
+fn identity(meta: &Metadata) -> Identity {
+    let born = meta
+        .created()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64);
+    (meta.dev(), meta.ino(), born)
+}
+
+/// The fileid of an object: a hash of its identity, so that the same
+/// object gets the same fileid every run and file handles survive a
+/// restart of the server. perturb is raised on collisions.
+fn identity_fileid(identity: Identity, perturb: u64) -> fileid3 {
+    let mut bytes = [0u8; 24];
+    bytes[0..8].copy_from_slice(&identity.0.to_le_bytes());
+    bytes[8..16].copy_from_slice(&identity.1.to_le_bytes());
+    bytes[16..24].copy_from_slice(&identity.2.to_le_bytes());
+    path_hash(&bytes, perturb)
 }
 
 #[derive(Debug)]
 struct FSMap {
     root: PathBuf,
-    next_fileid: AtomicU64,
     intern: SymbolTable,
     id_to_path: HashMap<fileid3, FSEntry>,
     path_to_id: HashMap<Vec<Symbol>, fileid3>,
+    /// The maximum number of entries a directory may have for us to
+    /// cache its children set.
+    max_cached_children: usize,
+    /// Records the ids we delete. Shared with MirrorFS::fh_to_id.
+    tombstones: Arc<Mutex<Tombstones>>,
+    /// The identity of each id. Shared with MirrorFS::id_to_fh and
+    /// MirrorFS::fh_to_id.
+    identities: Arc<Mutex<Identities>>,
+    /// The targets of recently read symlinks
+    links: SymlinkCache,
+    /// The entries only known from listing capped directories, and the
+    /// order they were listed in. See listed_entry.
+    listed_only: HashSet<fileid3>,
+    listed_order: VecDeque<fileid3>,
+    /// The listings of capped directories which stopped early, by the
+    /// directory and the cookie they resume after. See readdir_uncached.
+    dir_cursors: HashMap<(fileid3, cookie3), DirCursor>,
+    /// How modes are reported. Shared with MirrorFS::modes
+    modes: ModePolicy,
+}
+
+/// A listing of a directory left open to resume it
+#[derive(Debug)]
+struct DirCursor {
+    listing: tokio::fs::ReadDir,
+    /// The entry the last page stopped at, which was not taken
+    pending: Option<tokio::fs::DirEntry>,
+}
+
+/// The identity of each fileid and back, readable without the fsmap
+/// lock. File handles carry the identity, so that after a restart the
+/// object of a handle can be found again.
+#[derive(Debug, Default)]
+struct Identities {
+    by_id: HashMap<fileid3, Identity>,
+    by_identity: HashMap<Identity, fileid3>,
+}
+
+impl Identities {
+    fn insert(&mut self, id: fileid3, identity: Identity) {
+        self.by_id.insert(id, identity);
+        self.by_identity.insert(identity, id);
+    }
+
+    fn forget(&mut self, id: fileid3) {
+        if let Some(identity) = self.by_id.remove(&id) {
+            // hard links share the identity
+            if self.by_identity.get(&identity) == Some(&id) {
+                self.by_identity.remove(&identity);
+            }
+        }
+    }
+}
+
+/// The maximum number of entries walked to find the object of a file
+/// handle made by an earlier run of the server
+const MAX_RESOLVE_ENTRIES: usize = 100_000;
+
+/// Walks the tree under root (breadth first, not following symlinks) for
+/// the object of the given identity. Returns its path relative to root.
+fn find_identity(root: &Path, wanted: Identity) -> Option<(Vec<OsString>, Metadata)> {
+    let mut queue = VecDeque::from([Vec::<OsString>::new()]);
+    let mut walked = 0;
+    while let Some(dir) = queue.pop_front() {
+        let dirpath: PathBuf = std::iter::once(root.as_os_str())
+            .chain(dir.iter().map(|c| c.as_os_str()))
+            .collect();
+        let Ok(listing) = std::fs::read_dir(&dirpath) else {
+            continue;
+        };
+        for dirent in listing.flatten() {
+            walked += 1;
+            if walked > MAX_RESOLVE_ENTRIES {
+                return None;
+            }
+            let Ok(meta) = dirent.path().symlink_metadata() else {
+                continue;
+            };
+            let mut name = dir.clone();
+            name.push(dirent.file_name());
+            if identity(&meta) == wanted {
+                return Some((name, meta));
+            }
+            if meta.is_dir() {
+                queue.push_back(name);
+            }
+        }
+    }
+    None
+}
+
+/// How long a deleted fileid is remembered
+const TOMBSTONE_TTL: Duration = Duration::from_secs(600);
+/// The maximum number of deleted fileids remembered
+const MAX_TOMBSTONES: usize = 100_000;
+
+/// Why a fileid was deleted
+#[derive(Debug, Clone, Copy)]
+enum TombstoneReason {
+    /// Removed through REMOVE or RMDIR
+    Removed,
+    /// Found missing from the backing filesystem
+    Vanished,
+    /// Replaced by an object of another type in the backing filesystem
+    TypeChanged,
+    /// Replaced by the source of a RENAME
+    RenamedOver,
+    /// Replaced by another object in the backing filesystem
+    Replaced,
+}
+
+/// The recently deleted fileids. Clients may hold handles to them for a
+/// long time, and some (macOS) retry STALE aggressively on directories.
+/// Remembering the deleted ids lets fh_to_id refuse those handles right
+/// away, without touching the main maps.
+#[derive(Debug, Default)]
+struct Tombstones {
+    entries: HashMap<fileid3, (Instant, TombstoneReason)>,
+    /// insertion order, for expiry
+    order: VecDeque<(Instant, fileid3)>,
+}
+
+impl Tombstones {
+    fn insert(&mut self, id: fileid3, reason: TombstoneReason) {
+        let now = Instant::now();
+        while let Some(&(t, oldid)) = self.order.front() {
+            if now.duration_since(t) < TOMBSTONE_TTL && self.order.len() < MAX_TOMBSTONES {
+                break;
+            }
+            self.order.pop_front();
+            if self.entries.get(&oldid).is_some_and(|(et, _)| *et == t) {
+                self.entries.remove(&oldid);
+            }
+        }
+        self.entries.insert(id, (now, reason));
+        self.order.push_back((now, id));
+    }
+
+    fn remove(&mut self, id: fileid3) {
+        self.entries.remove(&id);
+    }
+
+    fn get(&self, id: fileid3) -> Option<TombstoneReason> {
+        self.entries
+            .get(&id)
+            .filter(|(t, _)| t.elapsed() < TOMBSTONE_TTL)
+            .map(|(_, reason)| *reason)
+    }
 }
 
+/// The default cap on the number of children we will cache for a single
+/// directory.
+const DEFAULT_MAX_CACHED_CHILDREN: usize = 100_000;
+
+/// The number of symlink targets cached
+const SYMLINK_CACHE_SIZE: usize = 10_000;
+
+/// The number of listings of capped directories kept open to resume
+const MAX_DIR_CURSORS: usize = 64;
+
 enum RefreshResult {
     /// The fileid was deleted
     Delete,
@@ -48,20 +254,52 @@ enum RefreshResult {
 }
 
 impl FSMap {
-    fn new(root: PathBuf) -> FSMap {
+    fn new(
+        root: PathBuf,
+        max_cached_children: usize,
+        tombstones: Arc<Mutex<Tombstones>>,
+        identities: Arc<Mutex<Identities>>,
+    ) -> FSMap {
         // create root entry
+        let root_meta = root.metadata().unwrap();
         let root_entry = FSEntry {
             name: Vec::new(),
-            fsmeta: metadata_to_fattr3(1, &root.metadata().unwrap()),
-            children_meta: metadata_to_fattr3(1, &root.metadata().unwrap()),
+            fsmeta: metadata_to_fattr3(fileid3(1), &root_meta),
+            children_meta: metadata_to_fattr3(fileid3(1), &root_meta),
             children: None,
+            children_capped: false,
+            identity: identity(&root_meta),
         };
+        identities
+            .lock()
+            .unwrap()
+            .insert(fileid3(0), root_entry.identity);
         FSMap {
             root,
-            next_fileid: AtomicU64::new(1),
             intern: SymbolTable::new(),
-            id_to_path: HashMap::from([(0, root_entry)]),
-            path_to_id: HashMap::from([(Vec::new(), 0)]),
+            id_to_path: HashMap::from([(fileid3(0), root_entry)]),
+            path_to_id: HashMap::from([(Vec::new(), fileid3(0))]),
+            max_cached_children,
+            tombstones,
+            identities,
+            links: SymlinkCache::new(SYMLINK_CACHE_SIZE),
+            listed_only: HashSet::new(),
+            listed_order: VecDeque::new(),
+            dir_cursors: HashMap::new(),
+            modes: ModePolicy::default(),
+        }
+    }
+
+    /// Reports modes under the given policy from now on, the root
+    /// included
+    fn set_modes(&mut self, modes: ModePolicy) {
+        self.modes = modes;
+        let Ok(root_meta) = self.root.metadata() else {
+            return;
+        };
+        if let Some(root_entry) = self.id_to_path.get_mut(&fileid3(0)) {
+            root_entry.fsmeta = modes.metadata_to_fattr3(fileid3(1), &root_meta);
+            root_entry.children_meta = root_entry.fsmeta;
         }
     }
     async fn sym_to_path(&self, symlist: &[Symbol]) -> PathBuf {
@@ -91,12 +329,17 @@ impl FSMap {
         }
     }
 
-    fn delete_entry(&mut self, id: fileid3) {
+    fn delete_entry(&mut self, id: fileid3, reason: TombstoneReason) {
         let mut children = Vec::new();
         self.collect_all_children(id, &mut children);
+        let mut tombstones = self.tombstones.lock().unwrap();
+        let mut identities = self.identities.lock().unwrap();
         for i in children.iter() {
             if let Some(ent) = self.id_to_path.remove(i) {
                 self.path_to_id.remove(&ent.name);
+                tombstones.insert(*i, reason);
+                identities.forget(*i);
+                self.links.invalidate(*i);
             }
         }
     }
@@ -134,7 +377,7 @@ impl FSMap {
         let path = self.sym_to_path(&entry.name).await;
         //
         if !exists_no_traverse(&path) {
-            self.delete_entry(id);
+            self.delete_entry(id, TombstoneReason::Vanished);
             debug!("Deleting entry A {:?}: {:?}. Ent: {:?}", id, path, entry);
             return Ok(RefreshResult::Delete);
         }
@@ -142,7 +385,18 @@ impl FSMap {
         let meta = tokio::fs::symlink_metadata(&path)
             .await
             .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-        let meta = metadata_to_fattr3(id, &meta);
+        // the root is served whatever it is
+        if identity(&meta) != entry.identity && !entry.name.is_empty() {
+            debug!(
+                "Replaced {:?}: {:?} vs {:?}",
+                id,
+                entry.identity,
+                identity(&meta)
+            );
+            self.delete_entry(id, TombstoneReason::Replaced);
+            return Ok(RefreshResult::Delete);
+        }
+        let meta = self.modes.metadata_to_fattr3(id, &meta);
         if !fattr3_differ(&meta, &entry.fsmeta) {
             return Ok(RefreshResult::Noop);
         }
@@ -159,13 +413,14 @@ impl FSMap {
                 "File Type Mismatch META {:?} : {:?} vs {:?}",
                 id, entry.fsmeta, meta
             );
-            self.delete_entry(id);
+            self.delete_entry(id, TombstoneReason::TypeChanged);
             debug!("Deleting entry B {:?}: {:?}. Ent: {:?}", id, path, entry);
             return Ok(RefreshResult::Delete);
         }
         // inplace modification.
         // update metadata
         self.id_to_path.get_mut(&id).unwrap().fsmeta = meta;
+        self.links.invalidate(id);
         debug!("Reloading entry {:?}: {:?}. Ent: {:?}", id, path, entry);
         Ok(RefreshResult::Reload)
     }
@@ -175,8 +430,11 @@ impl FSMap {
             .get(&id)
             .ok_or(nfsstat3::NFS3ERR_NOENT)?
             .clone();
-        // if there are children and the metadata did not change
-        if entry.children.is_some() && !fattr3_differ(&entry.children_meta, &entry.fsmeta) {
+        // if the children (or that there are too many of them) are known
+        // and the metadata did not change
+        if (entry.children.is_some() || entry.children_capped)
+            && !fattr3_differ(&entry.children_meta, &entry.fsmeta)
+        {
             return Ok(());
         }
         if !matches!(entry.fsmeta.ftype, ftype3::NF3DIR) {
@@ -184,58 +442,258 @@ impl FSMap {
         }
         let mut cur_path = entry.name.clone();
         let path = self.sym_to_path(&entry.name).await;
-        let mut new_children: Vec<u64> = Vec::new();
         debug!("Relisting entry {:?}: {:?}. Ent: {:?}", id, path, entry);
         if let Ok(mut listing) = tokio::fs::read_dir(&path).await {
-            while let Some(entry) = listing
+            // entries are only created once the directory is known to be
+            // under the cap
+            let mut listed = Vec::new();
+            while let Some(dirent) = listing
                 .next_entry()
                 .await
                 .map_err(|_| nfsstat3::NFS3ERR_IO)?
             {
-                let sym = self.intern.intern(entry.file_name()).unwrap();
-                cur_path.push(sym);
-                let meta = entry.metadata().await.unwrap();
-                let next_id = self.create_entry(&cur_path, meta).await;
-                new_children.push(next_id);
+                if listed.len() >= self.max_cached_children {
+                    debug!(
+                        "Directory {:?} has more than {:?} entries. Not caching children",
+                        path, self.max_cached_children
+                    );
+                    let dirent = self.find_entry_mut(id)?;
+                    dirent.children = None;
+                    dirent.children_capped = true;
+                    dirent.children_meta = entry.fsmeta;
+                    return Ok(());
+                }
+                let meta = dirent.metadata().await.unwrap();
+                listed.push((dirent.file_name(), meta));
+            }
+            let mut new_children = BTreeSet::new();
+            for (name, meta) in listed {
+                cur_path.push(self.intern.intern(name).unwrap());
+                new_children.insert(self.create_entry(&cur_path, meta));
                 cur_path.pop();
             }
-            self.id_to_path
-                .get_mut(&id)
-                .ok_or(nfsstat3::NFS3ERR_NOENT)?
-                .children = Some(BTreeSet::from_iter(new_children.into_iter()));
+            debug!("Cached {:?} children of {:?}", new_children.len(), path);
+            let dirent = self.find_entry_mut(id)?;
+            dirent.children = Some(new_children);
+            dirent.children_capped = false;
+            dirent.children_meta = entry.fsmeta;
         }
 
         Ok(())
     }
 
-    async fn create_entry(&mut self, fullpath: &Vec<Symbol>, meta: Metadata) -> fileid3 {
+    fn create_entry(&mut self, fullpath: &Vec<Symbol>, meta: Metadata) -> fileid3 {
+        let next_id = self.create_entry_inner(fullpath, meta);
+        // known other than from a listing now. See listed_entry.
+        self.listed_only.remove(&next_id);
+        next_id
+    }
+
+    /// create_entry for an entry of a capped directory. Entries only
+    /// known from such listings are forgotten past max_cached_children
+    /// of them, oldest listed first, so that listing huge directories
+    /// does not grow the maps without bound.
+    fn listed_entry(&mut self, fullpath: &Vec<Symbol>, meta: Metadata) -> fileid3 {
+        let before = self.path_to_id.get(fullpath).copied();
+        let listed_before = before.is_some_and(|id| self.listed_only.contains(&id));
+        let id = self.create_entry_inner(fullpath, meta);
+        if before == Some(id) && !listed_before {
+            return id;
+        }
+        // a new entry, or one still only known from listings, which keeps
+        // its place in listed_order
+        if self.listed_only.insert(id) && before != Some(id) {
+            self.listed_order.push_back(id);
+        }
+        while self.listed_only.len() > self.max_cached_children {
+            let Some(old) = self.listed_order.pop_front() else {
+                break;
+            };
+            if self.listed_only.remove(&old) {
+                self.forget_entry(old);
+            }
+        }
+        // drop the ids which have been created otherwise since
+        if self.listed_order.len() > 2 * self.max_cached_children {
+            let listed_only = &self.listed_only;
+            self.listed_order.retain(|id| listed_only.contains(id));
+        }
+        id
+    }
+
+    /// Drops an entry without a tombstone. Its handles are then resolved
+    /// again by identity, see MirrorFS::resolve_identity.
+    fn forget_entry(&mut self, id: fileid3) {
+        if let Some(ent) = self.id_to_path.remove(&id) {
+            self.path_to_id.remove(&ent.name);
+            self.identities.lock().unwrap().forget(id);
+            self.links.invalidate(id);
+        }
+    }
+
+    fn create_entry_inner(&mut self, fullpath: &Vec<Symbol>, meta: Metadata) -> fileid3 {
+        if let Some(&chid) = self.path_to_id.get(fullpath) {
+            let replaced = self
+                .id_to_path
+                .get(&chid)
+                .is_some_and(|chent| chent.identity != identity(&meta));
+            if replaced {
+                debug!("Replaced {:?}: {:?}", chid, fullpath);
+                self.delete_entry(chid, TombstoneReason::Replaced);
+            }
+        }
         let next_id = if let Some(chid) = self.path_to_id.get(fullpath) {
             if let Some(chent) = self.id_to_path.get_mut(chid) {
-                chent.fsmeta = metadata_to_fattr3(*chid, &meta);
+                chent.fsmeta = self.modes.metadata_to_fattr3(*chid, &meta);
             }
             *chid
         } else {
-            // path does not exist
-            let next_id = self.next_fileid.fetch_add(1, Ordering::Relaxed);
-            let metafattr = metadata_to_fattr3(next_id, &meta);
+            // path does not exist. Hard links (several paths to one
+            // identity) get distinct fileids by perturbation.
+            let mut perturb = 0;
+            let mut next_id = identity_fileid(identity(&meta), perturb);
+            while self.id_to_path.contains_key(&next_id) {
+                perturb += 1;
+                next_id = identity_fileid(identity(&meta), perturb);
+            }
+            // the object may have been seen gone before, e.g. renamed
+            // away and back outside of NFS
+            self.tombstones.lock().unwrap().remove(next_id);
+            let metafattr = self.modes.metadata_to_fattr3(next_id, &meta);
             let new_entry = FSEntry {
                 name: fullpath.clone(),
                 fsmeta: metafattr,
                 children_meta: metafattr,
                 children: None,
+                children_capped: false,
+                identity: identity(&meta),
             };
             debug!("creating new entry {:?}: {:?}", next_id, meta);
+            self.identities
+                .lock()
+                .unwrap()
+                .insert(next_id, new_entry.identity);
             self.id_to_path.insert(next_id, new_entry);
             self.path_to_id.insert(fullpath.clone(), next_id);
             next_id
         };
         next_id
     }
+
+    /// Lists a directory with a capped children set directly from the
+    /// backing filesystem. Entries are returned in the order the OS
+    /// lists them. A listing which stops early is kept as a cursor, so
+    /// that the next page resumes from it rather than reading the
+    /// directory again from the start.
+    async fn readdir_uncached(
+        &mut self,
+        dirid: fileid3,
+        start_after: cookie3,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
+        let entry = self.find_entry(dirid)?;
+        let DirCursor {
+            mut listing,
+            mut pending,
+        } = match self.dir_cursors.remove(&(dirid, start_after)) {
+            Some(cursor) => cursor,
+            None => self.open_uncached(&entry, start_after).await?,
+        };
+        let mut cur_path = entry.name.clone();
+        let mut last = start_after;
+        loop {
+            let dirent = match pending.take() {
+                Some(dirent) => dirent,
+                None => match listing
+                    .next_entry()
+                    .await
+                    .map_err(|_| nfsstat3::NFS3ERR_IO)?
+                {
+                    Some(dirent) => dirent,
+                    None => return Ok(true),
+                },
+            };
+            let name = dirent.file_name();
+            let meta = dirent.metadata().await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            cur_path.push(self.intern.intern(name.clone()).unwrap());
+            let fileid = self.listed_entry(&cur_path, meta);
+            cur_path.pop();
+            let taken = sink(DirEntry {
+                fileid,
+                name: name.as_bytes().into(),
+                attr: self.find_entry(fileid)?.fsmeta,
+                cookie: cookie3(fileid.0),
+            });
+            if !taken {
+                if self.dir_cursors.len() >= MAX_DIR_CURSORS {
+                    // make room, dropping an arbitrary one
+                    if let Some(&key) = self.dir_cursors.keys().next() {
+                        self.dir_cursors.remove(&key);
+                    }
+                }
+                let cursor = DirCursor {
+                    listing,
+                    pending: Some(dirent),
+                };
+                self.dir_cursors.insert((dirid, last), cursor);
+                return Ok(false);
+            }
+            last = cookie3(fileid.0);
+        }
+    }
+
+    /// Opens the listing of a capped directory, positioned after the
+    /// entry of start_after
+    async fn open_uncached(
+        &self,
+        entry: &FSEntry,
+        start_after: cookie3,
+    ) -> Result<DirCursor, nfsstat3> {
+        let path = self.sym_to_path(&entry.name).await;
+        let mut listing = tokio::fs::read_dir(&path)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        if start_after > cookie3(0) {
+            let ent = self
+                .find_entry(fileid3(start_after.0))
+                .or(Err(nfsstat3::NFS3ERR_BAD_COOKIE))?;
+            let after = self.sym_to_fname(&ent.name).await;
+            loop {
+                match listing
+                    .next_entry()
+                    .await
+                    .map_err(|_| nfsstat3::NFS3ERR_IO)?
+                {
+                    Some(dirent) if dirent.file_name() == after => break,
+                    Some(_) => continue,
+                    // the entry we were to resume after is gone
+                    None => return Err(nfsstat3::NFS3ERR_BAD_COOKIE),
+                }
+            }
+        }
+        Ok(DirCursor {
+            listing,
+            pending: None,
+        })
+    }
 }
 #[derive(Debug)]
 pub struct MirrorFS {
-    fsmap: tokio::sync::Mutex<FSMap>,
+    fsmap: Arc<tokio::sync::Mutex<FSMap>>,
+    tombstones: Arc<Mutex<Tombstones>>,
+    identities: Arc<Mutex<Identities>>,
+    /// The mirrored directory. Also in fsmap, but needed without its lock.
+    root: PathBuf,
+    /// The number of handles refused because their fileid was deleted
+    stale_hits: AtomicU64,
+    /// Orders the writes to each file. See write_ext
+    write_locks: Mutex<HashMap<fileid3, Arc<tokio::sync::Mutex<()>>>>,
+    /// Syncs small unstable writes in batches. See with_sync_batching
+    sync_batcher: Option<SyncBatcher>,
+    /// How modes are set and reported. See with_force_owner_writable
+    modes: ModePolicy,
 }
+nfsserve::assert_vfs!(MirrorFS);
 
 /// Enumeration for the create_fs_object method
 enum CreateFSObject {
@@ -247,14 +705,171 @@ enum CreateFSObject {
     Exclusive,
     /// Creates a symlink with a set of attributes to a target location
     Symlink((sattr3, nfspath3)),
+    /// Creates a named pipe with a set of attributes
+    Fifo(sattr3),
 }
 impl MirrorFS {
     pub fn new(root: PathBuf) -> MirrorFS {
+        MirrorFS::with_max_cached_children(root, DEFAULT_MAX_CACHED_CHILDREN)
+    }
+
+    /// Directories with more than max_cached_children entries do not
+    /// have their children cached, and are instead listed from the
+    /// backing filesystem on every readdir.
+    pub fn with_max_cached_children(root: PathBuf, max_cached_children: usize) -> MirrorFS {
+        let tombstones = Arc::new(Mutex::new(Tombstones::default()));
+        let identities = Arc::new(Mutex::new(Identities::default()));
         MirrorFS {
-            fsmap: tokio::sync::Mutex::new(FSMap::new(root)),
+            fsmap: Arc::new(tokio::sync::Mutex::new(FSMap::new(
+                root.clone(),
+                max_cached_children,
+                tombstones.clone(),
+                identities.clone(),
+            ))),
+            tombstones,
+            identities,
+            root,
+            stale_hits: AtomicU64::new(0),
+            write_locks: Mutex::new(HashMap::new()),
+            sync_batcher: None,
+            modes: ModePolicy::default(),
         }
     }
 
+    /// Adds the owner write bit to every mode set or reported, so that a
+    /// server which does not run as root can write to the files it
+    /// creates read-only. Off by default: modes are applied as sent.
+    pub fn with_force_owner_writable(mut self, enable: bool) -> MirrorFS {
+        self.modes.force_owner_writable = enable;
+        self.fsmap
+            .try_lock()
+            .expect("not serving yet")
+            .set_modes(self.modes);
+        self
+    }
+
+    /// Enables the small file fast path: UNSTABLE writes of at most
+    /// config.max_write bytes are synced in batches from a background
+    /// task (see SyncBatcher), so that most files are already synced
+    /// when they are committed. With NFSTcp::set_relaxed_durability
+    /// small FILE_SYNC writes are batched too.
+    pub fn with_sync_batching(mut self, config: SyncBatchConfig) -> MirrorFS {
+        let fsmap = Arc::downgrade(&self.fsmap);
+        self.sync_batcher = Some(SyncBatcher::new(config, move |id| {
+            let fsmap = fsmap.clone();
+            async move {
+                let Some(fsmap) = fsmap.upgrade() else {
+                    return Ok(());
+                };
+                let fsmap = fsmap.lock().await;
+                // a file removed since it was written has nothing to sync
+                let Ok(ent) = fsmap.find_entry(id) else {
+                    return Ok(());
+                };
+                let path = fsmap.sym_to_path(&ent.name).await;
+                drop(fsmap);
+                sync_file(&path).await
+            }
+        }));
+        self
+    }
+
+    /// The number of file handles refused as stale because their fileid
+    /// was recently deleted. A count which keeps growing points at a
+    /// client stuck retrying a dead handle.
+    pub fn stale_tombstone_hits(&self) -> u64 {
+        self.stale_hits.load(Ordering::Relaxed)
+    }
+
+    /// Finds the object of a handle the fsmap does not know (made by an
+    /// earlier run of the server) and enters it, under the same fileid
+    /// unless it collides. The walk is bounded by MAX_RESOLVE_ENTRIES, and
+    /// replied JUKEBOX (the client retries later) rather than waited for
+    /// if the fsmap is busy, as fh_to_id cannot await.
+    fn resolve_identity(&self, wanted: Identity) -> Result<fileid3, nfsstat3> {
+        let (components, meta) =
+            find_identity(&self.root, wanted).ok_or(nfsstat3::NFS3ERR_STALE)?;
+        let mut fsmap = self
+            .fsmap
+            .try_lock()
+            .map_err(|_| nfsstat3::NFS3ERR_JUKEBOX)?;
+        let name: Vec<Symbol> = components
+            .into_iter()
+            .map(|c| fsmap.intern.intern(c).unwrap())
+            .collect();
+        let id = fsmap.create_entry(&name, meta);
+        debug!("Resolved handle of {:?} to {:?}", wanted, id);
+        Ok(id)
+    }
+
+    /// write_ext, with the write lock of id held
+    async fn write_locked(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+        let path = fsmap.sym_to_path(&ent.name).await;
+        drop(fsmap);
+        if offset.checked_add(data.len() as u64).is_none() {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        debug!("write to init {:?}", path);
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                debug!("Unable to open {:?}", e);
+                nfsstat3::NFS3ERR_IO
+            })?;
+        let meta = f.metadata().await.or(Err(nfsstat3::NFS3ERR_IO))?;
+        let before = self.modes.metadata_to_fattr3(id, &meta);
+        let before = wcc_attr {
+            size: before.size,
+            mtime: before.mtime,
+            ctime: before.ctime,
+        };
+        f.seek(SeekFrom::Start(offset)).await.map_err(|e| {
+            debug!("Unable to seek {:?}", e);
+            nfsstat3::NFS3ERR_IO
+        })?;
+        f.write_all(data).await.map_err(|e| {
+            debug!("Unable to write {:?}", e);
+            nfsstat3::NFS3ERR_IO
+        })?;
+        debug!("write to {:?} {:?} {:?}", path, offset, data.len());
+        let _ = f.flush().await;
+        // unstable writes are left to the page cache until COMMIT, or
+        // until their batch is synced
+        let synced = match stable {
+            stable_how::UNSTABLE => {
+                if let Some(batcher) = &self.sync_batcher {
+                    batcher.add(id, data.len());
+                }
+                Ok(())
+            }
+            stable_how::DATA_SYNC => f.sync_data().await,
+            stable_how::FILE_SYNC => f.sync_all().await,
+        };
+        if let Err(e) = synced {
+            // a failed flush may have dropped earlier unstable writes too
+            discard_unstable_writes();
+            return Err(io_error_to_nfsstat3(&e));
+        }
+        let meta = f.metadata().await.or(Err(nfsstat3::NFS3ERR_IO))?;
+        Ok(WriteReply {
+            before: Some(before),
+            attr: self.modes.metadata_to_fattr3(id, &meta),
+            committed: stable,
+        })
+    }
+
     /// creates a FS object in a given directory and of a given type
     /// Updates as much metadata as we can in-place
     async fn create_fs_object(
@@ -263,6 +878,9 @@ impl MirrorFS {
         objectname: &filename3,
         object: &CreateFSObject,
     ) -> Result<(fileid3, fattr3), nfsstat3> {
+        // The map lock is held from the existence check to the children
+        // update, so concurrent creates of the same name are serialized and
+        // the second one finds (and reuses) the fileid of the first.
         let mut fsmap = self.fsmap.lock().await;
         let ent = fsmap.find_entry(dirid)?;
         let mut path = fsmap.sym_to_path(&ent.name).await;
@@ -277,12 +895,12 @@ impl MirrorFS {
                 }
                 tokio::fs::create_dir(&path)
                     .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+                    .map_err(|e| io_error_to_nfsstat3(&e))?;
             }
             CreateFSObject::File(setattr) => {
                 debug!("create {:?}", path);
-                let file = std::fs::File::create(&path).map_err(|_| nfsstat3::NFS3ERR_IO)?;
-                let _ = file_setattr(&file, setattr).await;
+                let file = std::fs::File::create(&path).map_err(|e| io_error_to_nfsstat3(&e))?;
+                let _ = self.modes.file_setattr(&file, setattr).await;
             }
             CreateFSObject::Exclusive => {
                 debug!("create exclusive {:?}", path);
@@ -299,9 +917,40 @@ impl MirrorFS {
                 }
                 tokio::fs::symlink(OsStr::from_bytes(target), &path)
                     .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+                    .map_err(|e| io_error_to_nfsstat3(&e))?;
                 // we do not set attributes on symlinks
             }
+            CreateFSObject::Fifo(setattr) => {
+                debug!("mkfifo {:?}", path);
+                let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())
+                    .map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+                // the mode is applied below, like the rest of the attributes
+                if unsafe { libc::mkfifo(cpath.as_ptr(), 0o644) } != 0 {
+                    return Err(io_error_to_nfsstat3(&std::io::Error::last_os_error()));
+                }
+                let _ = self.modes.path_setattr(&path, setattr).await;
+            }
+        }
+
+        // New objects belong to the caller (after squashing), unless the
+        // attributes set an owner. This only succeeds if the server runs
+        // as root, otherwise they stay owned by the server.
+        if let Some(user) = current_user() {
+            let attr = match object {
+                CreateFSObject::File(attr)
+                | CreateFSObject::Fifo(attr)
+                | CreateFSObject::Symlink((attr, _)) => Some(attr),
+                _ => None,
+            };
+            let uid = match attr.map(|a| a.uid) {
+                Some(set_uid3::uid(_)) => None,
+                _ => Some(user.uid),
+            };
+            let gid = match attr.map(|a| a.gid) {
+                Some(set_gid3::gid(_)) => None,
+                _ => Some(user.gid),
+            };
+            let _ = std::os::unix::fs::lchown(&path, uid, gid);
         }
 
         let _ = fsmap.refresh_entry(dirid).await;
@@ -310,25 +959,26 @@ impl MirrorFS {
         let mut name = ent.name.clone();
         name.push(sym);
         let meta = path.symlink_metadata().map_err(|_| nfsstat3::NFS3ERR_IO)?;
-        let fileid = fsmap.create_entry(&name, meta.clone()).await;
+        let fileid = fsmap.create_entry(&name, meta.clone());
 
-        // update the children list
-        if let Some(ref mut children) = fsmap
+        // update the children list. If the refresh above dropped the
+        // directory entry or its children, the next readdir relists it and
+        // picks up the new object, and the create itself has succeeded.
+        if let Some(children) = fsmap
             .id_to_path
             .get_mut(&dirid)
-            .ok_or(nfsstat3::NFS3ERR_NOENT)?
-            .children
+            .and_then(|dirent| dirent.children.as_mut())
         {
             children.insert(fileid);
         }
-        Ok((fileid, metadata_to_fattr3(fileid, &meta)))
+        Ok((fileid, self.modes.metadata_to_fattr3(fileid, &meta)))
     }
 }
 
 #[async_trait]
 impl NFSFileSystem for MirrorFS {
     fn root_dir(&self) -> fileid3 {
-        0
+        fileid3(0)
     }
     fn capabilities(&self) -> VFSCapabilities {
         VFSCapabilities::ReadWrite
@@ -338,6 +988,21 @@ impl NFSFileSystem for MirrorFS {
         let mut fsmap = self.fsmap.lock().await;
         if let Ok(id) = fsmap.find_child(dirid, filename).await {
             if fsmap.id_to_path.contains_key(&id) {
+                if !is_silly_rename(filename) {
+                    return Ok(id);
+                }
+                // silly renamed files are removed soon after they appear,
+                // possibly through another client: trust only the disk
+                if let RefreshResult::Delete = fsmap.refresh_entry(id).await? {
+                    // drop it from the listing too, or it lingers until
+                    // the directory is relisted
+                    if let Ok(dirent_mut) = fsmap.find_entry_mut(dirid) {
+                        if let Some(ref mut children) = dirent_mut.children {
+                            children.remove(&id);
+                        }
+                    }
+                    return Err(nfsstat3::NFS3ERR_NOENT);
+                }
                 return Ok(id);
             }
         }
@@ -355,7 +1020,7 @@ impl NFSFileSystem for MirrorFS {
         // refresh.
 
         if let RefreshResult::Delete = fsmap.refresh_entry(dirid).await? {
-            return Err(nfsstat3::NFS3ERR_NOENT);
+            return Err(nfsstat3::NFS3ERR_STALE);
         }
         let _ = fsmap.refresh_dir_list(dirid).await;
 
@@ -369,7 +1034,7 @@ impl NFSFileSystem for MirrorFS {
         //debug!("Stat query {:?}", id);
         let mut fsmap = self.fsmap.lock().await;
         if let RefreshResult::Delete = fsmap.refresh_entry(id).await? {
-            return Err(nfsstat3::NFS3ERR_NOENT);
+            return Err(nfsstat3::NFS3ERR_STALE);
         }
         let ent = fsmap.find_entry(id)?;
         let path = fsmap.sym_to_path(&ent.name).await;
@@ -383,35 +1048,77 @@ impl NFSFileSystem for MirrorFS {
         offset: u64,
         count: u32,
     ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let mut buf = Vec::new();
+        let reply = self.read_ext(id, offset, count, &mut buf).await?;
+        Ok((buf, reply.eof))
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
         let fsmap = self.fsmap.lock().await;
         let ent = fsmap.find_entry(id)?;
         let path = fsmap.sym_to_path(&ent.name).await;
         drop(fsmap);
         let mut f = File::open(&path).await.or(Err(nfsstat3::NFS3ERR_NOENT))?;
-        let len = f.metadata().await.or(Err(nfsstat3::NFS3ERR_NOENT))?.len();
-        let mut start = offset;
-        let mut end = offset + count as u64;
+        // the read is bounded by the length in this snapshot, so these
+        // attributes describe the data returned
+        let meta = f.metadata().await.or(Err(nfsstat3::NFS3ERR_NOENT))?;
+        let len = meta.len();
+        let start = offset.min(len);
+        let end = offset.saturating_add(count as u64);
         let eof = end >= len;
-        if start >= len {
-            start = len;
-        }
-        if end > len {
-            end = len;
-        }
+        let end = end.min(len);
         f.seek(SeekFrom::Start(start))
             .await
             .or(Err(nfsstat3::NFS3ERR_IO))?;
-        let mut buf = vec![0; (end - start) as usize];
-        f.read_exact(&mut buf).await.or(Err(nfsstat3::NFS3ERR_IO))?;
-        Ok((buf, eof))
+        // holes read back as zeros. If the file shrank since the metadata
+        // was read the read comes up short, and is then the end of the file
+        let n = (&mut f)
+            .take(end - start)
+            .read_to_end(buf)
+            .await
+            .or(Err(nfsstat3::NFS3ERR_IO))?;
+        let eof = eof || (n as u64) < end - start;
+        Ok(ReadReply {
+            eof,
+            attr: Some(self.modes.metadata_to_fattr3(id, &meta)),
+        })
     }
 
     async fn readdir(
         &self,
         dirid: fileid3,
-        start_after: fileid3,
+        start_after: cookie3,
         max_entries: usize,
     ) -> Result<ReadDirResult, nfsstat3> {
+        let mut ret = ReadDirResult::default();
+        ret.end = self
+            .readdir_each(dirid, start_after, max_entries, &mut |entry| {
+                if ret.entries.len() >= max_entries {
+                    return false;
+                }
+                ret.entries.push(entry);
+                true
+            })
+            .await?;
+        debug!("readdir_result:{:?}", ret);
+        Ok(ret)
+    }
+
+    /// Lists without collecting the entries, so that READDIRPLUS only
+    /// costs the stats of capped directories for the entries it sends
+    async fn readdir_each(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        _max_entries: usize,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
         let mut fsmap = self.fsmap.lock().await;
         fsmap.refresh_entry(dirid).await?;
         fsmap.refresh_dir_list(dirid).await?;
@@ -421,89 +1128,173 @@ impl NFSFileSystem for MirrorFS {
             return Err(nfsstat3::NFS3ERR_NOTDIR);
         }
         debug!("readdir({:?}, {:?})", entry, start_after);
+        if entry.children_capped {
+            return fsmap.readdir_uncached(dirid, start_after, sink).await;
+        }
         // we must have children here
         let children = entry.children.ok_or(nfsstat3::NFS3ERR_IO)?;
 
-        let mut ret = ReadDirResult {
-            entries: Vec::new(),
-            end: false,
-        };
-
-        let range_start = if start_after > 0 {
-            Bound::Excluded(start_after)
+        // the cookie of an entry is its fileid
+        let range_start = if start_after > cookie3(0) {
+            Bound::Excluded(fileid3(start_after.0))
         } else {
             Bound::Unbounded
         };
 
-        let remaining_length = children.range((range_start, Bound::Unbounded)).count();
         let path = fsmap.sym_to_path(&entry.name).await;
         debug!("path: {:?}", path);
         debug!("children len: {:?}", children.len());
-        debug!("remaining_len : {:?}", remaining_length);
         for i in children.range((range_start, Bound::Unbounded)) {
             let fileid = *i;
             let fileent = fsmap.find_entry(fileid)?;
             let name = fsmap.sym_to_fname(&fileent.name).await;
             debug!("\t --- {:?} {:?}", fileid, name);
-            ret.entries.push(DirEntry {
+            let taken = sink(DirEntry {
                 fileid,
                 name: name.as_bytes().into(),
                 attr: fileent.fsmeta,
+                cookie: cookie3(fileid.0),
             });
-            if ret.entries.len() >= max_entries {
-                break;
+            if !taken {
+                return Ok(false);
             }
         }
-        if ret.entries.len() == remaining_length {
-            ret.end = true;
-        }
-        debug!("readdir_result:{:?}", ret);
+        Ok(true)
+    }
 
-        Ok(ret)
+    /// Builds the page in one pass over the listing refresh_dir_list
+    /// loaded, with the metadata it read, rather than through a sink.
+    async fn readdirplus(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+        _max_entries: usize,
+    ) -> Result<ReadDirPlusResult, nfsstat3> {
+        let mut fsmap = self.fsmap.lock().await;
+        fsmap.refresh_entry(dirid).await?;
+        fsmap.refresh_dir_list(dirid).await?;
+
+        let entry = fsmap.find_entry(dirid)?;
+        if !matches!(entry.fsmeta.ftype, ftype3::NF3DIR) {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        debug!("readdirplus({:?}, {:?})", entry, cookie);
+        let mut page = ReadDirPlusPage::new(dircount, maxcount);
+        if entry.children_capped {
+            // the entries are read from the directory as the page takes them
+            let mut sink = |entry: DirEntry| {
+                page.push(DirEntryPlus {
+                    handle: post_op_fh3::handle(self.id_to_fh(entry.fileid)),
+                    fileid: entry.fileid,
+                    name: entry.name,
+                    attr: entry.attr,
+                    cookie: entry.cookie,
+                })
+            };
+            let end = fsmap.readdir_uncached(dirid, cookie, &mut sink).await?;
+            return Ok(page.finish(end));
+        }
+        let children = entry.children.ok_or(nfsstat3::NFS3ERR_IO)?;
+        // the cookie of an entry is its fileid
+        let range_start = if cookie > cookie3(0) {
+            Bound::Excluded(fileid3(cookie.0))
+        } else {
+            Bound::Unbounded
+        };
+        for i in children.range((range_start, Bound::Unbounded)) {
+            let fileid = *i;
+            let fileent = fsmap.find_entry(fileid)?;
+            let name = fsmap.sym_to_fname(&fileent.name).await;
+            let taken = page.push(DirEntryPlus {
+                fileid,
+                name: name.as_bytes().into(),
+                attr: fileent.fsmeta,
+                handle: post_op_fh3::handle(self.id_to_fh(fileid)),
+                cookie: cookie3(fileid.0),
+            });
+            if !taken {
+                return Ok(page.finish(false));
+            }
+        }
+        Ok(page.finish(true))
     }
 
     async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        // batched writes are synced before the file is truncated or
+        // extended. Other changes (the times set after unpacking a file,
+        // say) do not wait for the batch, or every file would be synced
+        // on its own again. The batch syncs take the fsmap lock.
+        if let (Some(batcher), set_size3::size(_)) = (&self.sync_batcher, setattr.size) {
+            batcher.flush(id).await.map_err(|e| {
+                discard_unstable_writes();
+                io_error_to_nfsstat3(&e)
+            })?;
+        }
         let mut fsmap = self.fsmap.lock().await;
         let entry = fsmap.find_entry(id)?;
         let path = fsmap.sym_to_path(&entry.name).await;
-        path_setattr(&path, &setattr).await?;
+        self.modes.path_setattr(&path, &setattr).await?;
+        fsmap.links.invalidate(id);
 
         // I have to lookup a second time to update
         let metadata = path.symlink_metadata().or(Err(nfsstat3::NFS3ERR_IO))?;
         if let Ok(entry) = fsmap.find_entry_mut(id) {
-            entry.fsmeta = metadata_to_fattr3(id, &metadata);
+            entry.fsmeta = self.modes.metadata_to_fattr3(id, &metadata);
         }
-        Ok(metadata_to_fattr3(id, &metadata))
+        Ok(self.modes.metadata_to_fattr3(id, &metadata))
     }
     async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        let reply = self
+            .write_ext(id, offset, data, stable_how::FILE_SYNC)
+            .await?;
+        Ok(reply.attr)
+    }
+
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        // writes to a file through this server are done one at a time, so
+        // the attributes read around one are not mixed up with another
+        // client's write to the same file
+        let lock = self
+            .write_locks
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .clone();
+        let res = {
+            let _guard = lock.lock().await;
+            self.write_locked(id, offset, data, stable).await
+        };
+        // the last writer out drops the lock of the file
+        let mut write_locks = self.write_locks.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            write_locks.remove(&id);
+        }
+        res
+    }
+
+    async fn commit(&self, id: fileid3, _offset: u64, _count: u32) -> Result<(), nfsstat3> {
         let fsmap = self.fsmap.lock().await;
         let ent = fsmap.find_entry(id)?;
         let path = fsmap.sym_to_path(&ent.name).await;
         drop(fsmap);
-        debug!("write to init {:?}", path);
-        let mut f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&path)
-            .await
-            .map_err(|e| {
-                debug!("Unable to open {:?}", e);
-                nfsstat3::NFS3ERR_IO
-            })?;
-        f.seek(SeekFrom::Start(offset)).await.map_err(|e| {
-            debug!("Unable to seek {:?}", e);
-            nfsstat3::NFS3ERR_IO
-        })?;
-        f.write_all(data).await.map_err(|e| {
-            debug!("Unable to write {:?}", e);
-            nfsstat3::NFS3ERR_IO
-        })?;
-        debug!("write to {:?} {:?} {:?}", path, offset, data.len());
-        let _ = f.flush().await;
-        let _ = f.sync_all().await;
-        let meta = f.metadata().await.or(Err(nfsstat3::NFS3ERR_IO))?;
-        Ok(metadata_to_fattr3(id, &meta))
+        let synced = match &self.sync_batcher {
+            Some(batcher) => batcher.flush(id).await,
+            None => sync_file(&path).await,
+        };
+        synced.map_err(|e| {
+            // the unstable writes being committed may be gone
+            discard_unstable_writes();
+            io_error_to_nfsstat3(&e)
+        })
     }
 
     async fn create(
@@ -536,11 +1327,11 @@ impl NFSFileSystem for MirrorFS {
             if meta.is_dir() {
                 tokio::fs::remove_dir(&path)
                     .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+                    .map_err(|e| io_error_to_nfsstat3(&e))?;
             } else {
                 tokio::fs::remove_file(&path)
                     .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+                    .map_err(|e| io_error_to_nfsstat3(&e))?;
             }
 
             let filesym = fsmap
@@ -554,6 +1345,13 @@ impl NFSFileSystem for MirrorFS {
                 // and the path -> fileid mappings for the deleted file
                 fsmap.id_to_path.remove(&fileid);
                 fsmap.path_to_id.remove(&sympath);
+                fsmap
+                    .tombstones
+                    .lock()
+                    .unwrap()
+                    .insert(fileid, TombstoneReason::Removed);
+                fsmap.identities.lock().unwrap().forget(fileid);
+                fsmap.links.invalidate(fileid);
                 // we need to update the children listing for the directories
                 if let Ok(dirent_mut) = fsmap.find_entry_mut(dirid) {
                     if let Some(ref mut fromch) = dirent_mut.children {
@@ -564,6 +1362,17 @@ impl NFSFileSystem for MirrorFS {
 
             let _ = fsmap.refresh_entry(dirid).await;
         } else {
+            // The directory is left untouched, so that the wcc_data of the
+            // reply shows it unchanged. Only forget a cached child which
+            // was deleted behind our back.
+            if let Ok(fileid) = fsmap.find_child(dirid, filename).await {
+                fsmap.delete_entry(fileid, TombstoneReason::Vanished);
+                if let Ok(dirent_mut) = fsmap.find_entry_mut(dirid) {
+                    if let Some(ref mut fromch) = dirent_mut.children {
+                        fromch.remove(&fileid);
+                    }
+                }
+            }
             return Err(nfsstat3::NFS3ERR_NOENT);
         }
 
@@ -598,7 +1407,7 @@ impl NFSFileSystem for MirrorFS {
         debug!("Rename {:?} to {:?}", from_path, to_path);
         tokio::fs::rename(&from_path, &to_path)
             .await
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            .map_err(|e| io_error_to_nfsstat3(&e))?;
 
         let oldsym = fsmap
             .intern
@@ -613,12 +1422,24 @@ impl NFSFileSystem for MirrorFS {
         from_sympath.push(oldsym);
         let mut to_sympath = to_dirent.name.clone();
         to_sympath.push(newsym);
+        // the object renamed over (if any) is gone
+        if let Some(replaced) = fsmap.path_to_id.get(&to_sympath).copied() {
+            if fsmap.path_to_id.get(&from_sympath) != Some(&replaced) {
+                fsmap.delete_entry(replaced, TombstoneReason::RenamedOver);
+                if let Ok(to_dirent_mut) = fsmap.find_entry_mut(to_dirid) {
+                    if let Some(ref mut toch) = to_dirent_mut.children {
+                        toch.remove(&replaced);
+                    }
+                }
+            }
+        }
         if let Some(fileid) = fsmap.path_to_id.get(&from_sympath).copied() {
             // update the fileid -> path
             // and the path -> fileid mappings for the new file
             fsmap.id_to_path.get_mut(&fileid).unwrap().name = to_sympath.clone();
             fsmap.path_to_id.remove(&from_sympath);
             fsmap.path_to_id.insert(to_sympath, fileid);
+            fsmap.links.invalidate(fileid);
             if to_dirid != from_dirid {
                 // moving across directories.
                 // we need to update the children listing for the directories
@@ -641,6 +1462,69 @@ impl NFSFileSystem for MirrorFS {
 
         Ok(())
     }
+
+    async fn link(
+        &self,
+        id: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let mut fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+        let path = fsmap.sym_to_path(&ent.name).await;
+        let dirent = fsmap.find_entry(linkdirid)?;
+        let mut link_path = fsmap.sym_to_path(&dirent.name).await;
+        let linkname_osstr = OsStr::from_bytes(linkname).to_os_string();
+        link_path.push(&linkname_osstr);
+
+        debug!("Link {:?} to {:?}", link_path, path);
+        std::fs::hard_link(&path, &link_path).map_err(|e| io_error_to_nfsstat3(&e))?;
+
+        // ids are per path, so the new name gets a fileid of its own
+        let _ = fsmap.refresh_entry(id).await;
+        let _ = fsmap.refresh_entry(linkdirid).await;
+        let sym = fsmap.intern.intern(linkname_osstr).unwrap();
+        let mut name = dirent.name.clone();
+        name.push(sym);
+        let meta = link_path
+            .symlink_metadata()
+            .map_err(|e| io_error_to_nfsstat3(&e))?;
+        let fileid = fsmap.create_entry(&name, meta);
+        if let Some(children) = fsmap
+            .id_to_path
+            .get_mut(&linkdirid)
+            .and_then(|dirent| dirent.children.as_mut())
+        {
+            children.insert(fileid);
+        }
+        Ok(())
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        true
+    }
+
+    async fn fs_stat(&self, _id: fileid3) -> Result<FsStat, nfsstat3> {
+        let root = self.fsmap.lock().await.root.clone();
+        let cpath = std::ffi::CString::new(root.as_os_str().as_bytes())
+            .map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } != 0 {
+            return Err(io_error_to_nfsstat3(&std::io::Error::last_os_error()));
+        }
+        let frsize = st.f_frsize as u64;
+        Ok(FsStat {
+            total_bytes: st.f_blocks as u64 * frsize,
+            free_bytes: st.f_bfree as u64 * frsize,
+            avail_bytes: st.f_bavail as u64 * frsize,
+            total_files: st.f_files as u64,
+            free_files: st.f_ffree as u64,
+            avail_files: st.f_favail as u64,
+            // the backing file system can change at any time
+            invarsec: 0,
+        })
+    }
+
     async fn mkdir(
         &self,
         dirid: fileid3,
@@ -664,14 +1548,37 @@ impl NFSFileSystem for MirrorFS {
         )
         .await
     }
+
+    async fn mknod(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        _spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        // creating devices needs privileges a mirror should not have
+        if !matches!(ftype, ftype3::NF3FIFO) {
+            return Err(nfsstat3::NFS3ERR_BADTYPE);
+        }
+        self.create_fs_object(dirid, filename, &CreateFSObject::Fifo(*attr))
+            .await
+    }
+
     async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
-        let fsmap = self.fsmap.lock().await;
+        // the lock is held until the target is cached, so that it cannot
+        // miss an invalidation
+        let mut fsmap = self.fsmap.lock().await;
+        if let Some(target) = fsmap.links.get(id) {
+            return Ok(target);
+        }
         let ent = fsmap.find_entry(id)?;
         let path = fsmap.sym_to_path(&ent.name).await;
-        drop(fsmap);
         if path.is_symlink() {
             if let Ok(target) = path.read_link() {
-                Ok(target.as_os_str().as_bytes().into())
+                let target: nfspath3 = target.as_os_str().as_bytes().into();
+                fsmap.links.insert(id, target.clone());
+                Ok(target)
             } else {
                 Err(nfsstat3::NFS3ERR_IO)
             }
@@ -679,6 +1586,89 @@ impl NFSFileSystem for MirrorFS {
             Err(nfsstat3::NFS3ERR_BADTYPE)
         }
     }
+
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        let fsmap = self.fsmap.lock().await;
+        let name = &fsmap.id_to_path.get(&id)?.name;
+        let (_, dir) = name.split_last()?;
+        fsmap.path_to_id.get(dir).copied()
+    }
+
+    /// Handles are a StableFh keyed by the identity of the object, so that
+    /// clients keep their handles when the server restarts over the same
+    /// directory.
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        let identity = self
+            .identities
+            .lock()
+            .unwrap()
+            .by_id
+            .get(&id)
+            .copied()
+            .unwrap_or_default();
+        let mut key = Vec::with_capacity(24);
+        key.extend_from_slice(&identity.0.to_le_bytes());
+        key.extend_from_slice(&identity.1.to_le_bytes());
+        key.extend_from_slice(&identity.2.to_le_bytes());
+        StableFh { fileid: id, key }.encode()
+    }
+
+    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        let fh = StableFh::decode(id)?;
+        let key: [u8; 24] = fh
+            .key
+            .as_slice()
+            .try_into()
+            .map_err(|_| nfsstat3::NFS3ERR_BADHANDLE)?;
+        let identity = (
+            u64::from_le_bytes(key[0..8].try_into().unwrap()),
+            u64::from_le_bytes(key[8..16].try_into().unwrap()),
+            u64::from_le_bytes(key[16..24].try_into().unwrap()),
+        );
+        let id = fh.fileid;
+        // refuse handles of deleted ids without taking the fsmap lock
+        if let Some(reason) = self.tombstones.lock().unwrap().get(id) {
+            self.stale_hits.fetch_add(1, Ordering::Relaxed);
+            debug!("Handle of deleted fileid {:?} ({:?})", id, reason);
+            return Err(nfsstat3::NFS3ERR_STALE);
+        }
+        let identities = self.identities.lock().unwrap();
+        if identities.by_id.get(&id) == Some(&identity) {
+            return Ok(id);
+        }
+        // a handle made by an earlier run of the server
+        if let Some(&known) = identities.by_identity.get(&identity) {
+            return Ok(known);
+        }
+        drop(identities);
+        let resolved = self.resolve_identity(identity);
+        if let Err(nfsstat3::NFS3ERR_STALE) = resolved {
+            // spare the walk to clients retrying the handle
+            self.tombstones
+                .lock()
+                .unwrap()
+                .insert(id, TombstoneReason::Vanished);
+        }
+        resolved
+    }
+
+    async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id).ok()?;
+        let path = fsmap.sym_to_path(&ent.name).await;
+        Some(path.to_string_lossy().into_owned())
+    }
+
+    async fn handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        let fsmap = self.fsmap.lock().await;
+        let mut ret = Vec::with_capacity(fsmap.id_to_path.len());
+        for (id, ent) in fsmap.id_to_path.iter() {
+            let path = fsmap.sym_to_path(&ent.name).await;
+            ret.push((*id, path.to_string_lossy().into_owned()));
+        }
+        ret.sort_by_key(|(id, _)| *id);
+        Some(ret)
+    }
 }
 
 const HOSTPORT: u32 = 11111;
@@ -694,11 +1684,21 @@ async fn main() {
         .nth(1)
         .expect("must supply directory to mirror");
     let path = PathBuf::from(path);
+    // batches the syncs of small writes, FILE_SYNC ones included, at the
+    // risk of losing them in a crash
+    let relaxed = std::env::args().nth(2).as_deref() == Some("--relaxed-durability");
 
-    let fs = MirrorFS::new(path);
-    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
+    let mut fs = MirrorFS::new(path);
+    let batching = SyncBatchConfig::default();
+    if relaxed {
+        fs = fs.with_sync_batching(batching);
+    }
+    let mut listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
         .await
         .unwrap();
+    if relaxed {
+        listener.set_relaxed_durability(batching.max_write as u32);
+    }
     listener.handle_forever().await.unwrap();
 }
 // Test with
@@ -0,0 +1,14 @@
+//! Prints the procedures of the served programs and how completely each
+//! is supported, as a markdown table.
+use nfsserve::support::matrix;
+
+fn main() {
+    println!("| Program | Procedure | # | Support | Notes |");
+    println!("|---|---|---|---|---|");
+    for p in matrix() {
+        println!(
+            "| {} | {} | {} | {} | {} |",
+            p.program, p.procedure, p.number, p.support, p.notes
+        );
+    }
+}
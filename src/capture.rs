@@ -0,0 +1,101 @@
+//! Targeted capture of the RPC traffic of a single client, for debugging
+//! interoperability problems on a running server.
+//!
+//! Captured exchanges are appended to `<dir>/<client ip>.rpclog`. Every
+//! exchange is the call record followed by the reply record, each in the
+//! record marking format used on the wire (a 4 byte big endian length with
+//! the high bit set, followed by the record), so the file can be replayed
+//! or decoded with the same code that reads a TCP stream. Calls which
+//! produce no reply are logged with an empty reply record.
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+#[derive(Debug)]
+struct Armed {
+    remaining: usize,
+    log: PathBuf,
+}
+
+/// The clients armed for capture, keyed by RPCContext::client_host
+#[derive(Debug, Default)]
+pub struct CaptureRegistry {
+    armed: Mutex<HashMap<String, Armed>>,
+}
+
+/// The RPCContext::client_host spelling of an address
+fn host_key(client: IpAddr) -> String {
+    match client {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+impl CaptureRegistry {
+    /// Arms capture of the next n_requests records from client, replacing
+    /// any capture already armed for it.
+    pub fn arm(&self, client: IpAddr, n_requests: usize, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let log = dir.join(format!("{client}.rpclog"));
+        info!(
+            target: "nfsserve::rpc",
+            "Capturing the next {} requests from {} to {:?}",
+            n_requests,
+            client,
+            log
+        );
+        let mut armed = self.armed.lock().unwrap();
+        if n_requests == 0 {
+            armed.remove(&host_key(client));
+        } else {
+            armed.insert(
+                host_key(client),
+                Armed {
+                    remaining: n_requests,
+                    log,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the log to capture the next record of client_host into, if
+    /// capture is armed for it. Disarms after the last armed record.
+    pub fn take(&self, client_host: &str) -> Option<PathBuf> {
+        let mut armed = self.armed.lock().unwrap();
+        let ent = armed.get_mut(client_host)?;
+        ent.remaining -= 1;
+        let log = ent.log.clone();
+        if ent.remaining == 0 {
+            armed.remove(client_host);
+            info!(target: "nfsserve::rpc", "Capture of {} complete", client_host);
+        }
+        Some(log)
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &[u8]) -> io::Result<()> {
+    out.write_all(&(record.len() as u32 | (1 << 31)).to_be_bytes())?;
+    out.write_all(record)
+}
+
+/// Appends an exchange to a capture log
+pub fn append_exchange(log: &Path, call: &[u8], reply: &[u8]) {
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .and_then(|mut f| {
+            let mut buf = Vec::with_capacity(call.len() + reply.len() + 8);
+            write_record(&mut buf, call)?;
+            write_record(&mut buf, reply)?;
+            f.write_all(&buf)
+        });
+    if let Err(e) = res {
+        warn!(target: "nfsserve::rpc", "Unable to write capture log {:?}: {:?}", log, e);
+    }
+}
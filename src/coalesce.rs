@@ -0,0 +1,389 @@
+//! An NFSFileSystem adapter which coalesces concurrent getattrs.
+use crate::nfs::*;
+use crate::vfs::{
+    DirEntry, FsInfoConfig, FsStat, NFSFileSystem, RawDirPage, ReadDirPlusResult, ReadDirResult,
+    ReadDirSimpleResult, ReadReply, VFSCapabilities, WriteReply,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+type InFlight = Arc<OnceCell<Result<fattr3, nfsstat3>>>;
+
+/// Wraps a file system so that concurrent getattrs of the same fileid
+/// share a single call to the wrapped file system.
+///
+/// Nothing is cached: a getattr which starts after all others on the same
+/// id have completed always reaches the wrapped file system. A mutation
+/// through this adapter also detaches any getattr in flight on the ids it
+/// touches, so that callers arriving after the mutation do not join a
+/// getattr which started before it.
+///
+/// All other methods are forwarded as is.
+pub struct CoalescingFS<T: NFSFileSystem> {
+    inner: T,
+    in_flight: Mutex<HashMap<fileid3, InFlight>>,
+}
+
+impl<T: NFSFileSystem> CoalescingFS<T> {
+    pub fn new(inner: T) -> CoalescingFS<T> {
+        CoalescingFS {
+            inner,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the wrapped file system
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Detaches the getattr in flight on id (if any)
+    fn forget(&self, id: fileid3) {
+        self.in_flight.lock().unwrap().remove(&id);
+    }
+}
+
+#[async_trait]
+impl<T: NFSFileSystem + Send> NFSFileSystem for CoalescingFS<T> {
+    fn capabilities(&self) -> VFSCapabilities {
+        self.inner.capabilities()
+    }
+    fn root_dir(&self) -> fileid3 {
+        self.inner.root_dir()
+    }
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.inner.lookup(dirid, filename).await
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .clone();
+        let res = *cell.get_or_init(|| self.inner.getattr(id)).await;
+        // the first caller to finish retires the entry so that the next
+        // getattr goes to the wrapped file system again
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(&id).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            in_flight.remove(&id);
+        }
+        res
+    }
+
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        let res = self.inner.setattr(id, setattr).await;
+        self.forget(id);
+        res
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.inner.read(id, offset, count).await
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        self.inner.read_ext(id, offset, count, buf).await
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        let res = self.inner.write(id, offset, data).await;
+        self.forget(id);
+        res
+    }
+
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        let res = self.inner.write_ext(id, offset, data, stable).await;
+        self.forget(id);
+        res
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let res = self.inner.create(dirid, filename, attr).await;
+        self.forget(dirid);
+        res
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        let res = self.inner.create_exclusive(dirid, filename).await;
+        self.forget(dirid);
+        res
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let res = self.inner.mkdir(dirid, dirname).await;
+        self.forget(dirid);
+        res
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        let res = self.inner.remove(dirid, filename).await;
+        self.forget(dirid);
+        res
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let res = self
+            .inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        self.forget(from_dirid);
+        self.forget(to_dirid);
+        res
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        self.inner.readdir(dirid, start_after, max_entries).await
+    }
+
+    async fn readdir_raw(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+    ) -> Option<Result<RawDirPage, nfsstat3>> {
+        self.inner
+            .readdir_raw(dirid, cookie, dircount, maxcount)
+            .await
+    }
+
+    async fn readdir_each(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
+        self.inner
+            .readdir_each(dirid, start_after, max_entries, sink)
+            .await
+    }
+
+    async fn readdirplus(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+        max_entries: usize,
+    ) -> Result<ReadDirPlusResult, nfsstat3> {
+        self.inner
+            .readdirplus(dirid, cookie, dircount, maxcount, max_entries)
+            .await
+    }
+
+    async fn readdir_simple(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        count: usize,
+    ) -> Result<ReadDirSimpleResult, nfsstat3> {
+        self.inner.readdir_simple(dirid, start_after, count).await
+    }
+
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let res = self.inner.symlink(dirid, linkname, symlink, attr).await;
+        self.forget(dirid);
+        res
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.inner.readlink(id).await
+    }
+
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        self.inner.parent_dir(id).await
+    }
+
+    async fn mknod(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let res = self.inner.mknod(dirid, filename, ftype, attr, spec).await;
+        self.forget(dirid);
+        res
+    }
+
+    async fn link(
+        &self,
+        id: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let res = self.inner.link(id, linkdirid, linkname).await;
+        // the link count of id changes too
+        self.forget(id);
+        self.forget(linkdirid);
+        res
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        self.inner.supports_hard_links()
+    }
+
+    async fn is_immutable_dir(&self, dirid: fileid3) -> bool {
+        self.inner.is_immutable_dir(dirid).await
+    }
+
+    async fn access(&self, id: fileid3, attr: &fattr3, requested: u32) -> u32 {
+        self.inner.access(id, attr, requested).await
+    }
+
+    async fn commit(&self, id: fileid3, offset: u64, count: u32) -> Result<(), nfsstat3> {
+        self.inner.commit(id, offset, count).await
+    }
+
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        self.inner.fsinfo_config()
+    }
+
+    async fn fs_stat(&self, id: fileid3) -> Result<FsStat, nfsstat3> {
+        self.inner.fs_stat(id).await
+    }
+
+    fn time_delta(&self) -> nfstime3 {
+        self.inner.time_delta()
+    }
+
+    async fn fsinfo(&self, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<fileid3, nfsstat3> {
+        self.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> cookieverf3 {
+        self.inner.serverid()
+    }
+
+    async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        self.inner.describe_fileid(id).await
+    }
+
+    async fn handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        self.inner.handle_table().await
+    }
+
+    async fn content_hash(&self, id: fileid3) -> Option<[u8; 32]> {
+        self.inner.content_hash(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::{forward_to_memfs, MemFS};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    /// A MemFS counting the getattrs which reach it. Each yields once
+    /// before it completes, so that concurrent getattrs overlap.
+    struct CountedFS {
+        inner: MemFS,
+        calls: AtomicUsize,
+    }
+
+    forward_to_memfs! {
+        CountedFS,
+        hooks {
+            async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                self.inner.getattr(id).await
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_getattrs_share_a_call() {
+        let fs = CoalescingFS::new(CountedFS {
+            inner: MemFS::new(),
+            calls: AtomicUsize::new(0),
+        });
+        let calls = || fs.inner().calls.swap(0, Ordering::SeqCst);
+        let root = fs.root_dir();
+        let attrs = block_on(futures::future::join_all((0..8).map(|_| fs.getattr(root))));
+        assert!(attrs.iter().all(|attr| attr.unwrap().fileid == root));
+        assert_eq!(calls(), 1);
+
+        // nothing is cached once they are done
+        block_on(fs.getattr(root)).unwrap();
+        assert_eq!(calls(), 1);
+
+        // getattrs of different ids are not shared
+        let (file, _) = block_on(fs.create(root, &b"file"[..].into(), sattr3::default())).unwrap();
+        let (a, b) = block_on(futures::future::join(fs.getattr(root), fs.getattr(file)));
+        assert_eq!((a.unwrap().fileid, b.unwrap().fileid), (root, file));
+        assert_eq!(calls(), 2);
+    }
+}
@@ -1 +1,148 @@
+//! The tunables of a listener as one value.
+//!
+//! NFSTcpListener::bind_with_config takes an NFSServerConfig and checks it
+//! up front. The NFSTcp setters change the same values on a bound
+//! listener, and NFSTcpListener::config returns the values in effect.
+use crate::tcp::{SquashMode, DEFAULT_LISTEN_BACKLOG, DEFAULT_MAX_REQUESTS_PER_CONNECTION};
+use std::fmt;
+use std::time::Duration;
 
+/// The configuration of a listener. The Default is what bind uses.
+///
+/// With the serde feature this can be deserialized (e.g. from TOML or
+/// JSON). Missing fields take their default, and unknown fields are an
+/// error. Policies given as trait objects (NFSTcp::set_mount_authorizer,
+/// NFSTcp::set_auth_handler, NFSTcp::set_symlink_rewriter), channels and
+/// handle keys kept across restarts (NFSTcp::set_handle_key) are not part
+/// of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct NFSServerConfig {
+    /// The accept backlog. Defaults to DEFAULT_LISTEN_BACKLOG. See
+    /// NFSTcpListener::bind_with_backlog
+    pub listen_backlog: u32,
+    /// Normalize Windows spellings of the MOUNT path. Defaults to false.
+    /// See NFSTcp::set_windows_path_compat
+    pub windows_path_compat: bool,
+    /// The budget in bytes for the transient memory of requests in
+    /// flight. Defaults to unlimited (usize::MAX). See
+    /// NFSTcp::set_memory_budget
+    pub memory_budget: usize,
+    /// Defaults to unlimited (usize::MAX). See
+    /// NFSTcp::set_max_mounts_per_client
+    pub max_mounts_per_client: usize,
+    /// Defaults to DEFAULT_MAX_REQUESTS_PER_CONNECTION. See
+    /// NFSTcp::set_max_requests_per_connection
+    pub max_requests_per_connection: usize,
+    /// Defaults to unlimited (usize::MAX). See NFSTcp::set_max_connections
+    pub max_connections: usize,
+    /// Defaults to SquashMode::NoSquash. See NFSTcp::set_squash
+    pub squash: SquashMode,
+    /// Defaults to false. See NFSTcp::set_hide_silly_renames
+    pub hide_silly_renames: bool,
+    /// Serve READDIRPLUS calls with a zero maxcount or dircount as if
+    /// they asked for dtpref. Defaults to true. See
+    /// NFSTcp::set_readdir_count_compat
+    pub readdir_count_compat: bool,
+    /// Serve READDIRPLUS. Defaults to true. See NFSTcp::set_readdirplus
+    pub readdirplus: bool,
+    /// Reply to the requests of a connection in the order they arrived.
+    /// Defaults to false. See NFSTcp::set_ordered_replies
+    pub ordered_replies: bool,
+    /// FILE_SYNC and DATA_SYNC WRITEs of at most this many bytes are
+    /// written UNSTABLE. Defaults to 0, none. See
+    /// NFSTcp::set_relaxed_durability
+    pub relaxed_durability: u32,
+    /// Stream long READDIR and READDIRPLUS replies. Defaults to true.
+    /// See NFSTcp::set_streamed_replies
+    pub streamed_replies: bool,
+    /// How long file handles stay valid after they are handed out.
+    /// Defaults to None, forever. See NFSTcp::set_handle_ttl
+    pub handle_ttl: Option<Duration>,
+    /// Sign file handles with a random key of the listener, made when it
+    /// is bound. Defaults to false. See NFSTcp::set_sign_handles
+    pub sign_handles: bool,
+}
+
+impl Default for NFSServerConfig {
+    fn default() -> NFSServerConfig {
+        NFSServerConfig {
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            windows_path_compat: false,
+            memory_budget: usize::MAX,
+            max_mounts_per_client: usize::MAX,
+            max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            max_connections: usize::MAX,
+            squash: SquashMode::NoSquash,
+            hide_silly_renames: false,
+            readdir_count_compat: true,
+            readdirplus: true,
+            ordered_replies: false,
+            relaxed_durability: 0,
+            streamed_replies: true,
+            handle_ttl: None,
+            sign_handles: false,
+        }
+    }
+}
+
+/// Why an NFSServerConfig was refused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// max_requests_per_connection is 0: no request would ever be read
+    NoRequestsPerConnection,
+    /// max_connections is 0: no connection would ever be accepted
+    NoConnections,
+    /// memory_budget is below what the largest READ or WRITE of the file
+    /// system holds (rtmax, or wtmax), so those would only be admitted
+    /// one at a time
+    MemoryBudgetTooSmall { budget: usize, needed: usize },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::NoRequestsPerConnection => {
+                write!(f, "max_requests_per_connection must be at least 1")
+            }
+            ConfigError::NoConnections => write!(f, "max_connections must be at least 1"),
+            ConfigError::MemoryBudgetTooSmall { budget, needed } => write!(
+                f,
+                "memory_budget {budget} is below the {needed} bytes of the largest READ or WRITE"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl NFSServerConfig {
+    /// Checks the values which can be checked without the file system
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_requests_per_connection == 0 {
+            return Err(ConfigError::NoRequestsPerConnection);
+        }
+        if self.max_connections == 0 {
+            return Err(ConfigError::NoConnections);
+        }
+        Ok(())
+    }
+
+    /// As validate, also checking memory_budget against the transfer
+    /// sizes (fsinfo rtmax and wtmax) of the file system served
+    pub fn validate_for(&self, rtmax: u32, wtmax: u32) -> Result<(), ConfigError> {
+        self.validate()?;
+        let needed = (rtmax as usize).max(wtmax as usize);
+        if self.memory_budget < needed {
+            return Err(ConfigError::MemoryBudgetTooSmall {
+                budget: self.memory_budget,
+                needed,
+            });
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
-use crate::vfs::NFSFileSystem;
+use crate::capture::CaptureRegistry;
+use crate::getport_cache::GetportCache;
+use crate::memory_budget::MemoryBudget;
+use crate::nfs::{fattr3, fileid3, nfsstat3};
+use crate::readdir_estimate::EntrySizeEstimator;
+use crate::registry::ProgramRegistry;
+use crate::rpcwire::ReplyStream;
+use crate::silly_rename::{is_silly_rename, SillyRenames};
+use crate::tcp::{AuthHandler, MountAuthorizer, SquashMode, SymlinkRewriter};
+use crate::vfs::{HandleCodec, NFSFileSystem};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
-use std::sync::Arc;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::sync::mpsc;
 #[derive(Clone)]
 pub struct RPCContext {
@@ -9,6 +21,218 @@ pub struct RPCContext {
     pub auth: crate::rpc::auth_unix,
     pub vfs: Arc<dyn NFSFileSystem + Send + Sync>,
     pub mount_signal: Option<mpsc::Sender<bool>>,
+    pub programs: Arc<RwLock<ProgramRegistry>>,
+    /// The GETPORT answers of the listener, cleared when programs changes
+    pub getport: Arc<GetportCache>,
+    /// The runtime on which connection and request tasks are spawned
+    pub runtime: tokio::runtime::Handle,
+    /// Normalize Windows spellings of mount paths. See mount_handlers::normalize_dirpath
+    pub windows_path_compat: bool,
+    /// The budget for transient request buffers. See memory_budget
+    pub memory_budget: Arc<MemoryBudget>,
+    /// The size of the record of the current call, which is counted in
+    /// memory_budget while it is served
+    pub record_len: usize,
+    /// The paths mounted by each client host
+    pub mounts: Arc<Mutex<BTreeMap<String, BTreeSet<Vec<u8>>>>>,
+    /// The maximum number of paths mounted per client host
+    pub max_mounts_per_client: usize,
+    /// The maximum number of requests of a connection in flight. See
+    /// NFSTcp::set_max_requests_per_connection
+    pub max_requests_per_connection: usize,
+    /// The clients armed for traffic capture. See NFSTcpListener::capture_next
+    pub capture: Arc<CaptureRegistry>,
+    /// How caller credentials are mapped
+    pub squash: SquashMode,
+    /// True if the credentials of this call were mapped by squash
+    pub squashed: bool,
+    /// The export paths listed by MOUNTPROC3_EXPORT
+    pub exports: Arc<Vec<Vec<u8>>>,
+    /// Buffers kept for reuse by the READs of this connection
+    pub read_buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The entry sizes of recently listed directories
+    pub readdir_sizes: Arc<EntrySizeEstimator>,
+    /// The policy consulted on MNT. See NFSTcp::set_mount_authorizer
+    pub mount_authorizer: Option<Arc<dyn MountAuthorizer>>,
+    /// The handler of RPCSEC_GSS credentials. See NFSTcp::set_auth_handler
+    pub auth_handler: Option<Arc<dyn AuthHandler>>,
+    /// The policy applied to READLINK targets. See
+    /// NFSTcp::set_symlink_rewriter
+    pub symlink_rewriter: Option<Arc<dyn SymlinkRewriter>>,
+    /// The attributes read while serving the current call. See getattr
+    pub attr_cache: Arc<Mutex<BTreeMap<fileid3, fattr3>>>,
+    /// Hide the files other clients silly renamed from directory listings.
+    /// See NFSTcp::set_hide_silly_renames
+    pub hide_silly_renames: bool,
+    /// The files silly renamed by each client
+    pub silly_renames: Arc<SillyRenames>,
+    /// Replace tiny READDIRPLUS counts by dtpref. See
+    /// NFSTcp::set_readdir_count_compat
+    pub readdir_count_compat: bool,
+    /// Set once a count of this connection has been replaced
+    pub readdir_count_substituted: Arc<AtomicBool>,
+    /// Serve READDIRPLUS. See NFSTcp::set_readdirplus
+    pub readdirplus: bool,
+    /// Write replies in request order. See NFSTcp::set_ordered_replies
+    pub ordered_replies: bool,
+    /// The largest stable WRITE written UNSTABLE. See
+    /// NFSTcp::set_relaxed_durability
+    pub relaxed_durability: u32,
+    /// Stream long READDIR replies. See NFSTcp::set_streamed_replies
+    pub streamed_replies: bool,
+    /// How the default file handles are made and checked, in effect
+    /// while a call is served. See vfs::current_handle_codec
+    pub handle_codec: HandleCodec,
+    /// What the reply of the current call is written to. See
+    /// send_reply_chunk
+    pub reply_stream: Option<ReplyStream>,
+}
+
+impl RPCContext {
+    /// Maps the owner in attributes sent by a caller whose credentials
+    /// were squashed. See NFSTcp::set_squash
+    pub fn squash_sattr3(&self, attr: &mut crate::nfs::sattr3) {
+        if self.squashed {
+            self.squash.apply_sattr3(attr);
+        }
+    }
+
+    /// vfs.getattr, remembering the result until the end of the call
+    /// (each call gets its own cache) or the next invalidate_attrs. Lets
+    /// a handler read the same attributes more than once, for instance
+    /// those of a directory which is both the source and target of a
+    /// RENAME, with a single call to the file system.
+    pub async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        if let Some(attr) = self.attr_cache.lock().unwrap().get(&id) {
+            return Ok(*attr);
+        }
+        let attr = self.vfs.getattr(id).await?;
+        self.attr_cache.lock().unwrap().insert(id, attr);
+        Ok(attr)
+    }
+
+    /// Forgets the attributes read so far in this call. Must follow any
+    /// file system call which may change attributes, so that the post
+    /// operation attributes are read afresh.
+    pub fn invalidate_attrs(&self) {
+        self.attr_cache.lock().unwrap().clear();
+    }
+
+    /// Records a successful RENAME for silly rename tracking
+    pub fn note_rename(&self, from_dirid: fileid3, from: &[u8], to_dirid: fileid3, to: &[u8]) {
+        if !self.hide_silly_renames {
+            return;
+        }
+        self.silly_renames.forget(from_dirid, from);
+        if is_silly_rename(to) {
+            self.silly_renames.record(to_dirid, to, self.client_host());
+        } else {
+            self.silly_renames.forget(to_dirid, to);
+        }
+    }
+
+    /// Records a successful REMOVE for silly rename tracking
+    pub fn note_remove(&self, dirid: fileid3, name: &[u8]) {
+        if self.hide_silly_renames {
+            self.silly_renames.forget(dirid, name);
+        }
+    }
+
+    /// Returns true if the entry name of dirid is left out of the
+    /// directory listings sent to this client
+    pub fn hides_dir_entry(&self, dirid: fileid3, name: &[u8]) -> bool {
+        self.hide_silly_renames
+            && self
+                .silly_renames
+                .hidden_from(dirid, name, self.client_host())
+    }
+
+    /// Lets the reply written so far go out to the client before the
+    /// handler is done, once there is a chunk of it. For the handlers of
+    /// long replies, which call this between the parts they write. See
+    /// rpcwire::ReplyStream
+    pub async fn send_reply_chunk(&self) -> Result<(), anyhow::Error> {
+        match &self.reply_stream {
+            Some(stream) => stream.send_chunk().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Appends data to the reply of the current call without copying it,
+    /// after what the handler wrote to output so far. See
+    /// ReplyStream::attach. Without a reply stream (as in unit tests) data
+    /// is written to output. recycle is given data back once it is
+    /// written.
+    pub fn write_reply_data(
+        &self,
+        output: &mut impl Write,
+        data: Vec<u8>,
+        recycle: impl FnOnce(Vec<u8>) + Send + 'static,
+    ) -> std::io::Result<()> {
+        match &self.reply_stream {
+            Some(stream) => stream.attach(data, recycle),
+            None => {
+                output.write_all(&data)?;
+                recycle(data);
+            }
+        }
+        Ok(())
+    }
+
+    /// The host part of client_addr, i.e. without the port
+    pub fn client_host(&self) -> &str {
+        self.client_addr
+            .rsplit_once(':')
+            .map_or(self.client_addr.as_str(), |(host, _)| host)
+    }
+}
+
+#[cfg(test)]
+impl RPCContext {
+    /// The context of a connection to a listener serving vfs with the
+    /// default configuration, for unit tests. Must be called on a runtime.
+    pub fn for_tests(vfs: Arc<dyn NFSFileSystem + Send + Sync>) -> RPCContext {
+        let config = crate::config::NFSServerConfig::default();
+        RPCContext {
+            local_port: 2049,
+            client_addr: "127.0.0.1:1000".to_string(),
+            auth: crate::rpc::auth_unix::default(),
+            vfs,
+            mount_signal: None,
+            programs: Arc::new(RwLock::new(ProgramRegistry::with_default_programs())),
+            getport: Arc::default(),
+            runtime: tokio::runtime::Handle::current(),
+            windows_path_compat: config.windows_path_compat,
+            memory_budget: Arc::new(MemoryBudget::unlimited()),
+            record_len: 0,
+            mounts: Arc::default(),
+            max_mounts_per_client: config.max_mounts_per_client,
+            max_requests_per_connection: config.max_requests_per_connection,
+            capture: Arc::default(),
+            squash: config.squash,
+            squashed: false,
+            exports: Arc::new(vec![b"/".to_vec()]),
+            read_buffers: Arc::default(),
+            readdir_sizes: Arc::default(),
+            mount_authorizer: None,
+            auth_handler: None,
+            symlink_rewriter: None,
+            attr_cache: Arc::default(),
+            hide_silly_renames: config.hide_silly_renames,
+            silly_renames: Arc::default(),
+            readdir_count_compat: config.readdir_count_compat,
+            readdir_count_substituted: Arc::default(),
+            readdirplus: config.readdirplus,
+            ordered_replies: config.ordered_replies,
+            relaxed_durability: config.relaxed_durability,
+            streamed_replies: config.streamed_replies,
+            handle_codec: HandleCodec {
+                ttl: config.handle_ttl,
+                key: None,
+            },
+            reply_stream: None,
+        }
+    }
 }
 
 impl fmt::Debug for RPCContext {
@@ -0,0 +1,617 @@
+//! Serving several file systems from one listener, each under its own
+//! export path. See NFSTcpListener::bind_multi.
+use crate::nfs::*;
+use crate::tcp::SquashMode;
+use crate::vfs::{
+    current_user, with_user, DirEntry, FsInfoConfig, FsStat, NFSFileSystem, ReadDirPlusResult,
+    ReadDirResult, ReadDirSimpleResult, ReadReply, VFSCapabilities, WriteReply,
+};
+use async_trait::async_trait;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use tracing::error;
+
+/// The number of high bits of a fileid which hold the export index
+const EXPORT_BITS: u32 = 8;
+const INNER_BITS: u32 = 64 - EXPORT_BITS;
+const INNER_MASK: u64 = (1 << INNER_BITS) - 1;
+
+/// The maximum number of exports of an ExportTable
+pub const MAX_EXPORTS: usize = 1 << EXPORT_BITS;
+
+struct Export {
+    path: Vec<u8>,
+    fs: Arc<dyn NFSFileSystem + Send + Sync>,
+    squash: SquashMode,
+}
+
+/// Runs fut, a call on export, with the caller's credentials (as seen by
+/// vfs::current_user) mapped by the squash of the export
+async fn as_caller<F: Future>(export: &Export, fut: F) -> F::Output {
+    if export.squash == SquashMode::NoSquash {
+        return fut.await;
+    }
+    let (user, _) = export.squash.apply_user(current_user());
+    with_user(user, fut).await
+}
+
+/// Maps the owner in attributes sent by a caller the squash of export
+/// applies to
+fn squash_sattr3(export: &Export, attr: &mut sattr3) {
+    if export.squash == SquashMode::NoSquash {
+        return;
+    }
+    if let (_, true) = export.squash.apply_user(current_user()) {
+        export.squash.apply_sattr3(attr);
+    }
+}
+
+/// An NFSFileSystem which serves several file systems, each under its own
+/// export path. MNT picks the export with the longest path which is a
+/// prefix of the mount path, so with exports "/data" and "/scratch",
+/// mounting "/data/a" mounts "/a" in the "/data" file system. Mounting a
+/// path under no export fails with MNT3ERR_NOENT.
+///
+/// The export index is kept in the top 8 bits of every fileid and in the
+/// first byte of every file handle, so that each call is routed to the
+/// file system the object belongs to. The wrapped file systems must
+/// therefore keep their fileids below 2^56 (fileid_alloc::path_hash does),
+/// and their file handles to at most 63 bytes (StableFh does). Larger
+/// fileids are NFS3ERR_SERVERFAULT, and longer handles are replaced by an
+/// empty handle, which is NFS3ERR_BADHANDLE.
+///
+/// readdir_raw is not forwarded since the fileids in its encoded entries
+/// cannot be remapped. READDIRPLUS falls back to readdir instead.
+pub struct ExportTable {
+    exports: Vec<Export>,
+}
+
+impl ExportTable {
+    /// Creates a table from (export path, file system) pairs. Export paths
+    /// must be absolute and distinct, and there can be at most MAX_EXPORTS
+    /// of them. The first export provides the root_dir, serverid and
+    /// time_delta of the table.
+    pub fn new(
+        exports: Vec<(&str, Arc<dyn NFSFileSystem + Send + Sync>)>,
+    ) -> io::Result<ExportTable> {
+        if exports.is_empty() || exports.len() > MAX_EXPORTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Between 1 and {MAX_EXPORTS} exports are required"),
+            ));
+        }
+        let mut table: Vec<Export> = Vec::with_capacity(exports.len());
+        for (path, fs) in exports {
+            if !path.starts_with('/') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Export path {path:?} is not absolute"),
+                ));
+            }
+            let trimmed = path.trim_end_matches('/');
+            let path = if trimmed.is_empty() { "/" } else { trimmed };
+            if table.iter().any(|e| e.path == path.as_bytes()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Export path {path:?} is registered twice"),
+                ));
+            }
+            table.push(Export {
+                path: path.as_bytes().to_vec(),
+                fs,
+                squash: SquashMode::NoSquash,
+            });
+        }
+        Ok(ExportTable { exports: table })
+    }
+
+    /// Sets how caller credentials are mapped for the calls on the export
+    /// at path, like NFSTcp::set_squash does for all calls. This applies
+    /// on top of the squash of the listener, so leave that at NoSquash
+    /// to give exports different policies. Defaults to
+    /// SquashMode::NoSquash.
+    pub fn set_squash(&mut self, path: &str, mode: SquashMode) -> io::Result<()> {
+        let trimmed = path.trim_end_matches('/');
+        let path = if trimmed.is_empty() { "/" } else { trimmed };
+        let export = self
+            .exports
+            .iter_mut()
+            .find(|e| e.path == path.as_bytes())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("No export at {path:?}"))
+            })?;
+        export.squash = mode;
+        Ok(())
+    }
+
+    /// The export paths, in registration order
+    pub fn export_paths(&self) -> Vec<Vec<u8>> {
+        self.exports.iter().map(|e| e.path.clone()).collect()
+    }
+
+    /// Finds the export of a mount path, returning its index and the rest
+    /// of the path within the export.
+    fn match_path<'a>(&self, path: &'a [u8]) -> Option<(usize, &'a [u8])> {
+        self.exports
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, e)| {
+                if e.path == b"/" {
+                    return Some((idx, e.path.len(), path));
+                }
+                let rest = path.strip_prefix(e.path.as_slice())?;
+                if rest.is_empty() || rest[0] == b'/' {
+                    Some((idx, e.path.len(), rest))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|(_, len, _)| *len)
+            .map(|(idx, _, rest)| (idx, rest))
+    }
+
+    /// Splits a fileid into its export and the fileid in the export
+    fn route(&self, id: fileid3) -> Result<(usize, &Export, fileid3), nfsstat3> {
+        let idx = (id.0 >> INNER_BITS) as usize;
+        let export = self.exports.get(idx).ok_or(nfsstat3::NFS3ERR_STALE)?;
+        Ok((idx, export, fileid3(id.0 & INNER_MASK)))
+    }
+
+    /// Tags a fileid of export idx with the export index
+    fn wrap(&self, idx: usize, id: fileid3) -> Result<fileid3, nfsstat3> {
+        if id.0 > INNER_MASK {
+            error!(
+                target: "nfsserve::nfs",
+                "fileid {} of export {:?} does not fit in {} bits",
+                id,
+                String::from_utf8_lossy(&self.exports[idx].path),
+                INNER_BITS
+            );
+            return Err(nfsstat3::NFS3ERR_SERVERFAULT);
+        }
+        Ok(fileid3(((idx as u64) << INNER_BITS) | id.0))
+    }
+
+    fn wrap_attr(&self, idx: usize, mut attr: fattr3) -> Result<fattr3, nfsstat3> {
+        attr.fileid = self.wrap(idx, attr.fileid)?;
+        Ok(attr)
+    }
+
+    fn wrap_created(
+        &self,
+        idx: usize,
+        (id, attr): (fileid3, fattr3),
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Ok((self.wrap(idx, id)?, self.wrap_attr(idx, attr)?))
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for ExportTable {
+    fn capabilities(&self) -> VFSCapabilities {
+        // read only exports refuse writes themselves
+        if self
+            .exports
+            .iter()
+            .any(|e| matches!(e.fs.capabilities(), VFSCapabilities::ReadWrite))
+        {
+            VFSCapabilities::ReadWrite
+        } else {
+            VFSCapabilities::ReadOnly
+        }
+    }
+
+    fn root_dir(&self) -> fileid3 {
+        self.exports[0].fs.root_dir()
+    }
+
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let id = as_caller(export, export.fs.lookup(dirid, filename)).await?;
+        self.wrap(idx, id)
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        let (idx, export, id) = self.route(id)?;
+        let attr = as_caller(export, export.fs.getattr(id)).await?;
+        self.wrap_attr(idx, attr)
+    }
+
+    async fn setattr(&self, id: fileid3, mut setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        let (idx, export, id) = self.route(id)?;
+        squash_sattr3(export, &mut setattr);
+        let attr = as_caller(export, export.fs.setattr(id, setattr)).await?;
+        self.wrap_attr(idx, attr)
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let (_, export, id) = self.route(id)?;
+        as_caller(export, export.fs.read(id, offset, count)).await
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        let (idx, export, id) = self.route(id)?;
+        let mut reply = as_caller(export, export.fs.read_ext(id, offset, count, buf)).await?;
+        reply.attr = reply.attr.map(|a| self.wrap_attr(idx, a)).transpose()?;
+        Ok(reply)
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        let (idx, export, id) = self.route(id)?;
+        let attr = as_caller(export, export.fs.write(id, offset, data)).await?;
+        self.wrap_attr(idx, attr)
+    }
+
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        let (idx, export, id) = self.route(id)?;
+        let mut reply = as_caller(export, export.fs.write_ext(id, offset, data, stable)).await?;
+        reply.attr = self.wrap_attr(idx, reply.attr)?;
+        Ok(reply)
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        mut attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        squash_sattr3(export, &mut attr);
+        let created = as_caller(export, export.fs.create(dirid, filename, attr)).await?;
+        self.wrap_created(idx, created)
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let id = as_caller(export, export.fs.create_exclusive(dirid, filename)).await?;
+        self.wrap(idx, id)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let created = as_caller(export, export.fs.mkdir(dirid, dirname)).await?;
+        self.wrap_created(idx, created)
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        let (_, export, dirid) = self.route(dirid)?;
+        as_caller(export, export.fs.remove(dirid, filename)).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let (from_idx, export, from_dirid) = self.route(from_dirid)?;
+        let (to_idx, _, to_dirid) = self.route(to_dirid)?;
+        if from_idx != to_idx {
+            return Err(nfsstat3::NFS3ERR_XDEV);
+        }
+        as_caller(
+            export,
+            export
+                .fs
+                .rename(from_dirid, from_filename, to_dirid, to_filename),
+        )
+        .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let mut res = as_caller(export, export.fs.readdir(dirid, start_after, max_entries)).await?;
+        for entry in res.entries.iter_mut() {
+            // the cookie is the export's, whatever the fileid becomes
+            entry.cookie = entry.effective_cookie();
+            entry.fileid = self.wrap(idx, entry.fileid)?;
+            entry.attr.fileid = self.wrap(idx, entry.attr.fileid)?;
+        }
+        Ok(res)
+    }
+
+    async fn readdir_each(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let mut wrap_failure = None;
+        let mut wrapping_sink = |mut entry: DirEntry| {
+            entry.cookie = entry.effective_cookie();
+            let wrapped = self
+                .wrap(idx, entry.fileid)
+                .and_then(|fileid| Ok((fileid, self.wrap(idx, entry.attr.fileid)?)));
+            match wrapped {
+                Ok((fileid, attr_fileid)) => {
+                    entry.fileid = fileid;
+                    entry.attr.fileid = attr_fileid;
+                    sink(entry)
+                }
+                Err(stat) => {
+                    wrap_failure = Some(stat);
+                    false
+                }
+            }
+        };
+        let end = as_caller(
+            export,
+            export
+                .fs
+                .readdir_each(dirid, start_after, max_entries, &mut wrapping_sink),
+        )
+        .await?;
+        match wrap_failure {
+            Some(stat) => Err(stat),
+            None => Ok(end),
+        }
+    }
+
+    async fn readdirplus(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+        max_entries: usize,
+    ) -> Result<ReadDirPlusResult, nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let mut page = as_caller(
+            export,
+            export
+                .fs
+                .readdirplus(dirid, cookie, dircount, maxcount, max_entries),
+        )
+        .await?;
+        for entry in page.entries.iter_mut() {
+            entry.cookie = entry.effective_cookie();
+            entry.fileid = self.wrap(idx, entry.fileid)?;
+            entry.attr.fileid = self.wrap(idx, entry.attr.fileid)?;
+            // the handle must be ours, not the export's
+            entry.handle = post_op_fh3::handle(self.id_to_fh(entry.fileid));
+        }
+        Ok(page)
+    }
+
+    async fn readdir_simple(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        count: usize,
+    ) -> Result<ReadDirSimpleResult, nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let mut res =
+            as_caller(export, export.fs.readdir_simple(dirid, start_after, count)).await?;
+        for entry in res.entries.iter_mut() {
+            entry.cookie = entry.effective_cookie();
+            entry.fileid = self.wrap(idx, entry.fileid)?;
+        }
+        Ok(res)
+    }
+
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let mut attr = *attr;
+        squash_sattr3(export, &mut attr);
+        let created = as_caller(export, export.fs.symlink(dirid, linkname, symlink, &attr)).await?;
+        self.wrap_created(idx, created)
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        let (_, export, id) = self.route(id)?;
+        as_caller(export, export.fs.readlink(id)).await
+    }
+
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        let (idx, export, id) = self.route(id).ok()?;
+        let dir = as_caller(export, export.fs.parent_dir(id)).await?;
+        self.wrap(idx, dir).ok()
+    }
+
+    async fn mknod(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let (idx, export, dirid) = self.route(dirid)?;
+        let mut attr = *attr;
+        squash_sattr3(export, &mut attr);
+        let created =
+            as_caller(export, export.fs.mknod(dirid, filename, ftype, &attr, spec)).await?;
+        self.wrap_created(idx, created)
+    }
+
+    async fn link(
+        &self,
+        id: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let (idx, export, id) = self.route(id)?;
+        let (dir_idx, _, linkdirid) = self.route(linkdirid)?;
+        if idx != dir_idx {
+            return Err(nfsstat3::NFS3ERR_XDEV);
+        }
+        as_caller(export, export.fs.link(id, linkdirid, linkname)).await
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        self.exports.iter().any(|e| e.fs.supports_hard_links())
+    }
+
+    async fn is_immutable_dir(&self, dirid: fileid3) -> bool {
+        match self.route(dirid) {
+            Ok((_, export, dirid)) => as_caller(export, export.fs.is_immutable_dir(dirid)).await,
+            Err(_) => false,
+        }
+    }
+
+    async fn access(&self, id: fileid3, attr: &fattr3, requested: u32) -> u32 {
+        match self.route(id) {
+            Ok((_, export, id)) => {
+                let attr = fattr3 {
+                    fileid: id,
+                    ..*attr
+                };
+                as_caller(export, export.fs.access(id, &attr, requested)).await
+            }
+            Err(_) => requested,
+        }
+    }
+
+    async fn commit(&self, id: fileid3, offset: u64, count: u32) -> Result<(), nfsstat3> {
+        let (_, export, id) = self.route(id)?;
+        as_caller(export, export.fs.commit(id, offset, count)).await
+    }
+
+    /// The smallest transfer, file size and name limits of all exports,
+    /// since READ and WRITE do not know which export they are for when
+    /// clamping.
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        let mut config = self.exports[0].fs.fsinfo_config();
+        for e in &self.exports[1..] {
+            let other = e.fs.fsinfo_config();
+            config.rtmax = config.rtmax.min(other.rtmax);
+            config.wtmax = config.wtmax.min(other.wtmax);
+            config.maxfilesize = config.maxfilesize.min(other.maxfilesize);
+            config.name_max = config.name_max.min(other.name_max);
+        }
+        config
+    }
+
+    async fn fs_stat(&self, id: fileid3) -> Result<FsStat, nfsstat3> {
+        let (_, export, id) = self.route(id)?;
+        as_caller(export, export.fs.fs_stat(id)).await
+    }
+
+    fn time_delta(&self) -> nfstime3 {
+        self.exports[0].fs.time_delta()
+    }
+
+    async fn fsinfo(&self, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        let (idx, export, root_fileid) = self.route(root_fileid)?;
+        let mut info = as_caller(export, export.fs.fsinfo(root_fileid)).await?;
+        if let post_op_attr::attributes(attr) = info.obj_attributes {
+            info.obj_attributes = post_op_attr::attributes(self.wrap_attr(idx, attr)?);
+        }
+        Ok(info)
+    }
+
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        let idx = (id.0 >> INNER_BITS) as usize;
+        let mut data = vec![idx as u8];
+        // an id of an unknown export gets a handle fh_to_id refuses
+        if let Some(export) = self.exports.get(idx) {
+            data.extend_from_slice(&export.fs.id_to_fh(fileid3(id.0 & INNER_MASK)).data);
+            if data.len() > NFS3_FHSIZE as usize {
+                error!(
+                    target: "nfsserve::nfs",
+                    "file handle of fileid {} of export {:?} is longer than {} bytes",
+                    fileid3(id.0 & INNER_MASK),
+                    String::from_utf8_lossy(&export.path),
+                    NFS3_FHSIZE - 1
+                );
+                data.clear();
+            }
+        }
+        nfs_fh3 { data }
+    }
+
+    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        let (&idx, rest) = id.data.split_first().ok_or(nfsstat3::NFS3ERR_BADHANDLE)?;
+        let idx = idx as usize;
+        let export = self.exports.get(idx).ok_or(nfsstat3::NFS3ERR_STALE)?;
+        let inner = export.fs.fh_to_id(&nfs_fh3 {
+            data: rest.to_vec(),
+        })?;
+        self.wrap(idx, inner)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<fileid3, nfsstat3> {
+        let (idx, rest) = self.match_path(path).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        let export = &self.exports[idx];
+        let id = as_caller(export, export.fs.path_to_id(rest)).await?;
+        self.wrap(idx, id)
+    }
+
+    fn serverid(&self) -> cookieverf3 {
+        self.exports[0].fs.serverid()
+    }
+
+    async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        let (_, export, id) = self.route(id).ok()?;
+        let desc = export.fs.describe_fileid(id).await?;
+        Some(format!(
+            "{}:{}",
+            String::from_utf8_lossy(&export.path),
+            desc
+        ))
+    }
+
+    /// The tables of all exports which keep one
+    async fn handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        let mut ret = Vec::new();
+        let mut any = false;
+        for (idx, export) in self.exports.iter().enumerate() {
+            let Some(table) = export.fs.handle_table().await else {
+                continue;
+            };
+            any = true;
+            let path = String::from_utf8_lossy(&export.path);
+            for (id, desc) in table {
+                if let Ok(id) = self.wrap(idx, id) {
+                    ret.push((id, format!("{path}:{desc}")));
+                }
+            }
+        }
+        any.then_some(ret)
+    }
+
+    async fn content_hash(&self, id: fileid3) -> Option<[u8; 32]> {
+        let (_, export, id) = self.route(id).ok()?;
+        as_caller(export, export.fs.content_hash(id)).await
+    }
+}
@@ -0,0 +1,154 @@
+//! Deterministic fileid assignment for virtual / generated file systems.
+//!
+//! File systems without a backing inode number can use this to derive a
+//! fileid from the path of each object, so that the same tree yields the same
+//! fileids (and hence the same file handles) every run. Combined with a fixed
+//! generation number (see vfs::set_generation_number) this keeps client
+//! caches and handles valid across server restarts.
+use crate::nfs::fileid3;
+use std::collections::HashMap;
+
+/// The number of bits of the fileids returned by path_hash
+pub const PATH_HASH_BITS: u32 = 56;
+
+/// 64-bit FNV-1a hash of a path, salted by perturb, xor-folded to
+/// PATH_HASH_BITS bits.
+///
+/// This is deliberately a fixed, documented hash rather than the std
+/// hasher, whose output is not guaranteed to be stable across Rust releases.
+/// The fileids are kept below 2^56 so that file systems using it can be
+/// served by exports::ExportTable, which keeps the export index in the top
+/// 8 bits. Never returns 0 as the 0 fileid is reserved.
+pub fn path_hash(path: &[u8], perturb: u64) -> fileid3 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    const MASK: u64 = (1 << PATH_HASH_BITS) - 1;
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in perturb.to_le_bytes().iter().chain(path.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    let hash = (hash >> PATH_HASH_BITS) ^ (hash & MASK);
+    if hash == 0 {
+        fileid3(1)
+    } else {
+        fileid3(hash)
+    }
+}
+
+/// Assigns fileids to paths by hashing them.
+///
+/// In the (rare) case where two paths hash to the same fileid, the path
+/// seen later is rehashed with an increasing perturbation until a free id
+/// is found. Ids of colliding paths therefore depend on the order in which
+/// they were first allocated. Every other id only depends on its path.
+#[derive(Default, Debug)]
+pub struct PathFileIdAllocator {
+    id_to_path: HashMap<fileid3, Vec<u8>>,
+    path_to_id: HashMap<Vec<u8>, fileid3>,
+    collisions: u64,
+}
+
+impl PathFileIdAllocator {
+    pub fn new() -> PathFileIdAllocator {
+        PathFileIdAllocator::default()
+    }
+
+    /// Returns the fileid of a path, allocating it if necessary
+    pub fn fileid(&mut self, path: &[u8]) -> fileid3 {
+        if let Some(id) = self.path_to_id.get(path) {
+            return *id;
+        }
+        let mut perturb = 0;
+        let mut id = path_hash(path, perturb);
+        while self.id_to_path.contains_key(&id) {
+            self.collisions += 1;
+            perturb += 1;
+            id = path_hash(path, perturb);
+        }
+        self.id_to_path.insert(id, path.to_vec());
+        self.path_to_id.insert(path.to_vec(), id);
+        id
+    }
+
+    /// Returns the fileid of a path if it was allocated
+    pub fn get_fileid(&self, path: &[u8]) -> Option<fileid3> {
+        self.path_to_id.get(path).copied()
+    }
+
+    /// Returns the path of a fileid
+    pub fn get_path(&self, id: fileid3) -> Option<&[u8]> {
+        self.id_to_path.get(&id).map(|p| p.as_slice())
+    }
+
+    /// Releases the fileid of a path. Returns the released fileid.
+    pub fn remove(&mut self, path: &[u8]) -> Option<fileid3> {
+        let id = self.path_to_id.remove(path)?;
+        self.id_to_path.remove(&id);
+        Some(id)
+    }
+
+    /// The number of hash collisions encountered so far
+    pub fn collisions(&self) -> u64 {
+        self.collisions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_hash_is_stable() {
+        // FNV-1a of the perturbation and the path, folded to 56 bits. A
+        // change here changes the fileids (and handles) of every user.
+        assert_eq!(path_hash(b"", 0), fileid3(0x00c7_f832_281a_396d));
+        assert_eq!(path_hash(b"/a/b", 0), fileid3(0x0021_e510_6ef9_66d0));
+        assert_ne!(path_hash(b"/a/b", 0), path_hash(b"/a/c", 0));
+        assert_ne!(path_hash(b"/a/b", 0), path_hash(b"/a/b", 1));
+    }
+
+    #[test]
+    fn path_hash_is_in_range() {
+        for i in 0..10_000u64 {
+            let path = format!("/dir/file{i}");
+            let id = path_hash(path.as_bytes(), i % 3).0;
+            assert_ne!(id, 0);
+            assert!(id < 1 << PATH_HASH_BITS);
+        }
+    }
+
+    #[test]
+    fn allocator_is_stable() {
+        let mut a = PathFileIdAllocator::new();
+        let mut b = PathFileIdAllocator::new();
+        let x = a.fileid(b"/x");
+        // allocated again, and in another allocator in another order
+        b.fileid(b"/y");
+        assert_eq!(a.fileid(b"/x"), x);
+        assert_eq!(b.fileid(b"/x"), x);
+        assert_eq!(x, path_hash(b"/x", 0));
+        assert_eq!(a.get_fileid(b"/x"), Some(x));
+        assert_eq!(a.get_path(x), Some(&b"/x"[..]));
+        assert_eq!(a.get_fileid(b"/y"), None);
+    }
+
+    #[test]
+    fn collisions_are_perturbed() {
+        let mut alloc = PathFileIdAllocator::new();
+        let first = alloc.fileid(b"/first");
+        // a path whose hash is taken: occupy the id of "/second"
+        let wanted = path_hash(b"/second", 0);
+        alloc.id_to_path.insert(wanted, b"/squatter".to_vec());
+        let second = alloc.fileid(b"/second");
+        assert_eq!(second, path_hash(b"/second", 1));
+        assert_ne!(second, first);
+        assert_eq!(alloc.collisions(), 1);
+        assert_eq!(alloc.get_path(second), Some(&b"/second"[..]));
+
+        // released ids can be taken again
+        assert_eq!(alloc.remove(b"/second"), Some(second));
+        assert_eq!(alloc.get_path(second), None);
+        assert_eq!(alloc.remove(b"/second"), None);
+    }
+}
@@ -0,0 +1,401 @@
+//! An NFSFileSystem adapter which hides directory entries.
+use crate::nfs::*;
+use crate::vfs::{
+    DirEntry, DirEntrySimple, FsInfoConfig, FsStat, NFSFileSystem, ReadDirResult,
+    ReadDirSimpleResult, ReadReply, VFSCapabilities, WriteReply,
+};
+use async_trait::async_trait;
+
+/// Decides which directory entries FilteredFS hides
+pub trait ReadDirFilter: Send + Sync {
+    /// Returns true if the entry name, with attributes attr, is hidden
+    fn hides(&self, name: &[u8], attr: &fattr3) -> bool;
+
+    /// Returns true if hides only looks at the name. attr is then a
+    /// default fattr3, and FilteredFS does not fetch the attributes of
+    /// looked up names or of the entries of readdir_simple.
+    fn name_only(&self) -> bool {
+        false
+    }
+}
+
+impl<F: Fn(&[u8], &fattr3) -> bool + Send + Sync> ReadDirFilter for F {
+    fn hides(&self, name: &[u8], attr: &fattr3) -> bool {
+        self(name, attr)
+    }
+}
+
+/// Hides the names starting with a '.'
+pub struct HideDotfiles;
+
+impl ReadDirFilter for HideDotfiles {
+    fn hides(&self, name: &[u8], _: &fattr3) -> bool {
+        name.starts_with(b".")
+    }
+    fn name_only(&self) -> bool {
+        true
+    }
+}
+
+/// Hides the names matching any of a list of glob patterns, in which '*'
+/// matches any run of bytes and '?' any single byte. For instance
+/// `HidePatterns::new(&["*.tmp", ".nfs*"])`.
+pub struct HidePatterns {
+    patterns: Vec<Vec<u8>>,
+}
+
+impl HidePatterns {
+    pub fn new<P: AsRef<[u8]>>(patterns: &[P]) -> HidePatterns {
+        HidePatterns {
+            patterns: patterns.iter().map(|p| p.as_ref().to_vec()).collect(),
+        }
+    }
+}
+
+impl ReadDirFilter for HidePatterns {
+    fn hides(&self, name: &[u8], _: &fattr3) -> bool {
+        self.patterns.iter().any(|p| glob_match(p, name))
+    }
+    fn name_only(&self) -> bool {
+        true
+    }
+}
+
+/// Matches name against pattern, backtracking to the last '*' only
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position after the last '*' and the name position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Wraps a file system so that the entries a ReadDirFilter hides are left
+/// out of directory listings, and looking them up fails with
+/// NFS3ERR_NOENT. "." and ".." are never hidden. Mounting a path through
+/// a hidden name fails too, as path_to_id walks the path with lookup.
+///
+/// Only lookups and listings are filtered. A client which knows a hidden
+/// name can still create, remove and rename it: the Linux client, for
+/// one, renames files which are removed while open to ".nfsXXXX" names
+/// and removes them by name later, which must keep working when
+/// dotfiles are hidden. A create of a hidden name which exists (even
+/// GUARDED) therefore replaces it.
+///
+/// readdir_raw is not forwarded since its encoded entries cannot be
+/// filtered. READDIRPLUS falls back to readdir_each instead.
+pub struct FilteredFS<T: NFSFileSystem, F: ReadDirFilter> {
+    inner: T,
+    filter: F,
+}
+
+impl<T: NFSFileSystem, F: ReadDirFilter> FilteredFS<T, F> {
+    pub fn new(inner: T, filter: F) -> FilteredFS<T, F> {
+        FilteredFS { inner, filter }
+    }
+
+    /// Returns the wrapped file system
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn hides(&self, name: &[u8], attr: &fattr3) -> bool {
+        name != b"." && name != b".." && self.filter.hides(name, attr)
+    }
+}
+
+#[async_trait]
+impl<T: NFSFileSystem + Send, F: ReadDirFilter> NFSFileSystem for FilteredFS<T, F> {
+    fn capabilities(&self) -> VFSCapabilities {
+        self.inner.capabilities()
+    }
+    fn root_dir(&self) -> fileid3 {
+        self.inner.root_dir()
+    }
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        let id = self.inner.lookup(dirid, filename).await?;
+        let attr = if self.filter.name_only() {
+            fattr3::default()
+        } else {
+            self.inner.getattr(id).await?
+        };
+        if self.hides(filename, &attr) {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        Ok(id)
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.inner.getattr(id).await
+    }
+
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.inner.setattr(id, setattr).await
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.inner.read(id, offset, count).await
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        self.inner.read_ext(id, offset, count, buf).await
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        self.inner.write(id, offset, data).await
+    }
+
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        self.inner.write_ext(id, offset, data, stable).await
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.create(dirid, filename, attr).await
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.inner.create_exclusive(dirid, filename).await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.mkdir(dirid, dirname).await
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.inner.remove(dirid, filename).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        // pages are refilled past the hidden entries, so that a page of
+        // only hidden entries does not come back empty
+        let mut ret = ReadDirResult::default();
+        let mut start_after = start_after;
+        loop {
+            let page = self
+                .inner
+                .readdir(dirid, start_after, (max_entries - ret.entries.len()).max(1))
+                .await?;
+            let next = page.entries.last().map(DirEntry::effective_cookie);
+            ret.entries.extend(
+                page.entries
+                    .into_iter()
+                    .filter(|e| !self.hides(&e.name, &e.attr)),
+            );
+            ret.end = page.end;
+            match next {
+                Some(next) if !page.end && ret.entries.len() < max_entries => start_after = next,
+                _ => return Ok(ret),
+            }
+        }
+    }
+
+    async fn readdir_each(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
+        // a hidden entry counts as taken, so that listing continues past it
+        let mut filtered = |entry: DirEntry| self.hides(&entry.name, &entry.attr) || sink(entry);
+        self.inner
+            .readdir_each(dirid, start_after, max_entries, &mut filtered)
+            .await
+    }
+
+    // readdirplus is left to the default, which lists through the
+    // readdir_each above and so skips the hidden entries
+
+    async fn readdir_simple(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        count: usize,
+    ) -> Result<ReadDirSimpleResult, nfsstat3> {
+        if !self.filter.name_only() {
+            return Ok(ReadDirSimpleResult::from_readdir_result(
+                &self.readdir(dirid, start_after, count).await?,
+            ));
+        }
+        let mut ret = ReadDirSimpleResult::default();
+        let mut start_after = start_after;
+        let attr = fattr3::default();
+        loop {
+            let page = self
+                .inner
+                .readdir_simple(dirid, start_after, (count - ret.entries.len()).max(1))
+                .await?;
+            let next = page.entries.last().map(DirEntrySimple::effective_cookie);
+            ret.entries.extend(
+                page.entries
+                    .into_iter()
+                    .filter(|e| !self.hides(&e.name, &attr)),
+            );
+            ret.end = page.end;
+            match next {
+                Some(next) if !page.end && ret.entries.len() < count => start_after = next,
+                _ => return Ok(ret),
+            }
+        }
+    }
+
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.symlink(dirid, linkname, symlink, attr).await
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.inner.readlink(id).await
+    }
+
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        self.inner.parent_dir(id).await
+    }
+
+    async fn mknod(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.mknod(dirid, filename, ftype, attr, spec).await
+    }
+
+    async fn link(
+        &self,
+        id: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.inner.link(id, linkdirid, linkname).await
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        self.inner.supports_hard_links()
+    }
+
+    async fn is_immutable_dir(&self, dirid: fileid3) -> bool {
+        self.inner.is_immutable_dir(dirid).await
+    }
+
+    async fn access(&self, id: fileid3, attr: &fattr3, requested: u32) -> u32 {
+        self.inner.access(id, attr, requested).await
+    }
+
+    async fn commit(&self, id: fileid3, offset: u64, count: u32) -> Result<(), nfsstat3> {
+        self.inner.commit(id, offset, count).await
+    }
+
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        self.inner.fsinfo_config()
+    }
+
+    async fn fs_stat(&self, id: fileid3) -> Result<FsStat, nfsstat3> {
+        self.inner.fs_stat(id).await
+    }
+
+    fn time_delta(&self) -> nfstime3 {
+        self.inner.time_delta()
+    }
+
+    async fn fsinfo(&self, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    fn serverid(&self) -> cookieverf3 {
+        self.inner.serverid()
+    }
+
+    async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        self.inner.describe_fileid(id).await
+    }
+
+    async fn handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        self.inner.handle_table().await
+    }
+
+    async fn content_hash(&self, id: fileid3) -> Option<[u8; 32]> {
+        self.inner.content_hash(id).await
+    }
+}
@@ -1,13 +1,104 @@
 use crate::nfs::*;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::Metadata;
 use std::fs::Permissions;
 
 #[cfg(unix)]
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::Path;
 use tokio::fs::OpenOptions;
 use tracing::debug;
 
+pub use crate::silly_rename::is_silly_rename;
+
+/// A least recently used cache of symlink targets by fileid, for file
+/// systems whose readlink would otherwise read the backing link on every
+/// call. Trees of symlinks (node_modules, say) are resolved over and over.
+///
+/// The cache does not notice changes by itself: the file system must
+/// invalidate a link whenever it may have changed. That is on setattr,
+/// remove and rename of the link, and when the backing object is found
+/// changed (a link replaced outside of NFS has a new mtime).
+#[derive(Debug, Default)]
+pub struct SymlinkCache {
+    capacity: usize,
+    /// the target and the last use of each link
+    targets: HashMap<fileid3, (nfspath3, u64)>,
+    /// the links by last use, least recent first
+    by_use: BTreeMap<u64, fileid3>,
+    next_use: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl SymlinkCache {
+    /// A cache of at most capacity links. A capacity of 0 caches nothing.
+    pub fn new(capacity: usize) -> SymlinkCache {
+        SymlinkCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the cached target of id, which becomes the most recently
+    /// used link
+    pub fn get(&mut self, id: fileid3) -> Option<nfspath3> {
+        let Some((target, last_use)) = self.targets.get_mut(&id) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.by_use.remove(last_use);
+        *last_use = self.next_use;
+        self.by_use.insert(self.next_use, id);
+        self.next_use += 1;
+        Some(target.clone())
+    }
+
+    /// Caches the target of id, evicting the least recently used link if
+    /// the cache is full
+    pub fn insert(&mut self, id: fileid3, target: nfspath3) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(id);
+        if self.targets.len() >= self.capacity {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.targets.remove(&oldest);
+            }
+        }
+        self.targets.insert(id, (target, self.next_use));
+        self.by_use.insert(self.next_use, id);
+        self.next_use += 1;
+    }
+
+    /// Forgets the target of id
+    pub fn invalidate(&mut self, id: fileid3) {
+        if let Some((_, last_use)) = self.targets.remove(&id) {
+            self.by_use.remove(&last_use);
+        }
+    }
+
+    /// The number of links cached
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// The number of gets answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of gets of links not cached
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
 /// Compares if file metadata has changed in a significant way
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 pub fn metadata_differ(lhs: &Metadata, rhs: &Metadata) -> bool {
@@ -31,12 +122,119 @@ pub fn exists_no_traverse(path: &Path) -> bool {
     path.symlink_metadata().is_ok()
 }
 
+/// Maps the error of a filesystem call to an nfsstat3.
+/// EPERM (the caller is not the owner, or a sticky bit forbids a removal)
+/// and EACCES (the filesystem denied access) are kept distinct as clients
+/// report them differently.
+pub fn io_error_to_nfsstat3(err: &std::io::Error) -> nfsstat3 {
+    match err.raw_os_error() {
+        Some(libc::EPERM) => nfsstat3::NFS3ERR_PERM,
+        Some(libc::EACCES) => nfsstat3::NFS3ERR_ACCES,
+        Some(libc::ENOENT) => nfsstat3::NFS3ERR_NOENT,
+        Some(libc::EEXIST) => nfsstat3::NFS3ERR_EXIST,
+        Some(libc::EXDEV) => nfsstat3::NFS3ERR_XDEV,
+        Some(libc::ENOTDIR) => nfsstat3::NFS3ERR_NOTDIR,
+        Some(libc::EISDIR) => nfsstat3::NFS3ERR_ISDIR,
+        Some(libc::ENOTEMPTY) => nfsstat3::NFS3ERR_NOTEMPTY,
+        Some(libc::ENOSPC) => nfsstat3::NFS3ERR_NOSPC,
+        Some(libc::EDQUOT) => nfsstat3::NFS3ERR_DQUOT,
+        Some(libc::ENAMETOOLONG) => nfsstat3::NFS3ERR_NAMETOOLONG,
+        Some(libc::EROFS) => nfsstat3::NFS3ERR_ROFS,
+        _ => nfsstat3::NFS3ERR_IO,
+    }
+}
+
+/// Returns the uid and gid to change to, if any
+fn sattr3_owner(setattr: &sattr3) -> (Option<uid3>, Option<gid3>) {
+    let uid = match setattr.uid {
+        set_uid3::uid(uid) => Some(uid),
+        set_uid3::Void => None,
+    };
+    let gid = match setattr.gid {
+        set_gid3::gid(gid) => Some(gid),
+        set_gid3::Void => None,
+    };
+    (uid, gid)
+}
+
+/// How the helpers of this module treat the modes they set (path_setattr,
+/// file_setattr) or report (metadata_to_fattr3). The free functions apply
+/// the default policy, under which modes are used as the client sent them.
+///
+/// The umask is the business of the client, which applies it before
+/// sending the mode of a CREATE or MKDIR, so the server must not apply one
+/// again. A file system keeps its own policy, so that two of them in one
+/// process may differ.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ModePolicy {
+    /// Adds the owner write bit to every mode, as earlier versions always
+    /// did. This lets a server which does not run as root write to a file
+    /// created read-only (e.g. the copy of a read-only file), at the cost
+    /// of modes which differ from what the client asked for.
+    pub force_owner_writable: bool,
+}
+
+impl ModePolicy {
+    /// The permission bits (including setuid, setgid and sticky) of a mode
+    fn unmask(&self, mode: u32) -> u32 {
+        let mode = if self.force_owner_writable {
+            mode | 0o200
+        } else {
+            mode
+        };
+        mode & 0o7777
+    }
+
+    /// The attributes with the mode to set, if any, under this policy
+    fn apply(&self, setattr: &sattr3) -> sattr3 {
+        let mut setattr = *setattr;
+        if let set_mode3::mode(mode) = setattr.mode {
+            setattr.mode = set_mode3::mode(self.unmask(mode));
+        }
+        setattr
+    }
+
+    /// Converts fs Metadata to NFS fattr3
+    pub fn metadata_to_fattr3(&self, fid: fileid3, meta: &Metadata) -> fattr3 {
+        let mut attr = metadata_to_fattr3(fid, meta);
+        attr.mode = self.unmask(attr.mode);
+        attr
+    }
+
+    /// Set attributes of a path
+    pub async fn path_setattr(&self, path: &Path, setattr: &sattr3) -> Result<(), nfsstat3> {
+        path_setattr(path, &self.apply(setattr)).await
+    }
+
+    /// Set attributes of a file
+    pub async fn file_setattr(
+        &self,
+        file: &std::fs::File,
+        setattr: &sattr3,
+    ) -> Result<(), nfsstat3> {
+        file_setattr(file, &self.apply(setattr)).await
+    }
+}
+
+/// The permission bits (including setuid, setgid and sticky) of a mode
 fn mode_unmask(mode: u32) -> u32 {
-    // it is possible to create a file we cannot write to.
-    // we force writable always.
-    let mode = mode | 0x80;
-    let mode = Permissions::from_mode(mode);
-    mode.mode() & 0x1FF
+    ModePolicy::default().unmask(mode)
+}
+
+/// The ftype3 of FIFOs, sockets and devices
+fn special_file_ftype3(meta: &Metadata) -> Option<ftype3> {
+    let ft = meta.file_type();
+    if ft.is_fifo() {
+        Some(ftype3::NF3FIFO)
+    } else if ft.is_socket() {
+        Some(ftype3::NF3SOCK)
+    } else if ft.is_char_device() {
+        Some(ftype3::NF3CHR)
+    } else if ft.is_block_device() {
+        Some(ftype3::NF3BLK)
+    } else {
+        None
+    }
 }
 
 /// Converts fs Metadata to NFS fattr3
@@ -93,6 +291,34 @@ pub fn metadata_to_fattr3(fid: fileid3, meta: &Metadata) -> fattr3 {
                 nseconds: meta.ctime_nsec() as u32,
             },
         }
+    } else if let Some(ftype) = special_file_ftype3(meta) {
+        fattr3 {
+            ftype,
+            mode: file_mode,
+            nlink: 1,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size,
+            used: size,
+            rdev: specdata3 {
+                specdata1: libc::major(meta.rdev() as libc::dev_t) as u32,
+                specdata2: libc::minor(meta.rdev() as libc::dev_t) as u32,
+            },
+            fsid: 0,
+            fileid: fid,
+            atime: nfstime3 {
+                seconds: meta.atime() as u32,
+                nseconds: meta.atime_nsec() as u32,
+            },
+            mtime: nfstime3 {
+                seconds: meta.mtime() as u32,
+                nseconds: meta.mtime_nsec() as u32,
+            },
+            ctime: nfstime3 {
+                seconds: meta.ctime() as u32,
+                nseconds: meta.ctime_nsec() as u32,
+            },
+        }
     } else {
         fattr3 {
             ftype: ftype3::NF3DIR,
@@ -141,17 +367,18 @@ pub async fn path_setattr(path: &Path, setattr: &sattr3) -> Result<(), nfsstat3>
         }
         _ => {}
     };
+    // chown before chmod as a chown may clear the setuid/setgid bits
+    let (uid, gid) = sattr3_owner(setattr);
+    if uid.is_some() || gid.is_some() {
+        debug!(target: "nfsserve::fs_util", " -- set owner {:?} {:?} {:?}", path, uid, gid);
+        std::os::unix::fs::lchown(path, uid, gid).map_err(|e| io_error_to_nfsstat3(&e))?;
+    }
     if let set_mode3::mode(mode) = setattr.mode {
-        debug!(" -- set permissions {:?} {:?}", path, mode);
+        debug!(target: "nfsserve::fs_util", " -- set permissions {:?} {:?}", path, mode);
         let mode = mode_unmask(mode);
-        let _ = std::fs::set_permissions(path, Permissions::from_mode(mode));
+        std::fs::set_permissions(path, Permissions::from_mode(mode))
+            .map_err(|e| io_error_to_nfsstat3(&e))?;
     };
-    if let set_uid3::uid(_) = setattr.uid {
-        debug!("Set uid not implemented");
-    }
-    if let set_gid3::gid(_) = setattr.gid {
-        debug!("Set gid not implemented");
-    }
     if let set_size3::size(size3) = setattr.size {
         let file = OpenOptions::new()
             .read(true)
@@ -160,7 +387,7 @@ pub async fn path_setattr(path: &Path, setattr: &sattr3) -> Result<(), nfsstat3>
             .open(path)
             .await
             .or(Err(nfsstat3::NFS3ERR_IO))?;
-        debug!(" -- set size {:?} {:?}", path, size3);
+        debug!(target: "nfsserve::fs_util", " -- set size {:?} {:?}", path, size3);
         file.set_len(size3).await.or(Err(nfsstat3::NFS3ERR_IO))?;
     }
     Ok(())
@@ -168,14 +395,141 @@ pub async fn path_setattr(path: &Path, setattr: &sattr3) -> Result<(), nfsstat3>
 
 /// Set attributes of a file
 pub async fn file_setattr(file: &std::fs::File, setattr: &sattr3) -> Result<(), nfsstat3> {
+    let (uid, gid) = sattr3_owner(setattr);
+    if uid.is_some() || gid.is_some() {
+        debug!(target: "nfsserve::fs_util", " -- set owner {:?} {:?}", uid, gid);
+        std::os::unix::fs::fchown(file, uid, gid).map_err(|e| io_error_to_nfsstat3(&e))?;
+    }
     if let set_mode3::mode(mode) = setattr.mode {
-        debug!(" -- set permissions {:?}", mode);
+        debug!(target: "nfsserve::fs_util", " -- set permissions {:?}", mode);
         let mode = mode_unmask(mode);
-        let _ = file.set_permissions(Permissions::from_mode(mode));
+        file.set_permissions(Permissions::from_mode(mode))
+            .map_err(|e| io_error_to_nfsstat3(&e))?;
     }
     if let set_size3::size(size3) = setattr.size {
-        debug!(" -- set size {:?}", size3);
+        debug!(target: "nfsserve::fs_util", " -- set size {:?}", size3);
         file.set_len(size3).or(Err(nfsstat3::NFS3ERR_IO))?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped(errno: i32) -> nfsstat3 {
+        io_error_to_nfsstat3(&std::io::Error::from_raw_os_error(errno))
+    }
+
+    #[test]
+    fn not_owner_is_perm() {
+        assert!(matches!(mapped(libc::EPERM), nfsstat3::NFS3ERR_PERM));
+    }
+
+    #[test]
+    fn denied_is_acces() {
+        assert!(matches!(mapped(libc::EACCES), nfsstat3::NFS3ERR_ACCES));
+    }
+
+    #[test]
+    fn unknown_is_io() {
+        assert!(matches!(mapped(libc::EBUSY), nfsstat3::NFS3ERR_IO));
+        let err = std::io::Error::other("no errno");
+        assert!(matches!(io_error_to_nfsstat3(&err), nfsstat3::NFS3ERR_IO));
+    }
+
+    const FORCED: ModePolicy = ModePolicy {
+        force_owner_writable: true,
+    };
+
+    /// A new empty file in the temporary directory, removed on drop
+    struct Scratch(std::path::PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let path = std::env::temp_dir()
+                .join(format!("nfsserve-fs_util-{}-{name}", std::process::id()));
+            std::fs::File::create(&path).unwrap();
+            Scratch(path)
+        }
+
+        fn mode(&self) -> u32 {
+            self.0.metadata().unwrap().mode() & 0o7777
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn set_mode(mode: u32) -> sattr3 {
+        sattr3 {
+            mode: set_mode3::mode(mode),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn path_setattr_applies_the_mode_as_sent() {
+        let file = Scratch::new("path-as-sent");
+        path_setattr(&file.0, &set_mode(0o640)).await.unwrap();
+        assert_eq!(file.mode(), 0o640);
+        path_setattr(&file.0, &set_mode(0o440)).await.unwrap();
+        assert_eq!(file.mode(), 0o440);
+    }
+
+    #[tokio::test]
+    async fn path_setattr_forces_the_owner_write_bit() {
+        let file = Scratch::new("path-forced");
+        FORCED
+            .path_setattr(&file.0, &set_mode(0o440))
+            .await
+            .unwrap();
+        assert_eq!(file.mode(), 0o640);
+        FORCED
+            .path_setattr(&file.0, &set_mode(0o640))
+            .await
+            .unwrap();
+        assert_eq!(file.mode(), 0o640);
+    }
+
+    #[tokio::test]
+    async fn file_setattr_applies_the_mode_as_sent() {
+        // what a create through the helpers does: the mode of the
+        // client, umask included, and not the umask of the server
+        let file = Scratch::new("file-as-sent");
+        let handle = std::fs::File::open(&file.0).unwrap();
+        file_setattr(&handle, &set_mode(0o640)).await.unwrap();
+        assert_eq!(file.mode(), 0o640);
+        file_setattr(&handle, &set_mode(0o4755)).await.unwrap();
+        assert_eq!(file.mode(), 0o4755);
+    }
+
+    #[tokio::test]
+    async fn file_setattr_forces_the_owner_write_bit() {
+        let file = Scratch::new("file-forced");
+        let handle = std::fs::File::open(&file.0).unwrap();
+        FORCED
+            .file_setattr(&handle, &set_mode(0o440))
+            .await
+            .unwrap();
+        assert_eq!(file.mode(), 0o640);
+    }
+
+    #[test]
+    fn metadata_to_fattr3_reports_the_mode_on_disk() {
+        let file = Scratch::new("fattr3");
+        std::fs::set_permissions(&file.0, Permissions::from_mode(0o440)).unwrap();
+        let meta = file.0.metadata().unwrap();
+        assert_eq!(metadata_to_fattr3(fileid3(1), &meta).mode, 0o440);
+        assert_eq!(
+            ModePolicy::default()
+                .metadata_to_fattr3(fileid3(1), &meta)
+                .mode,
+            0o440
+        );
+        assert_eq!(FORCED.metadata_to_fattr3(fileid3(1), &meta).mode, 0o640);
+    }
+}
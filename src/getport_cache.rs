@@ -0,0 +1,69 @@
+//! The answers of PMAPPROC_GETPORT, and the programs clients probed for
+use std::collections::BTreeMap;
+use std::sync::{Mutex, RwLock};
+
+/// The number of (prog, vers, prot) answers remembered. Queries beyond
+/// that are answered from the registry.
+const MAX_CACHED_ANSWERS: usize = 256;
+
+/// The number of unserved (prog, vers) pairs counted individually.
+/// Queries for others are counted under (u32::MAX, u32::MAX).
+const MAX_TRACKED_UNSERVED: usize = 64;
+
+/// The answer to a GETPORT query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetportAnswer {
+    /// The port replied, 0 if the mapping is not served
+    pub port: u32,
+    /// True if the version of the program is not served at all (whatever
+    /// the protocol), i.e. the client probed for something we lack
+    pub unserved: bool,
+}
+
+/// The GETPORT answers of a listener, so that the queries clients repeat
+/// (some on every reconnect, misconfigured ones in a loop) do not consult
+/// the program registry each time, and the count of queries for programs
+/// which are not served, i.e. what clients look for (NFSv4, NLM, NSM...).
+///
+/// The answers are cleared whenever the registry changes.
+#[derive(Debug, Default)]
+pub struct GetportCache {
+    answers: RwLock<BTreeMap<(u32, u32, u32), GetportAnswer>>,
+    unserved: Mutex<BTreeMap<(u32, u32), u64>>,
+}
+
+impl GetportCache {
+    /// Returns the remembered answer for (prog, vers, prot)
+    pub fn get(&self, key: (u32, u32, u32)) -> Option<GetportAnswer> {
+        self.answers.read().unwrap().get(&key).copied()
+    }
+
+    /// Remembers the answer for (prog, vers, prot) if there is room
+    pub fn insert(&self, key: (u32, u32, u32), answer: GetportAnswer) {
+        let mut answers = self.answers.write().unwrap();
+        if answers.len() < MAX_CACHED_ANSWERS {
+            answers.insert(key, answer);
+        }
+    }
+
+    /// Forgets all answers. Called when the served programs change.
+    pub fn clear(&self) {
+        self.answers.write().unwrap().clear();
+    }
+
+    /// Counts a query for a (prog, vers) which is not served
+    pub fn count_unserved(&self, prog: u32, vers: u32) {
+        let mut unserved = self.unserved.lock().unwrap();
+        let key = if unserved.len() < MAX_TRACKED_UNSERVED || unserved.contains_key(&(prog, vers)) {
+            (prog, vers)
+        } else {
+            (u32::MAX, u32::MAX)
+        };
+        *unserved.entry(key).or_default() += 1;
+    }
+
+    /// The number of queries for each unserved (prog, vers)
+    pub fn unserved(&self) -> BTreeMap<(u32, u32), u64> {
+        self.unserved.lock().unwrap().clone()
+    }
+}
@@ -1,8 +1,13 @@
 #![cfg_attr(feature = "strict", deny(warnings))]
 
+mod capture;
 mod context;
+mod getport_cache;
+mod memory_budget;
+mod readdir_estimate;
 mod rpc;
 mod rpcwire;
+mod silly_rename;
 mod write_counter;
 pub mod xdr;
 
@@ -12,11 +17,37 @@ mod mount_handlers;
 mod portmap;
 mod portmap_handlers;
 
+mod nlm;
+mod nlm_handlers;
+
 pub mod nfs;
 mod nfs_handlers;
 
+#[cfg(feature = "nfsacl")]
+mod nfsacl;
+#[cfg(feature = "nfsacl")]
+mod nfsacl_handlers;
+
+#[cfg(feature = "metadata")]
+mod metadata;
+#[cfg(feature = "metadata")]
+mod metadata_handlers;
+
 #[cfg(not(target_os = "windows"))]
 pub mod fs_util;
 
+#[cfg(feature = "log-reload")]
+pub mod logging;
+
+pub mod coalesce;
+pub mod config;
+pub mod exports;
+pub mod fileid_alloc;
+pub mod filter;
+pub mod memfs;
+pub mod registry;
+pub mod retry_hint;
+pub mod support;
+pub mod sync_batch;
 pub mod tcp;
 pub mod vfs;
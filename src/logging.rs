@@ -0,0 +1,69 @@
+//! Runtime control of the tracing output of the crate.
+//!
+//! Every subsystem logs under its own tracing target so that verbosity can
+//! be raised for just one part of the server:
+//!
+//!  - `nfsserve::tcp`: connection accept and lifecycle
+//!  - `nfsserve::rpc`: RPC record / message decoding and dispatch
+//!  - `nfsserve::portmap`: the Portmapper protocol
+//!  - `nfsserve::mount`: the Mount protocol
+//!  - `nfsserve::nfs`: NFS procedures not listed below
+//!  - `nfsserve::read`: NFS READ
+//!  - `nfsserve::write`: NFS WRITE
+//!  - `nfsserve::readdir`: NFS READDIR and READDIRPLUS
+//!  - `nfsserve::nfsacl`: the NFSACL sideband program
+//!  - `nfsserve::nlm`: the NLM lock manager
+//!  - `nfsserve::metadata`: the metadata program
+//!  - `nfsserve::fs_util`: the fs_util helpers
+//!
+//! Embedders which install their own subscriber can filter on these targets
+//! directly. Otherwise, [`install_subscriber`] installs a subscriber logging
+//! to stderr whose filter can be changed at runtime with [`set_log_filter`]
+//! (or `NFSTcpListener::set_log_filter`) without restarting the server.
+use anyhow::anyhow;
+use std::sync::OnceLock;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, Registry};
+
+static FILTER_HANDLE: OnceLock<reload::Handle<Targets, Registry>> = OnceLock::new();
+
+/// Installs a global subscriber writing to stderr, filtered by filter.
+///
+/// filter is a comma separated list of `target=level` directives, with a
+/// bare level setting the default. For instance
+/// "warn,nfsserve::readdir=trace,nfsserve::mount=debug".
+///
+/// Fails if the filter cannot be parsed or if a global subscriber is
+/// already installed.
+pub fn install_subscriber(filter: &str) -> Result<(), anyhow::Error> {
+    install_subscriber_with_writer(filter, std::io::stderr)
+}
+
+/// Like [`install_subscriber`], logging to writer instead of stderr
+pub fn install_subscriber_with_writer<W>(filter: &str, writer: W) -> Result<(), anyhow::Error>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let targets: Targets = filter.parse()?;
+    let (filter_layer, handle) = reload::Layer::new(targets);
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt::layer().with_writer(writer))
+        .try_init()?;
+    FILTER_HANDLE
+        .set(handle)
+        .map_err(|_| anyhow!("subscriber already installed"))
+}
+
+/// Replaces the filter of the subscriber installed with
+/// [`install_subscriber`]. See install_subscriber for the filter format.
+pub fn set_log_filter(filter: &str) -> Result<(), anyhow::Error> {
+    let targets: Targets = filter.parse()?;
+    let handle = FILTER_HANDLE
+        .get()
+        .ok_or_else(|| anyhow!("no subscriber installed by nfsserve"))?;
+    handle.reload(targets)?;
+    Ok(())
+}
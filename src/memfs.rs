@@ -0,0 +1,1199 @@
+//! A file system held entirely in memory.
+//!
+//! MemFS serves directories, regular files and symlinks, and is writable.
+//! It is meant for tests and scratch exports: nothing is persisted, and
+//! every operation takes a single lock over the whole tree.
+//!
+//! Fileids are deterministic: the root is 1 and every object created
+//! (including by from_entries) takes the next id, in order. Ids are never
+//! reused, so handles to removed objects stay STALE.
+use crate::nfs::{
+    cookie3, fattr3, fileid3, filename3, ftype3, nfspath3, nfsstat3, nfstime3, sattr3, set_atime,
+    set_gid3, set_mode3, set_mtime, set_size3, set_uid3, specdata3,
+};
+use crate::vfs::{
+    current_user, DirEntry, NFSFileSystem, ReadDirResult, ReadReply, VFSCapabilities,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The fileid of the root directory
+const ROOT: fileid3 = fileid3(1);
+
+/// An object to create with MemFS::from_entries. Paths are relative to
+/// the root, with components separated by '/'. Missing parent
+/// directories are created.
+#[derive(Clone, Debug)]
+pub enum MemEntry {
+    Dir(String),
+    File(String, Vec<u8>),
+    Symlink(String, String),
+}
+
+#[derive(Debug)]
+enum Contents {
+    File(Vec<u8>),
+    /// The entries in creation order, which is the listing order
+    Dir(Vec<fileid3>),
+    Symlink(nfspath3),
+}
+
+#[derive(Debug)]
+struct Node {
+    attr: fattr3,
+    name: filename3,
+    parent: fileid3,
+    contents: Contents,
+}
+
+#[derive(Debug)]
+struct Tree {
+    nodes: HashMap<fileid3, Node>,
+    next_id: u64,
+}
+
+fn now() -> nfstime3 {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    nfstime3 {
+        seconds: d.as_secs() as u32,
+        nseconds: d.subsec_nanos(),
+    }
+}
+
+/// Grows or shrinks the contents of a file to size, growing with zeros.
+/// Sizes past maxfilesize are NFS3ERR_FBIG and memory which cannot be had
+/// NFS3ERR_NOSPC, rather than a panic with the tree locked, which would
+/// poison the lock for every later call.
+fn resize_file(bytes: &mut Vec<u8>, size: u64, maxfilesize: u64) -> Result<(), nfsstat3> {
+    if size > maxfilesize {
+        return Err(nfsstat3::NFS3ERR_FBIG);
+    }
+    let size = usize::try_from(size).or(Err(nfsstat3::NFS3ERR_FBIG))?;
+    if size > bytes.len() {
+        bytes
+            .try_reserve_exact(size - bytes.len())
+            .or(Err(nfsstat3::NFS3ERR_NOSPC))?;
+    }
+    bytes.resize(size, 0);
+    Ok(())
+}
+
+impl Tree {
+    fn node(&self, id: fileid3) -> Result<&Node, nfsstat3> {
+        self.nodes.get(&id).ok_or(nfsstat3::NFS3ERR_STALE)
+    }
+
+    fn node_mut(&mut self, id: fileid3) -> Result<&mut Node, nfsstat3> {
+        self.nodes.get_mut(&id).ok_or(nfsstat3::NFS3ERR_STALE)
+    }
+
+    fn children(&self, dirid: fileid3) -> Result<&Vec<fileid3>, nfsstat3> {
+        match &self.node(dirid)?.contents {
+            Contents::Dir(children) => Ok(children),
+            _ => Err(nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    fn children_mut(&mut self, dirid: fileid3) -> Result<&mut Vec<fileid3>, nfsstat3> {
+        match &mut self.node_mut(dirid)?.contents {
+            Contents::Dir(children) => Ok(children),
+            _ => Err(nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    fn lookup(&self, dirid: fileid3, name: &[u8]) -> Result<fileid3, nfsstat3> {
+        let children = self.children(dirid)?;
+        match name {
+            b"." => Ok(dirid),
+            b".." => Ok(self.node(dirid)?.parent),
+            _ => children
+                .iter()
+                .copied()
+                .find(|id| self.nodes[id].name[..] == name[..])
+                .ok_or(nfsstat3::NFS3ERR_NOENT),
+        }
+    }
+
+    /// Marks a directory as changed
+    fn touch(&mut self, dirid: fileid3) {
+        if let Ok(node) = self.node_mut(dirid) {
+            let t = now();
+            node.attr.mtime = t;
+            node.attr.ctime = t;
+        }
+    }
+
+    /// Adds a new object named name to dirid, which must not exist
+    fn insert(
+        &mut self,
+        dirid: fileid3,
+        name: &[u8],
+        ftype: ftype3,
+        contents: Contents,
+    ) -> Result<fileid3, nfsstat3> {
+        if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        match self.lookup(dirid, name) {
+            Ok(_) => return Err(nfsstat3::NFS3ERR_EXIST),
+            Err(nfsstat3::NFS3ERR_NOENT) => {}
+            Err(e) => return Err(e),
+        }
+        let id = fileid3(self.next_id);
+        self.next_id += 1;
+        let (mode, nlink, size) = match &contents {
+            Contents::File(bytes) => (0o644, 1, bytes.len() as u64),
+            Contents::Dir(_) => (0o755, 2, 0),
+            Contents::Symlink(target) => (0o777, 1, target.len() as u64),
+        };
+        let user = current_user().unwrap_or_default();
+        let t = now();
+        let attr = fattr3 {
+            ftype,
+            mode,
+            nlink,
+            uid: user.uid,
+            gid: user.gid,
+            size,
+            used: size,
+            rdev: specdata3::default(),
+            fsid: 0,
+            fileid: id,
+            atime: t,
+            mtime: t,
+            ctime: t,
+        };
+        self.nodes.insert(
+            id,
+            Node {
+                attr,
+                name: name.to_vec().into(),
+                parent: dirid,
+                contents,
+            },
+        );
+        self.children_mut(dirid)?.push(id);
+        self.touch(dirid);
+        Ok(id)
+    }
+
+    fn setattr(
+        &mut self,
+        id: fileid3,
+        setattr: &sattr3,
+        maxfilesize: u64,
+    ) -> Result<fattr3, nfsstat3> {
+        let node = self.node_mut(id)?;
+        if let set_size3::size(size) = setattr.size {
+            let Contents::File(bytes) = &mut node.contents else {
+                return Err(match node.contents {
+                    Contents::Dir(_) => nfsstat3::NFS3ERR_ISDIR,
+                    _ => nfsstat3::NFS3ERR_INVAL,
+                });
+            };
+            // growing reads as zeros
+            resize_file(bytes, size, maxfilesize)?;
+            node.attr.size = size;
+            node.attr.used = size;
+            node.attr.mtime = now();
+        }
+        if let set_mode3::mode(mode) = setattr.mode {
+            node.attr.mode = mode & 0o7777;
+        }
+        if let set_uid3::uid(uid) = setattr.uid {
+            node.attr.uid = uid;
+        }
+        if let set_gid3::gid(gid) = setattr.gid {
+            node.attr.gid = gid;
+        }
+        match setattr.atime {
+            set_atime::DONT_CHANGE => {}
+            set_atime::SET_TO_SERVER_TIME => node.attr.atime = now(),
+            set_atime::SET_TO_CLIENT_TIME(t) => node.attr.atime = t,
+        }
+        match setattr.mtime {
+            set_mtime::DONT_CHANGE => {}
+            set_mtime::SET_TO_SERVER_TIME => node.attr.mtime = now(),
+            set_mtime::SET_TO_CLIENT_TIME(t) => node.attr.mtime = t,
+        }
+        node.attr.ctime = now();
+        Ok(node.attr)
+    }
+
+    /// Drops id and everything under it
+    fn drop_subtree(&mut self, id: fileid3) {
+        if let Some(node) = self.nodes.remove(&id) {
+            if let Contents::Dir(children) = node.contents {
+                for child in children {
+                    self.drop_subtree(child);
+                }
+            }
+        }
+    }
+
+    /// True if id is ancestor or lies under it
+    fn is_within(&self, mut id: fileid3, ancestor: fileid3) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.nodes.get(&id) {
+                Some(node) if node.parent != id => id = node.parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// A writable file system held in memory. See the module documentation.
+#[derive(Debug)]
+pub struct MemFS {
+    tree: Mutex<Tree>,
+}
+
+impl Default for MemFS {
+    /// An empty file system: a root directory only
+    fn default() -> MemFS {
+        let t = now();
+        let root = Node {
+            attr: fattr3 {
+                ftype: ftype3::NF3DIR,
+                mode: 0o777,
+                nlink: 2,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                used: 0,
+                rdev: specdata3::default(),
+                fsid: 0,
+                fileid: ROOT,
+                atime: t,
+                mtime: t,
+                ctime: t,
+            },
+            name: Vec::new().into(),
+            parent: ROOT,
+            contents: Contents::Dir(Vec::new()),
+        };
+        MemFS {
+            tree: Mutex::new(Tree {
+                nodes: HashMap::from([(ROOT, root)]),
+                next_id: ROOT.0 + 1,
+            }),
+        }
+    }
+}
+
+impl MemFS {
+    /// Creates an empty file system
+    pub fn new() -> MemFS {
+        MemFS::default()
+    }
+
+    /// Creates a file system holding entries, created in order. For
+    /// instance
+    /// ```ignore
+    /// MemFS::from_entries([
+    ///     MemEntry::File("a.txt".into(), b"hello\n".to_vec()),
+    ///     MemEntry::Symlink("dir/link".into(), "../a.txt".into()),
+    /// ])
+    /// ```
+    /// Fails with InvalidInput if a path is empty, names an existing
+    /// object, or passes through something which is not a directory.
+    pub fn from_entries(entries: impl IntoIterator<Item = MemEntry>) -> io::Result<MemFS> {
+        let fs = MemFS::default();
+        {
+            let mut tree = fs.tree.lock().unwrap();
+            for entry in entries {
+                let path = match &entry {
+                    MemEntry::Dir(p) | MemEntry::File(p, _) | MemEntry::Symlink(p, _) => p.clone(),
+                };
+                let invalid = |e: nfsstat3| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("cannot create {path:?}: {e:?}"),
+                    )
+                };
+                let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+                let Some((name, parents)) = components.split_last() else {
+                    return Err(invalid(nfsstat3::NFS3ERR_INVAL));
+                };
+                let mut dirid = ROOT;
+                for component in parents {
+                    dirid = match tree.lookup(dirid, component.as_bytes()) {
+                        Ok(id) => id,
+                        Err(nfsstat3::NFS3ERR_NOENT) => tree
+                            .insert(
+                                dirid,
+                                component.as_bytes(),
+                                ftype3::NF3DIR,
+                                Contents::Dir(Vec::new()),
+                            )
+                            .map_err(invalid)?,
+                        Err(e) => return Err(invalid(e)),
+                    };
+                }
+                let (ftype, contents) = match entry {
+                    MemEntry::Dir(_) => (ftype3::NF3DIR, Contents::Dir(Vec::new())),
+                    MemEntry::File(_, bytes) => (ftype3::NF3REG, Contents::File(bytes)),
+                    MemEntry::Symlink(_, target) => (
+                        ftype3::NF3LNK,
+                        Contents::Symlink(target.into_bytes().into()),
+                    ),
+                };
+                tree.insert(dirid, name.as_bytes(), ftype, contents)
+                    .map_err(invalid)?;
+            }
+        }
+        Ok(fs)
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for MemFS {
+    fn capabilities(&self) -> VFSCapabilities {
+        VFSCapabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> fileid3 {
+        ROOT
+    }
+
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.tree.lock().unwrap().lookup(dirid, filename)
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        Ok(self.tree.lock().unwrap().node(id)?.attr)
+    }
+
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        let maxfilesize = self.fsinfo_config().maxfilesize;
+        self.tree.lock().unwrap().setattr(id, &setattr, maxfilesize)
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let mut buf = Vec::new();
+        let reply = self.read_ext(id, offset, count, &mut buf).await?;
+        Ok((buf, reply.eof))
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        let tree = self.tree.lock().unwrap();
+        let node = tree.node(id)?;
+        match &node.contents {
+            Contents::File(bytes) => {
+                let start = (offset as usize).min(bytes.len());
+                let end = (offset as usize).saturating_add(count as usize);
+                let eof = end >= bytes.len();
+                let end = end.min(bytes.len());
+                buf.extend_from_slice(&bytes[start..end]);
+                Ok(ReadReply {
+                    eof,
+                    attr: Some(node.attr),
+                })
+            }
+            Contents::Dir(_) => Err(nfsstat3::NFS3ERR_ISDIR),
+            Contents::Symlink(_) => Err(nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        let maxfilesize = self.fsinfo_config().maxfilesize;
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.node_mut(id)?;
+        let bytes = match &mut node.contents {
+            Contents::File(bytes) => bytes,
+            Contents::Dir(_) => return Err(nfsstat3::NFS3ERR_ISDIR),
+            Contents::Symlink(_) => return Err(nfsstat3::NFS3ERR_INVAL),
+        };
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(nfsstat3::NFS3ERR_FBIG)?;
+        // a gap between the old end of the file and offset reads as zeros
+        if end > bytes.len() as u64 {
+            resize_file(bytes, end, maxfilesize)?;
+        }
+        // both fit in memory now
+        let (offset, end) = (offset as usize, end as usize);
+        bytes[offset..end].copy_from_slice(data);
+        let size = bytes.len() as u64;
+        let t = now();
+        node.attr.size = size;
+        node.attr.used = size;
+        node.attr.mtime = t;
+        node.attr.ctime = t;
+        Ok(node.attr)
+    }
+
+    /// Creates a file, or for an existing file (an UNCHECKED create)
+    /// applies attr to it
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let maxfilesize = self.fsinfo_config().maxfilesize;
+        let mut tree = self.tree.lock().unwrap();
+        let id = match tree.lookup(dirid, filename) {
+            Ok(id) if matches!(tree.node(id)?.contents, Contents::File(_)) => id,
+            Ok(_) => return Err(nfsstat3::NFS3ERR_EXIST),
+            Err(nfsstat3::NFS3ERR_NOENT) => {
+                tree.insert(dirid, filename, ftype3::NF3REG, Contents::File(Vec::new()))?
+            }
+            Err(e) => return Err(e),
+        };
+        let attr = tree.setattr(id, &attr, maxfilesize)?;
+        Ok((id, attr))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.tree.lock().unwrap().insert(
+            dirid,
+            filename,
+            ftype3::NF3REG,
+            Contents::File(Vec::new()),
+        )
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let mut tree = self.tree.lock().unwrap();
+        let id = tree.insert(dirid, dirname, ftype3::NF3DIR, Contents::Dir(Vec::new()))?;
+        Ok((id, tree.node(id)?.attr))
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        let mut tree = self.tree.lock().unwrap();
+        if filename[..] == b"."[..] || filename[..] == b".."[..] {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        let id = tree.lookup(dirid, filename)?;
+        if let Contents::Dir(children) = &tree.node(id)?.contents {
+            if !children.is_empty() {
+                return Err(nfsstat3::NFS3ERR_NOTEMPTY);
+            }
+        }
+        tree.children_mut(dirid)?.retain(|c| *c != id);
+        tree.nodes.remove(&id);
+        tree.touch(dirid);
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let mut tree = self.tree.lock().unwrap();
+        for name in [from_filename, to_filename] {
+            if name.is_empty() || name[..] == b"."[..] || name[..] == b".."[..] {
+                return Err(nfsstat3::NFS3ERR_INVAL);
+            }
+        }
+        if to_filename.contains(&b'/') {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        let id = tree.lookup(from_dirid, from_filename)?;
+        // the target directory must exist
+        tree.children(to_dirid)?;
+        let is_dir = matches!(tree.node(id)?.contents, Contents::Dir(_));
+        if is_dir && tree.is_within(to_dirid, id) {
+            // a directory cannot be moved under itself
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        match tree.lookup(to_dirid, to_filename) {
+            Ok(existing) if existing == id => return Ok(()),
+            Ok(existing) => {
+                match (&tree.node(existing)?.contents, is_dir) {
+                    (Contents::Dir(children), true) if !children.is_empty() => {
+                        return Err(nfsstat3::NFS3ERR_NOTEMPTY)
+                    }
+                    (Contents::Dir(_), false) => return Err(nfsstat3::NFS3ERR_ISDIR),
+                    (Contents::File(_) | Contents::Symlink(_), true) => {
+                        return Err(nfsstat3::NFS3ERR_NOTDIR)
+                    }
+                    _ => {}
+                }
+                tree.children_mut(to_dirid)?.retain(|c| *c != existing);
+                tree.drop_subtree(existing);
+            }
+            Err(nfsstat3::NFS3ERR_NOENT) => {}
+            Err(e) => return Err(e),
+        }
+        tree.children_mut(from_dirid)?.retain(|c| *c != id);
+        tree.children_mut(to_dirid)?.push(id);
+        let node = tree.node_mut(id)?;
+        node.name = to_filename.to_vec().into();
+        node.parent = to_dirid;
+        node.attr.ctime = now();
+        tree.touch(from_dirid);
+        tree.touch(to_dirid);
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        let tree = self.tree.lock().unwrap();
+        let children = tree.children(dirid)?;
+        // the cookie of an entry is its fileid
+        let start = if start_after == cookie3(0) {
+            0
+        } else {
+            children
+                .iter()
+                .position(|c| c.0 == start_after.0)
+                .ok_or(nfsstat3::NFS3ERR_BAD_COOKIE)?
+                + 1
+        };
+        let entries: Vec<DirEntry> = children[start..]
+            .iter()
+            .take(max_entries)
+            .map(|id| {
+                let node = &tree.nodes[id];
+                DirEntry {
+                    fileid: *id,
+                    name: node.name.clone(),
+                    attr: node.attr,
+                    cookie: cookie3(id.0),
+                }
+            })
+            .collect();
+        Ok(ReadDirResult {
+            end: start + entries.len() == children.len(),
+            entries,
+        })
+    }
+
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let maxfilesize = self.fsinfo_config().maxfilesize;
+        let mut tree = self.tree.lock().unwrap();
+        let id = tree.insert(
+            dirid,
+            linkname,
+            ftype3::NF3LNK,
+            Contents::Symlink(symlink.clone()),
+        )?;
+        // the size of a symlink is that of its target
+        let attr = sattr3 {
+            size: set_size3::Void,
+            ..*attr
+        };
+        let attr = tree.setattr(id, &attr, maxfilesize)?;
+        Ok((id, attr))
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        match &self.tree.lock().unwrap().node(id)?.contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        self.tree
+            .lock()
+            .unwrap()
+            .node(id)
+            .ok()
+            .map(|node| node.parent)
+    }
+}
+
+/// The required NFSFileSystem methods, as forward_to_memfs! implements
+/// them: each calls the MemFS of the struct, unless given in the hooks
+/// block of the macro
+#[cfg(test)]
+#[async_trait]
+pub(crate) trait MemFSHooks: Send + Sync {
+    fn memfs(&self) -> &MemFS;
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.memfs().lookup(dirid, filename).await
+    }
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.memfs().getattr(id).await
+    }
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.memfs().setattr(id, setattr).await
+    }
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.memfs().read(id, offset, count).await
+    }
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        self.memfs().write(id, offset, data).await
+    }
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.memfs().create(dirid, filename, attr).await
+    }
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.memfs().create_exclusive(dirid, filename).await
+    }
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.memfs().mkdir(dirid, dirname).await
+    }
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.memfs().remove(dirid, filename).await
+    }
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.memfs()
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        self.memfs().readdir(dirid, start_after, max_entries).await
+    }
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.memfs().symlink(dirid, linkname, symlink, attr).await
+    }
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.memfs().readlink(id).await
+    }
+}
+
+/// Implements NFSFileSystem for a struct with a field named inner which
+/// derefs to a MemFS, for the unit tests of the adapters, like the macro
+/// of the same name in tests/common. The required methods go through
+/// MemFSHooks, those given in a leading hooks block replacing its own,
+/// and the other methods given are added to the impl.
+#[cfg(test)]
+macro_rules! forward_to_memfs {
+    ($fs:ty, hooks { $($hooks:tt)* } $($methods:tt)*) => {
+        #[async_trait::async_trait]
+        impl $crate::memfs::MemFSHooks for $fs {
+            fn memfs(&self) -> &$crate::memfs::MemFS {
+                &self.inner
+            }
+            $($hooks)*
+        }
+
+        #[async_trait::async_trait]
+        impl $crate::vfs::NFSFileSystem for $fs {
+            fn capabilities(&self) -> $crate::vfs::VFSCapabilities {
+                self.inner.capabilities()
+            }
+            fn root_dir(&self) -> $crate::nfs::fileid3 {
+                self.inner.root_dir()
+            }
+            async fn lookup(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                filename: &$crate::nfs::filename3,
+            ) -> Result<$crate::nfs::fileid3, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::lookup(self, dirid, filename).await
+            }
+            async fn getattr(
+                &self,
+                id: $crate::nfs::fileid3,
+            ) -> Result<$crate::nfs::fattr3, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::getattr(self, id).await
+            }
+            async fn setattr(
+                &self,
+                id: $crate::nfs::fileid3,
+                setattr: $crate::nfs::sattr3,
+            ) -> Result<$crate::nfs::fattr3, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::setattr(self, id, setattr).await
+            }
+            async fn read(
+                &self,
+                id: $crate::nfs::fileid3,
+                offset: u64,
+                count: u32,
+            ) -> Result<(Vec<u8>, bool), $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::read(self, id, offset, count).await
+            }
+            async fn write(
+                &self,
+                id: $crate::nfs::fileid3,
+                offset: u64,
+                data: &[u8],
+            ) -> Result<$crate::nfs::fattr3, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::write(self, id, offset, data).await
+            }
+            async fn create(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                filename: &$crate::nfs::filename3,
+                attr: $crate::nfs::sattr3,
+            ) -> Result<($crate::nfs::fileid3, $crate::nfs::fattr3), $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::create(self, dirid, filename, attr).await
+            }
+            async fn create_exclusive(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                filename: &$crate::nfs::filename3,
+            ) -> Result<$crate::nfs::fileid3, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::create_exclusive(self, dirid, filename).await
+            }
+            async fn mkdir(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                dirname: &$crate::nfs::filename3,
+            ) -> Result<($crate::nfs::fileid3, $crate::nfs::fattr3), $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::mkdir(self, dirid, dirname).await
+            }
+            async fn remove(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                filename: &$crate::nfs::filename3,
+            ) -> Result<(), $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::remove(self, dirid, filename).await
+            }
+            async fn rename(
+                &self,
+                from_dirid: $crate::nfs::fileid3,
+                from_filename: &$crate::nfs::filename3,
+                to_dirid: $crate::nfs::fileid3,
+                to_filename: &$crate::nfs::filename3,
+            ) -> Result<(), $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::rename(
+                    self,
+                    from_dirid,
+                    from_filename,
+                    to_dirid,
+                    to_filename,
+                )
+                .await
+            }
+            async fn readdir(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                start_after: $crate::nfs::cookie3,
+                max_entries: usize,
+            ) -> Result<$crate::vfs::ReadDirResult, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::readdir(self, dirid, start_after, max_entries).await
+            }
+            async fn symlink(
+                &self,
+                dirid: $crate::nfs::fileid3,
+                linkname: &$crate::nfs::filename3,
+                symlink: &$crate::nfs::nfspath3,
+                attr: &$crate::nfs::sattr3,
+            ) -> Result<($crate::nfs::fileid3, $crate::nfs::fattr3), $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::symlink(self, dirid, linkname, symlink, attr).await
+            }
+            async fn readlink(
+                &self,
+                id: $crate::nfs::fileid3,
+            ) -> Result<$crate::nfs::nfspath3, $crate::nfs::nfsstat3> {
+                $crate::memfs::MemFSHooks::readlink(self, id).await
+            }
+            $($methods)*
+        }
+    };
+    ($fs:ty, $($methods:tt)*) => {
+        forward_to_memfs! { $fs, hooks {} $($methods)* }
+    };
+}
+#[cfg(test)]
+pub(crate) use forward_to_memfs;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    fn size(size: u64) -> sattr3 {
+        sattr3 {
+            size: set_size3::size(size),
+            ..Default::default()
+        }
+    }
+
+    fn name(s: &str) -> filename3 {
+        s.as_bytes().into()
+    }
+
+    fn lookup(fs: &MemFS, path: &str) -> Result<fileid3, nfsstat3> {
+        block_on(fs.path_to_id(path.as_bytes()))
+    }
+
+    fn names(fs: &MemFS, dirid: fileid3) -> Vec<String> {
+        block_on(fs.readdir(dirid, cookie3(0), usize::MAX))
+            .unwrap()
+            .entries
+            .iter()
+            .map(|e| String::from_utf8(e.name.to_vec()).unwrap())
+            .collect()
+    }
+
+    fn sample() -> MemFS {
+        MemFS::from_entries([
+            MemEntry::File("a.txt".into(), b"hello\n".to_vec()),
+            MemEntry::Dir("empty".into()),
+            MemEntry::File("dir/sub/b.txt".into(), b"b".to_vec()),
+            MemEntry::Symlink("dir/link".into(), "../a.txt".into()),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn from_entries() {
+        let fs = sample();
+        // ids are handed out in creation order, parents first
+        assert_eq!(lookup(&fs, "a.txt").unwrap(), fileid3(2));
+        assert_eq!(lookup(&fs, "empty").unwrap(), fileid3(3));
+        assert_eq!(lookup(&fs, "dir").unwrap(), fileid3(4));
+        assert_eq!(lookup(&fs, "dir/sub").unwrap(), fileid3(5));
+        assert_eq!(lookup(&fs, "dir/sub/b.txt").unwrap(), fileid3(6));
+        assert_eq!(names(&fs, ROOT), ["a.txt", "empty", "dir"]);
+
+        let a = lookup(&fs, "a.txt").unwrap();
+        let attr = block_on(fs.getattr(a)).unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3REG));
+        assert_eq!((attr.size, attr.mode), (6, 0o644));
+        let link = lookup(&fs, "dir/link").unwrap();
+        assert_eq!(&block_on(fs.readlink(link)).unwrap()[..], b"../a.txt");
+
+        for bad in [
+            vec![MemEntry::Dir("".into())],
+            vec![MemEntry::Dir("/".into())],
+            vec![MemEntry::Dir("x".into()), MemEntry::Dir("x".into())],
+            vec![
+                MemEntry::File("f".into(), vec![]),
+                MemEntry::Dir("f/g".into()),
+            ],
+        ] {
+            let err = MemFS::from_entries(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn lookup_dots() {
+        let fs = sample();
+        let dir = lookup(&fs, "dir").unwrap();
+        let sub = lookup(&fs, "dir/sub").unwrap();
+        assert_eq!(block_on(fs.lookup(sub, &name("."))).unwrap(), sub);
+        assert_eq!(block_on(fs.lookup(sub, &name(".."))).unwrap(), dir);
+        // the parent of the root is the root
+        assert_eq!(block_on(fs.lookup(ROOT, &name(".."))).unwrap(), ROOT);
+        assert_eq!(block_on(fs.parent_dir(sub)), Some(dir));
+        assert!(matches!(
+            block_on(fs.lookup(sub, &name("missing"))),
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        let a = lookup(&fs, "a.txt").unwrap();
+        assert!(matches!(
+            block_on(fs.lookup(a, &name("."))),
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        ));
+        assert!(matches!(
+            block_on(fs.lookup(fileid3(1000), &name("."))),
+            Err(nfsstat3::NFS3ERR_STALE)
+        ));
+    }
+
+    #[test]
+    fn setattr() {
+        let fs = sample();
+        let a = lookup(&fs, "a.txt").unwrap();
+        // growing reads as zeros, shrinking truncates
+        assert_eq!(block_on(fs.setattr(a, size(8))).unwrap().size, 8);
+        assert_eq!(block_on(fs.read(a, 0, 100)).unwrap().0, b"hello\n\0\0");
+        assert_eq!(block_on(fs.setattr(a, size(2))).unwrap().size, 2);
+        assert_eq!(
+            block_on(fs.read(a, 0, 100)).unwrap(),
+            (b"he".to_vec(), true)
+        );
+
+        let mode = sattr3 {
+            mode: set_mode3::mode(0o100640),
+            ..Default::default()
+        };
+        assert_eq!(block_on(fs.setattr(a, mode)).unwrap().mode, 0o640);
+
+        let t = nfstime3 {
+            seconds: 1234,
+            nseconds: 5678,
+        };
+        let times = sattr3 {
+            atime: set_atime::SET_TO_CLIENT_TIME(t),
+            mtime: set_mtime::SET_TO_CLIENT_TIME(t),
+            ..Default::default()
+        };
+        let attr = block_on(fs.setattr(a, times)).unwrap();
+        let secs = |t: nfstime3| (t.seconds, t.nseconds);
+        assert_eq!(secs(attr.atime), (1234, 5678));
+        assert_eq!(secs(attr.mtime), (1234, 5678));
+        let attr = block_on(fs.setattr(
+            a,
+            sattr3 {
+                mtime: set_mtime::SET_TO_SERVER_TIME,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        assert!(attr.mtime.seconds > t.seconds);
+        assert_eq!(secs(attr.atime), (1234, 5678));
+
+        let dir = lookup(&fs, "dir").unwrap();
+        assert!(matches!(
+            block_on(fs.setattr(dir, size(0))),
+            Err(nfsstat3::NFS3ERR_ISDIR)
+        ));
+    }
+
+    #[test]
+    fn read_write_create() {
+        let fs = sample();
+        let (id, attr) = block_on(fs.create(ROOT, &name("new"), size(3))).unwrap();
+        assert_eq!(attr.size, 3);
+        // a write past the end leaves a gap of zeros
+        let attr = block_on(fs.write(id, 5, b"xy")).unwrap();
+        assert_eq!(attr.size, 7);
+        assert_eq!(
+            block_on(fs.read(id, 0, 100)).unwrap(),
+            (b"\0\0\0\0\0xy".to_vec(), true)
+        );
+        assert_eq!(block_on(fs.read(id, 1, 2)).unwrap(), (vec![0, 0], false));
+        assert_eq!(block_on(fs.read(id, 100, 2)).unwrap(), (vec![], true));
+        // an UNCHECKED create of an existing file applies the attributes
+        let (again, attr) = block_on(fs.create(ROOT, &name("new"), size(0))).unwrap();
+        assert_eq!((again, attr.size), (id, 0));
+        assert!(matches!(
+            block_on(fs.create(ROOT, &name("dir"), sattr3::default())),
+            Err(nfsstat3::NFS3ERR_EXIST)
+        ));
+        assert!(matches!(
+            block_on(fs.create_exclusive(ROOT, &name("new"))),
+            Err(nfsstat3::NFS3ERR_EXIST)
+        ));
+        let excl = block_on(fs.create_exclusive(ROOT, &name("excl"))).unwrap();
+        assert_eq!(block_on(fs.getattr(excl)).unwrap().size, 0);
+
+        let dir = lookup(&fs, "dir").unwrap();
+        assert!(matches!(
+            block_on(fs.read(dir, 0, 1)),
+            Err(nfsstat3::NFS3ERR_ISDIR)
+        ));
+        assert!(matches!(
+            block_on(fs.write(dir, 0, b"x")),
+            Err(nfsstat3::NFS3ERR_ISDIR)
+        ));
+
+        let (sub, attr) = block_on(fs.mkdir(dir, &name("made"))).unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3DIR));
+        assert_eq!(block_on(fs.lookup(sub, &name(".."))).unwrap(), dir);
+        let (link, attr) =
+            block_on(fs.symlink(sub, &name("l"), &b"target"[..].into(), &sattr3::default()))
+                .unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3LNK));
+        assert_eq!(attr.size, 6);
+        assert_eq!(&block_on(fs.readlink(link)).unwrap()[..], b"target");
+        assert!(matches!(
+            block_on(fs.readlink(sub)),
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+    }
+
+    #[test]
+    fn remove() {
+        let fs = sample();
+        let dir = lookup(&fs, "dir").unwrap();
+        assert!(matches!(
+            block_on(fs.remove(ROOT, &name("dir"))),
+            Err(nfsstat3::NFS3ERR_NOTEMPTY)
+        ));
+        assert!(matches!(
+            block_on(fs.remove(dir, &name(".."))),
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+        block_on(fs.remove(ROOT, &name("empty"))).unwrap();
+        let a = lookup(&fs, "a.txt").unwrap();
+        block_on(fs.remove(ROOT, &name("a.txt"))).unwrap();
+        assert_eq!(names(&fs, ROOT), ["dir"]);
+        // ids are not reused
+        assert!(matches!(
+            block_on(fs.getattr(a)),
+            Err(nfsstat3::NFS3ERR_STALE)
+        ));
+        let (id, _) = block_on(fs.create(ROOT, &name("a.txt"), sattr3::default())).unwrap();
+        assert_eq!(id, fileid3(8));
+    }
+
+    #[test]
+    fn rename() {
+        let fs = sample();
+        let dir = lookup(&fs, "dir").unwrap();
+        let sub = lookup(&fs, "dir/sub").unwrap();
+        let empty = lookup(&fs, "empty").unwrap();
+        let a = lookup(&fs, "a.txt").unwrap();
+
+        // across directories, keeping the fileid
+        block_on(fs.rename(ROOT, &name("a.txt"), sub, &name("moved"))).unwrap();
+        assert_eq!(lookup(&fs, "dir/sub/moved").unwrap(), a);
+        assert_eq!(block_on(fs.parent_dir(a)), Some(sub));
+        assert_eq!(names(&fs, ROOT), ["empty", "dir"]);
+
+        // not into its own subtree
+        for target in [dir, sub] {
+            assert!(matches!(
+                block_on(fs.rename(ROOT, &name("dir"), target, &name("x"))),
+                Err(nfsstat3::NFS3ERR_INVAL)
+            ));
+        }
+        // not over a non-empty directory, nor a directory over a file
+        assert!(matches!(
+            block_on(fs.rename(ROOT, &name("empty"), dir, &name("sub"))),
+            Err(nfsstat3::NFS3ERR_NOTEMPTY)
+        ));
+        assert!(matches!(
+            block_on(fs.rename(sub, &name("moved"), ROOT, &name("dir"))),
+            Err(nfsstat3::NFS3ERR_ISDIR)
+        ));
+        assert!(matches!(
+            block_on(fs.rename(ROOT, &name("empty"), sub, &name("b.txt"))),
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        ));
+
+        // over an empty directory, which is dropped
+        block_on(fs.rename(dir, &name("sub"), ROOT, &name("empty"))).unwrap();
+        assert_eq!(lookup(&fs, "empty").unwrap(), sub);
+        assert!(matches!(
+            block_on(fs.getattr(empty)),
+            Err(nfsstat3::NFS3ERR_STALE)
+        ));
+        // over a file, which is dropped
+        let b = lookup(&fs, "empty/b.txt").unwrap();
+        block_on(fs.rename(sub, &name("moved"), sub, &name("b.txt"))).unwrap();
+        assert_eq!(lookup(&fs, "empty/b.txt").unwrap(), a);
+        assert!(matches!(
+            block_on(fs.getattr(b)),
+            Err(nfsstat3::NFS3ERR_STALE)
+        ));
+        // onto itself
+        block_on(fs.rename(sub, &name("b.txt"), sub, &name("b.txt"))).unwrap();
+        assert_eq!(names(&fs, sub), ["b.txt"]);
+    }
+
+    #[test]
+    fn readdir_resumes_from_cookies() {
+        let fs =
+            MemFS::from_entries((0..10).map(|i| MemEntry::File(format!("d/{i}"), vec![]))).unwrap();
+        let dir = lookup(&fs, "d").unwrap();
+        let mut listed = Vec::new();
+        let mut cookie = cookie3(0);
+        loop {
+            let page = block_on(fs.readdir(dir, cookie, 3)).unwrap();
+            assert!(page.entries.len() <= 3);
+            for entry in &page.entries {
+                assert_eq!(entry.cookie, cookie3(entry.fileid.0));
+                listed.push(String::from_utf8(entry.name.to_vec()).unwrap());
+            }
+            if page.end {
+                break;
+            }
+            cookie = page.entries.last().unwrap().cookie;
+        }
+        assert_eq!(listed, (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        // a cookie of an entry which is gone
+        let gone = lookup(&fs, "d/4").unwrap();
+        block_on(fs.remove(dir, &name("4"))).unwrap();
+        assert!(matches!(
+            block_on(fs.readdir(dir, cookie3(gone.0), 3)),
+            Err(nfsstat3::NFS3ERR_BAD_COOKIE)
+        ));
+        let a = lookup(&fs, "d/0").unwrap();
+        assert!(matches!(
+            block_on(fs.readdir(a, cookie3(0), 3)),
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        ));
+    }
+
+    #[test]
+    fn huge_sizes_are_refused() {
+        let fs = MemFS::from_entries([MemEntry::File("f".into(), b"data".to_vec())]).unwrap();
+        let id = block_on(fs.lookup(ROOT, &b"f"[..].into())).unwrap();
+        let maxfilesize = fs.fsinfo_config().maxfilesize;
+        assert!(matches!(
+            block_on(fs.setattr(id, size(1 << 63))),
+            Err(nfsstat3::NFS3ERR_FBIG)
+        ));
+        assert!(matches!(
+            block_on(fs.write(id, maxfilesize - 1, b"ab")),
+            Err(nfsstat3::NFS3ERR_FBIG)
+        ));
+        // the file is unchanged and the lock still usable
+        assert_eq!(block_on(fs.getattr(id)).unwrap().size, 4);
+        assert_eq!(block_on(fs.read(id, 0, 10)).unwrap().0, b"data");
+    }
+}
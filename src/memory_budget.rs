@@ -0,0 +1,111 @@
+//! Accounting of the transient memory held by requests in flight
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A global budget for the large transient buffers of requests in flight:
+/// the assembled request records, the READ and WRITE data and the reply
+/// buffers waiting to be written to the socket.
+///
+/// Memory which already exists (a reply which has been produced) is
+/// always counted with reserve. Allocations about to be made are refused
+/// with try_reserve: a fragment of a large request record, in which case
+/// the connection is closed and the client sends the call again once it
+/// reconnects, or the READ and WRITE buffers of a handler, which then
+/// replies NFS3ERR_JUKEBOX and the client retries later. This way the budget pushes back on bursts of large
+/// READs and WRITEs instead of letting them grow the process until it
+/// is killed.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    /// The most bytes used at once since the last reset_peak
+    peak: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// A budget which never refuses anything. Usage is still counted.
+    pub fn unlimited() -> MemoryBudget {
+        MemoryBudget {
+            limit: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the limit in bytes. usize::MAX means unlimited.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// The limit in bytes
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes currently reserved
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// The most bytes reserved at once since the last reset_peak
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Restarts peak from the bytes reserved now
+    pub fn reset_peak(&self) {
+        self.peak.store(self.used(), Ordering::Relaxed);
+    }
+
+    /// Reserves bytes for a request which already holds held bytes of the
+    /// budget (its record), if that keeps the usage within the limit. The
+    /// bytes are also granted when nothing else is in use, so that a
+    /// request larger than the limit is admitted once it is alone instead
+    /// of never.
+    pub fn try_reserve(self: &Arc<Self>, bytes: usize, held: usize) -> Option<MemoryReservation> {
+        let limit = self.limit();
+        let mut cur = self.used.load(Ordering::Relaxed);
+        loop {
+            let new = cur
+                .checked_add(bytes)
+                .filter(|n| *n <= limit || cur <= held)?;
+            match self
+                .used
+                .compare_exchange_weak(cur, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    self.peak.fetch_max(new, Ordering::Relaxed);
+                    return Some(MemoryReservation {
+                        budget: self.clone(),
+                        bytes,
+                    });
+                }
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    /// Reserves bytes regardless of the limit. For memory which is
+    /// already allocated and can only be accounted for.
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> MemoryReservation {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(used, Ordering::Relaxed);
+        MemoryReservation {
+            budget: self.clone(),
+            bytes,
+        }
+    }
+}
+
+/// Bytes reserved from a MemoryBudget. Released on drop.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,11 @@
+// this is just a complete enumeration of everything in the protocol
+#![allow(dead_code)]
+// And its nice to keep the original names and case
+#![allow(non_camel_case_types)]
+
+// The metadata program is a non-standard extension of this crate, served
+// alongside NFSv3 on the same port. It lets a co-designed client ask the
+// server for information NFSv3 has no room for, such as the content hash
+// of a file. Standard NFS clients never call it.
+pub const PROGRAM: u32 = 200024;
+pub const VERSION: u32 = 1;
@@ -0,0 +1,157 @@
+use crate::context::RPCContext;
+use crate::nfs;
+use crate::rpc::*;
+use crate::support::{ProcedureSupport, Support};
+use crate::xdr::*;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+use tracing::debug;
+
+/*
+ program NFS_METADATA_PROGRAM {
+    version NFS_METADATA_V1 {
+       void              METAPROC1_NULL(void)                     = 0;
+       CONTENTHASH1res   METAPROC1_CONTENTHASH(CONTENTHASH1args)  = 1;
+    } = 1;
+ } = 200024;
+
+ struct CONTENTHASH1args {
+      nfs_fh3      object;
+ };
+
+ struct CONTENTHASH1resok {
+      post_op_attr obj_attributes;
+      opaque       hash[32];
+ };
+
+ struct CONTENTHASH1resfail {
+      post_op_attr obj_attributes;
+ };
+
+ union CONTENTHASH1res switch (nfsstat3 status) {
+ case NFS3_OK:
+      CONTENTHASH1resok   resok;
+ default:
+      CONTENTHASH1resfail resfail;
+ };
+
+ The hash is returned along with the attributes of the object so that the
+ client can tell which version of the file it describes. What the hash is
+ (SHA-256 of the contents, a Merkle root, ...) is up to the VFS. NOTSUPP is
+ returned if the VFS does not provide hashes, or not for this object.
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum MetadataProgram {
+    METAPROC1_NULL = 0,
+    METAPROC1_CONTENTHASH = 1,
+    INVALID,
+}
+
+pub async fn handle_metadata(
+    xid: u32,
+    call: call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let prog = MetadataProgram::from_u32(call.proc).unwrap_or(MetadataProgram::INVALID);
+
+    match prog {
+        MetadataProgram::METAPROC1_NULL => metaproc1_null(xid, input, output)?,
+        MetadataProgram::METAPROC1_CONTENTHASH => {
+            metaproc1_contenthash(xid, input, output, context).await?
+        }
+        MetadataProgram::INVALID => {
+            proc_unavail_reply_message(xid).serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+/// How completely each procedure is supported. See support::matrix.
+/// Like the match in handle_metadata this has no wildcard arm.
+fn support(prog: MetadataProgram) -> (Support, &'static str) {
+    match prog {
+        MetadataProgram::METAPROC1_NULL => (Support::Full, ""),
+        MetadataProgram::METAPROC1_CONTENTHASH => (
+            Support::Full,
+            "NFS3ERR_NOTSUPP unless NFSFileSystem::content_hash gives a hash",
+        ),
+        MetadataProgram::INVALID => (Support::Unsupported, ""),
+    }
+}
+
+/// The support of the metadata procedures. See support::matrix
+pub fn support_matrix() -> Vec<ProcedureSupport> {
+    (0..)
+        .map_while(MetadataProgram::from_u32)
+        .take_while(|prog| !matches!(prog, MetadataProgram::INVALID))
+        .map(|prog| {
+            let (support, notes) = support(prog);
+            ProcedureSupport {
+                program: "METADATAv1",
+                number: prog as u32,
+                procedure: format!("{:?}", prog),
+                support,
+                notes,
+            }
+        })
+        .collect()
+}
+
+pub fn metaproc1_null(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    debug!(target: "nfsserve::metadata", "metaproc1_null({:?}) ", xid);
+    // build an RPC reply
+    let msg = make_success_reply(xid);
+    debug!(target: "nfsserve::metadata", "\t{:?} --> {:?}", xid, msg);
+    msg.serialize(output)?;
+    Ok(())
+}
+
+pub async fn metaproc1_contenthash(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut handle = nfs::nfs_fh3::default();
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::metadata", "metaproc1_contenthash({:?},{:?}) ", xid, handle);
+
+    let id = match context.vfs.fh_to_id(&handle) {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
+    let obj_attr = match context.vfs.getattr(id).await {
+        Ok(v) => nfs::post_op_attr::attributes(v),
+        Err(_) => nfs::post_op_attr::Void,
+    };
+    make_success_reply(xid).serialize(output)?;
+    match context.vfs.content_hash(id).await {
+        Some(hash) => {
+            debug!(target: "nfsserve::metadata", "\t{:?} --> {:02x?}", xid, hash);
+            nfs::nfsstat3::NFS3_OK.serialize(output)?;
+            obj_attr.serialize(output)?;
+            hash.serialize(output)?;
+        }
+        None => {
+            debug!(target: "nfsserve::metadata", "\t{:?} --> NFS3ERR_NOTSUPP", xid);
+            nfs::nfsstat3::NFS3ERR_NOTSUPP.serialize(output)?;
+            obj_attr.serialize(output)?;
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,13 @@
 use crate::context::RPCContext;
 use crate::mount::*;
+use crate::nfs;
 use crate::rpc::*;
+use crate::support::{ProcedureSupport, Support};
 use crate::xdr::*;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::{FromPrimitive, ToPrimitive};
 use std::io::{Read, Write};
-use tracing::debug;
+use tracing::{debug, warn};
 
 /*
 From RFC 1813 Appendix I
@@ -50,23 +52,57 @@ pub async fn handle_mount(
         MountProgram::MOUNTPROC3_UMNTALL => {
             mountproc3_umnt_all(xid, input, output, context).await?
         }
-        MountProgram::MOUNTPROC3_EXPORT => mountproc3_export(xid, input, output)?,
-        _ => {
+        MountProgram::MOUNTPROC3_EXPORT => mountproc3_export(xid, input, output, context)?,
+        MountProgram::MOUNTPROC3_DUMP | MountProgram::INVALID => {
             proc_unavail_reply_message(xid).serialize(output)?;
         }
     }
     Ok(())
 }
 
+/// How completely each procedure is supported. See support::matrix.
+/// Like the match in handle_mount this has no wildcard arm.
+fn support(prog: MountProgram) -> (Support, &'static str) {
+    match prog {
+        MountProgram::MOUNTPROC3_NULL
+        | MountProgram::MOUNTPROC3_MNT
+        | MountProgram::MOUNTPROC3_UMNT
+        | MountProgram::MOUNTPROC3_UMNTALL => (Support::Full, ""),
+        MountProgram::MOUNTPROC3_EXPORT => (
+            Support::Partial,
+            "Lists the export paths without client groups",
+        ),
+        MountProgram::MOUNTPROC3_DUMP | MountProgram::INVALID => (Support::Unsupported, ""),
+    }
+}
+
+/// The support of the MOUNT procedures. See support::matrix
+pub fn support_matrix() -> Vec<ProcedureSupport> {
+    (0..)
+        .map_while(MountProgram::from_u32)
+        .take_while(|prog| !matches!(prog, MountProgram::INVALID))
+        .map(|prog| {
+            let (support, notes) = support(prog);
+            ProcedureSupport {
+                program: "MOUNT",
+                number: prog as u32,
+                procedure: format!("{:?}", prog),
+                support,
+                notes,
+            }
+        })
+        .collect()
+}
+
 pub fn mountproc3_null(
     xid: u32,
     _: &mut impl Read,
     output: &mut impl Write,
 ) -> Result<(), anyhow::Error> {
-    debug!("mountproc3_null({:?}) ", xid);
+    debug!(target: "nfsserve::mount", "mountproc3_null({:?}) ", xid);
     // build an RPC reply
     let msg = make_success_reply(xid);
-    debug!("\t{:?} --> {:?}", xid, msg);
+    debug!(target: "nfsserve::mount", "\t{:?} --> {:?}", xid, msg);
     msg.serialize(output)?;
     Ok(())
 }
@@ -79,6 +115,40 @@ struct mountres3_ok {
 }
 XDRStruct!(mountres3_ok, fhandle, auth_flavors);
 
+/// Normalizes the spellings of a mount path sent by Windows clients
+/// to the canonical '/' separated form. i.e.
+///  - backslash separators are translated to '/'
+///  - A leading "\\server" (UNC) prefix is stripped
+///  - A leading drive letter prefix ("X:\" or "X:/") is stripped
+///
+/// "\\server\dir", "X:\dir" and "X:/dir" all normalize to "/dir". Other
+/// paths are left alone but for their backslashes: "//dir" and "/a//b"
+/// are valid POSIX paths, and are not rewritten.
+pub fn normalize_dirpath(path: &[u8]) -> Vec<u8> {
+    let is_sep = |c: &u8| *c == b'\\' || *c == b'/';
+    let rest = if let Some(unc) = path.strip_prefix(b"\\\\") {
+        // strip the \\server component
+        let server_end = unc.iter().position(is_sep).unwrap_or(unc.len());
+        &unc[server_end..]
+    } else if path.len() >= 2
+        && path[0].is_ascii_alphabetic()
+        && path[1] == b':'
+        && path.get(2).map_or(true, is_sep)
+    {
+        &path[2..]
+    } else {
+        path
+    };
+    let mut normalized: Vec<u8> = rest
+        .iter()
+        .map(|&c| if c == b'\\' { b'/' } else { c })
+        .collect();
+    if normalized.is_empty() {
+        normalized.push(b'/');
+    }
+    normalized
+}
+
 pub async fn mountproc3_mnt(
     xid: u32,
     input: &mut impl Read,
@@ -86,29 +156,104 @@ pub async fn mountproc3_mnt(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut path = dirpath::new();
-    path.deserialize(input)?;
+    decode_args(&mut path, input)?;
     let utf8path = std::str::from_utf8(&path).unwrap_or_default();
-    debug!("mountproc3_mnt({:?},{:?}) ", xid, utf8path);
-    if let Ok(fileid) = context.vfs.path_to_id(&path).await {
-        let response = mountres3_ok {
-            fhandle: context.vfs.id_to_fh(fileid).data,
-            auth_flavors: vec![
-                auth_flavor::AUTH_NULL.to_u32().unwrap(),
-                auth_flavor::AUTH_UNIX.to_u32().unwrap(),
-            ],
-        };
-        debug!("{:?} --> {:?}", xid, response);
-        if let Some(ref chan) = context.mount_signal {
-            let _ = chan.send(true).await;
+    debug!(target: "nfsserve::mount", "mountproc3_mnt({:?},{:?}) ", xid, utf8path);
+    if context.windows_path_compat {
+        let normalized = normalize_dirpath(&path);
+        if normalized != path {
+            debug!(
+                target: "nfsserve::mount",
+                "normalized mount path {:?} to {:?}",
+                utf8path,
+                String::from_utf8_lossy(&normalized)
+            );
+            path = normalized;
         }
-        make_success_reply(xid).serialize(output)?;
-        mountstat3::MNT3_OK.serialize(output)?;
-        response.serialize(output)?;
-    } else {
-        debug!("{:?} --> MNT3ERR_NOENT", xid);
+    }
+    let fileid = context.vfs.path_to_id(&path).await;
+    let attrs = match fileid {
+        Ok(fileid) => context.vfs.getattr(fileid).await.ok(),
+        Err(_) => None,
+    };
+    let Ok(fileid) = fileid else {
+        debug!(target: "nfsserve::mount", "{:?} --> MNT3ERR_NOENT", xid);
         make_success_reply(xid).serialize(output)?;
         mountstat3::MNT3ERR_NOENT.serialize(output)?;
+        return Ok(());
+    };
+    // the policy sees every object the path resolves to, so that it can
+    // refuse files with its own status
+    if let Some(ref authorizer) = context.mount_authorizer {
+        let verdict = match (&attrs, context.client_addr.parse()) {
+            (Some(attrs), Ok(client)) => {
+                authorizer
+                    .authorize_mount(client, &path, fileid, attrs)
+                    .await
+            }
+            (None, _) => Err(mountstat3::MNT3ERR_IO),
+            (_, Err(_)) => Err(mountstat3::MNT3ERR_SERVERFAULT),
+        };
+        if let Err(stat) = verdict {
+            warn!(
+                target: "nfsserve::mount",
+                "mount of {:?} by {} refused with {:?}",
+                String::from_utf8_lossy(&path),
+                context.client_addr,
+                stat
+            );
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            return Ok(());
+        }
+    }
+    let is_dir = attrs
+        .as_ref()
+        .map_or(true, |attr| matches!(attr.ftype, nfs::ftype3::NF3DIR));
+    if !is_dir {
+        // mounting a file leaves the client with a root it cannot list
+        warn!(
+            target: "nfsserve::mount",
+            "mount path {:?} is not a directory",
+            String::from_utf8_lossy(&path)
+        );
+        make_success_reply(xid).serialize(output)?;
+        mountstat3::MNT3ERR_NOTDIR.serialize(output)?;
+        return Ok(());
+    }
+    // count the path against the client, refusing it past the limit.
+    // Mounting a path again (e.g. on a retry) does not count.
+    {
+        let mut mounts = context.mounts.lock().unwrap();
+        let paths = mounts.entry(context.client_host().to_string()).or_default();
+        if !paths.contains(&path) && paths.len() >= context.max_mounts_per_client {
+            warn!(
+                target: "nfsserve::mount",
+                "{} already has {} mounts. Refusing mount of {:?}",
+                context.client_host(),
+                paths.len(),
+                String::from_utf8_lossy(&path)
+            );
+            make_success_reply(xid).serialize(output)?;
+            mountstat3::MNT3ERR_ACCES.serialize(output)?;
+            return Ok(());
+        }
+        paths.insert(path.clone());
+    }
+    let response = mountres3_ok {
+        fhandle: context.vfs.id_to_fh(fileid).data,
+        auth_flavors: vec![
+            auth_flavor::AUTH_NULL.to_u32().unwrap(),
+            auth_flavor::AUTH_UNIX.to_u32().unwrap(),
+        ],
+    };
+    debug!(target: "nfsserve::mount", "{:?} --> {:?}", xid, response);
+    if let Some(ref chan) = context.mount_signal {
+        let _ = chan.send(true).await;
     }
+    make_success_reply(xid).serialize(output)?;
+    mountstat3::MNT3_OK.serialize(output)?;
+    response.serialize(output)?;
     Ok(())
 }
 
@@ -150,14 +295,17 @@ pub fn mountproc3_export(
     xid: u32,
     _: &mut impl Read,
     output: &mut impl Write,
+    context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
-    debug!("mountproc3_export({:?}) ", xid);
+    debug!(target: "nfsserve::mount", "mountproc3_export({:?}) ", xid);
     make_success_reply(xid).serialize(output)?;
-    true.serialize(output)?;
-    // dirpath
-    "/".as_bytes().to_vec().serialize(output)?;
-    // groups
-    false.serialize(output)?;
+    for path in context.exports.iter() {
+        true.serialize(output)?;
+        // dirpath
+        path.serialize(output)?;
+        // groups
+        false.serialize(output)?;
+    }
     // next exports
     false.serialize(output)?;
     Ok(())
@@ -170,9 +318,21 @@ pub async fn mountproc3_umnt(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut path = dirpath::new();
-    path.deserialize(input)?;
+    decode_args(&mut path, input)?;
     let utf8path = std::str::from_utf8(&path).unwrap_or_default();
-    debug!("mountproc3_umnt({:?},{:?}) ", xid, utf8path);
+    debug!(target: "nfsserve::mount", "mountproc3_umnt({:?},{:?}) ", xid, utf8path);
+    if context.windows_path_compat {
+        path = normalize_dirpath(&path);
+    }
+    {
+        let mut mounts = context.mounts.lock().unwrap();
+        if let Some(paths) = mounts.get_mut(context.client_host()) {
+            paths.remove(&path);
+            if paths.is_empty() {
+                mounts.remove(context.client_host());
+            }
+        }
+    }
     if let Some(ref chan) = context.mount_signal {
         let _ = chan.send(false).await;
     }
@@ -187,7 +347,8 @@ pub async fn mountproc3_umnt_all(
     output: &mut impl Write,
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
-    debug!("mountproc3_umnt_all({:?}) ", xid);
+    debug!(target: "nfsserve::mount", "mountproc3_umnt_all({:?}) ", xid);
+    context.mounts.lock().unwrap().remove(context.client_host());
     if let Some(ref chan) = context.mount_signal {
         let _ = chan.send(false).await;
     }
@@ -195,3 +356,48 @@ pub async fn mountproc3_umnt_all(
     mountstat3::MNT3_OK.serialize(output)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_dirpath;
+
+    fn normalized(path: &str) -> String {
+        String::from_utf8(normalize_dirpath(path.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn backslashes() {
+        assert_eq!(normalized(r"\dir\sub"), "/dir/sub");
+        assert_eq!(normalized(r"\\server\dir\sub"), "/dir/sub");
+        assert_eq!(normalized(r"\\server"), "/");
+        assert_eq!(normalized(r"\\server\"), "/");
+        assert_eq!(normalized(r"\\127.0.0.1\dir"), "/dir");
+    }
+
+    #[test]
+    fn drive_letters() {
+        assert_eq!(normalized(r"X:\dir"), "/dir");
+        assert_eq!(normalized("X:/dir"), "/dir");
+        assert_eq!(normalized("x:"), "/");
+        // not a drive letter
+        assert_eq!(normalized("/X:/dir"), "/X:/dir");
+        assert_eq!(normalized("XY:/dir"), "XY:/dir");
+    }
+
+    #[test]
+    fn duplicate_slashes() {
+        // POSIX paths, not UNC prefixes
+        assert_eq!(normalized("//dir"), "//dir");
+        assert_eq!(normalized("//server/dir"), "//server/dir");
+        assert_eq!(normalized("/a//b"), "/a//b");
+        assert_eq!(normalized(r"\a\\b"), "/a//b");
+    }
+
+    #[test]
+    fn trailing_slash() {
+        assert_eq!(normalized("/dir/"), "/dir/");
+        assert_eq!(normalized(r"X:\dir\"), "/dir/");
+        assert_eq!(normalized("/"), "/");
+        assert_eq!(normalized(""), "/");
+    }
+}
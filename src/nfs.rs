@@ -81,11 +81,54 @@ impl fmt::Display for nfsstring {
         write!(f, "{:?}", String::from_utf8_lossy(&self.0))
     }
 }
+/// Defines a u64 newtype with the conversions and formatting of the
+/// underlying integer.
+macro_rules! u64_newtype {
+    ($t:ident) => {
+        #[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[repr(transparent)]
+        pub struct $t(pub u64);
+        impl From<u64> for $t {
+            fn from(value: u64) -> Self {
+                Self(value)
+            }
+        }
+        impl From<$t> for u64 {
+            fn from(value: $t) -> Self {
+                value.0
+            }
+        }
+        impl fmt::Debug for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&self.0, f)
+            }
+        }
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
+        impl XDR for $t {
+            fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
+                self.0.serialize(dest)
+            }
+            fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
+                self.0.deserialize(src)
+            }
+        }
+    };
+}
+
+// fileid3 and cookie3 are both 64 bit integers on the wire but are not
+// interchangeable. A cookie is an opaque position in a directory listing
+// (see NFSFileSystem::readdir) and converting between the two must be
+// explicit.
+u64_newtype!(fileid3);
+u64_newtype!(cookie3);
+
 pub type opaque = u8;
 pub type filename3 = nfsstring;
 pub type nfspath3 = nfsstring;
-pub type fileid3 = u64;
-pub type cookie3 = u64;
 pub type cookieverf3 = [opaque; NFS3_COOKIEVERFSIZE as usize];
 pub type createverf3 = [opaque; NFS3_CREATEVERFSIZE as usize];
 pub type writeverf3 = [opaque; NFS3_WRITEVERFSIZE as usize];
@@ -302,6 +345,27 @@ pub const FSF_HOMOGENEOUS: u32 = 0x0008;
 /// (FALSE), the server cannot set times as requested.
 pub const FSF_CANSETTIME: u32 = 0x0010;
 
+// Section 3.3.4. Procedure 4: ACCESS - Check Access Permission
+// The bits of the access argument and result.
+
+/// Read data from file or read a directory.
+pub const ACCESS3_READ: u32 = 0x0001;
+
+/// Look up a name in a directory (no meaning for non-directory objects).
+pub const ACCESS3_LOOKUP: u32 = 0x0002;
+
+/// Rewrite existing file data or modify existing directory entries.
+pub const ACCESS3_MODIFY: u32 = 0x0004;
+
+/// Write new data or add directory entries.
+pub const ACCESS3_EXTEND: u32 = 0x0008;
+
+/// Delete an existing directory entry.
+pub const ACCESS3_DELETE: u32 = 0x0010;
+
+/// Execute file (no meaning for a directory).
+pub const ACCESS3_EXECUTE: u32 = 0x0020;
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
 pub struct fsinfo3 {
@@ -560,6 +624,29 @@ pub struct symlinkdata3 {
 }
 XDRStruct!(symlinkdata3, symlink_attributes, symlink_data);
 
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default)]
+pub struct devicedata3 {
+    pub dev_attributes: sattr3,
+    pub spec: specdata3,
+}
+XDRStruct!(devicedata3, dev_attributes, spec);
+
+/// How much of a WRITE must be on stable storage before the reply
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum stable_how {
+    /// The data may be cached. A later COMMIT makes it stable
+    #[default]
+    UNSTABLE = 0,
+    /// The data, and the metadata needed to read it back, are stable
+    DATA_SYNC = 1,
+    /// The data and all the metadata are stable
+    FILE_SYNC = 2,
+}
+XDREnumSerde!(stable_how);
+
 /// We define the root handle here
 pub fn get_root_mount_handle() -> Vec<u8> {
     vec![0]
@@ -3,13 +3,15 @@
 use crate::context::RPCContext;
 use crate::nfs;
 use crate::rpc::*;
-use crate::vfs::VFSCapabilities;
+use crate::support::{ProcedureSupport, Support};
+use crate::vfs::{RawDirPage, VFSCapabilities};
 use crate::xdr::*;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::FromPrimitive;
 use std::io::{Read, Write};
-use tracing::{debug, error, trace, warn};
+use std::sync::Mutex;
+use tracing::{debug, error, info, trace, warn};
 /*
 program NFS_PROGRAM {
  version NFS_V3 {
@@ -120,15 +122,6 @@ pub async fn handle_nfs(
     output: &mut impl Write,
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
-    if call.vers != nfs::VERSION {
-        warn!(
-            "Invalid NFS Version number {} != {}",
-            call.vers,
-            nfs::VERSION
-        );
-        prog_mismatch_reply_message(xid, nfs::VERSION).serialize(output)?;
-        return Ok(());
-    }
     let prog = NFSProgram::from_u32(call.proc).unwrap_or(NFSProgram::INVALID);
 
     match prog {
@@ -145,6 +138,7 @@ pub async fn handle_nfs(
             nfsproc3_readdirplus(xid, input, output, context).await?
         }
         NFSProgram::NFSPROC3_WRITE => nfsproc3_write(xid, input, output, context).await?,
+        NFSProgram::NFSPROC3_COMMIT => nfsproc3_commit(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_CREATE => nfsproc3_create(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_SETATTR => nfsproc3_setattr(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_REMOVE => nfsproc3_remove(xid, input, output, context).await?,
@@ -153,26 +147,86 @@ pub async fn handle_nfs(
         NFSProgram::NFSPROC3_MKDIR => nfsproc3_mkdir(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_SYMLINK => nfsproc3_symlink(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_READLINK => nfsproc3_readlink(xid, input, output, context).await?,
-        _ => {
-            warn!("Unimplemented message {:?}", prog);
+        NFSProgram::NFSPROC3_LINK => nfsproc3_link(xid, input, output, context).await?,
+        NFSProgram::NFSPROC3_MKNOD => nfsproc3_mknod(xid, input, output, context).await?,
+        NFSProgram::INVALID => {
+            warn!(target: "nfsserve::nfs", "Unimplemented message {:?}", prog);
             proc_unavail_reply_message(xid).serialize(output)?;
-        } /*
-          NFSPROC3_MKNOD,
-          NFSPROC3_LINK,
-          NFSPROC3_COMMIT,
-          INVALID*/
+        }
     }
     Ok(())
 }
 
+/// How completely each procedure is supported. See support::matrix.
+/// Like the match in handle_nfs this has no wildcard arm, so that the
+/// two cannot disagree on the procedures there are.
+fn support(prog: NFSProgram) -> (Support, &'static str) {
+    match prog {
+        NFSProgram::NFSPROC3_NULL
+        | NFSProgram::NFSPROC3_GETATTR
+        | NFSProgram::NFSPROC3_SETATTR
+        | NFSProgram::NFSPROC3_LOOKUP
+        | NFSProgram::NFSPROC3_READLINK
+        | NFSProgram::NFSPROC3_READ
+        | NFSProgram::NFSPROC3_WRITE
+        | NFSProgram::NFSPROC3_MKDIR
+        | NFSProgram::NFSPROC3_SYMLINK
+        | NFSProgram::NFSPROC3_MKNOD
+        | NFSProgram::NFSPROC3_REMOVE
+        | NFSProgram::NFSPROC3_RENAME
+        | NFSProgram::NFSPROC3_LINK
+        | NFSProgram::NFSPROC3_READDIR
+        | NFSProgram::NFSPROC3_READDIRPLUS
+        | NFSProgram::NFSPROC3_FSSTAT
+        | NFSProgram::NFSPROC3_FSINFO
+        | NFSProgram::NFSPROC3_COMMIT => (Support::Full, ""),
+        NFSProgram::NFSPROC3_RMDIR => (Support::Full, "Served by NFSFileSystem::remove"),
+        NFSProgram::NFSPROC3_ACCESS => (
+            Support::Full,
+            "Evaluated by NFSFileSystem::access, by default from the mode \
+             bits and the caller's AUTH_UNIX credentials, less modification \
+             on read only file systems",
+        ),
+        NFSProgram::NFSPROC3_CREATE => (
+            Support::Partial,
+            "EXCLUSIVE does not keep the verifier, so a retransmitted \
+             EXCLUSIVE create may fail with NFS3ERR_EXIST",
+        ),
+        NFSProgram::NFSPROC3_PATHCONF => (
+            Support::Partial,
+            "name_max comes from NFSFileSystem::fsinfo_config, the other \
+             values are fixed",
+        ),
+        NFSProgram::INVALID => (Support::Unsupported, ""),
+    }
+}
+
+/// The support of the NFSv3 procedures. See support::matrix
+pub fn support_matrix() -> Vec<ProcedureSupport> {
+    (0..)
+        .map_while(NFSProgram::from_u32)
+        .take_while(|prog| !matches!(prog, NFSProgram::INVALID))
+        .map(|prog| {
+            let (support, notes) = support(prog);
+            ProcedureSupport {
+                program: "NFSv3",
+                number: prog as u32,
+                procedure: format!("{:?}", prog),
+                support,
+                notes,
+            }
+        })
+        .collect()
+}
+
 pub fn nfsproc3_null(
     xid: u32,
     _: &mut impl Read,
     output: &mut impl Write,
 ) -> Result<(), anyhow::Error> {
-    debug!("nfsproc3_null({:?}) ", xid);
+    debug!(target: "nfsserve::nfs", "nfsproc3_null({:?}) ", xid);
     let msg = make_success_reply(xid);
-    debug!("\t{:?} --> {:?}", xid, msg);
+    debug!(target: "nfsserve::nfs", "\t{:?} --> {:?}", xid, msg);
     msg.serialize(output)?;
     Ok(())
 }
@@ -200,8 +254,8 @@ pub async fn nfsproc3_getattr(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut handle = nfs::nfs_fh3::default();
-    handle.deserialize(input)?;
-    debug!("nfsproc3_getattr({:?},{:?}) ", xid, handle);
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_getattr({:?},{:?}) ", xid, handle);
 
     let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
@@ -211,15 +265,15 @@ pub async fn nfsproc3_getattr(
         return Ok(());
     }
     let id = id.unwrap();
-    match context.vfs.getattr(id).await {
+    match context.getattr(id).await {
         Ok(fh) => {
-            debug!(" {:?} --> {:?}", xid, fh);
+            debug!(target: "nfsserve::nfs", " {:?} --> {:?}", xid, fh);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             fh.serialize(output)?;
         }
         Err(stat) => {
-            error!("getattr error {:?} --> {:?}", xid, stat);
+            error!(target: "nfsserve::nfs", "getattr error {:?} --> {:?}", xid, stat);
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
         }
@@ -252,6 +306,45 @@ pub async fn nfsproc3_getattr(
  };
 *
 */
+/// Runs op, a call of the VFS naming an entry of a directory, unless the
+/// name is longer than the name_max of the file system (which PATHCONF
+/// advertises). Then op is never polled, and NFS3ERR_NAMETOOLONG is
+/// returned for the handler to reply with its usual wcc_data.
+async fn check_name<T>(
+    context: &RPCContext,
+    name: &nfs::filename3,
+    op: impl std::future::Future<Output = Result<T, nfs::nfsstat3>>,
+) -> Result<T, nfs::nfsstat3> {
+    if name.len() > context.vfs.fsinfo_config().name_max as usize {
+        debug!(target: "nfsserve::nfs", "name too long ({} bytes)", name.len());
+        return Err(nfs::nfsstat3::NFS3ERR_NAMETOOLONG);
+    }
+    op.await
+}
+
+/// The ACCESS3 bits a caller needs on a directory to add an entry to it
+const ADD_ENTRY: u32 = nfs::ACCESS3_EXTEND | nfs::ACCESS3_LOOKUP;
+/// The ACCESS3 bits a caller needs on a directory to remove an entry
+const REMOVE_ENTRY: u32 = nfs::ACCESS3_DELETE | nfs::ACCESS3_LOOKUP;
+
+/// Runs op, a call of the VFS changing the entries of dirid, if the caller
+/// may: if NFSFileSystem::access grants it all of required on dirid.
+/// Otherwise op is never polled, and NFS3ERR_ACCES is returned for the
+/// handler to reply with its usual wcc_data.
+async fn check_dir_access<T>(
+    context: &RPCContext,
+    dirid: nfs::fileid3,
+    required: u32,
+    op: impl std::future::Future<Output = Result<T, nfs::nfsstat3>>,
+) -> Result<T, nfs::nfsstat3> {
+    let attr = context.getattr(dirid).await?;
+    if context.vfs.access(dirid, &attr, required).await != required {
+        debug!(target: "nfsserve::nfs", "no access {:#x} to directory {}", required, dirid);
+        return Err(nfs::nfsstat3::NFS3ERR_ACCES);
+    }
+    op.await
+}
+
 pub async fn nfsproc3_lookup(
     xid: u32,
     input: &mut impl Read,
@@ -259,8 +352,8 @@ pub async fn nfsproc3_lookup(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut dirops = nfs::diropargs3::default();
-    dirops.deserialize(input)?;
-    debug!("nfsproc3_lookup({:?},{:?}) ", xid, dirops);
+    decode_args(&mut dirops, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_lookup({:?},{:?}) ", xid, dirops);
 
     let dirid = context.vfs.fh_to_id(&dirops.dir);
     // fail if unable to convert file handle
@@ -272,18 +365,24 @@ pub async fn nfsproc3_lookup(
     }
     let dirid = dirid.unwrap();
 
-    let dir_attr = match context.vfs.getattr(dirid).await {
+    let dir_attr = match context.getattr(dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
-    match context.vfs.lookup(dirid, &dirops.name).await {
+    match check_name(
+        context,
+        &dirops.name,
+        context.vfs.lookup(dirid, &dirops.name),
+    )
+    .await
+    {
         Ok(fid) => {
-            let obj_attr = match context.vfs.getattr(fid).await {
+            let obj_attr = match context.getattr(fid).await {
                 Ok(v) => nfs::post_op_attr::attributes(v),
                 Err(_) => nfs::post_op_attr::Void,
             };
 
-            debug!("lookup success {:?} --> {:?}", xid, obj_attr);
+            debug!(target: "nfsserve::nfs", "lookup success {:?} --> {:?}", xid, obj_attr);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             context.vfs.id_to_fh(fid).serialize(output)?;
@@ -291,7 +390,23 @@ pub async fn nfsproc3_lookup(
             dir_attr.serialize(output)?;
         }
         Err(stat) => {
-            debug!("lookup error {:?}({:?}) --> {:?}", xid, dirops.name, stat);
+            debug!(
+                target: "nfsserve::nfs",
+                "lookup error {:?}({:?}) --> {:?}",
+                xid,
+                dirops.name,
+                stat
+            );
+            let mut dir_attr = dir_attr;
+            if let nfs::post_op_attr::attributes(ref mut attr) = dir_attr {
+                // keep the directory attributes identical across replies so
+                // that the client keeps its negative cache entry
+                if matches!(stat, nfs::nfsstat3::NFS3ERR_NOENT)
+                    && context.vfs.is_immutable_dir(dirid).await
+                {
+                    attr.atime = attr.mtime;
+                }
+            }
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             dir_attr.serialize(output)?;
@@ -309,15 +424,26 @@ struct READ3args {
 }
 XDRStruct!(READ3args, file, offset, count);
 
-#[allow(non_camel_case_types)]
-#[derive(Debug, Default)]
-struct READ3resok {
-    file_attributes: nfs::post_op_attr,
-    count: nfs::count3,
-    eof: bool,
-    data: Vec<u8>,
+/// The number of READ buffers kept for reuse per connection. More READs
+/// than this in flight on a connection allocate buffers of their own.
+const MAX_POOLED_READ_BUFFERS: usize = 4;
+
+fn take_read_buffer(context: &RPCContext) -> Vec<u8> {
+    context
+        .read_buffers
+        .lock()
+        .unwrap()
+        .pop()
+        .unwrap_or_default()
+}
+
+fn put_read_buffer(pool: &Mutex<Vec<Vec<u8>>>, mut buf: Vec<u8>) {
+    buf.clear();
+    let mut pool = pool.lock().unwrap();
+    if pool.len() < MAX_POOLED_READ_BUFFERS {
+        pool.push(buf);
+    }
 }
-XDRStruct!(READ3resok, file_attributes, count, eof, data);
 /*
 READ3res NFSPROC3_READ(READ3args) = 6;
 
@@ -352,8 +478,8 @@ pub async fn nfsproc3_read(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut args = READ3args::default();
-    args.deserialize(input)?;
-    debug!("nfsproc3_read({:?},{:?}) ", xid, args);
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::read", "nfsproc3_read({:?},{:?}) ", xid, args);
 
     let id = context.vfs.fh_to_id(&args.file);
     if let Err(stat) = id {
@@ -364,27 +490,98 @@ pub async fn nfsproc3_read(
     }
     let id = id.unwrap();
 
-    let obj_attr = match context.vfs.getattr(id).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
+    // never forward a range which wraps around to the VFS
+    if args.offset.checked_add(args.count as u64).is_none() {
+        warn!(
+            target: "nfsserve::read",
+            "read {:?} range overflows. offset {} count {}",
+            xid,
+            args.offset,
+            args.count
+        );
+        let obj_attr = match context.getattr(id).await {
+            Ok(v) => nfs::post_op_attr::attributes(v),
+            Err(_) => nfs::post_op_attr::Void,
+        };
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_INVAL.serialize(output)?;
+        obj_attr.serialize(output)?;
+        return Ok(());
+    }
+    // clamp reads larger than we advertised. Returning fewer bytes than
+    // requested is always allowed.
+    let rtmax = context.vfs.fsinfo_config().rtmax;
+    if args.count > rtmax {
+        warn!(
+            target: "nfsserve::read",
+            "read {:?} of {} bytes from {} exceeds rtmax. Clamping to {}",
+            xid,
+            args.count,
+            context.client_addr,
+            rtmax
+        );
+        args.count = rtmax;
+    }
+    // held until the data has been attached to the reply, which is then
+    // accounted for until written
+    let _reservation = match context
+        .memory_budget
+        .try_reserve(args.count as usize, context.record_len)
+    {
+        Some(reservation) => reservation,
+        None => {
+            debug!(
+                target: "nfsserve::read",
+                "read {:?} of {} bytes deferred. {} bytes in flight",
+                xid,
+                args.count,
+                context.memory_budget.used()
+            );
+            make_success_reply(xid).serialize(output)?;
+            nfs::nfsstat3::NFS3ERR_JUKEBOX.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
     };
-    match context.vfs.read(id, args.offset, args.count).await {
-        Ok((bytes, eof)) => {
-            let res = READ3resok {
-                file_attributes: obj_attr,
-                count: bytes.len() as u32,
-                eof,
-                data: bytes,
+    let mut buf = take_read_buffer(context);
+    match context
+        .vfs
+        .read_ext(id, args.offset, args.count, &mut buf)
+        .await
+    {
+        Ok(reply) => {
+            // prefer the attributes the VFS saw with the data
+            let obj_attr = match reply.attr {
+                Some(v) => nfs::post_op_attr::attributes(v),
+                None => match context.getattr(id).await {
+                    Ok(v) => nfs::post_op_attr::attributes(v),
+                    Err(_) => nfs::post_op_attr::Void,
+                },
             };
+            // READ3resok, with the data<> opaque written by hand: its
+            // length, buf attached to the reply as it is (and back in the
+            // pool once written) and the padding
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
-            res.serialize(output)?;
+            obj_attr.serialize(output)?;
+            (buf.len() as u32).serialize(output)?;
+            reply.eof.serialize(output)?;
+            (buf.len() as u32).serialize(output)?;
+            let padding = (4 - buf.len() % 4) % 4;
+            let pool = context.read_buffers.clone();
+            context.write_reply_data(output, buf, move |buf| put_read_buffer(&pool, buf))?;
+            output.write_all(&[0; 3][..padding])?;
         }
         Err(stat) => {
-            error!("read error {:?} --> {:?}", xid, stat);
+            error!(target: "nfsserve::read", "read error {:?} --> {:?}", xid, stat);
+            let obj_attr = match context.getattr(id).await {
+                Ok(v) => nfs::post_op_attr::attributes(v),
+                Err(_) => nfs::post_op_attr::Void,
+            };
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             obj_attr.serialize(output)?;
+            put_read_buffer(&context.read_buffers, buf);
         }
     }
     Ok(())
@@ -436,8 +633,8 @@ pub async fn nfsproc3_fsinfo(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut handle = nfs::nfs_fh3::default();
-    handle.deserialize(input)?;
-    debug!("nfsproc3_fsinfo({:?},{:?}) ", xid, handle);
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_fsinfo({:?},{:?}) ", xid, handle);
 
     let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
@@ -451,26 +648,35 @@ pub async fn nfsproc3_fsinfo(
 
     match context.vfs.fsinfo(id).await {
         Ok(fsinfo) => {
-            debug!(" {:?} --> {:?}", xid, fsinfo);
+            debug!(target: "nfsserve::nfs", " {:?} --> {:?}", xid, fsinfo);
+            // clients pick their rsize / wsize from these at mount time
+            info!(
+                target: "nfsserve::nfs",
+                "advertising to {}: rtmax {} rtpref {} wtmax {} wtpref {}",
+                context.client_addr,
+                fsinfo.rtmax,
+                fsinfo.rtpref,
+                fsinfo.wtmax,
+                fsinfo.wtpref
+            );
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             fsinfo.serialize(output)?;
         }
         Err(stat) => {
-            error!("fsinfo error {:?} --> {:?}", xid, stat);
+            error!(target: "nfsserve::nfs", "fsinfo error {:?} --> {:?}", xid, stat);
+            let obj_attr = match context.getattr(id).await {
+                Ok(v) => nfs::post_op_attr::attributes(v),
+                Err(_) => nfs::post_op_attr::Void,
+            };
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
+            obj_attr.serialize(output)?;
         }
     }
     Ok(())
 }
 
-const ACCESS3_READ: u32 = 0x0001;
-const ACCESS3_LOOKUP: u32 = 0x0002;
-const ACCESS3_MODIFY: u32 = 0x0004;
-const ACCESS3_EXTEND: u32 = 0x0008;
-const ACCESS3_DELETE: u32 = 0x0010;
-const ACCESS3_EXECUTE: u32 = 0x0020;
 /*
 
  ACCESS3res NFSPROC3_ACCESS(ACCESS3args) = 4;
@@ -505,10 +711,10 @@ pub async fn nfsproc3_access(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut handle = nfs::nfs_fh3::default();
-    handle.deserialize(input)?;
+    decode_args(&mut handle, input)?;
     let mut access: u32 = 0;
-    access.deserialize(input)?;
-    debug!("nfsproc3_access({:?},{:?},{:?})", xid, handle, access);
+    decode_args(&mut access, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_access({:?},{:?},{:?})", xid, handle, access);
 
     let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
@@ -520,15 +726,18 @@ pub async fn nfsproc3_access(
     }
     let id = id.unwrap();
 
-    let obj_attr = match context.vfs.getattr(id).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
+    let obj_attr = match context.getattr(id).await {
+        Ok(v) => {
+            // what the caller may do with this object in particular
+            access = context.vfs.access(id, &v, access).await;
+            nfs::post_op_attr::attributes(v)
+        }
         Err(_) => nfs::post_op_attr::Void,
     };
-    // TODO better checks here
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        access &= ACCESS3_READ | ACCESS3_LOOKUP;
+        access &= nfs::ACCESS3_READ | nfs::ACCESS3_LOOKUP;
     }
-    debug!(" {:?} ---> {:?}", xid, access);
+    debug!(target: "nfsserve::nfs", " {:?} ---> {:?}", xid, access);
     make_success_reply(xid).serialize(output)?;
     nfs::nfsstat3::NFS3_OK.serialize(output)?;
     obj_attr.serialize(output)?;
@@ -593,8 +802,8 @@ pub async fn nfsproc3_pathconf(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut handle = nfs::nfs_fh3::default();
-    handle.deserialize(input)?;
-    debug!("nfsproc3_pathconf({:?},{:?})", xid, handle);
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_pathconf({:?},{:?})", xid, handle);
 
     let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
@@ -606,20 +815,20 @@ pub async fn nfsproc3_pathconf(
     }
     let id = id.unwrap();
 
-    let obj_attr = match context.vfs.getattr(id).await {
+    let obj_attr = match context.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
     let res = PATHCONF3resok {
         obj_attributes: obj_attr,
         linkmax: 0,
-        name_max: 32768,
+        name_max: context.vfs.fsinfo_config().name_max,
         no_trunc: true,
         chown_restricted: true,
         case_insensitive: false,
         case_preserving: true,
     };
-    debug!(" {:?} ---> {:?}", xid, res);
+    debug!(target: "nfsserve::nfs", " {:?} ---> {:?}", xid, res);
     make_success_reply(xid).serialize(output)?;
     nfs::nfsstat3::NFS3_OK.serialize(output)?;
     res.serialize(output)?;
@@ -688,8 +897,8 @@ pub async fn nfsproc3_fsstat(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut handle = nfs::nfs_fh3::default();
-    handle.deserialize(input)?;
-    debug!("nfsproc3_fsstat({:?},{:?}) ", xid, handle);
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_fsstat({:?},{:?}) ", xid, handle);
     let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
     if let Err(stat) = id {
@@ -700,23 +909,33 @@ pub async fn nfsproc3_fsstat(
     }
     let id = id.unwrap();
 
-    let obj_attr = match context.vfs.getattr(id).await {
+    let obj_attr = match context.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
+    let stat = match context.vfs.fs_stat(id).await {
+        Ok(stat) => stat,
+        Err(stat) => {
+            error!(target: "nfsserve::nfs", "fsstat error {:?} --> {:?}", xid, stat);
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            obj_attr.serialize(output)?;
+            return Ok(());
+        }
+    };
     let res = FSSTAT3resok {
         obj_attributes: obj_attr,
-        tbytes: 1024 * 1024 * 1024 * 1024,
-        fbytes: 1024 * 1024 * 1024 * 1024,
-        abytes: 1024 * 1024 * 1024 * 1024,
-        tfiles: 1024 * 1024 * 1024,
-        ffiles: 1024 * 1024 * 1024,
-        afiles: 1024 * 1024 * 1024,
-        invarsec: u32::MAX,
+        tbytes: stat.total_bytes,
+        fbytes: stat.free_bytes,
+        abytes: stat.avail_bytes,
+        tfiles: stat.total_files,
+        ffiles: stat.free_files,
+        afiles: stat.avail_files,
+        invarsec: stat.invarsec,
     };
     make_success_reply(xid).serialize(output)?;
     nfs::nfsstat3::NFS3_OK.serialize(output)?;
-    debug!(" {:?} ---> {:?}", xid, res);
+    debug!(target: "nfsserve::nfs", " {:?} ---> {:?}", xid, res);
     res.serialize(output)?;
     Ok(())
 }
@@ -775,6 +994,36 @@ XDRStruct!(
     name_attributes,
     name_handle
 );
+
+/// The dircount of the smallest entry: fileid, a 1 byte name and cookie
+const MIN_ENTRY_DIRCOUNT: u32 = 8 + 4 + 1 + 8;
+
+/// Returns count, or the dtpref of the file system if count is below min
+/// (i.e. cannot hold a single entry) and the connection has
+/// NFSTcp::set_readdir_count_compat. Some clients send a READDIRPLUS
+/// with zero counts before they have the FSINFO reply, and loop on the
+/// TOOSMALL it would otherwise get.
+fn readdir_count_compat(context: &RPCContext, what: &str, count: u32, min: u32) -> u32 {
+    if !context.readdir_count_compat || count >= min {
+        return count;
+    }
+    let dtpref = context.vfs.fsinfo_config().dtpref;
+    if !context
+        .readdir_count_substituted
+        .swap(true, std::sync::atomic::Ordering::Relaxed)
+    {
+        debug!(
+            target: "nfsserve::readdir",
+            "READDIRPLUS {} {} from {} cannot hold an entry. Using dtpref {} instead",
+            what,
+            count,
+            context.client_addr,
+            dtpref
+        );
+    }
+    dtpref
+}
+
 /*
 
       READDIRPLUS3res NFSPROC3_READDIRPLUS(READDIRPLUS3args) = 17;
@@ -809,8 +1058,18 @@ pub async fn nfsproc3_readdirplus(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut args = READDIRPLUS3args::default();
-    args.deserialize(input)?;
-    debug!("nfsproc3_readdirplus({:?},{:?}) ", xid, args);
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::readdir", "nfsproc3_readdirplus({:?},{:?}) ", xid, args);
+    if !context.readdirplus {
+        // clients fall back to READDIR
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_NOTSUPP.serialize(output)?;
+        nfs::post_op_attr::Void.serialize(output)?;
+        return Ok(());
+    }
+    args.maxcount = readdir_count_compat(context, "maxcount", args.maxcount, 128 + 1);
+    args.dircount =
+        readdir_count_compat(context, "dircount", args.dircount, MIN_ENTRY_DIRCOUNT + 1);
 
     let dirid = context.vfs.fh_to_id(&args.dir);
     // fail if unable to convert file handle
@@ -821,7 +1080,7 @@ pub async fn nfsproc3_readdirplus(
         return Ok(());
     }
     let dirid = dirid.unwrap();
-    let dir_attr_maybe = context.vfs.getattr(dirid).await;
+    let dir_attr_maybe = context.getattr(dirid).await;
 
     let dir_attr = match dir_attr_maybe {
         Ok(v) => nfs::post_op_attr::attributes(v),
@@ -834,8 +1093,8 @@ pub async fn nfsproc3_readdirplus(
     } else {
         nfs::cookieverf3::default()
     };
-    debug!(" -- Dir attr {:?}", dir_attr);
-    debug!(" -- Dir version {:?}", dirversion);
+    debug!(target: "nfsserve::readdir", " -- Dir attr {:?}", dir_attr);
+    debug!(target: "nfsserve::readdir", " -- Dir version {:?}", dirversion);
     let has_version = args.cookieverf != nfs::cookieverf3::default();
     // initial call should hve empty cookie verf
     // subsequent calls should have cvf_version as defined above
@@ -901,91 +1160,154 @@ pub async fn nfsproc3_readdirplus(
         return Ok(());
     }*/
     // subtract off the final entryplus* field (which must be false) and the eof
-    let max_bytes_allowed = args.maxcount as usize - 128;
-    // args.dircount is bytes of just fileid, name, cookie.
-    // This is hard to ballpark, so we just divide it by 16
-    let estimated_max_results = args.dircount / 16;
-    let max_dircount_bytes = args.dircount as usize;
-    let mut ctr = 0;
-    match context
+    let max_bytes_allowed = (args.maxcount as usize).saturating_sub(128);
+    // if the VFS can hand us pre-encoded entries, splice them in directly
+    if let Some(page) = context
         .vfs
-        .readdir(dirid, args.cookie, estimated_max_results as usize)
+        .readdir_raw(dirid, args.cookie, args.dircount, args.maxcount)
         .await
     {
-        Ok(result) => {
-            // we count dir_count seperately as it is just a subset of fields
-            let mut accumulated_dircount: usize = 0;
-            let mut all_entries_written = true;
-
-            // this is a wrapper around a writer that also just counts the number of bytes
-            // written
-            let mut counting_output = crate::write_counter::WriteCounter::new(output);
-
-            make_success_reply(xid).serialize(&mut counting_output)?;
-            nfs::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
-            dir_attr.serialize(&mut counting_output)?;
-            dirversion.serialize(&mut counting_output)?;
-            for entry in result.entries {
-                let obj_attr = entry.attr;
-                let handle = nfs::post_op_fh3::handle(context.vfs.id_to_fh(entry.fileid));
-
-                let entry = entryplus3 {
-                    fileid: entry.fileid,
-                    name: entry.name,
-                    cookie: entry.fileid,
-                    name_attributes: nfs::post_op_attr::attributes(obj_attr),
-                    name_handle: handle,
-                };
-                // write the entry into a buffer first
-                let mut write_buf: Vec<u8> = Vec::new();
-                let mut write_cursor = std::io::Cursor::new(&mut write_buf);
-                // true flag for the entryplus3* to mark that this contains an entry
-                true.serialize(&mut write_cursor)?;
-                entry.serialize(&mut write_cursor)?;
-                write_cursor.flush()?;
-                let added_dircount = std::mem::size_of::<nfs::fileid3>()                   // fileid
-                                    + std::mem::size_of::<u32>() + entry.name.len()  // name
-                                    + std::mem::size_of::<nfs::cookie3>(); // cookie
-                let added_output_bytes = write_buf.len();
-                // check if we can write without hitting the limits
-                if added_output_bytes + counting_output.bytes_written() < max_bytes_allowed
-                    && added_dircount + accumulated_dircount < max_dircount_bytes
-                {
-                    trace!("  -- dirent {:?}", entry);
-                    // commit the entry
-                    ctr += 1;
-                    counting_output.write_all(&write_buf)?;
-                    accumulated_dircount += added_dircount;
-                    trace!(
-                        "  -- lengths: {:?} / {:?} {:?} / {:?}",
-                        accumulated_dircount,
-                        max_dircount_bytes,
-                        counting_output.bytes_written(),
-                        max_bytes_allowed
-                    );
-                } else {
-                    trace!(" -- insufficient space. truncating");
-                    all_entries_written = false;
-                    break;
-                }
+        match page {
+            Ok(page) => {
+                write_raw_dir_page(xid, output, &dir_attr, &page, max_bytes_allowed)?;
+            }
+            Err(stat) => {
+                error!(target: "nfsserve::readdir", "readdir error {:?} --> {:?} ", xid, stat);
+                make_success_reply(xid).serialize(output)?;
+                stat.serialize(output)?;
+                dir_attr.serialize(output)?;
+            }
+        }
+        return Ok(());
+    }
+    let max_dircount_bytes = args.dircount as usize;
+    // args.dircount is bytes of just fileid, name, cookie.
+    // Without entry sizes seen for this directory, this is hard to
+    // ballpark, so we just divide it by 16
+    let estimated_max_results = context.readdir_sizes.estimate(
+        dirid,
+        true,
+        max_bytes_allowed,
+        max_dircount_bytes,
+        (args.dircount / 16) as usize,
+    );
+    let mut ctr = 0;
+    // we count dir_count seperately as it is just a subset of fields
+    let mut accumulated_dircount: usize = 0;
+    let mut accumulated_entry_bytes: usize = 0;
+    let mut all_entries_written = true;
+
+    // the reply is built up in a buffer as it is replaced with
+    // TOOSMALL if not even one entry fits.
+    let mut reply: Vec<u8> = Vec::new();
+    // this is a wrapper around a writer that also just counts the number of bytes
+    // written
+    let mut counting_output = crate::write_counter::WriteCounter::new(&mut reply);
+
+    make_success_reply(xid).serialize(&mut counting_output)?;
+    nfs::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
+    dir_attr.serialize(&mut counting_output)?;
+    dirversion.serialize(&mut counting_output)?;
+    let mut cookie = args.cookie;
+    let listed = loop {
+        let page = match context
+            .vfs
+            // cookies are the DirEntry::cookie of the last entry returned
+            .readdirplus(
+                dirid,
+                cookie,
+                args.dircount,
+                args.maxcount,
+                estimated_max_results,
+            )
+            .await
+        {
+            Ok(page) => page,
+            Err(stat) => break Err(stat),
+        };
+        let mut last_hidden = None;
+        for entry in page.entries {
+            if context.hides_dir_entry(dirid, &entry.name) {
+                last_hidden = Some(entry.effective_cookie());
+                continue;
+            }
+            last_hidden = None;
+            let entry = entryplus3 {
+                cookie: entry.effective_cookie(),
+                fileid: entry.fileid,
+                name: entry.name,
+                name_attributes: nfs::post_op_attr::attributes(entry.attr),
+                name_handle: entry.handle,
+            };
+            // write the entry into a buffer first
+            let mut write_buf: Vec<u8> = Vec::new();
+            // true flag for the entryplus3* to mark that this contains an entry
+            true.serialize(&mut write_buf)?;
+            entry.serialize(&mut write_buf)?;
+            let added_dircount = std::mem::size_of::<nfs::fileid3>()                   // fileid
+                                + std::mem::size_of::<u32>() + entry.name.len()  // name
+                                + std::mem::size_of::<nfs::cookie3>(); // cookie
+            let added_output_bytes = write_buf.len();
+            // check if we can write without hitting the limits
+            if added_output_bytes + counting_output.bytes_written() < max_bytes_allowed
+                && added_dircount + accumulated_dircount < max_dircount_bytes
+            {
+                trace!(target: "nfsserve::readdir", "  -- dirent {:?}", entry);
+                // commit the entry
+                counting_output.write_all(&write_buf)?;
+                ctr += 1;
+                accumulated_dircount += added_dircount;
+                accumulated_entry_bytes += added_output_bytes;
+                trace!(
+                    target: "nfsserve::readdir",
+                    "  -- lengths: {:?} / {:?} {:?} / {:?}",
+                    accumulated_dircount,
+                    max_dircount_bytes,
+                    counting_output.bytes_written(),
+                    max_bytes_allowed
+                );
+                stream_dir_entries(counting_output.get_mut(), output, context).await?;
+            } else {
+                trace!(target: "nfsserve::readdir", " -- insufficient space. truncating");
+                all_entries_written = false;
+                break;
             }
+        }
+        // a page of only hidden entries gives the client no cookie to
+        // continue from, so the listing goes on past them
+        match last_hidden {
+            Some(next) if ctr == 0 && all_entries_written && !page.end => cookie = next,
+            _ => break Ok(page.end),
+        }
+    };
+    match listed {
+        Ok(end) => {
+            context.readdir_sizes.observe(
+                dirid,
+                true,
+                ctr,
+                accumulated_entry_bytes,
+                accumulated_dircount,
+            );
             // false flag for the final entryplus* linked list
             false.serialize(&mut counting_output)?;
             // eof flag is only valid here if we wrote everything
-            if all_entries_written {
-                debug!("  -- readdir eof {:?}", result.end);
-                result.end.serialize(&mut counting_output)?;
-            } else {
-                debug!("  -- readdir eof {:?}", false);
-                false.serialize(&mut counting_output)?;
-            }
+            let eof = end && all_entries_written;
+            debug!(target: "nfsserve::readdir", "  -- readdir eof {:?}", eof);
+            eof.serialize(&mut counting_output)?;
             debug!(
+                target: "nfsserve::readdir",
                 "readir {}, has_version {},  start at {}, flushing {} entries, complete {}",
                 dirid, has_version, args.cookie, ctr, all_entries_written
             );
+            if ctr == 0 && !eof {
+                write_dir_too_small(xid, output, &dir_attr)?;
+            } else {
+                output.write_all(&reply)?;
+            }
         }
         Err(stat) => {
-            error!("readdir error {:?} --> {:?} ", xid, stat);
+            error!(target: "nfsserve::readdir", "readdir error {:?} --> {:?} ", xid, stat);
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             dir_attr.serialize(output)?;
@@ -994,6 +1316,117 @@ pub async fn nfsproc3_readdirplus(
     Ok(())
 }
 
+/// Moves the part of a READDIR or READDIRPLUS reply buffered in reply to
+/// output once it makes a chunk, and sends it off to the client. Only
+/// called once an entry is in the reply, which then is never replaced by
+/// NFS3ERR_TOOSMALL. A long listing is so never held whole.
+async fn stream_dir_entries(
+    reply: &mut Vec<u8>,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    if reply.len() >= crate::rpcwire::REPLY_CHUNK_SIZE {
+        output.write_all(reply)?;
+        reply.clear();
+        context.send_reply_chunk().await?;
+    }
+    Ok(())
+}
+
+/// Writes a READDIRPLUS reply from a page of pre-encoded entries,
+/// truncating the page at the last entry boundary which fits
+/// within max_bytes_allowed.
+fn write_raw_dir_page(
+    xid: u32,
+    output: &mut impl Write,
+    dir_attr: &nfs::post_op_attr,
+    page: &RawDirPage,
+    max_bytes_allowed: usize,
+) -> Result<(), anyhow::Error> {
+    // entries are cut at the offsets, so these must partition entries:
+    // the first entry starts at 0 and each one ends before the next
+    let offsets = &page.entry_offsets;
+    let valid = match (offsets.first(), offsets.last()) {
+        (Some(&first), Some(&last)) => {
+            first == 0 && last < page.entries.len() && offsets.windows(2).all(|w| w[0] < w[1])
+        }
+        _ => page.entries.is_empty(),
+    };
+    if !valid {
+        error!(
+            target: "nfsserve::readdir",
+            "readdir_raw returned entry offsets {:?} which do not partition {} bytes",
+            offsets,
+            page.entries.len()
+        );
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_SERVERFAULT.serialize(output)?;
+        dir_attr.serialize(output)?;
+        return Ok(());
+    }
+
+    let mut reply: Vec<u8> = Vec::new();
+    let mut counting_output = crate::write_counter::WriteCounter::new(&mut reply);
+    make_success_reply(xid).serialize(&mut counting_output)?;
+    nfs::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
+    dir_attr.serialize(&mut counting_output)?;
+    page.cookieverf.serialize(&mut counting_output)?;
+
+    // the end of entry i is the start of entry i+1, or the end of entries
+    // for the last one
+    let available = max_bytes_allowed.saturating_sub(counting_output.bytes_written());
+    let mut cut = 0;
+    for end in offsets
+        .iter()
+        .skip(1)
+        .copied()
+        .chain(std::iter::once(page.entries.len()))
+    {
+        if end >= available {
+            break;
+        }
+        cut = end;
+    }
+    let all_entries_written = cut == page.entries.len();
+    counting_output.write_all(&page.entries[..cut])?;
+    // false flag for the final entryplus* linked list
+    false.serialize(&mut counting_output)?;
+    // eof flag is only valid here if we wrote everything
+    (all_entries_written && page.end).serialize(&mut counting_output)?;
+    debug!(
+        target: "nfsserve::readdir",
+        "readdir raw page, flushing {} of {} bytes, complete {}",
+        cut,
+        page.entries.len(),
+        all_entries_written
+    );
+    if cut == 0 && !all_entries_written {
+        write_dir_too_small(xid, output, dir_attr)?;
+    } else {
+        output.write_all(&reply)?;
+    }
+    Ok(())
+}
+
+/// Replies NFS3ERR_TOOSMALL to a READDIR or READDIRPLUS whose count is too
+/// small to hold even one entry. An empty page which is not eof would just
+/// have the client retry the same request forever.
+fn write_dir_too_small(
+    xid: u32,
+    output: &mut impl Write,
+    dir_attr: &nfs::post_op_attr,
+) -> Result<(), anyhow::Error> {
+    warn!(
+        target: "nfsserve::readdir",
+        "readdir {:?} count too small to return a single entry",
+        xid
+    );
+    make_success_reply(xid).serialize(output)?;
+    nfs::nfsstat3::NFS3ERR_TOOSMALL.serialize(output)?;
+    dir_attr.serialize(output)?;
+    Ok(())
+}
+
 pub async fn nfsproc3_readdir(
     xid: u32,
     input: &mut impl Read,
@@ -1001,8 +1434,8 @@ pub async fn nfsproc3_readdir(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut args = READDIR3args::default();
-    args.deserialize(input)?;
-    debug!("nfsproc3_readdirplus({:?},{:?}) ", xid, args);
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::readdir", "nfsproc3_readdirplus({:?},{:?}) ", xid, args);
 
     let dirid = context.vfs.fh_to_id(&args.dir);
     // fail if unable to convert file handle
@@ -1013,7 +1446,7 @@ pub async fn nfsproc3_readdir(
         return Ok(());
     }
     let dirid = dirid.unwrap();
-    let dir_attr_maybe = context.vfs.getattr(dirid).await;
+    let dir_attr_maybe = context.getattr(dirid).await;
 
     let dir_attr = match dir_attr_maybe {
         Ok(v) => nfs::post_op_attr::attributes(v),
@@ -1026,86 +1459,129 @@ pub async fn nfsproc3_readdir(
     } else {
         nfs::cookieverf3::default()
     };
-    debug!(" -- Dir attr {:?}", dir_attr);
-    debug!(" -- Dir version {:?}", dirversion);
+    debug!(target: "nfsserve::readdir", " -- Dir attr {:?}", dir_attr);
+    debug!(target: "nfsserve::readdir", " -- Dir version {:?}", dirversion);
     let has_version = args.cookieverf != nfs::cookieverf3::default();
     // subtract off the final entryplus* field (which must be false) and the eof
-    let max_bytes_allowed = args.dircount as usize - 128;
+    let max_bytes_allowed = (args.dircount as usize).saturating_sub(128);
     // args.dircount is bytes of just fileid, name, cookie.
-    // This is hard to ballpark, so we just divide it by 16
-    let estimated_max_results = args.dircount / 16;
+    // Without entry sizes seen for this directory, this is hard to
+    // ballpark, so we just divide it by 16
+    let estimated_max_results = context.readdir_sizes.estimate(
+        dirid,
+        false,
+        max_bytes_allowed,
+        usize::MAX,
+        (args.dircount / 16) as usize,
+    );
     let mut ctr = 0;
-    match context
-        .vfs
-        .readdir_simple(dirid, estimated_max_results as usize)
-        .await
-    {
-        Ok(result) => {
-            // we count dir_count seperately as it is just a subset of fields
-            let mut accumulated_dircount: usize = 0;
-            let mut all_entries_written = true;
-
-            // this is a wrapper around a writer that also just counts the number of bytes
-            // written
-            let mut counting_output = crate::write_counter::WriteCounter::new(output);
-
-            make_success_reply(xid).serialize(&mut counting_output)?;
-            nfs::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
-            dir_attr.serialize(&mut counting_output)?;
-            dirversion.serialize(&mut counting_output)?;
-            for entry in result.entries {
-                let entry = entry3 {
-                    fileid: entry.fileid,
-                    name: entry.name,
-                    cookie: entry.fileid,
-                };
-                // write the entry into a buffer first
-                let mut write_buf: Vec<u8> = Vec::new();
-                let mut write_cursor = std::io::Cursor::new(&mut write_buf);
-                // true flag for the entryplus3* to mark that this contains an entry
-                true.serialize(&mut write_cursor)?;
-                entry.serialize(&mut write_cursor)?;
-                write_cursor.flush()?;
-                let added_dircount = std::mem::size_of::<nfs::fileid3>()                   // fileid
-                                    + std::mem::size_of::<u32>() + entry.name.len()  // name
-                                    + std::mem::size_of::<nfs::cookie3>(); // cookie
-                let added_output_bytes = write_buf.len();
-                // check if we can write without hitting the limits
-                if added_output_bytes + counting_output.bytes_written() < max_bytes_allowed {
-                    trace!("  -- dirent {:?}", entry);
-                    // commit the entry
-                    ctr += 1;
-                    counting_output.write_all(&write_buf)?;
-                    accumulated_dircount += added_dircount;
-                    trace!(
-                        "  -- lengths: {:?} / {:?} / {:?}",
-                        accumulated_dircount,
-                        counting_output.bytes_written(),
-                        max_bytes_allowed
-                    );
-                } else {
-                    trace!(" -- insufficient space. truncating");
-                    all_entries_written = false;
-                    break;
-                }
+    // we count dir_count seperately as it is just a subset of fields
+    let mut accumulated_dircount: usize = 0;
+    let mut accumulated_entry_bytes: usize = 0;
+    let mut all_entries_written = true;
+
+    // the reply is built up in a buffer as it is replaced with
+    // TOOSMALL if not even one entry fits.
+    let mut reply: Vec<u8> = Vec::new();
+    // this is a wrapper around a writer that also just counts the number of bytes
+    // written
+    let mut counting_output = crate::write_counter::WriteCounter::new(&mut reply);
+
+    make_success_reply(xid).serialize(&mut counting_output)?;
+    nfs::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
+    dir_attr.serialize(&mut counting_output)?;
+    dirversion.serialize(&mut counting_output)?;
+    let mut cookie = args.cookie;
+    let listed = loop {
+        let result = match context
+            .vfs
+            // cookies are the DirEntry::cookie of the last entry returned
+            .readdir_simple(dirid, cookie, estimated_max_results)
+            .await
+        {
+            Ok(result) => result,
+            Err(stat) => break Err(stat),
+        };
+        let mut last_hidden = None;
+        for entry in result.entries {
+            if context.hides_dir_entry(dirid, &entry.name) {
+                last_hidden = Some(entry.effective_cookie());
+                continue;
             }
+            last_hidden = None;
+            let entry = entry3 {
+                cookie: entry.effective_cookie(),
+                fileid: entry.fileid,
+                name: entry.name,
+            };
+            // write the entry into a buffer first
+            let mut write_buf: Vec<u8> = Vec::new();
+            let mut write_cursor = std::io::Cursor::new(&mut write_buf);
+            // true flag for the entryplus3* to mark that this contains an entry
+            true.serialize(&mut write_cursor)?;
+            entry.serialize(&mut write_cursor)?;
+            write_cursor.flush()?;
+            let added_dircount = std::mem::size_of::<nfs::fileid3>()                   // fileid
+                                + std::mem::size_of::<u32>() + entry.name.len()  // name
+                                + std::mem::size_of::<nfs::cookie3>(); // cookie
+            let added_output_bytes = write_buf.len();
+            // check if we can write without hitting the limits
+            if added_output_bytes + counting_output.bytes_written() < max_bytes_allowed {
+                trace!(target: "nfsserve::readdir", "  -- dirent {:?}", entry);
+                // commit the entry
+                ctr += 1;
+                counting_output.write_all(&write_buf)?;
+                accumulated_dircount += added_dircount;
+                accumulated_entry_bytes += added_output_bytes;
+                trace!(
+                    target: "nfsserve::readdir",
+                    "  -- lengths: {:?} / {:?} / {:?}",
+                    accumulated_dircount,
+                    counting_output.bytes_written(),
+                    max_bytes_allowed
+                );
+                stream_dir_entries(counting_output.get_mut(), output, context).await?;
+            } else {
+                trace!(target: "nfsserve::readdir", " -- insufficient space. truncating");
+                all_entries_written = false;
+                break;
+            }
+        }
+        // a page of only hidden entries gives the client no cookie to
+        // continue from, so the listing goes on past them
+        match last_hidden {
+            Some(next) if ctr == 0 && all_entries_written && !result.end => cookie = next,
+            _ => break Ok(result.end),
+        }
+    };
+    match listed {
+        Ok(end) => {
+            context.readdir_sizes.observe(
+                dirid,
+                false,
+                ctr,
+                accumulated_entry_bytes,
+                accumulated_dircount,
+            );
             // false flag for the final entryplus* linked list
             false.serialize(&mut counting_output)?;
             // eof flag is only valid here if we wrote everything
-            if all_entries_written {
-                debug!("  -- readdir eof {:?}", result.end);
-                result.end.serialize(&mut counting_output)?;
-            } else {
-                debug!("  -- readdir eof {:?}", false);
-                false.serialize(&mut counting_output)?;
-            }
+            let eof = end && all_entries_written;
+            debug!(target: "nfsserve::readdir", "  -- readdir eof {:?}", eof);
+            eof.serialize(&mut counting_output)?;
             debug!(
+                target: "nfsserve::readdir",
                 "readir {}, has_version {},  start at {}, flushing {} entries, complete {}",
                 dirid, has_version, args.cookie, ctr, all_entries_written
             );
+            if ctr == 0 && !eof {
+                write_dir_too_small(xid, output, &dir_attr)?;
+            } else {
+                output.write_all(&reply)?;
+            }
         }
         Err(stat) => {
-            error!("readdir error {:?} --> {:?} ", xid, stat);
+            error!(target: "nfsserve::readdir", "readdir error {:?} --> {:?} ", xid, stat);
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             dir_attr.serialize(output)?;
@@ -1114,24 +1590,13 @@ pub async fn nfsproc3_readdir(
     Ok(())
 }
 
-#[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
-#[repr(u32)]
-pub enum stable_how {
-    #[default]
-    UNSTABLE = 0,
-    DATA_SYNC = 1,
-    FILE_SYNC = 2,
-}
-XDREnumSerde!(stable_how);
-
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
 struct WRITE3args {
     file: nfs::nfs_fh3,
     offset: nfs::offset3,
     count: nfs::count3,
-    stable: u32,
+    stable: nfs::stable_how,
     data: Vec<u8>,
 }
 XDRStruct!(WRITE3args, file, offset, count, stable, data);
@@ -1141,7 +1606,7 @@ XDRStruct!(WRITE3args, file, offset, count, stable, data);
 struct WRITE3resok {
     file_wcc: nfs::wcc_data,
     count: nfs::count3,
-    committed: stable_how,
+    committed: nfs::stable_how,
     verf: nfs::writeverf3,
 }
 XDRStruct!(WRITE3resok, file_wcc, count, committed, verf);
@@ -1190,7 +1655,7 @@ pub async fn nfsproc3_write(
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::write", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
@@ -1198,8 +1663,8 @@ pub async fn nfsproc3_write(
     }
 
     let mut args = WRITE3args::default();
-    args.deserialize(input)?;
-    debug!("nfsproc3_write({:?},...) ", xid);
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::write", "nfsproc3_write({:?},...) ", xid);
     // sanity check the length
     if args.data.len() != args.count as usize {
         garbage_args_reply_message(xid).serialize(output)?;
@@ -1216,7 +1681,7 @@ pub async fn nfsproc3_write(
     let id = id.unwrap();
 
     // get the object attributes before the write
-    let pre_obj_attr = match context.vfs.getattr(id).await {
+    let pre_obj_attr = match context.getattr(id).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1228,16 +1693,121 @@ pub async fn nfsproc3_write(
         Err(_) => nfs::pre_op_attr::Void,
     };
 
-    match context.vfs.write(id, args.offset, &args.data).await {
-        Ok(fattr) => {
-            debug!("write success {:?} --> {:?}", xid, fattr);
+    // never forward a range which wraps around to the VFS
+    if args.offset.checked_add(args.count as u64).is_none() {
+        warn!(
+            target: "nfsserve::write",
+            "write {:?} range overflows. offset {} count {}",
+            xid,
+            args.offset,
+            args.count
+        );
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_INVAL.serialize(output)?;
+        nfs::wcc_data {
+            before: pre_obj_attr,
+            after: nfs::post_op_attr::Void,
+        }
+        .serialize(output)?;
+        return Ok(());
+    }
+
+    // Reject writes which would go past maxfilesize before touching the
+    // file, so that the file is never left partially extended.
+    let config = context.vfs.fsinfo_config();
+    let (maxfilesize, wtmax) = (config.maxfilesize, config.wtmax);
+    // the write is still accepted in full, but the client is not using
+    // the sizes we advertised
+    if args.count > wtmax {
+        warn!(
+            target: "nfsserve::write",
+            "write {:?} of {} bytes from {} exceeds wtmax {}",
+            xid,
+            args.count,
+            context.client_addr,
+            wtmax
+        );
+    }
+    // the bytes actually sent, whatever count the client declared
+    if args.offset.saturating_add(args.data.len() as u64) > maxfilesize {
+        warn!(
+            target: "nfsserve::write",
+            "write {:?} of {} bytes at offset {} exceeds maxfilesize {}",
+            xid,
+            args.data.len(),
+            args.offset,
+            maxfilesize
+        );
+        let post_obj_attr = match context.getattr(id).await {
+            Ok(v) => nfs::post_op_attr::attributes(v),
+            Err(_) => nfs::post_op_attr::Void,
+        };
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_FBIG.serialize(output)?;
+        nfs::wcc_data {
+            before: pre_obj_attr,
+            after: post_obj_attr,
+        }
+        .serialize(output)?;
+        return Ok(());
+    }
+
+    // the data came in the request record, which is counted already. This
+    // only defers the write while others hold the budget.
+    let _reservation = match context.memory_budget.try_reserve(0, context.record_len) {
+        Some(reservation) => reservation,
+        None => {
+            debug!(
+                target: "nfsserve::write",
+                "write {:?} of {} bytes deferred. {} bytes in flight",
+                xid,
+                args.count,
+                context.memory_budget.used()
+            );
+            make_success_reply(xid).serialize(output)?;
+            nfs::nfsstat3::NFS3ERR_JUKEBOX.serialize(output)?;
+            nfs::wcc_data {
+                before: pre_obj_attr,
+                after: nfs::post_op_attr::Void,
+            }
+            .serialize(output)?;
+            return Ok(());
+        }
+    };
+
+    // small stable writes are left to the file system to sync, when
+    // the listener trades durability for throughput
+    let relaxed = context.relaxed_durability > 0
+        && !matches!(args.stable, nfs::stable_how::UNSTABLE)
+        && args.count <= context.relaxed_durability;
+    let stable = if relaxed {
+        nfs::stable_how::UNSTABLE
+    } else {
+        args.stable
+    };
+    let res = context
+        .vfs
+        .write_ext(id, args.offset, &args.data, stable)
+        .await;
+    context.invalidate_attrs();
+    match res {
+        Ok(reply) => {
+            debug!(target: "nfsserve::write", "write success {:?} --> {:?}", xid, reply.attr);
+            // prefer the attributes the VFS saw right before the write
+            let before = reply
+                .before
+                .map_or(pre_obj_attr, nfs::pre_op_attr::attributes);
             let res = WRITE3resok {
                 file_wcc: nfs::wcc_data {
-                    before: pre_obj_attr,
-                    after: nfs::post_op_attr::attributes(fattr),
+                    before,
+                    after: nfs::post_op_attr::attributes(reply.attr),
                 },
                 count: args.count,
-                committed: stable_how::FILE_SYNC,
+                committed: if relaxed {
+                    args.stable
+                } else {
+                    reply.committed
+                },
                 verf: context.vfs.serverid(),
             };
             make_success_reply(xid).serialize(output)?;
@@ -1245,39 +1815,159 @@ pub async fn nfsproc3_write(
             res.serialize(output)?;
         }
         Err(stat) => {
-            error!("write error {:?} --> {:?}", xid, stat);
+            error!(target: "nfsserve::write", "write error {:?} --> {:?}", xid, stat);
+            let post_obj_attr = match context.getattr(id).await {
+                Ok(v) => nfs::post_op_attr::attributes(v),
+                Err(_) => nfs::post_op_attr::Void,
+            };
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
-            nfs::wcc_data::default().serialize(output)?;
+            nfs::wcc_data {
+                before: pre_obj_attr,
+                after: post_obj_attr,
+            }
+            .serialize(output)?;
         }
     }
     Ok(())
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
-#[repr(u32)]
-pub enum createmode3 {
-    #[default]
-    UNCHECKED = 0,
-    GUARDED = 1,
-    EXCLUSIVE = 2,
+#[derive(Debug, Default)]
+struct COMMIT3args {
+    file: nfs::nfs_fh3,
+    offset: nfs::offset3,
+    count: nfs::count3,
 }
-XDREnumSerde!(createmode3);
+XDRStruct!(COMMIT3args, file, offset, count);
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default)]
+struct COMMIT3resok {
+    file_wcc: nfs::wcc_data,
+    verf: nfs::writeverf3,
+}
+XDRStruct!(COMMIT3resok, file_wcc, verf);
 /*
-CREATE3res NFSPROC3_CREATE(CREATE3args) = 8;
+COMMIT3res NFSPROC3_COMMIT(COMMIT3args) = 21;
 
-      enum createmode3 {
-           UNCHECKED = 0,
-           GUARDED   = 1,
-           EXCLUSIVE = 2
-      };
+struct COMMIT3args {
+    nfs_fh3    file;
+    offset3    offset;
+    count3     count;
+};
 
-      union createhow3 switch (createmode3 mode) {
-      case UNCHECKED:
-      case GUARDED:
-           sattr3       obj_attributes;
-      case EXCLUSIVE:
+struct COMMIT3resok {
+    wcc_data   file_wcc;
+    writeverf3 verf;
+};
+
+struct COMMIT3resfail {
+    wcc_data   file_wcc;
+};
+
+union COMMIT3res switch (nfsstat3 status) {
+    case NFS3_OK:
+        COMMIT3resok   resok;
+    default:
+        COMMIT3resfail resfail;
+};
+
+ If count is 0, a flush from offset to the end of file is done.
+ */
+pub async fn nfsproc3_commit(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = COMMIT3args::default();
+    decode_args(&mut args, input)?;
+    debug!(
+        target: "nfsserve::write",
+        "nfsproc3_commit({:?},{:?},{:?}) ",
+        xid,
+        args.offset,
+        args.count
+    );
+
+    let id = context.vfs.fh_to_id(&args.file);
+    if let Err(stat) = id {
+        make_success_reply(xid).serialize(output)?;
+        stat.serialize(output)?;
+        nfs::wcc_data::default().serialize(output)?;
+        return Ok(());
+    }
+    let id = id.unwrap();
+
+    // get the object attributes before the commit
+    let pre_obj_attr = match context.getattr(id).await {
+        Ok(v) => {
+            let wccattr = nfs::wcc_attr {
+                size: v.size,
+                mtime: v.mtime,
+                ctime: v.ctime,
+            };
+            nfs::pre_op_attr::attributes(wccattr)
+        }
+        Err(_) => nfs::pre_op_attr::Void,
+    };
+
+    let res = context.vfs.commit(id, args.offset, args.count).await;
+    context.invalidate_attrs();
+    let post_obj_attr = match context.getattr(id).await {
+        Ok(v) => nfs::post_op_attr::attributes(v),
+        Err(_) => nfs::post_op_attr::Void,
+    };
+    let file_wcc = nfs::wcc_data {
+        before: pre_obj_attr,
+        after: post_obj_attr,
+    };
+    match res {
+        Ok(()) => {
+            debug!(target: "nfsserve::write", "commit success {:?}", xid);
+            let res = COMMIT3resok {
+                file_wcc,
+                verf: context.vfs.serverid(),
+            };
+            make_success_reply(xid).serialize(output)?;
+            nfs::nfsstat3::NFS3_OK.serialize(output)?;
+            res.serialize(output)?;
+        }
+        Err(stat) => {
+            error!(target: "nfsserve::write", "commit error {:?} --> {:?}", xid, stat);
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            file_wcc.serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum createmode3 {
+    #[default]
+    UNCHECKED = 0,
+    GUARDED = 1,
+    EXCLUSIVE = 2,
+}
+XDREnumSerde!(createmode3);
+/*
+CREATE3res NFSPROC3_CREATE(CREATE3args) = 8;
+
+      enum createmode3 {
+           UNCHECKED = 0,
+           GUARDED   = 1,
+           EXCLUSIVE = 2
+      };
+
+      union createhow3 switch (createmode3 mode) {
+      case UNCHECKED:
+      case GUARDED:
+           sattr3       obj_attributes;
+      case EXCLUSIVE:
            createverf3  verf;
       };
 
@@ -1312,7 +2002,7 @@ pub async fn nfsproc3_create(
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
@@ -1320,11 +2010,11 @@ pub async fn nfsproc3_create(
     }
 
     let mut dirops = nfs::diropargs3::default();
-    dirops.deserialize(input)?;
+    decode_args(&mut dirops, input)?;
     let mut createhow = createmode3::default();
-    createhow.deserialize(input)?;
+    decode_args(&mut createhow, input)?;
 
-    debug!("nfsproc3_create({:?}, {:?}, {:?}) ", xid, dirops, createhow);
+    debug!(target: "nfsserve::nfs", "nfsproc3_create({:?}, {:?}, {:?}) ", xid, dirops, createhow);
 
     // find the directory we are supposed to create the
     // new file in
@@ -1334,14 +2024,14 @@ pub async fn nfsproc3_create(
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
+        error!(target: "nfsserve::nfs", "Directory does not exist");
         return Ok(());
     }
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
     // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
+    let pre_dir_attr = match context.getattr(dirid).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1351,7 +2041,7 @@ pub async fn nfsproc3_create(
             nfs::pre_op_attr::attributes(wccattr)
         }
         Err(stat) => {
-            error!("Cannot stat directory");
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
@@ -1362,17 +2052,19 @@ pub async fn nfsproc3_create(
 
     match createhow {
         createmode3::UNCHECKED => {
-            target_attributes.deserialize(input)?;
-            debug!("create unchecked {:?}", target_attributes);
+            decode_args(&mut target_attributes, input)?;
+            context.squash_sattr3(&mut target_attributes);
+            debug!(target: "nfsserve::nfs", "create unchecked {:?}", target_attributes);
         }
         createmode3::GUARDED => {
-            target_attributes.deserialize(input)?;
-            debug!("create guarded {:?}", target_attributes);
+            decode_args(&mut target_attributes, input)?;
+            context.squash_sattr3(&mut target_attributes);
+            debug!(target: "nfsserve::nfs", "create guarded {:?}", target_attributes);
             if context.vfs.lookup(dirid, &dirops.name).await.is_ok() {
                 // file exists. Fail with NFS3ERR_EXIST.
                 // Re-read dir attributes
                 // for post op attr
-                let post_dir_attr = match context.vfs.getattr(dirid).await {
+                let post_dir_attr = match context.getattr(dirid).await {
                     Ok(v) => nfs::post_op_attr::attributes(v),
                     Err(_) => nfs::post_op_attr::Void,
                 };
@@ -1388,7 +2080,7 @@ pub async fn nfsproc3_create(
             }
         }
         createmode3::EXCLUSIVE => {
-            debug!("create exclusive");
+            debug!(target: "nfsserve::nfs", "create exclusive");
         }
     }
 
@@ -1398,14 +2090,31 @@ pub async fn nfsproc3_create(
     if matches!(createhow, createmode3::EXCLUSIVE) {
         // the API for exclusive is very slightly different
         // We are not returning a post op attribute
-        fid = context.vfs.create_exclusive(dirid, &dirops.name).await;
+        fid = check_name(
+            context,
+            &dirops.name,
+            check_dir_access(
+                context,
+                dirid,
+                ADD_ENTRY,
+                context.vfs.create_exclusive(dirid, &dirops.name),
+            ),
+        )
+        .await;
         postopattr = nfs::post_op_attr::Void;
     } else {
         // create!
-        let res = context
-            .vfs
-            .create(dirid, &dirops.name, target_attributes)
-            .await;
+        let res = check_name(
+            context,
+            &dirops.name,
+            check_dir_access(
+                context,
+                dirid,
+                ADD_ENTRY,
+                context.vfs.create(dirid, &dirops.name, target_attributes),
+            ),
+        )
+        .await;
         fid = res.map(|x| x.0);
         postopattr = if let Ok((_, fattr)) = res {
             nfs::post_op_attr::attributes(fattr)
@@ -1413,9 +2122,10 @@ pub async fn nfsproc3_create(
             nfs::post_op_attr::Void
         };
     }
+    context.invalidate_attrs();
 
     // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
+    let post_dir_attr = match context.getattr(dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
@@ -1426,7 +2136,7 @@ pub async fn nfsproc3_create(
 
     match fid {
         Ok(fid) => {
-            debug!("create success --> {:?}, {:?}", fid, postopattr);
+            debug!(target: "nfsserve::nfs", "create success --> {:?}, {:?}", fid, postopattr);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             // serialize CREATE3resok
@@ -1436,7 +2146,7 @@ pub async fn nfsproc3_create(
             wcc_res.serialize(output)?;
         }
         Err(e) => {
-            error!("create error --> {:?}", e);
+            error!(target: "nfsserve::nfs", "create error --> {:?}", e);
             // serialize CREATE3resfail
             make_success_reply(xid).serialize(output)?;
             e.serialize(output)?;
@@ -1504,28 +2214,30 @@ pub async fn nfsproc3_setattr(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
         return Ok(());
     }
     let mut args = SETATTR3args::default();
-    args.deserialize(input)?;
-    debug!("nfsproc3_setattr({:?},{:?}) ", xid, args);
+    decode_args(&mut args, input)?;
+    context.squash_sattr3(&mut args.new_attribute);
+    debug!(target: "nfsserve::nfs", "nfsproc3_setattr({:?},{:?}) ", xid, args);
 
     let id = context.vfs.fh_to_id(&args.object);
     // fail if unable to convert file handle
     if let Err(stat) = id {
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
+        nfs::wcc_data::default().serialize(output)?;
         return Ok(());
     }
     let id = id.unwrap();
 
     let ctime;
 
-    let pre_op_attr = match context.vfs.getattr(id).await {
+    let pre_op_attr = match context.getattr(id).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1547,16 +2259,29 @@ pub async fn nfsproc3_setattr(
         sattrguard3::Void => {}
         sattrguard3::obj_ctime(c) => {
             if c.seconds != ctime.seconds || c.nseconds != ctime.nseconds {
+                // nothing was changed, so the attributes before are the
+                // attributes after
+                let post_op_attr = match context.getattr(id).await {
+                    Ok(v) => nfs::post_op_attr::attributes(v),
+                    Err(_) => nfs::post_op_attr::Void,
+                };
                 make_success_reply(xid).serialize(output)?;
                 nfs::nfsstat3::NFS3ERR_NOT_SYNC.serialize(output)?;
-                nfs::wcc_data::default().serialize(output)?;
+                nfs::wcc_data {
+                    before: pre_op_attr,
+                    after: post_op_attr,
+                }
+                .serialize(output)?;
+                return Ok(());
             }
         }
     }
 
-    match context.vfs.setattr(id, args.new_attribute).await {
+    let res = context.vfs.setattr(id, args.new_attribute).await;
+    context.invalidate_attrs();
+    match res {
         Ok(post_op_attr) => {
-            debug!(" setattr success {:?} --> {:?}", xid, post_op_attr);
+            debug!(target: "nfsserve::nfs", " setattr success {:?} --> {:?}", xid, post_op_attr);
             let wcc_res = nfs::wcc_data {
                 before: pre_op_attr,
                 after: nfs::post_op_attr::attributes(post_op_attr),
@@ -1566,7 +2291,7 @@ pub async fn nfsproc3_setattr(
             wcc_res.serialize(output)?;
         }
         Err(stat) => {
-            error!("setattr error {:?} --> {:?}", xid, stat);
+            error!(target: "nfsserve::nfs", "setattr error {:?} --> {:?}", xid, stat);
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
@@ -1608,7 +2333,7 @@ pub async fn nfsproc3_remove(
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
@@ -1616,9 +2341,9 @@ pub async fn nfsproc3_remove(
     }
 
     let mut dirops = nfs::diropargs3::default();
-    dirops.deserialize(input)?;
+    decode_args(&mut dirops, input)?;
 
-    debug!("nfsproc3_remove({:?}, {:?}) ", xid, dirops);
+    debug!(target: "nfsserve::nfs", "nfsproc3_remove({:?}, {:?}) ", xid, dirops);
 
     // find the directory with the file
     let dirid = context.vfs.fh_to_id(&dirops.dir);
@@ -1627,13 +2352,13 @@ pub async fn nfsproc3_remove(
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
+        error!(target: "nfsserve::nfs", "Directory does not exist");
         return Ok(());
     }
     let dirid = dirid.unwrap();
 
     // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
+    let pre_dir_attr = match context.getattr(dirid).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1643,7 +2368,7 @@ pub async fn nfsproc3_remove(
             nfs::pre_op_attr::attributes(wccattr)
         }
         Err(stat) => {
-            error!("Cannot stat directory");
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
@@ -1652,10 +2377,21 @@ pub async fn nfsproc3_remove(
     };
 
     // delete!
-    let res = context.vfs.remove(dirid, &dirops.name).await;
+    let res = check_name(
+        context,
+        &dirops.name,
+        check_dir_access(
+            context,
+            dirid,
+            REMOVE_ENTRY,
+            context.vfs.remove(dirid, &dirops.name),
+        ),
+    )
+    .await;
+    context.invalidate_attrs();
 
     // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
+    let post_dir_attr = match context.getattr(dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
@@ -1666,13 +2402,14 @@ pub async fn nfsproc3_remove(
 
     match res {
         Ok(()) => {
-            debug!("remove success");
+            debug!(target: "nfsserve::nfs", "remove success");
+            context.note_remove(dirid, &dirops.name);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             wcc_res.serialize(output)?;
         }
         Err(e) => {
-            error!("remove error {:?} --> {:?}", xid, e);
+            error!(target: "nfsserve::nfs", "remove error {:?} --> {:?}", xid, e);
             // serialize CREATE3resfail
             make_success_reply(xid).serialize(output)?;
             e.serialize(output)?;
@@ -1717,19 +2454,22 @@ pub async fn nfsproc3_rename(
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
+        // RENAME3resfail has a fromdir_wcc and a todir_wcc
+        nfs::wcc_data::default().serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
         return Ok(());
     }
 
     let mut fromdirops = nfs::diropargs3::default();
     let mut todirops = nfs::diropargs3::default();
-    fromdirops.deserialize(input)?;
-    todirops.deserialize(input)?;
+    decode_args(&mut fromdirops, input)?;
+    decode_args(&mut todirops, input)?;
 
     debug!(
+        target: "nfsserve::nfs",
         "nfsproc3_rename({:?}, {:?}, {:?}) ",
         xid, fromdirops, todirops
     );
@@ -1741,7 +2481,8 @@ pub async fn nfsproc3_rename(
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
+        nfs::wcc_data::default().serialize(output)?;
+        error!(target: "nfsserve::nfs", "Directory does not exist");
         return Ok(());
     }
 
@@ -1752,7 +2493,8 @@ pub async fn nfsproc3_rename(
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
+        nfs::wcc_data::default().serialize(output)?;
+        error!(target: "nfsserve::nfs", "Directory does not exist");
         return Ok(());
     }
 
@@ -1761,7 +2503,7 @@ pub async fn nfsproc3_rename(
     let to_dirid = to_dirid.unwrap();
 
     // get the object attributes before the write
-    let pre_from_dir_attr = match context.vfs.getattr(from_dirid).await {
+    let pre_from_dir_attr = match context.getattr(from_dirid).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1771,16 +2513,17 @@ pub async fn nfsproc3_rename(
             nfs::pre_op_attr::attributes(wccattr)
         }
         Err(stat) => {
-            error!("Cannot stat directory");
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
             return Ok(());
         }
     };
 
     // get the object attributes before the write
-    let pre_to_dir_attr = match context.vfs.getattr(to_dirid).await {
+    let pre_to_dir_attr = match context.getattr(to_dirid).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1790,26 +2533,39 @@ pub async fn nfsproc3_rename(
             nfs::pre_op_attr::attributes(wccattr)
         }
         Err(stat) => {
-            error!("Cannot stat directory");
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
             return Ok(());
         }
     };
 
     // rename!
-    let res = context
+    let rename = context
         .vfs
-        .rename(from_dirid, &fromdirops.name, to_dirid, &todirops.name)
-        .await;
+        .rename(from_dirid, &fromdirops.name, to_dirid, &todirops.name);
+    let rename = check_dir_access(
+        context,
+        from_dirid,
+        REMOVE_ENTRY,
+        check_dir_access(context, to_dirid, ADD_ENTRY, rename),
+    );
+    let res = check_name(
+        context,
+        &fromdirops.name,
+        check_name(context, &todirops.name, rename),
+    )
+    .await;
+    context.invalidate_attrs();
 
     // Re-read dir attributes for post op attr
-    let post_from_dir_attr = match context.vfs.getattr(from_dirid).await {
+    let post_from_dir_attr = match context.getattr(from_dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
-    let post_to_dir_attr = match context.vfs.getattr(to_dirid).await {
+    let post_to_dir_attr = match context.getattr(to_dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
@@ -1825,14 +2581,15 @@ pub async fn nfsproc3_rename(
 
     match res {
         Ok(()) => {
-            debug!("rename success");
+            debug!(target: "nfsserve::nfs", "rename success");
+            context.note_rename(from_dirid, &fromdirops.name, to_dirid, &todirops.name);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             from_wcc_res.serialize(output)?;
             to_wcc_res.serialize(output)?;
         }
         Err(e) => {
-            error!("rename error {:?} --> {:?}", xid, e);
+            error!(target: "nfsserve::nfs", "rename error {:?} --> {:?}", xid, e);
             // serialize CREATE3resfail
             make_success_reply(xid).serialize(output)?;
             e.serialize(output)?;
@@ -1879,6 +2636,21 @@ struct MKDIR3args {
 }
 XDRStruct!(MKDIR3args, dirops, attributes);
 
+/// true if attr sets nothing
+fn is_empty_sattr3(attr: &nfs::sattr3) -> bool {
+    matches!(
+        attr,
+        nfs::sattr3 {
+            mode: nfs::set_mode3::Void,
+            uid: nfs::set_uid3::Void,
+            gid: nfs::set_gid3::Void,
+            size: nfs::set_size3::Void,
+            atime: nfs::set_atime::DONT_CHANGE,
+            mtime: nfs::set_mtime::DONT_CHANGE,
+        }
+    )
+}
+
 pub async fn nfsproc3_mkdir(
     xid: u32,
     input: &mut impl Read,
@@ -1887,16 +2659,17 @@ pub async fn nfsproc3_mkdir(
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
         return Ok(());
     }
     let mut args = MKDIR3args::default();
-    args.deserialize(input)?;
+    decode_args(&mut args, input)?;
+    context.squash_sattr3(&mut args.attributes);
 
-    debug!("nfsproc3_mkdir({:?}, {:?}) ", xid, args);
+    debug!(target: "nfsserve::nfs", "nfsproc3_mkdir({:?}, {:?}) ", xid, args);
 
     // find the directory we are supposed to create the
     // new file in
@@ -1906,14 +2679,14 @@ pub async fn nfsproc3_mkdir(
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
+        error!(target: "nfsserve::nfs", "Directory does not exist");
         return Ok(());
     }
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
     // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
+    let pre_dir_attr = match context.getattr(dirid).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -1923,7 +2696,7 @@ pub async fn nfsproc3_mkdir(
             nfs::pre_op_attr::attributes(wccattr)
         }
         Err(stat) => {
-            error!("Cannot stat directory");
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
@@ -1931,10 +2704,36 @@ pub async fn nfsproc3_mkdir(
         }
     };
 
-    let res = context.vfs.mkdir(dirid, &args.dirops.name).await;
+    let res = check_name(
+        context,
+        &args.dirops.name,
+        check_dir_access(
+            context,
+            dirid,
+            ADD_ENTRY,
+            context.vfs.mkdir(dirid, &args.dirops.name),
+        ),
+    )
+    .await;
+    // vfs.mkdir takes no attributes, so those asked for are set after.
+    // The directory exists by then, so failing to set them does not fail
+    // the MKDIR: a retry would only get NFS3ERR_EXIST.
+    let res = match res {
+        Ok((fid, fattr)) if !is_empty_sattr3(&args.attributes) => {
+            match context.vfs.setattr(fid, args.attributes).await {
+                Ok(fattr) => Ok((fid, fattr)),
+                Err(e) => {
+                    warn!(target: "nfsserve::nfs", "mkdir setattr {:?} failed: {:?}", fid, e);
+                    Ok((fid, fattr))
+                }
+            }
+        }
+        res => res,
+    };
+    context.invalidate_attrs();
 
     // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
+    let post_dir_attr = match context.getattr(dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
@@ -1945,7 +2744,7 @@ pub async fn nfsproc3_mkdir(
 
     match res {
         Ok((fid, fattr)) => {
-            debug!("mkdir success --> {:?}, {:?}", fid, fattr);
+            debug!(target: "nfsserve::nfs", "mkdir success --> {:?}, {:?}", fid, fattr);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             // serialize CREATE3resok
@@ -1955,7 +2754,7 @@ pub async fn nfsproc3_mkdir(
             wcc_res.serialize(output)?;
         }
         Err(e) => {
-            debug!("mkdir error {:?} --> {:?}", xid, e);
+            debug!(target: "nfsserve::nfs", "mkdir error {:?} --> {:?}", xid, e);
             // serialize CREATE3resfail
             make_success_reply(xid).serialize(output)?;
             e.serialize(output)?;
@@ -2013,16 +2812,17 @@ pub async fn nfsproc3_symlink(
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        warn!("No write capabilities.");
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
         make_success_reply(xid).serialize(output)?;
         nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
         return Ok(());
     }
     let mut args = SYMLINK3args::default();
-    args.deserialize(input)?;
+    decode_args(&mut args, input)?;
+    context.squash_sattr3(&mut args.symlink.symlink_attributes);
 
-    debug!("nfsproc3_symlink({:?}, {:?}) ", xid, args);
+    debug!(target: "nfsserve::nfs", "nfsproc3_symlink({:?}, {:?}) ", xid, args);
 
     // find the directory we are supposed to create the
     // new file in
@@ -2032,14 +2832,14 @@ pub async fn nfsproc3_symlink(
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
+        error!(target: "nfsserve::nfs", "Directory does not exist");
         return Ok(());
     }
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
     // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
+    let pre_dir_attr = match context.getattr(dirid).await {
         Ok(v) => {
             let wccattr = nfs::wcc_attr {
                 size: v.size,
@@ -2049,7 +2849,7 @@ pub async fn nfsproc3_symlink(
             nfs::pre_op_attr::attributes(wccattr)
         }
         Err(stat) => {
-            error!("Cannot stat directory");
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
@@ -2057,18 +2857,18 @@ pub async fn nfsproc3_symlink(
         }
     };
 
-    let res = context
-        .vfs
-        .symlink(
-            dirid,
-            &args.dirops.name,
-            &args.symlink.symlink_data,
-            &args.symlink.symlink_attributes,
-        )
-        .await;
+    let symlink = context.vfs.symlink(
+        dirid,
+        &args.dirops.name,
+        &args.symlink.symlink_data,
+        &args.symlink.symlink_attributes,
+    );
+    let symlink = check_dir_access(context, dirid, ADD_ENTRY, symlink);
+    let res = check_name(context, &args.dirops.name, symlink).await;
+    context.invalidate_attrs();
 
     // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
+    let post_dir_attr = match context.getattr(dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
@@ -2079,7 +2879,7 @@ pub async fn nfsproc3_symlink(
 
     match res {
         Ok((fid, fattr)) => {
-            debug!("symlink success --> {:?}, {:?}", fid, fattr);
+            debug!(target: "nfsserve::nfs", "symlink success --> {:?}, {:?}", fid, fattr);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             // serialize CREATE3resok
@@ -2089,7 +2889,7 @@ pub async fn nfsproc3_symlink(
             wcc_res.serialize(output)?;
         }
         Err(e) => {
-            debug!("symlink error --> {:?}", e);
+            debug!(target: "nfsserve::nfs", "symlink error --> {:?}", e);
             // serialize CREATE3resfail
             make_success_reply(xid).serialize(output)?;
             e.serialize(output)?;
@@ -2131,19 +2931,20 @@ pub async fn nfsproc3_readlink(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut handle = nfs::nfs_fh3::default();
-    handle.deserialize(input)?;
-    debug!("nfsproc3_readlink({:?},{:?}) ", xid, handle);
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::nfs", "nfsproc3_readlink({:?},{:?}) ", xid, handle);
 
     let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
     if let Err(stat) = id {
         make_success_reply(xid).serialize(output)?;
         stat.serialize(output)?;
+        nfs::post_op_attr::Void.serialize(output)?;
         return Ok(());
     }
     let id = id.unwrap();
     // if the id does not exist, we fail
-    let symlink_attr = match context.vfs.getattr(id).await {
+    let symlink_attr = match context.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(stat) => {
             make_success_reply(xid).serialize(output)?;
@@ -2153,8 +2954,16 @@ pub async fn nfsproc3_readlink(
         }
     };
     match context.vfs.readlink(id).await {
-        Ok(path) => {
-            debug!(" {:?} --> {:?}", xid, path);
+        Ok(mut path) => {
+            if let Some(ref rewriter) = context.symlink_rewriter {
+                if let Some(link_dir) = context.vfs.parent_dir(id).await {
+                    if let Some(target) = rewriter.rewrite_symlink_target(&path, link_dir) {
+                        debug!(target: "nfsserve::nfs", " {:?} rewritten from {:?}", xid, path);
+                        path = target.into();
+                    }
+                }
+            }
+            debug!(target: "nfsserve::nfs", " {:?} --> {:?}", xid, path);
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             symlink_attr.serialize(output)?;
@@ -2170,3 +2979,308 @@ pub async fn nfsproc3_readlink(
     }
     Ok(())
 }
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default)]
+struct LINK3args {
+    file: nfs::nfs_fh3,
+    link: nfs::diropargs3,
+}
+XDRStruct!(LINK3args, file, link);
+
+/*
+      LINK3res NFSPROC3_LINK(LINK3args) = 15;
+
+      struct LINK3args {
+           nfs_fh3     file;
+           diropargs3  link;
+      };
+
+      struct LINK3resok {
+           post_op_attr   file_attributes;
+           wcc_data       linkdir_wcc;
+      };
+
+      struct LINK3resfail {
+           post_op_attr   file_attributes;
+           wcc_data       linkdir_wcc;
+      };
+
+      union LINK3res switch (nfsstat3 status) {
+      case NFS3_OK:
+           LINK3resok    resok;
+      default:
+           LINK3resfail  resfail;
+      };
+*/
+pub async fn nfsproc3_link(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    // if we do not have write capabilities
+    if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
+        nfs::post_op_attr::Void.serialize(output)?;
+        nfs::wcc_data::default().serialize(output)?;
+        return Ok(());
+    }
+
+    let mut args = LINK3args::default();
+    decode_args(&mut args, input)?;
+
+    debug!(target: "nfsserve::nfs", "nfsproc3_link({:?}, {:?}) ", xid, args);
+
+    // find the file and the directory to link into
+    let id = match context.vfs.fh_to_id(&args.file) {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            return Ok(());
+        }
+    };
+    let dirid = match context.vfs.fh_to_id(&args.link.dir) {
+        Ok(dirid) => dirid,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            error!(target: "nfsserve::nfs", "Directory does not exist");
+            return Ok(());
+        }
+    };
+
+    // get the directory attributes before the link
+    let pre_dir_attr = match context.getattr(dirid).await {
+        Ok(v) => {
+            let wccattr = nfs::wcc_attr {
+                size: v.size,
+                mtime: v.mtime,
+                ctime: v.ctime,
+            };
+            nfs::pre_op_attr::attributes(wccattr)
+        }
+        Err(stat) => {
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            return Ok(());
+        }
+    };
+
+    // link!
+    let res = check_name(
+        context,
+        &args.link.name,
+        check_dir_access(
+            context,
+            dirid,
+            ADD_ENTRY,
+            context.vfs.link(id, dirid, &args.link.name),
+        ),
+    )
+    .await;
+    context.invalidate_attrs();
+
+    // Re-read the file (its nlink changed) and the directory attributes
+    let file_attr = match context.getattr(id).await {
+        Ok(v) => nfs::post_op_attr::attributes(v),
+        Err(_) => nfs::post_op_attr::Void,
+    };
+    let post_dir_attr = match context.getattr(dirid).await {
+        Ok(v) => nfs::post_op_attr::attributes(v),
+        Err(_) => nfs::post_op_attr::Void,
+    };
+    let wcc_res = nfs::wcc_data {
+        before: pre_dir_attr,
+        after: post_dir_attr,
+    };
+
+    match res {
+        Ok(()) => {
+            debug!(target: "nfsserve::nfs", "link success");
+            make_success_reply(xid).serialize(output)?;
+            nfs::nfsstat3::NFS3_OK.serialize(output)?;
+        }
+        Err(e) => {
+            debug!(target: "nfsserve::nfs", "link error {:?} --> {:?}", xid, e);
+            make_success_reply(xid).serialize(output)?;
+            e.serialize(output)?;
+        }
+    }
+    file_attr.serialize(output)?;
+    wcc_res.serialize(output)?;
+
+    Ok(())
+}
+
+/*
+      MKNOD3res NFSPROC3_MKNOD(MKNOD3args) = 11;
+
+      struct devicedata3 {
+           sattr3     dev_attributes;
+           specdata3  spec;
+      };
+
+      union mknoddata3 switch (ftype3 type) {
+      case NF3CHR:
+      case NF3BLK:
+           devicedata3  device;
+      case NF3SOCK:
+      case NF3FIFO:
+           sattr3       pipe_attributes;
+      default:
+           void;
+      };
+
+      struct MKNOD3args {
+           diropargs3   where;
+           mknoddata3   what;
+      };
+
+      struct MKNOD3resok {
+           post_op_fh3   obj;
+           post_op_attr  obj_attributes;
+           wcc_data      dir_wcc;
+      };
+
+      struct MKNOD3resfail {
+           wcc_data      dir_wcc;
+      };
+
+      union MKNOD3res switch (nfsstat3 status) {
+      case NFS3_OK:
+           MKNOD3resok   resok;
+      default:
+           MKNOD3resfail resfail;
+      };
+*/
+pub async fn nfsproc3_mknod(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut dirops = nfs::diropargs3::default();
+    decode_args(&mut dirops, input)?;
+    let mut ftype = nfs::ftype3::default();
+    decode_args(&mut ftype, input)?;
+    // the mknoddata3 union
+    let mut device = nfs::devicedata3::default();
+    match ftype {
+        nfs::ftype3::NF3CHR | nfs::ftype3::NF3BLK => decode_args(&mut device, input)?,
+        nfs::ftype3::NF3SOCK | nfs::ftype3::NF3FIFO => {
+            decode_args(&mut device.dev_attributes, input)?
+        }
+        _ => {}
+    }
+    context.squash_sattr3(&mut device.dev_attributes);
+
+    debug!(
+        target: "nfsserve::nfs",
+        "nfsproc3_mknod({:?}, {:?}, {:?}, {:?}) ",
+        xid, dirops, ftype, device
+    );
+
+    // if we do not have write capabilities
+    if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
+        warn!(target: "nfsserve::nfs", "No write capabilities.");
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
+        nfs::wcc_data::default().serialize(output)?;
+        return Ok(());
+    }
+
+    // regular files, directories and symlinks have procedures of their own
+    if !matches!(
+        ftype,
+        nfs::ftype3::NF3CHR | nfs::ftype3::NF3BLK | nfs::ftype3::NF3SOCK | nfs::ftype3::NF3FIFO
+    ) {
+        make_success_reply(xid).serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_BADTYPE.serialize(output)?;
+        nfs::wcc_data::default().serialize(output)?;
+        return Ok(());
+    }
+
+    // find the directory we are supposed to create the
+    // new node in
+    let dirid = match context.vfs.fh_to_id(&dirops.dir) {
+        Ok(dirid) => dirid,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            error!(target: "nfsserve::nfs", "Directory does not exist");
+            return Ok(());
+        }
+    };
+
+    // get the object attributes before the write
+    let pre_dir_attr = match context.getattr(dirid).await {
+        Ok(v) => {
+            let wccattr = nfs::wcc_attr {
+                size: v.size,
+                mtime: v.mtime,
+                ctime: v.ctime,
+            };
+            nfs::pre_op_attr::attributes(wccattr)
+        }
+        Err(stat) => {
+            error!(target: "nfsserve::nfs", "Cannot stat directory");
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            return Ok(());
+        }
+    };
+
+    let mknod = context.vfs.mknod(
+        dirid,
+        &dirops.name,
+        ftype,
+        &device.dev_attributes,
+        device.spec,
+    );
+    let mknod = check_dir_access(context, dirid, ADD_ENTRY, mknod);
+    let res = check_name(context, &dirops.name, mknod).await;
+    context.invalidate_attrs();
+
+    // Re-read dir attributes for post op attr
+    let post_dir_attr = match context.getattr(dirid).await {
+        Ok(v) => nfs::post_op_attr::attributes(v),
+        Err(_) => nfs::post_op_attr::Void,
+    };
+    let wcc_res = nfs::wcc_data {
+        before: pre_dir_attr,
+        after: post_dir_attr,
+    };
+
+    match res {
+        Ok((fid, fattr)) => {
+            debug!(target: "nfsserve::nfs", "mknod success --> {:?}, {:?}", fid, fattr);
+            make_success_reply(xid).serialize(output)?;
+            nfs::nfsstat3::NFS3_OK.serialize(output)?;
+            let fh = context.vfs.id_to_fh(fid);
+            nfs::post_op_fh3::handle(fh).serialize(output)?;
+            nfs::post_op_attr::attributes(fattr).serialize(output)?;
+            wcc_res.serialize(output)?;
+        }
+        Err(e) => {
+            debug!(target: "nfsserve::nfs", "mknod error --> {:?}", e);
+            make_success_reply(xid).serialize(output)?;
+            e.serialize(output)?;
+            wcc_res.serialize(output)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+// this is just a complete enumeration of everything in the protocol
+#![allow(dead_code)]
+// And its nice to keep the original names and case
+#![allow(non_camel_case_types)]
+
+// The NFSACL sideband protocol is not described by any RFC.
+// It is the protocol used by Solaris and Linux to carry POSIX ACLs
+// alongside NFSv3. See for instance the Linux kernel fs/nfs/nfs3acl.c
+pub const PROGRAM: u32 = 100227;
+pub const VERSION: u32 = 3;
+
+/// Bits of the mask in GETACL/SETACL selecting what is requested
+pub const NFS_ACL: u32 = 0x0001;
+pub const NFS_ACLCNT: u32 = 0x0002;
+pub const NFS_DFACL: u32 = 0x0004;
+pub const NFS_DFACLCNT: u32 = 0x0008;
@@ -0,0 +1,141 @@
+use crate::context::RPCContext;
+use crate::nfs;
+use crate::rpc::*;
+use crate::support::{ProcedureSupport, Support};
+use crate::xdr::*;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+use tracing::debug;
+
+/*
+ program NFS_ACL_PROGRAM {
+    version NFS_ACL_V3 {
+       void         ACLPROC3_NULL(void)        = 0;
+       GETACL3res   ACLPROC3_GETACL(GETACL3args) = 1;
+       SETACL3res   ACLPROC3_SETACL(SETACL3args) = 2;
+    } = 3;
+ } = 100227;
+
+ struct GETACL3args {
+      nfs_fh3      fh;
+      u_int        mask;
+ };
+
+ struct SETACL3args {
+      nfs_fh3      fh;
+      secattr      acl;
+ };
+
+ struct GETACL3resfail {
+      post_op_attr attr;
+ };
+
+ struct SETACL3resfail {
+      post_op_attr attr;
+ };
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum NFSACLProgram {
+    ACLPROC3_NULL = 0,
+    ACLPROC3_GETACL = 1,
+    ACLPROC3_SETACL = 2,
+    INVALID,
+}
+
+pub async fn handle_nfsacl(
+    xid: u32,
+    call: call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let prog = NFSACLProgram::from_u32(call.proc).unwrap_or(NFSACLProgram::INVALID);
+
+    match prog {
+        NFSACLProgram::ACLPROC3_NULL => aclproc3_null(xid, input, output)?,
+        NFSACLProgram::ACLPROC3_GETACL | NFSACLProgram::ACLPROC3_SETACL => {
+            aclproc3_notsupp(xid, prog, input, output, context).await?
+        }
+        NFSACLProgram::INVALID => {
+            proc_unavail_reply_message(xid).serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+/// How completely each procedure is supported. See support::matrix.
+/// Like the match in handle_nfsacl this has no wildcard arm.
+fn support(prog: NFSACLProgram) -> (Support, &'static str) {
+    match prog {
+        NFSACLProgram::ACLPROC3_NULL => (Support::Full, ""),
+        NFSACLProgram::ACLPROC3_GETACL | NFSACLProgram::ACLPROC3_SETACL => (
+            Support::Partial,
+            "Always NFS3ERR_NOTSUPP: ACLs are not supported",
+        ),
+        NFSACLProgram::INVALID => (Support::Unsupported, ""),
+    }
+}
+
+/// The support of the NFSACL procedures. See support::matrix
+pub fn support_matrix() -> Vec<ProcedureSupport> {
+    (0..)
+        .map_while(NFSACLProgram::from_u32)
+        .take_while(|prog| !matches!(prog, NFSACLProgram::INVALID))
+        .map(|prog| {
+            let (support, notes) = support(prog);
+            ProcedureSupport {
+                program: "NFSACLv3",
+                number: prog as u32,
+                procedure: format!("{:?}", prog),
+                support,
+                notes,
+            }
+        })
+        .collect()
+}
+
+pub fn aclproc3_null(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    debug!(target: "nfsserve::nfsacl", "aclproc3_null({:?}) ", xid);
+    // build an RPC reply
+    let msg = make_success_reply(xid);
+    debug!(target: "nfsserve::nfsacl", "\t{:?} --> {:?}", xid, msg);
+    msg.serialize(output)?;
+    Ok(())
+}
+
+/// ACLs are not supported by the VFS. Reply NOTSUPP to GETACL / SETACL with
+/// the attributes of the object (the failure replies of both procedures are
+/// identical), so that clients cleanly disable ACL support for the mount.
+async fn aclproc3_notsupp(
+    xid: u32,
+    prog: NFSACLProgram,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    // Both GETACL3args and SETACL3args start with the file handle.
+    // We do not need the rest of the arguments.
+    let mut handle = nfs::nfs_fh3::default();
+    decode_args(&mut handle, input)?;
+    debug!(target: "nfsserve::nfsacl", "aclproc3({:?},{:?},{:?}) ", xid, prog, handle);
+
+    let obj_attr = match context.vfs.fh_to_id(&handle) {
+        Ok(id) => match context.vfs.getattr(id).await {
+            Ok(v) => nfs::post_op_attr::attributes(v),
+            Err(_) => nfs::post_op_attr::Void,
+        },
+        Err(_) => nfs::post_op_attr::Void,
+    };
+    make_success_reply(xid).serialize(output)?;
+    nfs::nfsstat3::NFS3ERR_NOTSUPP.serialize(output)?;
+    obj_attr.serialize(output)?;
+    Ok(())
+}
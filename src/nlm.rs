@@ -0,0 +1,174 @@
+// this is just a complete enumeration of everything in the RFC
+#![allow(dead_code)]
+// And its nice to keep the original RFC names and case
+#![allow(non_camel_case_types)]
+
+use crate::xdr::*;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+// Transcribed from RFC 1813 Section 6 (NLM Version 4)
+
+pub const PROGRAM: u32 = 100021;
+pub const VERSION: u32 = 4;
+
+pub const LM_MAXSTRLEN: u32 = 1024;
+pub const MAXNAMELEN: u32 = LM_MAXSTRLEN + 1;
+
+pub type netobj = Vec<u8>;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum nlm4_stats {
+    #[default]
+    NLM4_GRANTED = 0,
+    NLM4_DENIED = 1,
+    NLM4_DENIED_NOLOCKS = 2,
+    NLM4_BLOCKED = 3,
+    NLM4_DENIED_GRACE_PERIOD = 4,
+    NLM4_DEADLCK = 5,
+    NLM4_ROFS = 6,
+    NLM4_STALE_FH = 7,
+    NLM4_FBIG = 8,
+    NLM4_FAILED = 9,
+}
+XDREnumSerde!(nlm4_stats);
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum fsh4_mode {
+    #[default]
+    fsm_DN = 0, /* deny none */
+    fsm_DR = 1,  /* deny read */
+    fsm_DW = 2,  /* deny write */
+    fsm_DRW = 3, /* deny read/write */
+}
+XDREnumSerde!(fsh4_mode);
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum fsh4_access {
+    #[default]
+    fsa_NONE = 0, /* for completeness */
+    fsa_R = 1,  /* read-only */
+    fsa_W = 2,  /* write-only */
+    fsa_RW = 3, /* read/write */
+}
+XDREnumSerde!(fsh4_access);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_holder {
+    pub exclusive: bool,
+    pub svid: i32,
+    pub oh: netobj,
+    pub l_offset: u64,
+    pub l_len: u64,
+}
+XDRStruct!(nlm4_holder, exclusive, svid, oh, l_offset, l_len);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_lock {
+    pub caller_name: Vec<u8>,
+    pub fh: netobj,
+    pub oh: netobj,
+    pub svid: i32,
+    pub l_offset: u64,
+    pub l_len: u64,
+}
+XDRStruct!(nlm4_lock, caller_name, fh, oh, svid, l_offset, l_len);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_share {
+    pub caller_name: Vec<u8>,
+    pub fh: netobj,
+    pub oh: netobj,
+    pub mode: fsh4_mode,
+    pub access: fsh4_access,
+}
+XDRStruct!(nlm4_share, caller_name, fh, oh, mode, access);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_testargs {
+    pub cookie: netobj,
+    pub exclusive: bool,
+    pub alock: nlm4_lock,
+}
+XDRStruct!(nlm4_testargs, cookie, exclusive, alock);
+
+/// The test_stat of nlm4_testres when it is not NLM4_DENIED (the only
+/// case carrying a holder, which this server never replies)
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_testres {
+    pub cookie: netobj,
+    pub stat: nlm4_stats,
+}
+XDRStruct!(nlm4_testres, cookie, stat);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_lockargs {
+    pub cookie: netobj,
+    pub block: bool,
+    pub exclusive: bool,
+    pub alock: nlm4_lock,
+    pub reclaim: bool,
+    pub state: i32,
+}
+XDRStruct!(
+    nlm4_lockargs,
+    cookie,
+    block,
+    exclusive,
+    alock,
+    reclaim,
+    state
+);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_res {
+    pub cookie: netobj,
+    pub stat: nlm4_stats,
+}
+XDRStruct!(nlm4_res, cookie, stat);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_cancargs {
+    pub cookie: netobj,
+    pub block: bool,
+    pub exclusive: bool,
+    pub alock: nlm4_lock,
+}
+XDRStruct!(nlm4_cancargs, cookie, block, exclusive, alock);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_unlockargs {
+    pub cookie: netobj,
+    pub alock: nlm4_lock,
+}
+XDRStruct!(nlm4_unlockargs, cookie, alock);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_shareargs {
+    pub cookie: netobj,
+    pub share: nlm4_share,
+    pub reclaim: bool,
+}
+XDRStruct!(nlm4_shareargs, cookie, share, reclaim);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_shareres {
+    pub cookie: netobj,
+    pub stat: nlm4_stats,
+    pub sequence: i32,
+}
+XDRStruct!(nlm4_shareres, cookie, stat, sequence);
+
+#[derive(Clone, Debug, Default)]
+pub struct nlm4_notify {
+    pub name: Vec<u8>,
+    pub state: i32,
+}
+XDRStruct!(nlm4_notify, name, state);
@@ -0,0 +1,275 @@
+use crate::context::RPCContext;
+use crate::nlm::*;
+use crate::rpc::*;
+use crate::support::{ProcedureSupport, Support};
+use crate::xdr::*;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+use tracing::debug;
+
+/*
+ From RFC 1813 Section 6.2
+
+ program NLM_PROG {
+    version NLM4_VERS {
+       void         NLMPROC4_NULL(void)                  = 0;
+       nlm4_testres NLMPROC4_TEST(nlm4_testargs)         = 1;
+       nlm4_res     NLMPROC4_LOCK(nlm4_lockargs)         = 2;
+       nlm4_res     NLMPROC4_CANCEL(nlm4_cancargs)       = 3;
+       nlm4_res     NLMPROC4_UNLOCK(nlm4_unlockargs)     = 4;
+       nlm4_res     NLMPROC4_GRANTED(nlm4_testargs)      = 5;
+       void         NLMPROC4_TEST_MSG(nlm4_testargs)     = 6;
+       void         NLMPROC4_LOCK_MSG(nlm4_lockargs)     = 7;
+       void         NLMPROC4_CANCEL_MSG(nlm4_cancargs)   = 8;
+       void         NLMPROC4_UNLOCK_MSG(nlm4_unlockargs) = 9;
+       void         NLMPROC4_GRANTED_MSG(nlm4_testargs)  = 10;
+       void         NLMPROC4_TEST_RES(nlm4_testres)      = 11;
+       void         NLMPROC4_LOCK_RES(nlm4_res)          = 12;
+       void         NLMPROC4_CANCEL_RES(nlm4_res)        = 13;
+       void         NLMPROC4_UNLOCK_RES(nlm4_res)        = 14;
+       void         NLMPROC4_GRANTED_RES(nlm4_res)       = 15;
+       nlm4_shareres NLMPROC4_SHARE(nlm4_shareargs)      = 20;
+       nlm4_shareres NLMPROC4_UNSHARE(nlm4_shareargs)    = 21;
+       nlm4_res     NLMPROC4_NM_LOCK(nlm4_lockargs)      = 22;
+       void         NLMPROC4_FREE_ALL(nlm4_notify)       = 23;
+    } = 4;
+ } = 100021;
+
+ Locking is not supported: every request for a lock or share is denied
+ with NLM4_DENIED_NOLOCKS, which clients report as ENOLCK instead of
+ waiting on a lock manager which never answers. Callbacks (GRANTED, and
+ the replies to the asynchronous _MSG procedures) are never issued, so
+ those procedures are PROC_UNAVAIL.
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum NLMProgram {
+    NLMPROC4_NULL = 0,
+    NLMPROC4_TEST = 1,
+    NLMPROC4_LOCK = 2,
+    NLMPROC4_CANCEL = 3,
+    NLMPROC4_UNLOCK = 4,
+    NLMPROC4_GRANTED = 5,
+    NLMPROC4_TEST_MSG = 6,
+    NLMPROC4_LOCK_MSG = 7,
+    NLMPROC4_CANCEL_MSG = 8,
+    NLMPROC4_UNLOCK_MSG = 9,
+    NLMPROC4_GRANTED_MSG = 10,
+    NLMPROC4_TEST_RES = 11,
+    NLMPROC4_LOCK_RES = 12,
+    NLMPROC4_CANCEL_RES = 13,
+    NLMPROC4_UNLOCK_RES = 14,
+    NLMPROC4_GRANTED_RES = 15,
+    NLMPROC4_SHARE = 20,
+    NLMPROC4_UNSHARE = 21,
+    NLMPROC4_NM_LOCK = 22,
+    NLMPROC4_FREE_ALL = 23,
+    INVALID,
+}
+
+pub fn handle_nlm(
+    xid: u32,
+    call: call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let prog = NLMProgram::from_u32(call.proc).unwrap_or(NLMProgram::INVALID);
+
+    match prog {
+        NLMProgram::NLMPROC4_NULL => nlmproc4_null(xid, input, output)?,
+        NLMProgram::NLMPROC4_TEST => nlmproc4_test(xid, input, output, context)?,
+        NLMProgram::NLMPROC4_LOCK | NLMProgram::NLMPROC4_NM_LOCK => {
+            nlmproc4_lock(xid, input, output, context)?
+        }
+        NLMProgram::NLMPROC4_CANCEL => nlmproc4_cancel(xid, input, output, context)?,
+        NLMProgram::NLMPROC4_UNLOCK => nlmproc4_unlock(xid, input, output, context)?,
+        NLMProgram::NLMPROC4_SHARE | NLMProgram::NLMPROC4_UNSHARE => {
+            nlmproc4_share(xid, input, output, context)?
+        }
+        NLMProgram::NLMPROC4_FREE_ALL => nlmproc4_free_all(xid, input, output)?,
+        NLMProgram::NLMPROC4_GRANTED
+        | NLMProgram::NLMPROC4_TEST_MSG
+        | NLMProgram::NLMPROC4_LOCK_MSG
+        | NLMProgram::NLMPROC4_CANCEL_MSG
+        | NLMProgram::NLMPROC4_UNLOCK_MSG
+        | NLMProgram::NLMPROC4_GRANTED_MSG
+        | NLMProgram::NLMPROC4_TEST_RES
+        | NLMProgram::NLMPROC4_LOCK_RES
+        | NLMProgram::NLMPROC4_CANCEL_RES
+        | NLMProgram::NLMPROC4_UNLOCK_RES
+        | NLMProgram::NLMPROC4_GRANTED_RES
+        | NLMProgram::INVALID => {
+            proc_unavail_reply_message(xid).serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+/// How completely each procedure is supported. See support::matrix.
+/// Like the match in handle_nlm this has no wildcard arm.
+fn support(prog: NLMProgram) -> (Support, &'static str) {
+    match prog {
+        NLMProgram::NLMPROC4_NULL | NLMProgram::NLMPROC4_FREE_ALL => (Support::Full, ""),
+        NLMProgram::NLMPROC4_TEST
+        | NLMProgram::NLMPROC4_LOCK
+        | NLMProgram::NLMPROC4_CANCEL
+        | NLMProgram::NLMPROC4_UNLOCK
+        | NLMProgram::NLMPROC4_SHARE
+        | NLMProgram::NLMPROC4_UNSHARE
+        | NLMProgram::NLMPROC4_NM_LOCK => (
+            Support::Partial,
+            "Always NLM4_DENIED_NOLOCKS: locking is not supported",
+        ),
+        NLMProgram::NLMPROC4_GRANTED
+        | NLMProgram::NLMPROC4_TEST_MSG
+        | NLMProgram::NLMPROC4_LOCK_MSG
+        | NLMProgram::NLMPROC4_CANCEL_MSG
+        | NLMProgram::NLMPROC4_UNLOCK_MSG
+        | NLMProgram::NLMPROC4_GRANTED_MSG
+        | NLMProgram::NLMPROC4_TEST_RES
+        | NLMProgram::NLMPROC4_LOCK_RES
+        | NLMProgram::NLMPROC4_CANCEL_RES
+        | NLMProgram::NLMPROC4_UNLOCK_RES
+        | NLMProgram::NLMPROC4_GRANTED_RES
+        | NLMProgram::INVALID => (Support::Unsupported, ""),
+    }
+}
+
+/// The support of the NLM procedures. See support::matrix
+pub fn support_matrix() -> Vec<ProcedureSupport> {
+    // the procedure numbers have a gap (16 to 19)
+    (0..NLMProgram::INVALID as u32)
+        .filter_map(NLMProgram::from_u32)
+        .map(|prog| {
+            let (support, notes) = support(prog);
+            ProcedureSupport {
+                program: "NLMv4",
+                number: prog as u32,
+                procedure: format!("{:?}", prog),
+                support,
+                notes,
+            }
+        })
+        .collect()
+}
+
+pub fn nlmproc4_null(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    debug!(target: "nfsserve::nlm", "nlmproc4_null({:?}) ", xid);
+    // build an RPC reply
+    let msg = make_success_reply(xid);
+    debug!(target: "nfsserve::nlm", "\t{:?} --> {:?}", xid, msg);
+    msg.serialize(output)?;
+    Ok(())
+}
+
+pub fn nlmproc4_test(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_testargs::default();
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::nlm", "nlmproc4_test({:?}, {:?}, {:?}) ", xid, context.client_addr, args);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_testres {
+        cookie: args.cookie,
+        stat: nlm4_stats::NLM4_DENIED_NOLOCKS,
+    }
+    .serialize(output)?;
+    Ok(())
+}
+
+pub fn nlmproc4_lock(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_lockargs::default();
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::nlm", "nlmproc4_lock({:?}, {:?}, {:?}) ", xid, context.client_addr, args);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_res {
+        cookie: args.cookie,
+        stat: nlm4_stats::NLM4_DENIED_NOLOCKS,
+    }
+    .serialize(output)?;
+    Ok(())
+}
+
+pub fn nlmproc4_cancel(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_cancargs::default();
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::nlm", "nlmproc4_cancel({:?}, {:?}, {:?}) ", xid, context.client_addr, args);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_res {
+        cookie: args.cookie,
+        stat: nlm4_stats::NLM4_DENIED_NOLOCKS,
+    }
+    .serialize(output)?;
+    Ok(())
+}
+
+pub fn nlmproc4_unlock(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_unlockargs::default();
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::nlm", "nlmproc4_unlock({:?}, {:?}, {:?}) ", xid, context.client_addr, args);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_res {
+        cookie: args.cookie,
+        stat: nlm4_stats::NLM4_DENIED_NOLOCKS,
+    }
+    .serialize(output)?;
+    Ok(())
+}
+
+pub fn nlmproc4_share(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_shareargs::default();
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::nlm", "nlmproc4_share({:?}, {:?}, {:?}) ", xid, context.client_addr, args);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_shareres {
+        cookie: args.cookie,
+        stat: nlm4_stats::NLM4_DENIED_NOLOCKS,
+        sequence: 0,
+    }
+    .serialize(output)?;
+    Ok(())
+}
+
+/// A client rebooted and asks us to drop its locks. It holds none.
+pub fn nlmproc4_free_all(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_notify::default();
+    decode_args(&mut args, input)?;
+    debug!(target: "nfsserve::nlm", "nlmproc4_free_all({:?}, {:?}) ", xid, args);
+    make_success_reply(xid).serialize(output)?;
+    Ok(())
+}
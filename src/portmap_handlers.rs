@@ -1,11 +1,13 @@
 use crate::context::RPCContext;
+use crate::getport_cache::GetportAnswer;
 use crate::portmap;
 use crate::rpc::*;
+use crate::support::{ProcedureSupport, Support};
 use crate::xdr::*;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::FromPrimitive;
 use std::io::{Read, Write};
-use tracing::{debug, error};
+use tracing::debug;
 
 /*
  From RFC 1057 Appendix A
@@ -42,42 +44,75 @@ pub fn handle_portmap(
     output: &mut impl Write,
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
-    if call.vers != portmap::VERSION {
-        error!(
-            "Invalid Portmap Version number {} != {}",
-            call.vers,
-            portmap::VERSION
-        );
-        prog_mismatch_reply_message(xid, portmap::VERSION).serialize(output)?;
-        return Ok(());
-    }
     let prog = PortmapProgram::from_u32(call.proc).unwrap_or(PortmapProgram::INVALID);
 
     match prog {
         PortmapProgram::PMAPPROC_NULL => pmapproc_null(xid, input, output)?,
         PortmapProgram::PMAPPROC_GETPORT => pmapproc_getport(xid, input, output, context)?,
-        _ => {
+        PortmapProgram::PMAPPROC_SET
+        | PortmapProgram::PMAPPROC_UNSET
+        | PortmapProgram::PMAPPROC_DUMP
+        | PortmapProgram::PMAPPROC_CALLIT
+        | PortmapProgram::INVALID => {
             proc_unavail_reply_message(xid).serialize(output)?;
         }
     }
     Ok(())
 }
 
+/// How completely each procedure is supported. See support::matrix.
+/// Like the match in handle_portmap this has no wildcard arm.
+fn support(prog: PortmapProgram) -> (Support, &'static str) {
+    match prog {
+        PortmapProgram::PMAPPROC_NULL => (Support::Full, ""),
+        PortmapProgram::PMAPPROC_GETPORT => (
+            Support::Partial,
+            "Answers the port of this listener for the programs it serves over TCP \
+             (or any protocol, prot 0), 0 otherwise",
+        ),
+        PortmapProgram::PMAPPROC_SET
+        | PortmapProgram::PMAPPROC_UNSET
+        | PortmapProgram::PMAPPROC_DUMP
+        | PortmapProgram::PMAPPROC_CALLIT
+        | PortmapProgram::INVALID => (Support::Unsupported, ""),
+    }
+}
+
+/// The support of the PORTMAP procedures. See support::matrix
+pub fn support_matrix() -> Vec<ProcedureSupport> {
+    (0..)
+        .map_while(PortmapProgram::from_u32)
+        .take_while(|prog| !matches!(prog, PortmapProgram::INVALID))
+        .map(|prog| {
+            let (support, notes) = support(prog);
+            ProcedureSupport {
+                program: "PORTMAP",
+                number: prog as u32,
+                procedure: format!("{:?}", prog),
+                support,
+                notes,
+            }
+        })
+        .collect()
+}
+
 pub fn pmapproc_null(
     xid: u32,
     _: &mut impl Read,
     output: &mut impl Write,
 ) -> Result<(), anyhow::Error> {
-    debug!("pmapproc_null({:?}) ", xid);
+    debug!(target: "nfsserve::portmap", "pmapproc_null({:?}) ", xid);
     // build an RPC reply
     let msg = make_success_reply(xid);
-    debug!("\t{:?} --> {:?}", xid, msg);
+    debug!(target: "nfsserve::portmap", "\t{:?} --> {:?}", xid, msg);
     msg.serialize(output)?;
     Ok(())
 }
 
 /*
  * We fake a portmapper here. And always direct back to the same host port
+ * for the programs we serve. Only TCP is served, so a prot of 0 ("any",
+ * which some clients probe with) gets the TCP port.
  */
 pub fn pmapproc_getport(
     xid: u32,
@@ -86,11 +121,35 @@ pub fn pmapproc_getport(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut mapping = portmap::mapping::default();
-    mapping.deserialize(read)?;
-    debug!("pmapproc_getport({:?}, {:?}) ", xid, mapping);
+    decode_args(&mut mapping, read)?;
+    debug!(target: "nfsserve::portmap", "pmapproc_getport({:?}, {:?}) ", xid, mapping);
+    let key = (mapping.prog, mapping.vers, mapping.prot);
+    let answer = match context.getport.get(key) {
+        Some(answer) => answer,
+        None => {
+            // the registry stays locked until the answer is cached, so that a
+            // registration cannot clear the cache in between
+            let programs = context.programs.read().unwrap();
+            let served = programs.is_served(mapping.prog, mapping.vers);
+            let tcp = matches!(mapping.prot, portmap::IPPROTO_TCP | 0);
+            let answer = GetportAnswer {
+                port: if served && tcp {
+                    context.local_port as u32
+                } else {
+                    0
+                },
+                unserved: !served,
+            };
+            context.getport.insert(key, answer);
+            answer
+        }
+    };
+    if answer.unserved {
+        context.getport.count_unserved(mapping.prog, mapping.vers);
+    }
     make_success_reply(xid).serialize(output)?;
-    let port = context.local_port as u32;
-    debug!("\t{:?} --> {:?}", xid, port);
+    let port = answer.port;
+    debug!(target: "nfsserve::portmap", "\t{:?} --> {:?}", xid, port);
     port.serialize(output)?;
     Ok(())
 }
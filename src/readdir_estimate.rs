@@ -0,0 +1,208 @@
+//! Estimation of how many entries fit in a READDIR / READDIRPLUS reply
+use crate::nfs::fileid3;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The number of directories whose entry sizes are remembered
+const MAX_TRACKED_DIRS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct EntrySizes {
+    /// average encoded size of an entry in the reply
+    reply_bytes: usize,
+    /// average size of an entry as counted against dircount
+    dircount_bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    dirs: HashMap<(fileid3, bool), EntrySizes>,
+    tick: u64,
+}
+
+/// Remembers the average entry size of the directories recently listed,
+/// so that the number of entries asked of the VFS for a page matches what
+/// the reply can hold. A directory of short names fits many more entries
+/// than the dircount / 16 guess, and one of long names far fewer.
+///
+/// READDIR and READDIRPLUS entries are tracked separately since the
+/// latter carry attributes and a file handle.
+#[derive(Debug, Default)]
+pub struct EntrySizeEstimator {
+    inner: Mutex<Inner>,
+}
+
+impl EntrySizeEstimator {
+    /// The number of entries to ask the VFS for, given the reply byte
+    /// budget and the dircount budget of the call. fallback is used for
+    /// directories we have not seen yet. Always at least one, so that a
+    /// count too small for the first entry is told apart from an empty page.
+    pub fn estimate(
+        &self,
+        dirid: fileid3,
+        plus: bool,
+        reply_budget: usize,
+        dircount_budget: usize,
+        fallback: usize,
+    ) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let Some(sizes) = inner.dirs.get_mut(&(dirid, plus)) else {
+            return fallback.max(1);
+        };
+        sizes.last_used = tick;
+        let by_reply = reply_budget / sizes.reply_bytes.max(1);
+        let by_dircount = dircount_budget / sizes.dircount_bytes.max(1);
+        // one more than fits tells the reply it is not at the end
+        by_reply.min(by_dircount) + 1
+    }
+
+    /// Records the entries written to a reply
+    pub fn observe(
+        &self,
+        dirid: fileid3,
+        plus: bool,
+        entries: usize,
+        reply_bytes: usize,
+        dircount_bytes: usize,
+    ) {
+        if entries == 0 {
+            return;
+        }
+        // rounded down, as asking for an entry too many costs less than
+        // asking for one too few, which takes another call to fill the page
+        let reply_avg = reply_bytes / entries;
+        let dircount_avg = dircount_bytes / entries;
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some(sizes) = inner.dirs.get_mut(&(dirid, plus)) {
+            // weigh the latest page as much as the history
+            sizes.reply_bytes = (sizes.reply_bytes + reply_avg) / 2;
+            sizes.dircount_bytes = (sizes.dircount_bytes + dircount_avg) / 2;
+            sizes.last_used = tick;
+            return;
+        }
+        if inner.dirs.len() >= MAX_TRACKED_DIRS {
+            // evict the least recently used directory
+            if let Some(oldest) = inner
+                .dirs
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(k, _)| *k)
+            {
+                inner.dirs.remove(&oldest);
+            }
+        }
+        inner.dirs.insert(
+            (dirid, plus),
+            EntrySizes {
+                reply_bytes: reply_avg,
+                dircount_bytes: dircount_avg,
+                last_used: tick,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lists a directory of entries, all of reply_size bytes in a reply and
+    /// dircount_size bytes of dircount, in pages of reply_budget and
+    /// dircount_budget, the way the handlers do: the VFS is asked for
+    /// estimate entries at a time until the page is full. Returns the VFS
+    /// calls and the entries loaded for each page.
+    fn list(
+        estimator: &EntrySizeEstimator,
+        dirid: fileid3,
+        entries: usize,
+        (reply_size, dircount_size): (usize, usize),
+        (reply_budget, dircount_budget): (usize, usize),
+    ) -> Vec<(usize, usize)> {
+        let fits = (reply_budget / reply_size).min(dircount_budget / dircount_size);
+        let mut left = entries;
+        let mut pages = Vec::new();
+        while left > 0 {
+            let asked = estimator.estimate(
+                dirid,
+                false,
+                reply_budget,
+                dircount_budget,
+                dircount_budget / 16,
+            );
+            let page = fits.min(left);
+            // one more than the page, to tell it is not the last
+            let wanted = (page + 1).min(left);
+            let calls = wanted.div_ceil(asked);
+            pages.push((calls, (calls * asked).min(left)));
+            estimator.observe(dirid, false, page, page * reply_size, page * dircount_size);
+            left -= page;
+        }
+        pages
+    }
+
+    #[test]
+    fn estimates_converge() {
+        let estimator = EntrySizeEstimator::default();
+        let budgets = (8000, 8192);
+        // 4 and 200 byte names in READDIR entries
+        for (dirid, sizes) in [(fileid3(1), (28, 24)), (fileid3(2), (224, 220))] {
+            let fits = (budgets.0 / sizes.0).min(budgets.1 / sizes.1);
+            let pages = list(&estimator, dirid, 100 * fits, sizes, budgets);
+            assert_eq!(pages.len(), 100);
+            // the first page is listed with dircount / 16 entries, the
+            // others with one more than fits
+            assert_eq!(pages[0], (1, 512));
+            for &page in &pages[1..99] {
+                assert_eq!(page, (1, fits + 1));
+            }
+            let loaded: usize = pages.iter().map(|&(_, loaded)| loaded).sum();
+            assert!(loaded < 100 * fits + 100 + 512);
+            assert!(loaded < pages.len() * 512);
+        }
+    }
+
+    #[test]
+    fn estimates_follow_changing_sizes() {
+        let estimator = EntrySizeEstimator::default();
+        let (dirid, budgets) = (fileid3(1), (8000, 8192));
+        // a directory of long names, then of short names
+        list(&estimator, dirid, 1000, (224, 220), budgets);
+        let pages = list(&estimator, dirid, 10000, (28, 24), budgets);
+        // too few entries are asked for at first, and it takes more than
+        // one call to fill a page, but the gap halves with every page
+        assert!(pages[0].0 > 1);
+        assert!(pages[10..pages.len() - 1]
+            .iter()
+            .all(|&(calls, _)| calls == 1));
+    }
+
+    #[test]
+    fn plus_is_tracked_apart() {
+        let estimator = EntrySizeEstimator::default();
+        let dirid = fileid3(1);
+        estimator.observe(dirid, true, 10, 10 * 200, 10 * 24);
+        assert_eq!(estimator.estimate(dirid, true, 8000, 8192, 512), 41);
+        assert_eq!(estimator.estimate(dirid, false, 8000, 8192, 512), 512);
+        estimator.observe(dirid, false, 10, 10 * 28, 10 * 24);
+        assert_eq!(estimator.estimate(dirid, false, 8000, 8192, 512), 286);
+    }
+
+    #[test]
+    fn least_recently_used_dirs_are_forgotten() {
+        let estimator = EntrySizeEstimator::default();
+        for id in 0..MAX_TRACKED_DIRS as u64 {
+            estimator.observe(fileid3(id), false, 10, 10 * 100, 10 * 24);
+        }
+        // directory 0 is used again, so 1 is the one to go
+        assert_eq!(estimator.estimate(fileid3(0), false, 8000, 8192, 512), 81);
+        estimator.observe(fileid3(u64::MAX), false, 10, 10 * 100, 10 * 24);
+        assert_eq!(estimator.estimate(fileid3(0), false, 8000, 8192, 512), 81);
+        assert_eq!(estimator.estimate(fileid3(1), false, 8000, 8192, 512), 512);
+        assert_eq!(estimator.estimate(fileid3(2), false, 8000, 8192, 512), 81);
+    }
+}
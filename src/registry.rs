@@ -0,0 +1,104 @@
+//! The set of RPC programs and versions served by a listener.
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The RPC programs and versions served by a listener.
+///
+/// This is consulted when dispatching every call: calls to a program which
+/// is not registered are replied PROG_UNAVAIL, and calls to a version which
+/// is not registered are replied PROG_MISMATCH with the lowest and highest
+/// registered versions of the program.
+///
+/// Only the versions this crate implements can be registered (see
+/// is_implemented), as calls to a registered version are handed to the
+/// program handler, which decodes them as the version it implements.
+#[derive(Clone, Debug, Default)]
+pub struct ProgramRegistry {
+    programs: BTreeMap<u32, BTreeSet<u32>>,
+}
+
+impl ProgramRegistry {
+    /// Creates an empty registry
+    pub fn new() -> ProgramRegistry {
+        ProgramRegistry::default()
+    }
+
+    /// Creates a registry with the programs implemented by this crate:
+    /// Portmapper v2, Mount v3, NFS v3, NLM v4 (and NFSACL v3 with the nfsacl
+    /// feature, and the metadata program v1 with the metadata feature)
+    pub fn with_default_programs() -> ProgramRegistry {
+        let mut ret = ProgramRegistry::new();
+        ret.register(crate::portmap::PROGRAM, crate::portmap::VERSION);
+        ret.register(crate::mount::PROGRAM, crate::mount::VERSION);
+        ret.register(crate::nfs::PROGRAM, crate::nfs::VERSION);
+        ret.register(crate::nlm::PROGRAM, crate::nlm::VERSION);
+        #[cfg(feature = "nfsacl")]
+        ret.register(crate::nfsacl::PROGRAM, crate::nfsacl::VERSION);
+        #[cfg(feature = "metadata")]
+        ret.register(crate::metadata::PROGRAM, crate::metadata::VERSION);
+        ret
+    }
+
+    /// Returns true if this crate has a handler for this version of the
+    /// program, i.e. if it is one of with_default_programs
+    pub fn is_implemented(prog: u32, vers: u32) -> bool {
+        match prog {
+            crate::portmap::PROGRAM => vers == crate::portmap::VERSION,
+            crate::mount::PROGRAM => vers == crate::mount::VERSION,
+            crate::nfs::PROGRAM => vers == crate::nfs::VERSION,
+            crate::nlm::PROGRAM => vers == crate::nlm::VERSION,
+            #[cfg(feature = "nfsacl")]
+            crate::nfsacl::PROGRAM => vers == crate::nfsacl::VERSION,
+            #[cfg(feature = "metadata")]
+            crate::metadata::PROGRAM => vers == crate::metadata::VERSION,
+            _ => false,
+        }
+    }
+
+    /// Registers a version of a program as served. Returns false, leaving
+    /// the registry unchanged, if this version is not implemented.
+    pub fn register(&mut self, prog: u32, vers: u32) -> bool {
+        if !ProgramRegistry::is_implemented(prog, vers) {
+            return false;
+        }
+        self.programs.entry(prog).or_default().insert(vers);
+        true
+    }
+
+    /// Deregisters a version of a program. The program is removed
+    /// entirely when its last version is deregistered.
+    /// Returns false if the version was not registered.
+    pub fn deregister(&mut self, prog: u32, vers: u32) -> bool {
+        let Some(versions) = self.programs.get_mut(&prog) else {
+            return false;
+        };
+        let removed = versions.remove(&vers);
+        if versions.is_empty() {
+            self.programs.remove(&prog);
+        }
+        removed
+    }
+
+    /// Returns the served versions of a program, or None if the program
+    /// is not served at all.
+    pub fn lookup(&self, prog: u32) -> Option<&BTreeSet<u32>> {
+        self.programs.get(&prog)
+    }
+
+    /// Returns the (lowest, highest) served versions of a program
+    pub fn version_range(&self, prog: u32) -> Option<(u32, u32)> {
+        let versions = self.lookup(prog)?;
+        Some((*versions.first()?, *versions.last()?))
+    }
+
+    /// Returns true if this version of the program is served
+    pub fn is_served(&self, prog: u32, vers: u32) -> bool {
+        self.lookup(prog).is_some_and(|v| v.contains(&vers))
+    }
+
+    /// Iterates over all served (program, version) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.programs
+            .iter()
+            .flat_map(|(prog, versions)| versions.iter().map(move |vers| (*prog, *vers)))
+    }
+}
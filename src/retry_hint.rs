@@ -0,0 +1,417 @@
+//! An NFSFileSystem adapter which asks clients to retry transient errors.
+use crate::nfs::*;
+use crate::vfs::{
+    DirEntry, FsInfoConfig, FsStat, NFSFileSystem, RawDirPage, ReadDirPlusResult, ReadDirResult,
+    ReadDirSimpleResult, ReadReply, VFSCapabilities, WriteReply,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::debug;
+
+/// The number of fileids with failures tracked at once. Past this the
+/// counts are reset, which at worst gives a few ids extra retries.
+const MAX_TRACKED_IDS: usize = 65536;
+
+/// Wraps a file system so that NFS3ERR_IO from it is replied as
+/// NFS3ERR_JUKEBOX ("try again later"), up to max_retries consecutive times
+/// per fileid. After that the NFS3ERR_IO is passed through, and any other
+/// result resets the count.
+///
+/// This is meant for backends with transient failures (a remote object
+/// store timing out, say). A client mounted with `soft` gives up after its
+/// retransmissions (timeo, retrans) and fails the syscall with EIO, so an
+/// error which would have cleared a moment later still reaches the
+/// application. On JUKEBOX the client instead waits and reissues the call,
+/// which gives the backend that moment. With `hard` mounts the client
+/// retries forever anyway, and the bound keeps a persistent failure from
+/// turning into an endless retry loop.
+///
+/// Only calls which are safe to reissue are translated: lookups, reads,
+/// attribute get and set, writes and commits. The directory mutations
+/// (create, remove, rename, ...) may have partly happened when they fail,
+/// and are forwarded as is.
+pub struct TransientRetryFS<T: NFSFileSystem> {
+    inner: T,
+    max_retries: u32,
+    failures: Mutex<HashMap<fileid3, u32>>,
+}
+
+impl<T: NFSFileSystem> TransientRetryFS<T> {
+    pub fn new(inner: T, max_retries: u32) -> TransientRetryFS<T> {
+        TransientRetryFS {
+            inner,
+            max_retries,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the wrapped file system
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Translates the result of a call on id
+    fn hint<R>(&self, id: fileid3, res: Result<R, nfsstat3>) -> Result<R, nfsstat3> {
+        let mut failures = self.failures.lock().unwrap();
+        match res {
+            Err(nfsstat3::NFS3ERR_IO) => {
+                if failures.len() >= MAX_TRACKED_IDS && !failures.contains_key(&id) {
+                    failures.clear();
+                }
+                let count = failures.entry(id).or_default();
+                if *count < self.max_retries {
+                    *count += 1;
+                    debug!(
+                        target: "nfsserve::nfs",
+                        "NFS3ERR_IO on {:?} replied as NFS3ERR_JUKEBOX ({}/{})",
+                        id,
+                        count,
+                        self.max_retries
+                    );
+                    Err(nfsstat3::NFS3ERR_JUKEBOX)
+                } else {
+                    failures.remove(&id);
+                    Err(nfsstat3::NFS3ERR_IO)
+                }
+            }
+            res => {
+                failures.remove(&id);
+                res
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: NFSFileSystem + Send> NFSFileSystem for TransientRetryFS<T> {
+    fn capabilities(&self) -> VFSCapabilities {
+        self.inner.capabilities()
+    }
+    fn root_dir(&self) -> fileid3 {
+        self.inner.root_dir()
+    }
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.hint(dirid, self.inner.lookup(dirid, filename).await)
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.hint(id, self.inner.getattr(id).await)
+    }
+
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.hint(id, self.inner.setattr(id, setattr).await)
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.hint(id, self.inner.read(id, offset, count).await)
+    }
+
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        self.hint(id, self.inner.read_ext(id, offset, count, buf).await)
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        self.hint(id, self.inner.write(id, offset, data).await)
+    }
+
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        self.hint(id, self.inner.write_ext(id, offset, data, stable).await)
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.create(dirid, filename, attr).await
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.inner.create_exclusive(dirid, filename).await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.mkdir(dirid, dirname).await
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.inner.remove(dirid, filename).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        self.hint(
+            dirid,
+            self.inner.readdir(dirid, start_after, max_entries).await,
+        )
+    }
+
+    async fn readdir_raw(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+    ) -> Option<Result<RawDirPage, nfsstat3>> {
+        let res = self
+            .inner
+            .readdir_raw(dirid, cookie, dircount, maxcount)
+            .await?;
+        Some(self.hint(dirid, res))
+    }
+
+    async fn readdir_each(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
+        self.hint(
+            dirid,
+            self.inner
+                .readdir_each(dirid, start_after, max_entries, sink)
+                .await,
+        )
+    }
+
+    async fn readdirplus(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+        max_entries: usize,
+    ) -> Result<ReadDirPlusResult, nfsstat3> {
+        self.hint(
+            dirid,
+            self.inner
+                .readdirplus(dirid, cookie, dircount, maxcount, max_entries)
+                .await,
+        )
+    }
+
+    async fn readdir_simple(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        count: usize,
+    ) -> Result<ReadDirSimpleResult, nfsstat3> {
+        self.hint(
+            dirid,
+            self.inner.readdir_simple(dirid, start_after, count).await,
+        )
+    }
+
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.symlink(dirid, linkname, symlink, attr).await
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.hint(id, self.inner.readlink(id).await)
+    }
+
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        self.inner.parent_dir(id).await
+    }
+
+    async fn mknod(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.inner.mknod(dirid, filename, ftype, attr, spec).await
+    }
+
+    async fn link(
+        &self,
+        id: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.inner.link(id, linkdirid, linkname).await
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        self.inner.supports_hard_links()
+    }
+
+    async fn is_immutable_dir(&self, dirid: fileid3) -> bool {
+        self.inner.is_immutable_dir(dirid).await
+    }
+
+    async fn access(&self, id: fileid3, attr: &fattr3, requested: u32) -> u32 {
+        self.inner.access(id, attr, requested).await
+    }
+
+    async fn commit(&self, id: fileid3, offset: u64, count: u32) -> Result<(), nfsstat3> {
+        self.hint(id, self.inner.commit(id, offset, count).await)
+    }
+
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        self.inner.fsinfo_config()
+    }
+
+    async fn fs_stat(&self, id: fileid3) -> Result<FsStat, nfsstat3> {
+        self.hint(id, self.inner.fs_stat(id).await)
+    }
+
+    fn time_delta(&self) -> nfstime3 {
+        self.inner.time_delta()
+    }
+
+    async fn fsinfo(&self, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        self.hint(root_fileid, self.inner.fsinfo(root_fileid).await)
+    }
+
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<fileid3, nfsstat3> {
+        self.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> cookieverf3 {
+        self.inner.serverid()
+    }
+
+    async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        self.inner.describe_fileid(id).await
+    }
+
+    async fn handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        self.inner.handle_table().await
+    }
+
+    async fn content_hash(&self, id: fileid3) -> Option<[u8; 32]> {
+        self.inner.content_hash(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::{forward_to_memfs, MemFS};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    /// A MemFS whose next getattrs fail with NFS3ERR_IO, as many as set in
+    /// failures
+    struct FlakyFS {
+        inner: MemFS,
+        failures: AtomicU32,
+    }
+
+    forward_to_memfs! {
+        FlakyFS,
+        hooks {
+            async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+                let failing = self
+                    .failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if failing {
+                    return Err(nfsstat3::NFS3ERR_IO);
+                }
+                self.inner.getattr(id).await
+            }
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let fs = TransientRetryFS::new(
+            FlakyFS {
+                inner: MemFS::new(),
+                failures: AtomicU32::new(0),
+            },
+            2,
+        );
+        let fail = |n| fs.inner().failures.store(n, Ordering::SeqCst);
+        let root = fs.root_dir();
+        let getattr = || block_on(fs.getattr(root)).map(|attr| attr.fileid);
+
+        // a failure which clears: the client is asked to come back, and
+        // then succeeds
+        fail(1);
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_JUKEBOX)));
+        assert_eq!(getattr().unwrap(), root);
+
+        // the success reset the count, so this takes two retries too
+        fail(2);
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_JUKEBOX)));
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_JUKEBOX)));
+        assert_eq!(getattr().unwrap(), root);
+
+        // a persistent failure is passed through after max_retries
+        fail(u32::MAX);
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_JUKEBOX)));
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_JUKEBOX)));
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_IO)));
+        // and the retries start over
+        assert!(matches!(getattr(), Err(nfsstat3::NFS3ERR_JUKEBOX)));
+    }
+}
@@ -82,6 +82,10 @@ pub enum auth_stat {
     AUTH_REJECTEDVERF = 4,
     /// rejected for security reasons
     AUTH_TOOWEAK = 5,
+    /// no credentials for user (RFC 2203)
+    RPCSEC_GSS_CREDPROBLEM = 13,
+    /// problem with context (RFC 2203)
+    RPCSEC_GSS_CTXPROBLEM = 14,
 }
 XDREnumSerde!(auth_stat);
 
@@ -94,20 +98,68 @@ pub enum auth_flavor {
     AUTH_UNIX = 1,
     AUTH_SHORT = 2,
     AUTH_DES = 3, /* and more to be defined */
+    /// RFC 2203
+    RPCSEC_GSS = 6,
 }
 XDREnumSerde!(auth_flavor);
 
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug, Default)]
 pub struct auth_unix {
-    stamp: u32,
-    machinename: Vec<u8>,
-    uid: u32,
-    gid: u32,
-    gids: Vec<u32>,
+    pub stamp: u32,
+    pub machinename: Vec<u8>,
+    pub uid: u32,
+    pub gid: u32,
+    pub gids: Vec<u32>,
 }
 XDRStruct!(auth_unix, stamp, machinename, uid, gid, gids);
 
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+/// The control procedure of an RPCSEC_GSS call (RFC 2203 section 5)
+pub enum rpc_gss_proc_t {
+    /// an ordinary call of the program, under an established context
+    #[default]
+    RPCSEC_GSS_DATA = 0,
+    /// creates a context. The call arguments are a GSS-API token
+    RPCSEC_GSS_INIT = 1,
+    /// continues the creation of a context
+    RPCSEC_GSS_CONTINUE_INIT = 2,
+    /// destroys a context
+    RPCSEC_GSS_DESTROY = 3,
+}
+XDREnumSerde!(rpc_gss_proc_t);
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+/// The protection of the arguments and results of an RPCSEC_GSS call
+pub enum rpc_gss_service_t {
+    /// authentication of the header only
+    #[default]
+    rpc_gss_svc_none = 1,
+    /// arguments and results are checksummed
+    rpc_gss_svc_integrity = 2,
+    /// arguments and results are encrypted
+    rpc_gss_svc_privacy = 3,
+}
+XDREnumSerde!(rpc_gss_service_t);
+
+/// The body of an RPCSEC_GSS credential (rpc_gss_cred_vers_1_t in
+/// RFC 2203), preceded on the wire by the version, which must be 1.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Default)]
+pub struct rpc_gss_cred {
+    pub version: u32,
+    pub gss_proc: rpc_gss_proc_t,
+    pub seq_num: u32,
+    pub service: rpc_gss_service_t,
+    /// the context handle, empty on RPCSEC_GSS_INIT
+    pub handle: Vec<u8>,
+}
+XDRStruct!(rpc_gss_cred, version, gss_proc, seq_num, service, handle);
+
 ///Provisions for authentication of caller to service and vice-versa are
 ///provided as a part of the RPC protocol.  The call message has two
 ///authentication fields, the credentials and verifier.  The reply
@@ -466,19 +518,39 @@ pub fn prog_unavail_reply_message(xid: u32) -> rpc_msg {
         body: rpc_body::REPLY(reply),
     }
 }
-pub fn prog_mismatch_reply_message(xid: u32, accepted_ver: u32) -> rpc_msg {
+pub fn prog_mismatch_reply_message(xid: u32, low: u32, high: u32) -> rpc_msg {
     let reply = reply_body::MSG_ACCEPTED(accepted_reply {
         verf: opaque_auth::default(),
-        reply_data: accept_body::PROG_MISMATCH(mismatch_info {
-            low: accepted_ver,
-            high: accepted_ver,
-        }),
+        reply_data: accept_body::PROG_MISMATCH(mismatch_info { low, high }),
     });
     rpc_msg {
         xid,
         body: rpc_body::REPLY(reply),
     }
 }
+/// The arguments of a call could not be decoded. Handlers return it (via
+/// decode_args) so that the call is replied GARBAGE_ARGS, as opposed to
+/// other errors which drop the connection.
+#[derive(Debug)]
+pub struct GarbageArgs(pub std::io::Error);
+
+impl std::fmt::Display for GarbageArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "undecodable call arguments: {}", self.0)
+    }
+}
+
+impl std::error::Error for GarbageArgs {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Decodes (part of) the arguments of a call into args
+pub fn decode_args<T: XDR>(args: &mut T, input: &mut impl Read) -> Result<(), GarbageArgs> {
+    args.deserialize(input).map_err(GarbageArgs)
+}
+
 pub fn garbage_args_reply_message(xid: u32) -> rpc_msg {
     let reply = reply_body::MSG_ACCEPTED(accepted_reply {
         verf: opaque_auth::default(),
@@ -491,7 +563,18 @@ pub fn garbage_args_reply_message(xid: u32) -> rpc_msg {
 }
 
 pub fn rpc_vers_mismatch(xid: u32) -> rpc_msg {
-    let reply = reply_body::MSG_DENIED(rejected_reply::RPC_MISMATCH(mismatch_info::default()));
+    let reply = reply_body::MSG_DENIED(rejected_reply::RPC_MISMATCH(mismatch_info {
+        low: 2,
+        high: 2,
+    }));
+    rpc_msg {
+        xid,
+        body: rpc_body::REPLY(reply),
+    }
+}
+
+pub fn auth_error_reply_message(xid: u32, stat: auth_stat) -> rpc_msg {
+    let reply = reply_body::MSG_DENIED(rejected_reply::AUTH_ERROR(stat));
     rpc_msg {
         xid,
         body: rpc_body::REPLY(reply),
@@ -1,10 +1,20 @@
 use anyhow::anyhow;
+use smallvec::SmallVec;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io::Cursor;
-use std::io::{Read, Write};
+use std::io::{IoSlice, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use tracing::{error, trace, warn};
 
+use crate::capture;
 use crate::context::RPCContext;
+use crate::memory_budget::{MemoryBudget, MemoryReservation};
 use crate::rpc::*;
+use crate::tcp::GssAccepted;
+use crate::vfs::{with_handle_codec, with_user, UserContext};
+use crate::write_counter::WriteCounter;
 use crate::xdr::*;
 
 use crate::mount;
@@ -13,12 +23,18 @@ use crate::mount_handlers;
 use crate::nfs;
 use crate::nfs_handlers;
 
+#[cfg(feature = "metadata")]
+use crate::metadata_handlers;
+#[cfg(feature = "nfsacl")]
+use crate::nfsacl_handlers;
+
+use crate::nlm;
+use crate::nlm_handlers;
 use crate::portmap;
 use crate::portmap_handlers;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
 use tokio::io::DuplexStream;
-use tokio::sync::mpsc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 
 // Information from RFC 5531
 // https://datatracker.ietf.org/doc/html/rfc5531
@@ -27,7 +43,147 @@ const NFS_ACL_PROGRAM: u32 = 100227;
 const NFS_ID_MAP_PROGRAM: u32 = 100270;
 const NFS_METADATA_PROGRAM: u32 = 200024;
 
-async fn handle_rpc(
+/// Writes the replies of the program handlers, replacing the AUTH_NULL
+/// verifier they all reply with by the verifier of an authenticated call.
+struct ReplyVerifier<W> {
+    inner: W,
+    /// The encoded verifier, None to pass the reply through unchanged
+    verf: Option<Vec<u8>>,
+    written: usize,
+}
+
+impl<W: Write> ReplyVerifier<W> {
+    /// The AUTH_NULL verifier follows the xid, msg_type and reply_stat
+    const VERF_START: usize = 12;
+    const VERF_END: usize = 20;
+
+    fn new(inner: W, verf: Option<opaque_auth>) -> std::io::Result<Self> {
+        let verf = match verf {
+            Some(verf) => {
+                let mut encoded = Vec::new();
+                verf.serialize(&mut encoded)?;
+                Some(encoded)
+            }
+            None => None,
+        };
+        Ok(ReplyVerifier {
+            inner,
+            verf,
+            written: 0,
+        })
+    }
+}
+
+impl<W: Write> Write for ReplyVerifier<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(ref verf) = self.verf else {
+            return self.inner.write(buf);
+        };
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if self.written < Self::VERF_START {
+                let n = rest.len().min(Self::VERF_START - self.written);
+                self.inner.write_all(&rest[..n])?;
+                self.written += n;
+                rest = &rest[n..];
+            } else if self.written < Self::VERF_END {
+                let n = rest.len().min(Self::VERF_END - self.written);
+                self.written += n;
+                rest = &rest[n..];
+                if self.written == Self::VERF_END {
+                    self.inner.write_all(verf)?;
+                }
+            } else {
+                self.inner.write_all(rest)?;
+                self.written += rest.len();
+                break;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Authenticates a call with RPCSEC_GSS credentials through the auth
+/// handler of the listener. Returns None if the call has been replied to
+/// here: refused, or a context control procedure.
+async fn authenticate_gss(
+    xid: u32,
+    call: &call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<Option<GssAccepted>, anyhow::Error> {
+    let Some(handler) = context.auth_handler.clone() else {
+        warn!(target: "nfsserve::rpc", "RPCSEC_GSS call {} without an auth handler", xid);
+        auth_error_reply_message(xid, auth_stat::AUTH_TOOWEAK).serialize(output)?;
+        return Ok(None);
+    };
+    let mut cred = rpc_gss_cred::default();
+    let parsed = cred.deserialize(&mut Cursor::new(&call.cred.body));
+    let client = context.client_addr.parse();
+    let (Ok(()), 1, Ok(client)) = (parsed, cred.version, client) else {
+        warn!(target: "nfsserve::rpc", "Bad RPCSEC_GSS credential on {}", xid);
+        auth_error_reply_message(xid, auth_stat::AUTH_BADCRED).serialize(output)?;
+        return Ok(None);
+    };
+    // the part of the call the verifier is the checksum of
+    let mut header = Vec::new();
+    xid.serialize(&mut header)?;
+    0_u32.serialize(&mut header)?;
+    call.rpcvers.serialize(&mut header)?;
+    call.prog.serialize(&mut header)?;
+    call.vers.serialize(&mut header)?;
+    call.proc.serialize(&mut header)?;
+    call.cred.serialize(&mut header)?;
+    if cred.gss_proc != rpc_gss_proc_t::RPCSEC_GSS_DATA {
+        let mut args = Vec::new();
+        input.read_to_end(&mut args)?;
+        match handler
+            .control(client, &cred, &header, &call.verf, &args)
+            .await
+        {
+            Ok((results, verf)) => {
+                let reply = reply_body::MSG_ACCEPTED(accepted_reply {
+                    verf,
+                    reply_data: accept_body::SUCCESS,
+                });
+                rpc_msg {
+                    xid,
+                    body: rpc_body::REPLY(reply),
+                }
+                .serialize(output)?;
+                output.write_all(&results)?;
+            }
+            Err(stat) => {
+                warn!(target: "nfsserve::rpc", "RPCSEC_GSS {:?} refused with {:?}", cred.gss_proc, stat);
+                auth_error_reply_message(xid, stat).serialize(output)?;
+            }
+        }
+        return Ok(None);
+    }
+    if cred.service != rpc_gss_service_t::rpc_gss_svc_none {
+        warn!(target: "nfsserve::rpc", "Unsupported RPCSEC_GSS service {:?}", cred.service);
+        auth_error_reply_message(xid, auth_stat::AUTH_BADCRED).serialize(output)?;
+        return Ok(None);
+    }
+    match handler
+        .authenticate(client, &cred, &header, &call.verf)
+        .await
+    {
+        Ok(accepted) => Ok(Some(accepted)),
+        Err(stat) => {
+            warn!(target: "nfsserve::rpc", "RPCSEC_GSS call {} refused with {:?}", xid, stat);
+            auth_error_reply_message(xid, stat).serialize(output)?;
+            Ok(None)
+        }
+    }
+}
+
+pub async fn handle_rpc(
     input: &mut impl Read,
     output: &mut impl Write,
     mut context: RPCContext,
@@ -35,41 +191,163 @@ async fn handle_rpc(
     let mut recv = rpc_msg::default();
     recv.deserialize(input)?;
     let xid = recv.xid;
+    // the attributes cached by one call are not valid for the next
+    context.attr_cache = Arc::default();
     if let rpc_body::CALL(call) = recv.body {
-        if let auth_flavor::AUTH_UNIX = call.cred.flavor {
+        // reject other RPC versions before looking at their credentials
+        if call.rpcvers != 2 {
+            warn!(target: "nfsserve::rpc", "Invalid RPC version {} != 2", call.rpcvers);
+            rpc_vers_mismatch(xid).serialize(output)?;
+            return Ok(());
+        }
+        let mut user = None;
+        // the reply verifier, if not AUTH_NULL
+        let mut reply_verf = None;
+        let mut unix_cred = None;
+        if let auth_flavor::RPCSEC_GSS = call.cred.flavor {
+            let Some(accepted) = authenticate_gss(xid, &call, input, output, &context).await?
+            else {
+                return Ok(());
+            };
+            reply_verf = Some(accepted.verf);
+            // the principal's credentials are mapped as AUTH_UNIX ones are
+            unix_cred = accepted.user.map(|principal| auth_unix {
+                stamp: 0,
+                machinename: principal.machinename,
+                uid: principal.uid,
+                gid: principal.gid,
+                gids: principal.gids,
+            });
+        } else if let auth_flavor::AUTH_UNIX = call.cred.flavor {
             let mut auth = auth_unix::default();
-            auth.deserialize(&mut Cursor::new(&call.cred.body))?;
+            if let Err(e) = auth.deserialize(&mut Cursor::new(&call.cred.body)) {
+                warn!(target: "nfsserve::rpc", "Bad AUTH_UNIX credential in {}: {:?}", xid, e);
+                auth_error_reply_message(xid, auth_stat::AUTH_BADCRED).serialize(output)?;
+                return Ok(());
+            }
+            unix_cred = Some(auth);
+        }
+        if let Some(mut auth) = unix_cred {
+            context.squashed = context.squash.apply(&mut auth);
+            user = Some(UserContext {
+                uid: auth.uid,
+                gid: auth.gid,
+                gids: auth.gids.clone(),
+                machinename: auth.machinename.clone(),
+            });
             context.auth = auth;
+        } else if let Some((uid, gid)) = context.squash.anonymous() {
+            context.auth.uid = uid;
+            context.auth.gid = gid;
+            context.squashed = true;
+            user = Some(UserContext {
+                uid,
+                gid,
+                ..Default::default()
+            });
         }
-        if call.rpcvers != 2 {
-            warn!("Invalid RPC version {} != 2", call.rpcvers);
-            rpc_vers_mismatch(xid).serialize(output)?;
+        // check that this program and version is served by the listener
+        let (served, version_range) = {
+            let programs = context.programs.read().unwrap();
+            (
+                programs.is_served(call.prog, call.vers),
+                programs.version_range(call.prog),
+            )
+        };
+        if !served {
+            if let Some((low, high)) = version_range {
+                warn!(
+                    target: "nfsserve::rpc",
+                    "Invalid version number {} for program {}. Served {}..={}",
+                    call.vers,
+                    call.prog,
+                    low,
+                    high
+                );
+                prog_mismatch_reply_message(xid, low, high).serialize(output)?;
+            } else {
+                if call.prog == NFS_ACL_PROGRAM
+                    || call.prog == NFS_ID_MAP_PROGRAM
+                    || call.prog == NFS_METADATA_PROGRAM
+                {
+                    trace!(
+                        target: "nfsserve::rpc",
+                        "ignoring sideband program {} packet",
+                        call.prog
+                    );
+                } else {
+                    warn!(
+                        target: "nfsserve::rpc",
+                        "Unknown RPC Program number {}",
+                        call.prog
+                    );
+                }
+                prog_unavail_reply_message(xid).serialize(output)?;
+            }
             return Ok(());
         }
-        if call.prog == nfs::PROGRAM {
-            nfs_handlers::handle_nfs(xid, call, input, output, &context).await
-        } else if call.prog == portmap::PROGRAM {
-            portmap_handlers::handle_portmap(xid, call, input, output, &context)
-        } else if call.prog == mount::PROGRAM {
-            mount_handlers::handle_mount(xid, call, input, output, &context).await
-        } else if call.prog == NFS_ACL_PROGRAM
-            || call.prog == NFS_ID_MAP_PROGRAM
-            || call.prog == NFS_METADATA_PROGRAM
-        {
-            trace!("ignoring NFS_ACL packet");
-            prog_unavail_reply_message(xid).serialize(output)?;
-            Ok(())
-        } else {
-            warn!(
-                "Unknown RPC Program number {} != {}",
-                call.prog,
-                nfs::PROGRAM
-            );
-            prog_unavail_reply_message(xid).serialize(output)?;
-            Ok(())
+        // count what the program handler writes so that we can tell if it
+        // failed before producing any part of a reply.
+        let mut verified_output = ReplyVerifier::new(&mut *output, reply_verf)?;
+        let mut counting_output = WriteCounter::new(&mut verified_output);
+        let output = &mut counting_output;
+        // the caller is visible to the file system through vfs::current_user,
+        // and the default file handles follow the codec of the listener
+        let codec = context.handle_codec.clone();
+        let res = with_handle_codec(
+            codec,
+            with_user(user, async {
+                match call.prog {
+                    nfs::PROGRAM => {
+                        nfs_handlers::handle_nfs(xid, call, input, output, &context).await
+                    }
+                    portmap::PROGRAM => {
+                        portmap_handlers::handle_portmap(xid, call, input, output, &context)
+                    }
+                    mount::PROGRAM => {
+                        mount_handlers::handle_mount(xid, call, input, output, &context).await
+                    }
+                    nlm::PROGRAM => nlm_handlers::handle_nlm(xid, call, input, output, &context),
+                    #[cfg(feature = "nfsacl")]
+                    NFS_ACL_PROGRAM => {
+                        nfsacl_handlers::handle_nfsacl(xid, call, input, output, &context).await
+                    }
+                    #[cfg(feature = "metadata")]
+                    NFS_METADATA_PROGRAM => {
+                        metadata_handlers::handle_metadata(xid, call, input, output, &context).await
+                    }
+                    _ => {
+                        // registered, but we have no implementation for it
+                        warn!(
+                            target: "nfsserve::rpc",
+                            "No handler for RPC Program number {}",
+                            call.prog
+                        );
+                        prog_unavail_reply_message(xid).serialize(output)?;
+                        Ok(())
+                    }
+                }
+            }),
+        )
+        .await;
+        match res {
+            // The procedure arguments could not be decoded: reply
+            // GARBAGE_ARGS on this xid and keep the connection alive
+            // instead of dropping it. This needs the handler to have
+            // failed before writing any part of its reply, otherwise the
+            // error is returned as any other.
+            Err(e)
+                if counting_output.bytes_written() == 0
+                    && e.downcast_ref::<GarbageArgs>().is_some() =>
+            {
+                warn!(target: "nfsserve::rpc", "Unable to decode arguments of {}: {:?}", xid, e);
+                garbage_args_reply_message(xid).serialize(&mut counting_output)?;
+                Ok(())
+            }
+            res => res,
         }
     } else {
-        error!("Unexpectedly received a Reply instead of a Call");
+        error!(target: "nfsserve::rpc", "Unexpectedly received a Reply instead of a Call");
         Err(anyhow!("Bad RPC Call format"))
     }
 }
@@ -92,20 +370,39 @@ async fn handle_rpc(
 /// length in bytes of the fragment's data.  The boolean value is the
 /// highest-order bit of the header; the length is the 31 low-order bits.
 /// (Note that this record specification is NOT in XDR standard form!)
+///
+/// The fragment is refused before anything is allocated for it if the
+/// record would grow past max_record_len, or if admit (given the length
+/// of the fragment) refuses it.
 async fn read_fragment(
-    socket: &mut DuplexStream,
+    socket: &mut (impl AsyncRead + Unpin),
     append_to: &mut Vec<u8>,
+    max_record_len: usize,
+    admit: impl FnOnce(usize) -> Result<(), anyhow::Error>,
 ) -> Result<bool, anyhow::Error> {
     let mut header_buf = [0_u8; 4];
     socket.read_exact(&mut header_buf).await?;
     let fragment_header = u32::from_be_bytes(header_buf);
     let is_last = (fragment_header & (1 << 31)) > 0;
     let length = (fragment_header & ((1 << 31) - 1)) as usize;
-    trace!("Reading fragment length:{}, last:{}", length, is_last);
+    trace!(target: "nfsserve::rpc", "Reading fragment length:{}, last:{}", length, is_last);
     let start_offset = append_to.len();
-    append_to.resize(append_to.len() + length, 0);
+    let record_len = start_offset
+        .checked_add(length)
+        .filter(|len| *len <= max_record_len)
+        .ok_or_else(|| {
+            anyhow!(
+                "record of {} bytes or more exceeds {}",
+                start_offset.saturating_add(length),
+                max_record_len
+            )
+        })?;
+    admit(length)?;
+    append_to.try_reserve_exact(length)?;
+    append_to.resize(record_len, 0);
     socket.read_exact(&mut append_to[start_offset..]).await?;
     trace!(
+        target: "nfsserve::rpc",
         "Finishing Reading fragment length:{}, last:{}",
         length,
         is_last
@@ -113,50 +410,497 @@ async fn read_fragment(
     Ok(is_last)
 }
 
+/// Room for the RPC header, credentials and arguments of a call besides
+/// its WRITE data, in the largest record read
+const MAX_CALL_OVERHEAD: usize = 64 * 1024;
+
+/// Records up to this size are always admitted, and only counted in the
+/// memory budget. A spent budget then defers large WRITEs, not the small
+/// calls (such as COMMIT, or the READs which are replied JUKEBOX) which
+/// let the client make progress.
+const UNMETERED_RECORD_LEN: usize = 8 * 1024;
+
+/// The largest fragment write_fragment emits. Replies larger than this
+/// are split into several fragments of one record.
+const MAX_FRAGMENT_SIZE: usize = 1024 * 1024;
+
+/// Writes the concatenation of parts as one record, split into fragments
+/// of at most MAX_FRAGMENT_SIZE bytes. See read_fragment for the record
+/// marking. The parts are gathered into the writes to the socket rather
+/// than copied into one buffer, so that the data of a READ goes out from
+/// the buffer it was read into.
 pub async fn write_fragment(
-    socket: &mut tokio::net::TcpStream,
-    buf: &Vec<u8>,
+    socket: &mut (impl AsyncWrite + Unpin),
+    parts: &[&[u8]],
+) -> Result<(), anyhow::Error> {
+    write_fragments(socket, parts, true).await
+}
+
+/// Writes the concatenation of parts as fragments of at most
+/// MAX_FRAGMENT_SIZE bytes, the last of which ends the record if last is
+/// set
+async fn write_fragments(
+    socket: &mut (impl AsyncWrite + Unpin),
+    parts: &[&[u8]],
+    last: bool,
 ) -> Result<(), anyhow::Error> {
-    // TODO: split into many fragments
-    assert!(buf.len() < (1 << 31));
-    // set the last flag
-    let fragment_header = buf.len() as u32 + (1 << 31);
-    let header_buf = u32::to_be_bytes(fragment_header);
-    socket.write_all(&header_buf).await?;
-    trace!("Writing fragment length:{}", buf.len());
-    socket.write_all(buf).await?;
+    let mut remaining: usize = parts.iter().map(|part| part.len()).sum();
+    // an empty record is still one (empty) last fragment
+    if remaining == 0 {
+        if last {
+            socket.write_all(&u32::to_be_bytes(1 << 31)).await?;
+        }
+        return Ok(());
+    }
+    let mut parts = parts.iter().copied();
+    let mut part: &[u8] = &[];
+    while remaining > 0 {
+        let len = remaining.min(MAX_FRAGMENT_SIZE);
+        remaining -= len;
+        let is_last = last && remaining == 0;
+        let mut fragment_header = len as u32;
+        if is_last {
+            // set the last flag
+            fragment_header |= 1 << 31;
+        }
+        trace!(
+            target: "nfsserve::rpc",
+            "Writing fragment length:{}, last:{}",
+            len,
+            is_last
+        );
+        let header = u32::to_be_bytes(fragment_header);
+        // the header, then the pieces of the parts the fragment spans
+        let mut bufs: SmallVec<[&[u8]; 4]> = SmallVec::new();
+        bufs.push(&header);
+        let mut wanted = len;
+        while wanted > 0 {
+            while part.is_empty() {
+                // there are parts left as long as bytes are
+                part = parts.next().unwrap();
+            }
+            let n = wanted.min(part.len());
+            bufs.push(&part[..n]);
+            part = &part[n..];
+            wanted -= n;
+        }
+        write_all_gathered(socket, &mut bufs).await?;
+    }
     Ok(())
 }
 
-pub type SocketMessageType = Result<Vec<u8>, anyhow::Error>;
+/// Writes all of bufs, gathered into as few writes as the socket allows.
+/// Saves a separate write of each fragment header, and of each part.
+async fn write_all_gathered(
+    socket: &mut (impl AsyncWrite + Unpin),
+    bufs: &mut [&[u8]],
+) -> std::io::Result<()> {
+    let mut first = 0;
+    loop {
+        while first < bufs.len() && bufs[first].is_empty() {
+            first += 1;
+        }
+        if first == bufs.len() {
+            return Ok(());
+        }
+        let slices: SmallVec<[IoSlice; 4]> =
+            bufs[first..].iter().map(|b| IoSlice::new(b)).collect();
+        let mut n = socket.write_vectored(&slices).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        while n > 0 {
+            let advance = n.min(bufs[first].len());
+            bufs[first] = &bufs[first][advance..];
+            n -= advance;
+            if bufs[first].is_empty() {
+                first += 1;
+            }
+        }
+    }
+}
+
+/// Writes the chunks of a streamed reply as they come, each as fragments
+/// of the one record. Fails if the chunks end before the last one, as
+/// when the handler failed part way: the connection is then closed.
+pub async fn write_streamed(
+    socket: &mut (impl AsyncWrite + Unpin),
+    chunks: &mut mpsc::Receiver<ReplyChunk>,
+) -> Result<(), anyhow::Error> {
+    while let Some(chunk) = chunks.recv().await {
+        write_fragments(socket, &reply_slices(&chunk.data), chunk.last).await?;
+        if chunk.last {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("reply stream ended before its last chunk"))
+}
+
+/// The size from which the reply buffered by a ReplyStream is sent off
+/// as a chunk
+pub const REPLY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The chunks of a streamed reply queued for the writer at most. Past
+/// that the handler waits, instead of buffering more of the reply.
+const QUEUED_REPLY_CHUNKS: usize = 2;
+
+/// A part of a reply: bytes the handler wrote, or a buffer it attached
+/// whole (see ReplyStream::attach), which is given back to its owner once
+/// written
+pub struct ReplyPart {
+    data: Vec<u8>,
+    recycle: Option<Box<dyn FnOnce(Vec<u8>) + Send>>,
+}
+
+impl ReplyPart {
+    fn new(data: Vec<u8>) -> ReplyPart {
+        ReplyPart {
+            data,
+            recycle: None,
+        }
+    }
+}
+
+impl Drop for ReplyPart {
+    fn drop(&mut self) {
+        if let Some(recycle) = self.recycle.take() {
+            recycle(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+impl fmt::Debug for ReplyPart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReplyPart")
+            .field("len", &self.data.len())
+            .field("attached", &self.recycle.is_some())
+            .finish()
+    }
+}
+
+/// The bytes of the parts of a reply, in order, for write_fragment
+pub fn reply_slices(parts: &[ReplyPart]) -> SmallVec<[&[u8]; 4]> {
+    parts.iter().map(|part| &part.data[..]).collect()
+}
+
+/// What a reply is written from
+#[derive(Debug)]
+pub enum ReplyBody {
+    /// The whole reply
+    Whole(Vec<ReplyPart>),
+    /// The reply, a chunk at a time as the handler produces it. See
+    /// ReplyStream
+    Streamed(mpsc::Receiver<ReplyChunk>),
+}
+
+/// A part of a streamed reply, with the reservation of its buffer
+#[derive(Debug)]
+pub struct ReplyChunk {
+    data: Vec<ReplyPart>,
+    /// Set on the chunk which ends the reply
+    last: bool,
+    _reservation: MemoryReservation,
+}
+
+/// A reply, with the reservation accounting for its buffer and the
+/// in-flight permit of its request, both held until it is written
+pub type SocketMessageType =
+    Result<(ReplyBody, MemoryReservation, OwnedSemaphorePermit), anyhow::Error>;
+
+/// The replies of a connection which are ready but wait for the reply of
+/// an earlier request. See NFSTcp::set_ordered_replies
+#[derive(Debug, Default)]
+struct ReplyOrder {
+    /// The arrival sequence number of the next reply to queue
+    next: u64,
+    ready: BTreeMap<u64, SocketMessageType>,
+}
+
+/// The reply of a request whose task is running. If the task ends without
+/// replying, because the runtime it was spawned on is shutting down (its
+/// future is then dropped, possibly before it ever ran) or the handler
+/// panicked, the connection is closed so that the client reconnects and
+/// retransmits instead of waiting forever for the reply.
+struct PendingReply {
+    send: mpsc::Sender<SocketMessageType>,
+    /// Taken when the reply is queued
+    permit: Option<OwnedSemaphorePermit>,
+    /// With ordered replies, the order of the connection and the arrival
+    /// sequence number of the request
+    order: Option<(Arc<Mutex<ReplyOrder>>, u64)>,
+}
+
+impl PendingReply {
+    async fn reply(mut self, reply: Result<(ReplyBody, MemoryReservation), anyhow::Error>) {
+        let permit = self.permit.take().unwrap();
+        let Some((order, seq)) = self.order.take() else {
+            let reply = reply.map(|(msg, reservation)| (msg, reservation, permit));
+            let _ = self.send.send(reply).await;
+            return;
+        };
+        let reply = match reply {
+            Ok((msg, reservation)) => Ok((msg, reservation, permit)),
+            Err(e) => {
+                // the connection is closed on an error, so it need not
+                // wait its turn. There is room as the permit is held.
+                let _ = self.send.try_send(Err(e));
+                return;
+            }
+        };
+        let mut order = order.lock().unwrap();
+        order.ready.insert(seq, reply);
+        loop {
+            let next = order.next;
+            let Some(reply) = order.ready.remove(&next) else {
+                break;
+            };
+            order.next += 1;
+            // there is room: every queued or waiting reply holds a permit
+            let _ = self.send.try_send(reply);
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        if self.permit.is_some() {
+            warn!(target: "nfsserve::rpc", "Request task ended without a reply");
+            // there is room: every queued reply holds a permit, and this
+            // one is still held
+            let _ = self
+                .send
+                .try_send(Err(anyhow::anyhow!("request task ended without a reply")));
+        }
+    }
+}
+
+/// What the handlers write their replies to. The reply is buffered, and
+/// queued whole once the handler is done, unless the handler streams it:
+/// each send_chunk (through RPCContext::send_reply_chunk) with at least
+/// REPLY_CHUNK_SIZE bytes buffered sends those bytes off to the writer of
+/// the connection, which writes them as fragments of the reply record.
+/// A long reply is then never held whole. The handler waits in send_chunk
+/// while QUEUED_REPLY_CHUNKS chunks are queued.
+///
+/// A handler which fails after streaming part of its reply has the
+/// connection closed, as the rest of the reply can no longer be
+/// replaced by an error.
+#[derive(Clone)]
+pub struct ReplyStream {
+    state: Arc<Mutex<StreamState>>,
+}
+
+struct StreamState {
+    /// The parts of the reply before buf. See ReplyStream::attach
+    parts: Vec<ReplyPart>,
+    buf: Vec<u8>,
+    /// Set if the reply may be streamed. Not when the exchange is
+    /// captured, as the capture needs the whole reply.
+    streaming: bool,
+    /// Taken when the reply is queued, whole or as its first chunk
+    pending: Option<PendingReply>,
+    /// Set once the reply is streamed
+    chunks: Option<mpsc::Sender<ReplyChunk>>,
+    budget: Arc<MemoryBudget>,
+}
+
+impl StreamState {
+    /// The number of bytes buffered, attached parts included
+    fn buffered(&self) -> usize {
+        self.parts.iter().map(|part| part.data.len()).sum::<usize>() + self.buf.len()
+    }
+
+    /// Takes what is buffered, as parts of the reply
+    fn take(&mut self) -> Vec<ReplyPart> {
+        let mut parts = std::mem::take(&mut self.parts);
+        if !self.buf.is_empty() {
+            parts.push(ReplyPart::new(std::mem::take(&mut self.buf)));
+        }
+        parts
+    }
+}
+
+impl ReplyStream {
+    fn new(pending: PendingReply, budget: Arc<MemoryBudget>, streaming: bool) -> ReplyStream {
+        ReplyStream {
+            state: Arc::new(Mutex::new(StreamState {
+                parts: Vec::new(),
+                buf: Vec::new(),
+                streaming,
+                pending: Some(pending),
+                chunks: None,
+                budget,
+            })),
+        }
+    }
+
+    /// Sends what is buffered off as a chunk of the reply, if it is at
+    /// least REPLY_CHUNK_SIZE bytes and the reply may be streamed. The
+    /// first chunk queues the reply.
+    pub async fn send_chunk(&self) -> Result<(), anyhow::Error> {
+        let (data, len, start, chunks, budget) = {
+            let mut state = self.state.lock().unwrap();
+            let len = state.buffered();
+            if !state.streaming || len < REPLY_CHUNK_SIZE {
+                return Ok(());
+            }
+            let data = state.take();
+            let mut start = None;
+            if state.chunks.is_none() {
+                let (send, recv) = mpsc::channel(QUEUED_REPLY_CHUNKS);
+                state.chunks = Some(send);
+                start = state.pending.take().map(|pending| (pending, recv));
+            }
+            let chunks = state.chunks.clone().unwrap();
+            (data, len, start, chunks, state.budget.clone())
+        };
+        if let Some((pending, recv)) = start {
+            let reservation = budget.reserve(0);
+            pending
+                .reply(Ok((ReplyBody::Streamed(recv), reservation)))
+                .await;
+        }
+        let chunk = ReplyChunk {
+            _reservation: budget.reserve(len),
+            data,
+            last: false,
+        };
+        chunks
+            .send(chunk)
+            .await
+            .map_err(|_| anyhow!("connection closed while streaming a reply"))
+    }
+
+    /// Queues the rest of the reply. A reply which was not streamed is
+    /// captured to capture_to (the log and the call), if set.
+    async fn finish(&self, capture_to: Option<(PathBuf, Vec<u8>)>) {
+        let (len, parts, pending, chunks, budget) = {
+            let mut state = self.state.lock().unwrap();
+            (
+                state.buffered(),
+                state.take(),
+                state.pending.take(),
+                state.chunks.take(),
+                state.budget.clone(),
+            )
+        };
+        let reservation = budget.reserve(len);
+        if let Some(chunks) = chunks {
+            let chunk = ReplyChunk {
+                data: parts,
+                last: true,
+                _reservation: reservation,
+            };
+            let _ = chunks.send(chunk).await;
+            return;
+        }
+        if let Some((log, call)) = capture_to {
+            capture::append_exchange(&log, &call, &reply_slices(&parts).concat());
+        }
+        if let Some(pending) = pending {
+            pending
+                .reply(Ok((ReplyBody::Whole(parts), reservation)))
+                .await;
+        }
+    }
+
+    /// Appends data to the reply as it is, after what was written so far,
+    /// instead of copying it into the reply buffer: it is handed to the
+    /// socket as a buffer of its own. recycle is given data back once it
+    /// has been written, or the reply dropped.
+    pub fn attach(&self, data: Vec<u8>, recycle: impl FnOnce(Vec<u8>) + Send + 'static) {
+        let mut state = self.state.lock().unwrap();
+        if !state.buf.is_empty() {
+            let buf = std::mem::take(&mut state.buf);
+            state.parts.push(ReplyPart::new(buf));
+        }
+        state.parts.push(ReplyPart {
+            data,
+            recycle: Some(Box::new(recycle)),
+        });
+    }
+
+    /// Fails the reply. If part of it was streamed, the connection is
+    /// closed once that part is written.
+    async fn fail(&self, e: anyhow::Error) {
+        let (pending, chunks) = {
+            let mut state = self.state.lock().unwrap();
+            (state.pending.take(), state.chunks.take())
+        };
+        drop(chunks);
+        if let Some(pending) = pending {
+            pending.reply(Err(e)).await;
+        }
+    }
+}
+
+impl Write for ReplyStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.state.lock().unwrap().buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
 /// The Socket Message Handler reads from a TcpStream and spawns off
 /// subtasks to handle each message. replies are queued into the
 /// reply_send_channel.
+///
+/// At most max_requests_per_connection requests are handled or have
+/// replies queued at a time. Past that no more records are read until a
+/// reply has been written, which stalls the reads from the socket.
+///
+/// With ordered replies (NFSTcp::set_ordered_replies) each request is
+/// numbered as it arrives, and a reply is only queued once the replies of
+/// all earlier requests are.
+///
+/// The record being read is counted in the memory budget one fragment at
+/// a time, before the fragment is allocated. Records larger than twice
+/// the wtmax of the file system (plus room for the call header) are
+/// refused outright, and so are fragments the budget has no room for.
+/// Either closes the connection, after which the client reconnects and
+/// sends the call again.
 #[derive(Debug)]
 pub struct SocketMessageHandler {
     cur_fragment: Vec<u8>,
+    /// The budget held by cur_fragment
+    cur_reservations: Vec<MemoryReservation>,
+    max_record_len: usize,
     socket_receive_channel: DuplexStream,
-    reply_send_channel: mpsc::UnboundedSender<SocketMessageType>,
+    reply_send_channel: mpsc::Sender<SocketMessageType>,
+    in_flight: Arc<Semaphore>,
+    /// Set with ordered replies. See NFSTcp::set_ordered_replies
+    reply_order: Option<Arc<Mutex<ReplyOrder>>>,
+    /// The arrival sequence number of the next request
+    next_seq: u64,
     context: RPCContext,
 }
 
 impl SocketMessageHandler {
     /// Creates a new SocketMessageHandler with the receiver for queued message replies
-    pub fn new(
-        context: &RPCContext,
-    ) -> (
-        Self,
-        DuplexStream,
-        mpsc::UnboundedReceiver<SocketMessageType>,
-    ) {
+    pub fn new(context: &RPCContext) -> (Self, DuplexStream, mpsc::Receiver<SocketMessageType>) {
         let (socksend, sockrecv) = tokio::io::duplex(256000);
-        let (msgsend, msgrecv) = mpsc::unbounded_channel();
+        let limit = context
+            .max_requests_per_connection
+            .clamp(1, Semaphore::MAX_PERMITS);
+        // every queued reply holds a permit, so sends never wait
+        let (msgsend, msgrecv) = mpsc::channel(limit);
+        let max_record_len = (context.vfs.fsinfo_config().wtmax as usize)
+            .saturating_mul(2)
+            .saturating_add(MAX_CALL_OVERHEAD);
         (
             Self {
                 cur_fragment: Vec::new(),
+                cur_reservations: Vec::new(),
+                max_record_len,
                 socket_receive_channel: sockrecv,
                 reply_send_channel: msgsend,
+                in_flight: Arc::new(Semaphore::new(limit)),
+                reply_order: context.ordered_replies.then(Arc::default),
+                next_seq: 0,
                 context: context.clone(),
             },
             socksend,
@@ -166,29 +910,289 @@ impl SocketMessageHandler {
 
     /// Reads a fragment from the socket. This should be looped.
     pub async fn read(&mut self) -> Result<(), anyhow::Error> {
-        let is_last =
-            read_fragment(&mut self.socket_receive_channel, &mut self.cur_fragment).await?;
+        let budget = &self.context.memory_budget;
+        let record_len = self.cur_fragment.len();
+        let reservations = &mut self.cur_reservations;
+        let admit = |length: usize| {
+            let reservation = if record_len + length <= UNMETERED_RECORD_LEN {
+                budget.reserve(length)
+            } else {
+                budget
+                    .try_reserve(length, record_len)
+                    .ok_or_else(|| anyhow!("no memory budget for a fragment of {} bytes", length))?
+            };
+            reservations.push(reservation);
+            Ok(())
+        };
+        let is_last = read_fragment(
+            &mut self.socket_receive_channel,
+            &mut self.cur_fragment,
+            self.max_record_len,
+            admit,
+        )
+        .await?;
         if is_last {
             let fragment = std::mem::take(&mut self.cur_fragment);
-            let context = self.context.clone();
-            let send = self.reply_send_channel.clone();
-            tokio::spawn(async move {
-                let mut write_buf: Vec<u8> = Vec::new();
-                let mut write_cursor = Cursor::new(&mut write_buf);
+            let record_reservations = std::mem::take(&mut self.cur_reservations);
+            // wait for a request to complete before taking on another
+            let permit = self.in_flight.clone().acquire_owned().await?;
+            let mut context = self.context.clone();
+            context.record_len = fragment.len();
+            let pending = PendingReply {
+                send: self.reply_send_channel.clone(),
+                permit: Some(permit),
+                order: self
+                    .reply_order
+                    .as_ref()
+                    .map(|order| (order.clone(), self.next_seq)),
+            };
+            self.next_seq += 1;
+            let budget = self.context.memory_budget.clone();
+            let capture_to = self
+                .context
+                .capture
+                .take(self.context.client_host())
+                .map(|log| (log, fragment.clone()));
+            let streaming = context.streamed_replies && capture_to.is_none();
+            let stream = ReplyStream::new(pending, budget, streaming);
+            context.reply_stream = Some(stream.clone());
+            self.context.runtime.spawn(async move {
                 let maybe_reply =
-                    handle_rpc(&mut Cursor::new(fragment), &mut write_cursor, context).await;
+                    handle_rpc(&mut Cursor::new(fragment), &mut stream.clone(), context).await;
+                drop(record_reservations);
                 match maybe_reply {
                     Err(e) => {
-                        error!("RPC Error: {:?}", e);
-                        let _ = send.send(Err(e));
-                    }
-                    Ok(_) => {
-                        let _ = std::io::Write::flush(&mut write_cursor);
-                        let _ = send.send(Ok(write_buf));
+                        error!(target: "nfsserve::rpc", "RPC Error: {:?}", e);
+                        if let Some((log, call)) = capture_to {
+                            capture::append_exchange(&log, &call, &[]);
+                        }
+                        stream.fail(e).await;
                     }
+                    Ok(_) => stream.finish(capture_to).await,
                 }
             });
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    /// Writes buf as a record, and returns the fragment lengths and last
+    /// flags read back, and the record they make up
+    fn round_trip(buf: &[u8]) -> (Vec<(usize, bool)>, Vec<u8>) {
+        let mut wire: Vec<u8> = Vec::new();
+        block_on(write_fragment(&mut wire, &[buf])).unwrap();
+        read_back(&wire)
+    }
+
+    /// The fragment lengths and last flags of a record on the wire, and
+    /// the record they make up
+    fn read_back(wire: &[u8]) -> (Vec<(usize, bool)>, Vec<u8>) {
+        block_on(async {
+            let mut fragments = Vec::new();
+            let mut pos = 0;
+            while pos < wire.len() {
+                let header = u32::from_be_bytes(wire[pos..pos + 4].try_into().unwrap());
+                let len = (header & ((1 << 31) - 1)) as usize;
+                fragments.push((len, header & (1 << 31) != 0));
+                pos += 4 + len;
+            }
+            assert_eq!(pos, wire.len());
+
+            let mut record = Vec::new();
+            let mut src = wire;
+            while !read_fragment(&mut src, &mut record, usize::MAX, |_| Ok(()))
+                .await
+                .unwrap()
+            {}
+            assert!(src.is_empty());
+            (fragments, record)
+        })
+    }
+
+    #[test]
+    fn large_records_are_split() {
+        let buf: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|i| (i % 251) as u8).collect();
+        let (fragments, record) = round_trip(&buf);
+        assert_eq!(
+            fragments,
+            [
+                (MAX_FRAGMENT_SIZE, false),
+                (MAX_FRAGMENT_SIZE, false),
+                (MAX_FRAGMENT_SIZE, false),
+                (5, true)
+            ]
+        );
+        assert!(record == buf);
+    }
+
+    #[test]
+    fn oversized_fragments_are_refused_before_allocation() {
+        block_on(async {
+            // the largest fragment length, with no data behind it
+            let wire = u32::to_be_bytes(0xffff_ffff);
+            let mut record = Vec::new();
+            let err = read_fragment(&mut &wire[..], &mut record, 1 << 20, |_| Ok(()))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("exceeds"), "{err}");
+            assert_eq!(record.capacity(), 0);
+
+            // within the cap, but refused by the budget
+            let wire = u32::to_be_bytes(0x8000_1000);
+            let err = read_fragment(&mut &wire[..], &mut record, 1 << 20, |len| {
+                assert_eq!(len, 0x1000);
+                Err(anyhow!("no budget"))
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(err.to_string(), "no budget");
+            assert_eq!(record.capacity(), 0);
+
+            // the cap counts the fragments already read
+            let mut wire = u32::to_be_bytes(4).to_vec();
+            wire.extend_from_slice(b"abcd");
+            wire.extend_from_slice(&u32::to_be_bytes(0x8000_0000 | 8));
+            let mut src = &wire[..];
+            assert!(!read_fragment(&mut src, &mut record, 10, |_| Ok(()))
+                .await
+                .unwrap());
+            assert!(read_fragment(&mut src, &mut record, 10, |_| Ok(()))
+                .await
+                .is_err());
+            assert_eq!(record, b"abcd");
+        })
+    }
+
+    #[test]
+    fn small_and_empty_records() {
+        let (fragments, record) = round_trip(b"reply");
+        assert_eq!(fragments, [(5, true)]);
+        assert_eq!(record, b"reply");
+
+        let (fragments, record) = round_trip(&[]);
+        assert_eq!(fragments, [(0, true)]);
+        assert!(record.is_empty());
+
+        let buf = vec![1u8; MAX_FRAGMENT_SIZE];
+        let (fragments, record) = round_trip(&buf);
+        assert_eq!(fragments, [(MAX_FRAGMENT_SIZE, true)]);
+        assert!(record == buf);
+    }
+
+    /// Takes at most 5 bytes a write, across the buffers of a vectored one
+    struct Trickle(Vec<u8>);
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let n = buf.len().min(5);
+            self.0.extend_from_slice(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+            bufs: &[IoSlice],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(5 - n);
+                self.0.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn parts_are_written_as_one_buffer() {
+        let buf: Vec<u8> = (0..2 * MAX_FRAGMENT_SIZE + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut whole: Vec<u8> = Vec::new();
+        block_on(write_fragment(&mut whole, &[&buf])).unwrap();
+        // cut anywhere, across fragment boundaries, and into empty parts
+        let m = MAX_FRAGMENT_SIZE;
+        for cuts in [[0, 0, 7], [3, m - 3, m], [m + 1, m + 1, 2 * m + 9]] {
+            let parts = [
+                &buf[..cuts[0]],
+                &buf[cuts[0]..cuts[1]],
+                &buf[cuts[1]..cuts[2]],
+                &buf[cuts[2]..],
+            ];
+            let mut wire: Vec<u8> = Vec::new();
+            block_on(write_fragment(&mut wire, &parts)).unwrap();
+            assert!(wire == whole, "cut at {cuts:?}");
+        }
+
+        // a socket taking a few bytes at a time
+        let parts: [&[u8]; 4] = [b"head", b"", b"data of a read", b"\0\0"];
+        let mut trickle = Trickle(Vec::new());
+        block_on(write_fragment(&mut trickle, &parts)).unwrap();
+        let (fragments, record) = read_back(&trickle.0);
+        assert_eq!(fragments, [(20, true)]);
+        assert_eq!(record, b"headdata of a read\0\0");
+    }
+
+    #[test]
+    fn streamed_records() {
+        let budget = Arc::new(MemoryBudget::unlimited());
+        let chunk = |data: &[u8], last| ReplyChunk {
+            data: vec![ReplyPart::new(data.to_vec())],
+            last,
+            _reservation: budget.reserve(data.len()),
+        };
+        let (send, mut recv) = mpsc::channel(4);
+        block_on(async {
+            send.send(chunk(b"long", false)).await.unwrap();
+            send.send(chunk(b"", false)).await.unwrap();
+            send.send(chunk(b" reply", false)).await.unwrap();
+            send.send(chunk(b"", true)).await.unwrap();
+        });
+        let mut wire: Vec<u8> = Vec::new();
+        block_on(write_streamed(&mut wire, &mut recv)).unwrap();
+        // the empty chunk before the last is not a fragment
+        let (fragments, record) = read_back(&wire);
+        assert_eq!(fragments, [(4, false), (6, false), (0, true)]);
+        assert_eq!(record, b"long reply");
+
+        // the handler failed part way
+        block_on(send.send(chunk(b"part", false))).unwrap();
+        drop(send);
+        let mut wire: Vec<u8> = Vec::new();
+        assert!(block_on(write_streamed(&mut wire, &mut recv)).is_err());
+    }
+}
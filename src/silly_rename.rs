@@ -0,0 +1,75 @@
+//! Tracking of the files clients "silly rename" on delete-while-open
+use crate::nfs::fileid3;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The number of silly renamed files remembered. Past this the oldest
+/// records are dropped, which at worst shows a file to other clients.
+const MAX_TRACKED: usize = 65536;
+
+/// Returns true if name is of the form clients give to a file removed
+/// while open: ".nfs" followed by hex digits (Linux uses the fileid and
+/// a counter, e.g. ".nfs000000000012d68700000001")
+pub fn is_silly_rename(name: &[u8]) -> bool {
+    name.len() > 4 && name.starts_with(b".nfs") && name[4..].iter().all(u8::is_ascii_hexdigit)
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// (directory, name) -> the client host which renamed it
+    owners: BTreeMap<(fileid3, Vec<u8>), String>,
+    /// insertion order, for eviction
+    order: Vec<(fileid3, Vec<u8>)>,
+}
+
+/// Remembers which client silly renamed which file, so that the file can
+/// be hidden from the directory listings of other clients. See
+/// NFSTcp::set_hide_silly_renames.
+#[derive(Debug, Default)]
+pub struct SillyRenames {
+    inner: Mutex<Inner>,
+}
+
+impl SillyRenames {
+    /// Records that client renamed a file to name in dirid
+    pub fn record(&self, dirid: fileid3, name: &[u8], client: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (dirid, name.to_vec());
+        if inner
+            .owners
+            .insert(key.clone(), client.to_string())
+            .is_none()
+        {
+            inner.order.push(key);
+        }
+        if inner.order.len() > MAX_TRACKED {
+            let excess = inner.order.len() - MAX_TRACKED;
+            for key in inner.order.drain(..excess).collect::<Vec<_>>() {
+                inner.owners.remove(&key);
+            }
+        }
+    }
+
+    /// Forgets name in dirid, once it is removed or renamed away
+    pub fn forget(&self, dirid: fileid3, name: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (dirid, name.to_vec());
+        if inner.owners.remove(&key).is_some() {
+            inner.order.retain(|k| *k != key);
+        }
+    }
+
+    /// Returns true if name in dirid should be hidden from client: it is
+    /// a silly rename by some other client, or by a client not known
+    /// (e.g. before a server restart)
+    pub fn hidden_from(&self, dirid: fileid3, name: &[u8], client: &str) -> bool {
+        if !is_silly_rename(name) {
+            return false;
+        }
+        let inner = self.inner.lock().unwrap();
+        inner
+            .owners
+            .get(&(dirid, name.to_vec()))
+            .map_or(true, |owner| owner != client)
+    }
+}
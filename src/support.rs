@@ -0,0 +1,166 @@
+//! Which procedures of the served programs are supported.
+//!
+//! The table is built from the same procedure enums the handlers dispatch
+//! on. Each handler module classifies its procedures in a match without a
+//! wildcard arm, next to its (also exhaustive) dispatch match, so a
+//! procedure cannot be dispatched without being listed here or the
+//! reverse.
+use std::fmt;
+
+/// How completely a procedure is implemented
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Support {
+    /// Implemented as the RFC describes
+    Full,
+    /// Answered, with the limitations given in the notes
+    Partial,
+    /// Replied PROC_UNAVAIL
+    Unsupported,
+}
+
+impl fmt::Display for Support {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Support::Full => write!(f, "Full"),
+            Support::Partial => write!(f, "Partial"),
+            Support::Unsupported => write!(f, "Unsupported"),
+        }
+    }
+}
+
+/// The support of one procedure of a served program
+#[derive(Clone, Debug)]
+pub struct ProcedureSupport {
+    /// The program, e.g. "NFSv3"
+    pub program: &'static str,
+    /// The procedure number within the program
+    pub number: u32,
+    /// The procedure name as in the RFC, e.g. "NFSPROC3_READ"
+    pub procedure: String,
+    pub support: Support,
+    /// The limitations of a Partial procedure. May be empty
+    pub notes: &'static str,
+}
+
+/// Lists every procedure of the MOUNT, NFSv3, NLMv4 and PORTMAP programs
+/// (and NFSACLv3 and METADATAv1 with the nfsacl and metadata features) with
+/// its support, ordered by program then procedure number.
+///
+/// Some of the support depends on the NFSFileSystem served: procedures
+/// whose VFS method is optional reply NFS3ERR_NOTSUPP (or ROFS) when it
+/// is not implemented.
+pub fn matrix() -> Vec<ProcedureSupport> {
+    let mut ret = Vec::new();
+    #[cfg(feature = "metadata")]
+    ret.extend(crate::metadata_handlers::support_matrix());
+    ret.extend(crate::mount_handlers::support_matrix());
+    #[cfg(feature = "nfsacl")]
+    ret.extend(crate::nfsacl_handlers::support_matrix());
+    ret.extend(crate::nfs_handlers::support_matrix());
+    ret.extend(crate::nlm_handlers::support_matrix());
+    ret.extend(crate::portmap_handlers::support_matrix());
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::RPCContext;
+    use crate::memfs::MemFS;
+    use crate::registry::ProgramRegistry;
+    use crate::rpc::*;
+    use crate::xdr::XDR;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    /// The RPC program and version of a program of the matrix
+    fn program(name: &str) -> (u32, u32) {
+        match name {
+            "MOUNT" => (crate::mount::PROGRAM, crate::mount::VERSION),
+            "NFSv3" => (crate::nfs::PROGRAM, crate::nfs::VERSION),
+            "NLMv4" => (crate::nlm::PROGRAM, crate::nlm::VERSION),
+            "PORTMAP" => (crate::portmap::PROGRAM, crate::portmap::VERSION),
+            #[cfg(feature = "nfsacl")]
+            "NFSACLv3" => (crate::nfsacl::PROGRAM, crate::nfsacl::VERSION),
+            #[cfg(feature = "metadata")]
+            "METADATAv1" => (crate::metadata::PROGRAM, crate::metadata::VERSION),
+            _ => panic!("unknown program {name}"),
+        }
+    }
+
+    /// Dispatches a call of proc without arguments, and returns the
+    /// accept_stat of the reply
+    async fn dispatch(context: &RPCContext, prog: u32, vers: u32, proc: u32) -> accept_body {
+        let call = rpc_msg {
+            xid: 1,
+            body: rpc_body::CALL(call_body {
+                rpcvers: 2,
+                prog,
+                vers,
+                proc,
+                cred: opaque_auth::default(),
+                verf: opaque_auth::default(),
+            }),
+        };
+        let mut input = Vec::new();
+        call.serialize(&mut input).unwrap();
+        let mut output = Vec::new();
+        let res =
+            crate::rpcwire::handle_rpc(&mut Cursor::new(input), &mut output, context.clone()).await;
+        if res.is_err() {
+            // failed part way through a reply, which PROC_UNAVAIL is not
+            return accept_body::SUCCESS;
+        }
+        let mut reply = rpc_msg::default();
+        reply.deserialize(&mut Cursor::new(output)).unwrap();
+        match reply.body {
+            rpc_body::REPLY(reply_body::MSG_ACCEPTED(accepted)) => accepted.reply_data,
+            body => panic!("{prog}/{proc} replied {body:?}"),
+        }
+    }
+
+    #[test]
+    fn every_served_program_is_listed() {
+        let listed: BTreeSet<(u32, u32)> = matrix().iter().map(|p| program(p.program)).collect();
+        let served: BTreeSet<(u32, u32)> =
+            ProgramRegistry::with_default_programs().iter().collect();
+        assert_eq!(listed, served);
+    }
+
+    #[test]
+    fn unsupported_is_what_dispatch_does_not_serve() {
+        block_on(async {
+            let context = RPCContext::for_tests(Arc::new(MemFS::new()));
+            let mut ends = BTreeMap::new();
+            for p in matrix() {
+                let (prog, vers) = program(p.program);
+                let reply = dispatch(&context, prog, vers, p.number).await;
+                assert_eq!(
+                    p.support == Support::Unsupported,
+                    matches!(reply, accept_body::PROC_UNAVAIL),
+                    "{} {} is {} and replied {:?}",
+                    p.program,
+                    p.procedure,
+                    p.support,
+                    reply
+                );
+                let end = ends.entry((prog, vers)).or_insert(0);
+                *end = (*end).max(p.number + 1);
+            }
+            // and nothing past the procedures listed is served
+            for ((prog, vers), end) in ends {
+                let reply = dispatch(&context, prog, vers, end).await;
+                assert!(matches!(reply, accept_body::PROC_UNAVAIL), "{prog}/{end}");
+            }
+        });
+    }
+}
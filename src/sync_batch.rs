@@ -0,0 +1,374 @@
+//! Batched syncing of small writes, for path backed file systems.
+use crate::nfs::fileid3;
+use crate::vfs::discard_unstable_writes;
+use futures::future::{join_all, BoxFuture};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tracing::{debug, warn};
+
+/// When a SyncBatcher syncs the files written since its last batch
+#[derive(Clone, Copy, Debug)]
+pub struct SyncBatchConfig {
+    /// Writes of more bytes than this are not batched, and are synced as
+    /// the write asks
+    pub max_write: usize,
+    /// A batch is synced once this many files were written,
+    pub max_files: usize,
+    /// or this many bytes,
+    pub max_bytes: usize,
+    /// or once its first write is this old
+    pub max_delay: Duration,
+}
+
+impl Default for SyncBatchConfig {
+    fn default() -> SyncBatchConfig {
+        SyncBatchConfig {
+            max_write: 64 * 1024,
+            max_files: 256,
+            max_bytes: 16 * 1024 * 1024,
+            max_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+type SyncFn = Box<dyn Fn(fileid3) -> BoxFuture<'static, io::Result<()>> + Send + Sync>;
+
+/// Syncs the small UNSTABLE writes of a file system from a background
+/// task, a batch of files at a time, instead of leaving every file to a
+/// sync of its own (at its COMMIT, or before each reply of a FILE_SYNC
+/// write). Unpacking an archive of many small files otherwise waits on
+/// one sync per file.
+///
+/// The file system calls add after each UNSTABLE write, and flush where
+/// it must sync a file before replying: on COMMIT, and before changing
+/// the size of a file with batched writes. A flush waits for a batch
+/// syncing the file to end, then syncs the file itself.
+///
+/// A failed batched sync calls vfs::discard_unstable_writes, which
+/// changes the write verifier so that clients send their uncommitted
+/// writes again, and the next flush of each file of the batch fails.
+/// The failure is reported to that one flush.
+pub struct SyncBatcher {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    config: SyncBatchConfig,
+    sync: SyncFn,
+    state: Mutex<BatchState>,
+    /// Wakes the batch task on the first write of a batch and once a
+    /// batch is full
+    wake: Notify,
+    /// Counts the batches synced. flush waits on it for a batch in flight.
+    synced: watch::Sender<u64>,
+}
+
+#[derive(Default)]
+struct BatchState {
+    /// The files written since the last batch was taken
+    dirty: BTreeSet<fileid3>,
+    /// The bytes written to them
+    bytes: usize,
+    /// When the first write of dirty was added
+    since: Option<Instant>,
+    /// The files of the batch being synced
+    in_flight: BTreeSet<fileid3>,
+    /// The files whose batched sync failed, until their next flush
+    failed: BTreeSet<fileid3>,
+    /// Set once the batch task is spawned
+    started: bool,
+    /// Set when the batcher is dropped
+    closed: bool,
+}
+
+impl BatchState {
+    fn is_due(&self, config: &SyncBatchConfig) -> bool {
+        self.closed
+            || self.dirty.len() >= config.max_files
+            || self.bytes >= config.max_bytes
+            || self
+                .since
+                .is_some_and(|since| since.elapsed() >= config.max_delay)
+    }
+}
+
+impl SyncBatcher {
+    /// A batcher calling sync to sync each file of a batch. The batch
+    /// task is spawned by the first add, on the runtime it is called
+    /// from.
+    pub fn new<F, Fut>(config: SyncBatchConfig, sync: F) -> SyncBatcher
+    where
+        F: Fn(fileid3) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            config,
+            sync: Box::new(move |id| Box::pin(sync(id))),
+            state: Mutex::default(),
+            wake: Notify::new(),
+            synced: watch::Sender::new(0),
+        });
+        SyncBatcher { shared }
+    }
+
+    pub fn config(&self) -> &SyncBatchConfig {
+        &self.shared.config
+    }
+
+    /// Adds a write of len bytes to id, which is done but not synced, to
+    /// the next batch. Returns false if the write is too large to be
+    /// batched, in which case the caller is left to sync it.
+    pub fn add(&self, id: fileid3, len: usize) -> bool {
+        let config = &self.shared.config;
+        if len > config.max_write {
+            return false;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if !state.started {
+            state.started = true;
+            tokio::spawn(run_batches(self.shared.clone()));
+        }
+        state.dirty.insert(id);
+        state.bytes = state.bytes.saturating_add(len);
+        if state.since.is_none() {
+            // start the delay of the batch
+            state.since = Some(Instant::now());
+            self.shared.wake.notify_one();
+        } else if state.is_due(config) {
+            self.shared.wake.notify_one();
+        }
+        true
+    }
+
+    /// Syncs id now. Fails if the sync fails, or if a batched sync of id
+    /// failed since its last flush.
+    pub async fn flush(&self, id: fileid3) -> io::Result<()> {
+        let mut synced = self.shared.synced.subscribe();
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if !state.in_flight.contains(&id) {
+                    state.dirty.remove(&id);
+                    if state.failed.remove(&id) {
+                        return Err(io::Error::other(
+                            "a batched sync of the file failed, writes may be lost",
+                        ));
+                    }
+                    break;
+                }
+            }
+            // a batched sync of id is running, and whether it fails
+            // matters to this flush
+            if synced.changed().await.is_err() {
+                break;
+            }
+        }
+        (self.shared.sync)(id).await
+    }
+}
+
+impl Drop for SyncBatcher {
+    fn drop(&mut self) {
+        // the batch task syncs what is left, then ends
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.wake.notify_one();
+    }
+}
+
+/// The batch task
+async fn run_batches(shared: Arc<Shared>) {
+    let config = shared.config;
+    loop {
+        let deadline = {
+            let state = shared.state.lock().unwrap();
+            if state.closed && state.dirty.is_empty() {
+                return;
+            }
+            (!state.closed).then_some(state.since.map(|since| since + config.max_delay))
+        };
+        match deadline {
+            // nothing written yet
+            Some(None) => shared.wake.notified().await,
+            Some(Some(deadline)) => {
+                let deadline = tokio::time::Instant::from_std(deadline);
+                let _ = tokio::time::timeout_at(deadline, shared.wake.notified()).await;
+            }
+            None => {}
+        }
+        let batch = {
+            let mut state = shared.state.lock().unwrap();
+            if state.dirty.is_empty() {
+                // flushed meanwhile
+                state.since = None;
+                state.bytes = 0;
+                continue;
+            }
+            if !state.is_due(&config) {
+                continue;
+            }
+            state.since = None;
+            state.bytes = 0;
+            let batch = std::mem::take(&mut state.dirty);
+            state.in_flight.clone_from(&batch);
+            batch
+        };
+        debug!(target: "nfsserve::sync_batch", "syncing a batch of {} files", batch.len());
+        let results = join_all(batch.iter().map(|id| (shared.sync)(*id))).await;
+        let failed: Vec<fileid3> = batch
+            .iter()
+            .zip(results)
+            .filter_map(|(id, res)| {
+                res.inspect_err(|e| {
+                    warn!(target: "nfsserve::sync_batch", "batched sync of {} failed: {:?}", id, e)
+                })
+                .err()
+                .map(|_| *id)
+            })
+            .collect();
+        if !failed.is_empty() {
+            // the writes of the batch may be gone
+            discard_unstable_writes();
+        }
+        {
+            let mut state = shared.state.lock().unwrap();
+            state.in_flight.clear();
+            state.failed.extend(failed);
+        }
+        shared.synced.send_modify(|n| *n += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::default_write_verifier;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    /// Records the syncs, which fail while fail is set
+    #[derive(Default)]
+    struct Disk {
+        syncs: Mutex<Vec<fileid3>>,
+        fail: AtomicBool,
+    }
+
+    impl Disk {
+        fn batcher(self: &Arc<Self>, config: SyncBatchConfig) -> SyncBatcher {
+            let disk = self.clone();
+            SyncBatcher::new(config, move |id| {
+                let disk = disk.clone();
+                async move {
+                    disk.syncs.lock().unwrap().push(id);
+                    if disk.fail.load(Ordering::SeqCst) {
+                        return Err(io::Error::other("injected sync failure"));
+                    }
+                    Ok(())
+                }
+            })
+        }
+
+        fn syncs(&self) -> Vec<fileid3> {
+            self.syncs.lock().unwrap().clone()
+        }
+    }
+
+    const NEVER: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn full_batches_are_synced() {
+        block_on(async {
+            let disk = Arc::new(Disk::default());
+            let batcher = disk.batcher(SyncBatchConfig {
+                max_write: 100,
+                max_files: 3,
+                max_bytes: usize::MAX,
+                max_delay: NEVER,
+            });
+            // too large to batch
+            assert!(!batcher.add(fileid3(9), 101));
+            for id in 1..=3 {
+                assert!(batcher.add(fileid3(id), 10));
+            }
+            while disk.syncs().len() < 3 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(disk.syncs(), vec![fileid3(1), fileid3(2), fileid3(3)]);
+        });
+    }
+
+    #[test]
+    fn old_batches_are_synced() {
+        block_on(async {
+            let disk = Arc::new(Disk::default());
+            let batcher = disk.batcher(SyncBatchConfig {
+                max_delay: Duration::from_millis(20),
+                ..Default::default()
+            });
+            assert!(batcher.add(fileid3(1), 10));
+            assert!(batcher.add(fileid3(1), 10));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert_eq!(disk.syncs(), vec![fileid3(1)]);
+        });
+    }
+
+    #[test]
+    fn failed_batches_fail_the_next_flush() {
+        block_on(async {
+            let disk = Arc::new(Disk::default());
+            let batcher = disk.batcher(SyncBatchConfig {
+                max_files: 2,
+                max_delay: NEVER,
+                ..Default::default()
+            });
+            let verf = default_write_verifier();
+            disk.fail.store(true, Ordering::SeqCst);
+            batcher.add(fileid3(1), 10);
+            batcher.add(fileid3(2), 10);
+            while disk.syncs().len() < 2 {
+                tokio::task::yield_now().await;
+            }
+            disk.fail.store(false, Ordering::SeqCst);
+            // flush waits for the batch to end
+            assert!(batcher.flush(fileid3(1)).await.is_err());
+            assert_ne!(default_write_verifier(), verf);
+            // reported once
+            assert!(batcher.flush(fileid3(1)).await.is_ok());
+            // and to every file of the batch
+            assert!(batcher.flush(fileid3(2)).await.is_err());
+            // files which were not in it are unaffected
+            assert!(batcher.flush(fileid3(3)).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn flush_syncs_now() {
+        block_on(async {
+            let disk = Arc::new(Disk::default());
+            let batcher = disk.batcher(SyncBatchConfig {
+                max_delay: NEVER,
+                ..Default::default()
+            });
+            batcher.add(fileid3(1), 10);
+            batcher.flush(fileid3(1)).await.unwrap();
+            assert_eq!(disk.syncs(), vec![fileid3(1)]);
+            // and takes the file out of the batch
+            drop(batcher);
+            tokio::task::yield_now().await;
+            assert_eq!(disk.syncs(), vec![fileid3(1)]);
+        });
+    }
+}
@@ -1,15 +1,211 @@
+use crate::capture::CaptureRegistry;
+use crate::config::NFSServerConfig;
 use crate::context::RPCContext;
+use crate::exports::ExportTable;
+use crate::getport_cache::GetportCache;
+use crate::memory_budget::MemoryBudget;
+pub use crate::mount::mountstat3;
+use crate::nfs::{fattr3, fileid3, ftype3, sattr3, set_gid3, set_uid3};
+use crate::readdir_estimate::EntrySizeEstimator;
+use crate::registry::ProgramRegistry;
+pub use crate::rpc::{
+    auth_flavor, auth_stat, opaque_auth, rpc_gss_cred, rpc_gss_proc_t, rpc_gss_service_t,
+};
 use crate::rpcwire::*;
-use crate::vfs::NFSFileSystem;
+use crate::silly_rename::SillyRenames;
+use crate::vfs::{HandleCodec, NFSFileSystem, UserContext};
 use anyhow;
 use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::{io, net::IpAddr};
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+/// A policy deciding which mounts are allowed, given the object being
+/// mounted. See NFSTcp::set_mount_authorizer.
+#[async_trait]
+pub trait MountAuthorizer: Send + Sync {
+    /// Called on each MNT after the path has been resolved to fileid (with
+    /// attributes attrs), before the reply. An error is replied to the
+    /// client as is, and the mount is neither counted nor signalled. This
+    /// sees files too: those it allows are refused with MNT3ERR_NOTDIR.
+    async fn authorize_mount(
+        &self,
+        client: SocketAddr,
+        path: &[u8],
+        fileid: fileid3,
+        attrs: &fattr3,
+    ) -> Result<(), mountstat3>;
+}
+
+/// A policy rewriting the targets of symlinks as READLINK replies them.
+/// See NFSTcp::set_symlink_rewriter.
+pub trait SymlinkRewriter: Send + Sync {
+    /// Called on each READLINK with the target read from the file system
+    /// and the directory holding the link. Returns the target to reply
+    /// instead, or None to reply it as is.
+    fn rewrite_symlink_target(&self, target: &[u8], link_dir: fileid3) -> Option<Vec<u8>>;
+}
+
+/// What an AuthHandler decided about an RPCSEC_GSS call it accepted
+#[derive(Clone, Debug, Default)]
+pub struct GssAccepted {
+    /// The credentials of the context's principal, as vfs::current_user
+    /// returns them (after squashing). None leaves the caller anonymous.
+    pub user: Option<UserContext>,
+    /// The verifier of the reply. For RPCSEC_GSS_DATA calls RFC 2203
+    /// makes this the GSS-API checksum of the sequence number.
+    pub verf: opaque_auth,
+}
+
+/// The security mechanism behind RPCSEC_GSS (RFC 2203) credentials,
+/// typically Kerberos through a GSS-API library. See
+/// NFSTcp::set_auth_handler.
+///
+/// Only the rpc_gss_svc_none service is passed to the handler: calls
+/// asking for integrity or privacy protection of their arguments are
+/// refused with AUTH_BADCRED, as their arguments cannot be unwrapped.
+#[async_trait]
+pub trait AuthHandler: Send + Sync {
+    /// Checks an RPCSEC_GSS_DATA call made under an established context.
+    /// header is the XDR encoding of the call from the xid up to and
+    /// including the credential, of which verf should be the checksum.
+    /// An error is replied to the client as AUTH_ERROR and the call is
+    /// not served.
+    async fn authenticate(
+        &self,
+        client: SocketAddr,
+        cred: &rpc_gss_cred,
+        header: &[u8],
+        verf: &opaque_auth,
+    ) -> Result<GssAccepted, auth_stat>;
+
+    /// Serves RPCSEC_GSS_INIT, RPCSEC_GSS_CONTINUE_INIT and
+    /// RPCSEC_GSS_DESTROY calls, which create and destroy contexts. args
+    /// is the rest of the call after the verifier (the GSS-API token of
+    /// the INIT calls). Returns the XDR encoded results (rpc_gss_init_res
+    /// for the INIT calls, nothing for DESTROY) and the reply verifier.
+    async fn control(
+        &self,
+        client: SocketAddr,
+        cred: &rpc_gss_cred,
+        header: &[u8],
+        verf: &opaque_auth,
+        args: &[u8],
+    ) -> Result<(Vec<u8>, opaque_auth), auth_stat>;
+}
+
+/// How the credentials of callers are mapped before they reach the file
+/// system. The equivalent of the root_squash / all_squash export options.
+/// See NFSTcp::set_squash.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub enum SquashMode {
+    /// Credentials are used as sent
+    #[default]
+    NoSquash,
+    /// Calls from uid 0 are mapped to anon_uid, and gid 0 (as the primary
+    /// or a supplementary group) to anon_gid
+    RootSquash { anon_uid: u32, anon_gid: u32 },
+    /// All calls are mapped to anon_uid / anon_gid
+    AllSquash { anon_uid: u32, anon_gid: u32 },
+}
+
+impl SquashMode {
+    /// Maps the credentials of a call. Returns true if they were changed
+    pub(crate) fn apply(&self, auth: &mut crate::rpc::auth_unix) -> bool {
+        match *self {
+            SquashMode::NoSquash => false,
+            SquashMode::RootSquash { anon_uid, anon_gid } => {
+                let squashed = auth.uid == 0 || auth.gid == 0 || auth.gids.contains(&0);
+                if auth.uid == 0 {
+                    auth.uid = anon_uid;
+                }
+                if auth.gid == 0 {
+                    auth.gid = anon_gid;
+                }
+                for gid in auth.gids.iter_mut().filter(|g| **g == 0) {
+                    *gid = anon_gid;
+                }
+                squashed
+            }
+            SquashMode::AllSquash { anon_uid, anon_gid } => {
+                auth.uid = anon_uid;
+                auth.gid = anon_gid;
+                auth.gids.clear();
+                true
+            }
+        }
+    }
+
+    /// Maps the credentials of a caller as seen by vfs::current_user.
+    /// Returns the mapped credentials, and true if they were changed
+    pub(crate) fn apply_user(&self, user: Option<UserContext>) -> (Option<UserContext>, bool) {
+        match user {
+            Some(mut user) => {
+                let mut auth = crate::rpc::auth_unix {
+                    uid: user.uid,
+                    gid: user.gid,
+                    gids: std::mem::take(&mut user.gids),
+                    ..Default::default()
+                };
+                let squashed = self.apply(&mut auth);
+                user.uid = auth.uid;
+                user.gid = auth.gid;
+                user.gids = auth.gids;
+                (Some(user), squashed)
+            }
+            None => match self.anonymous() {
+                Some((uid, gid)) => (
+                    Some(UserContext {
+                        uid,
+                        gid,
+                        ..Default::default()
+                    }),
+                    true,
+                ),
+                None => (None, false),
+            },
+        }
+    }
+
+    /// The credentials of calls without AUTH_UNIX credentials, if mapped
+    pub(crate) fn anonymous(&self) -> Option<(u32, u32)> {
+        match *self {
+            SquashMode::NoSquash => None,
+            SquashMode::RootSquash { anon_uid, anon_gid }
+            | SquashMode::AllSquash { anon_uid, anon_gid } => Some((anon_uid, anon_gid)),
+        }
+    }
+
+    /// Maps the owner set by a squashed caller, so that it cannot give
+    /// files away to root (or, with AllSquash, to anyone)
+    pub(crate) fn apply_sattr3(&self, attr: &mut sattr3) {
+        let (anon_uid, anon_gid, all) = match *self {
+            SquashMode::NoSquash => return,
+            SquashMode::RootSquash { anon_uid, anon_gid } => (anon_uid, anon_gid, false),
+            SquashMode::AllSquash { anon_uid, anon_gid } => (anon_uid, anon_gid, true),
+        };
+        if let set_uid3::uid(uid) = attr.uid {
+            if all || uid == 0 {
+                attr.uid = set_uid3::uid(anon_uid);
+            }
+        }
+        if let set_gid3::gid(gid) = attr.gid {
+            if all || gid == 0 {
+                attr.gid = set_gid3::gid(anon_gid);
+            }
+        }
+    }
+}
 
 /// A NFS Tcp Connection Handler
 pub struct NFSTcpListener<T: NFSFileSystem + Send + Sync + 'static> {
@@ -17,6 +213,25 @@ pub struct NFSTcpListener<T: NFSFileSystem + Send + Sync + 'static> {
     port: u16,
     arcfs: Arc<T>,
     mount_signal: Option<mpsc::Sender<bool>>,
+    programs: Arc<RwLock<ProgramRegistry>>,
+    getport: Arc<GetportCache>,
+    runtime: tokio::runtime::Handle,
+    /// true if the IP was automatically selected ("auto" binding)
+    auto_ip: bool,
+    memory_budget: Arc<MemoryBudget>,
+    mounts: Arc<Mutex<BTreeMap<String, BTreeSet<Vec<u8>>>>>,
+    capture: Arc<CaptureRegistry>,
+    exports: Arc<Vec<Vec<u8>>>,
+    readdir_sizes: Arc<EntrySizeEstimator>,
+    mount_authorizer: Option<Arc<dyn MountAuthorizer>>,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
+    symlink_rewriter: Option<Arc<dyn SymlinkRewriter>>,
+    silly_renames: Arc<SillyRenames>,
+    /// The key file handles are signed with, if config.sign_handles
+    handle_key: Option<Arc<[u8]>>,
+    /// True if handle_key was given rather than random
+    handle_key_kept: bool,
+    config: NFSServerConfig,
 }
 
 pub fn generate_host_ip(hostnum: u16) -> String {
@@ -27,39 +242,92 @@ pub fn generate_host_ip(hostnum: u16) -> String {
     )
 }
 
+/// The accept backlog used by NFSTcpListener::bind
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// The number of requests of a connection in flight at a time, unless
+/// set with NFSTcp::set_max_requests_per_connection. Clients which
+/// pipeline more are slowed down, not refused.
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 128;
+
+/// TcpListener::bind with a given backlog. Tries every address ipstr
+/// resolves to, like TcpListener::bind does.
+async fn listen_with_backlog(ipstr: &str, backlog: u32) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(ipstr).await? {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // as TcpListener::bind does
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+        match socket.bind(addr).and_then(|_| socket.listen(backlog)) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
 /// processes an established socket
 async fn process_socket(
     mut socket: tokio::net::TcpStream,
     context: RPCContext,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), anyhow::Error> {
     let (mut message_handler, mut socksend, mut msgrecvchan) = SocketMessageHandler::new(&context);
     let _ = socket.set_nodelay(true);
 
-    tokio::spawn(async move {
+    context.runtime.spawn(async move {
         loop {
             if let Err(e) = message_handler.read().await {
-                debug!("Message loop broken due to {:?}", e);
+                debug!(target: "nfsserve::tcp", "Message loop broken due to {:?}", e);
                 break;
             }
         }
     });
+    let mut buf = vec![0; 128000];
+    // the bytes of buf read from the socket but not yet passed on
+    let mut pending = 0..0;
     loop {
         tokio::select! {
-            _ = socket.readable() => {
-                let mut buf = [0; 128000];
-
+            // the listener is shutting down (or gone)
+            _ = shutdown.changed() => {
+                debug!(target: "nfsserve::tcp", "Closing connection for shutdown");
+                return Ok(());
+            },
+            // Pass what was read on as the message handler takes it. It
+            // stops taking records while the connection has too many
+            // requests in flight, and then we stop reading the socket.
+            // Replies keep being written meanwhile.
+            written = socksend.write(&buf[pending.clone()]), if !pending.is_empty() => {
+                match written {
+                    Ok(n) => pending.start += n,
+                    Err(e) => {
+                        debug!(target: "nfsserve::tcp", "Message handling closed : {:?}", e);
+                        return Err(e.into());
+                    }
+                }
+            },
+            _ = socket.readable(), if pending.is_empty() => {
                 match socket.try_read(&mut buf) {
                     Ok(0) => {
                         return Ok(());
                     }
                     Ok(n) => {
-                        let _ = socksend.write_all(&buf[..n]).await;
+                        pending = 0..n;
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         continue;
                     }
                     Err(e) => {
-                        debug!("Message handling closed : {:?}", e);
+                        debug!(target: "nfsserve::tcp", "Message handling closed : {:?}", e);
                         return Err(e.into());
                     }
                 }
@@ -68,12 +336,21 @@ async fn process_socket(
             reply = msgrecvchan.recv() => {
                 match reply {
                     Some(Err(e)) => {
-                        debug!("Message handling closed : {:?}", e);
+                        debug!(target: "nfsserve::tcp", "Message handling closed : {:?}", e);
                         return Err(e);
                     }
-                    Some(Ok(msg)) => {
-                        if let Err(e) = write_fragment(&mut socket, &msg).await {
-                            error!("Write error {:?}", e);
+                    Some(Ok((ReplyBody::Whole(msg), _reservation, _permit))) => {
+                        // the reply buffer stays accounted for, and its
+                        // request in flight, until written
+                        if let Err(e) = write_fragment(&mut socket, &reply_slices(&msg)).await {
+                            error!(target: "nfsserve::tcp", "Write error {:?}", e);
+                        }
+                    }
+                    Some(Ok((ReplyBody::Streamed(mut chunks), _reservation, _permit))) => {
+                        // the record cannot be completed after a failure
+                        if let Err(e) = write_streamed(&mut socket, &mut chunks).await {
+                            debug!(target: "nfsserve::tcp", "Streamed reply failed: {:?}", e);
+                            return Err(e);
                         }
                     }
                     None => {
@@ -97,8 +374,200 @@ pub trait NFSTcp: Send + Sync {
     /// and a "false" will be sent on an unmount
     fn set_mount_listener(&mut self, signal: mpsc::Sender<bool>);
 
+    /// Sets a listener for the IP selected when binding with "auto".
+    /// Since binding has already completed, if the IP was automatically
+    /// selected it is sent immediately, waiting for room in the channel if
+    /// it is full. Nothing is sent if an explicit IP
+    /// was used. This allows the embedding application to tell the client
+    /// which address to mount.
+    fn set_auto_ip_listener(&mut self, signal: mpsc::Sender<IpAddr>);
+
+    /// Sets the runtime on which the per-connection and per-request tasks
+    /// are spawned. Defaults to the runtime bind() was called from.
+    /// Note that the accept loop itself runs wherever handle_forever()
+    /// is awaited.
+    fn set_runtime_handle(&mut self, handle: tokio::runtime::Handle);
+
+    /// Sets whether Windows spellings of the MOUNT path (backslash
+    /// separators, UNC "\\server" prefixes, drive letters) are normalized
+    /// to the canonical '/' separated form. See
+    /// mount_handlers::normalize_dirpath. Defaults to false.
+    fn set_windows_path_compat(&mut self, enable: bool);
+
+    /// Sets a budget in bytes for the transient memory of the requests in
+    /// flight across all connections: request records, READ and WRITE
+    /// data and reply buffers. READs and WRITEs which would exceed it are
+    /// replied NFS3ERR_JUKEBOX, which clients retry after a delay. A large
+    /// request record which would exceed it closes its connection before
+    /// it is read, and the client sends it again once reconnected.
+    /// Defaults to unlimited (usize::MAX). This is a backstop against
+    /// bursts of large requests, not a precise bound on the process size.
+    /// It should be well above rtmax and wtmax: larger requests are only
+    /// admitted when nothing else is in flight.
+    fn set_memory_budget(&mut self, bytes: usize);
+
+    /// Sets the maximum number of distinct paths a single client host may
+    /// have mounted. Mounts of further paths from that host are refused
+    /// with MNT3ERR_ACCES until it unmounts one; mounting a path again is
+    /// always allowed. Defaults to unlimited (usize::MAX).
+    fn set_max_mounts_per_client(&mut self, limit: usize);
+
+    /// Sets the maximum number of requests of one connection being
+    /// handled or with replies waiting to be written. Past it the
+    /// connection is not read from until a reply has been written, which
+    /// pushes back on the client through TCP flow control rather than
+    /// dropping requests. Defaults to DEFAULT_MAX_REQUESTS_PER_CONNECTION.
+    fn set_max_requests_per_connection(&mut self, limit: usize);
+
+    /// Sets the maximum number of connections served at a time. Past it
+    /// no connections are accepted (they wait in the listen backlog)
+    /// until one closes. Defaults to unlimited (usize::MAX).
+    fn set_max_connections(&mut self, limit: usize);
+
+    /// Makes the replies of the server reproducible, for chasing bugs
+    /// which depend on timing. The generation number, the write verifier
+    /// and a random handle key (see set_sign_handles) are derived from seed
+    /// (see vfs::set_random_seed), and the requests of a connection are
+    /// handled one at a time in the order they arrive: this sets
+    /// set_max_requests_per_connection to 1 and set_ordered_replies.
+    /// Replaying a captured session (see NFSTcpListener::capture_next)
+    /// against the same file system contents then gives the same replies,
+    /// byte for byte, provided the file system is deterministic itself
+    /// (MemFS, for one, stamps times from the clock).
+    ///
+    /// The cost is the concurrency within a connection: each request
+    /// waits for the one before it, so a client pipelining its requests
+    /// waits on every round trip to the file system in turn. Must be
+    /// called before the first file handle is produced. Not for
+    /// production.
+    fn set_deterministic(&mut self, seed: u64);
+
+    /// Sets how caller credentials are mapped, like the root_squash and
+    /// all_squash export options. The mapped credentials are what
+    /// vfs::current_user returns, and the owner in the attributes of
+    /// CREATE, MKDIR, SETATTR, SYMLINK and MKNOD calls from mapped callers is
+    /// mapped the same way. Calls without AUTH_UNIX credentials are given
+    /// the anonymous credentials. Defaults to SquashMode::NoSquash.
+    fn set_squash(&mut self, mode: SquashMode);
+
+    /// Sets a policy consulted on every MNT once the path has been
+    /// resolved, which can refuse the mount based on the object being
+    /// mounted rather than the path string. A mount whose attributes
+    /// cannot be read is refused with MNT3ERR_IO while a policy is set.
+    /// Defaults to none (all mounts of existing directories are allowed).
+    fn set_mount_authorizer(&mut self, authorizer: Arc<dyn MountAuthorizer>);
+
+    /// Sets whether the files a client "silly renames" (to ".nfs" followed
+    /// by hex digits, when a file it has open is removed) are left out of
+    /// the READDIR and READDIRPLUS replies to other clients, who would
+    /// otherwise see them come and go. Such files renamed before the
+    /// server started, or by a client whose record was dropped, are
+    /// hidden from all clients. Defaults to false.
+    fn set_hide_silly_renames(&mut self, enable: bool);
+
+    /// Sets whether READDIRPLUS calls whose maxcount or dircount is too
+    /// small to hold any entry (0 from clients which send READDIRPLUS
+    /// before they have the FSINFO reply) are served as if the count were
+    /// the dtpref of the file system, instead of replied NFS3ERR_TOOSMALL.
+    /// Correct clients never send such counts. Defaults to true.
+    fn set_readdir_count_compat(&mut self, enable: bool);
+
+    /// Sets whether READDIRPLUS is served. When disabled it is replied
+    /// NFS3ERR_NOTSUPP, on which clients fall back to READDIR and fetch
+    /// the attributes they need with GETATTR. READDIRPLUS gets the
+    /// attributes of every entry listed, which is the cheaper way for
+    /// file systems with inexpensive getattr, but can be much slower when
+    /// each getattr is a round trip to a remote backend and the client
+    /// only needs the names. Defaults to true.
+    fn set_readdirplus(&mut self, enable: bool);
+
+    /// Sets the handler of RPCSEC_GSS credentials. Without one, calls
+    /// with RPCSEC_GSS credentials are refused with AUTH_TOOWEAK. The
+    /// credentials a handler maps a principal to are squashed as
+    /// AUTH_UNIX credentials are. Defaults to none.
+    fn set_auth_handler(&mut self, handler: Arc<dyn AuthHandler>);
+
+    /// Sets a policy applied to the target of every symlink READLINK
+    /// replies, whatever the file system. For instance absolute targets
+    /// can be made relative, so that clients do not follow them to paths
+    /// of their own host. The directory of the link comes from
+    /// NFSFileSystem::parent_dir, and targets of links whose directory is
+    /// unknown are replied as is. Defaults to none.
+    fn set_symlink_rewriter(&mut self, rewriter: Arc<dyn SymlinkRewriter>);
+
+    /// Sets whether the replies on a connection are written in the order
+    /// their requests arrived. Requests are always handled concurrently,
+    /// and by default each reply is written as soon as it is ready, so a
+    /// slow request is overtaken by the ones after it. RPC matches
+    /// replies to calls by xid and allows this, but some clients assume
+    /// replies come back in about the order they sent the calls. When
+    /// enabled, ready replies wait for those of the earlier requests.
+    /// They count against set_max_requests_per_connection while they
+    /// wait, so a stuck request stalls its connection once the limit is
+    /// reached. Defaults to false.
+    fn set_ordered_replies(&mut self, enable: bool);
+
+    /// Trades crash safety for the throughput of small stable writes.
+    /// FILE_SYNC and DATA_SYNC WRITEs of at most max_write bytes are
+    /// passed to the file system as UNSTABLE, and replied as stable as
+    /// they asked, so the client never sends a COMMIT for them. Such a
+    /// write is only as durable as the file system makes an UNSTABLE
+    /// write on its own: if the server crashes before it does, the write
+    /// is lost although the client was told it is on stable storage.
+    ///
+    /// Meant for a file system which syncs small UNSTABLE writes in the
+    /// background, such as one with a sync_batch::SyncBatcher whose
+    /// max_write is at least this. Unpacking an archive of many small
+    /// files then waits for batches of syncs instead of one per file.
+    /// With other file systems the writes are made durable whenever the
+    /// backing store gets to it. Defaults to 0, which leaves every write
+    /// as stable as asked.
+    fn set_relaxed_durability(&mut self, max_write: u32);
+
+    /// Sets whether long READDIR and READDIRPLUS replies are streamed:
+    /// sent to the client in chunks of 64KiB as they are produced, each
+    /// chunk a fragment of the reply record, instead of built whole
+    /// before they are sent. Bounds the memory a listing holds by the
+    /// chunk size rather than the size of the reply. Clients see the
+    /// same reply either way. Defaults to true.
+    fn set_streamed_replies(&mut self, enable: bool);
+
+    /// Makes the file handles of the default NFSFileSystem::id_to_fh
+    /// expire ttl after they are handed out, or never with None. The
+    /// default fh_to_id replies NFS3ERR_STALE to a handle past its
+    /// expiry, and to a handle made without one while a ttl is set. See
+    /// vfs::HandleCodec::ttl. Should be set before the first client
+    /// mounts. Defaults to None.
+    fn set_handle_ttl(&mut self, ttl: Option<Duration>);
+
+    /// Sets whether the file handles of the default
+    /// NFSFileSystem::id_to_fh are signed with an HMAC, under a random 32
+    /// byte key which only this listener knows. The default fh_to_id then
+    /// refuses handles it did not produce (forged ones, or those of
+    /// another server) with NFS3ERR_BADHANDLE, and unsigned ones with
+    /// NFS3ERR_STALE. Handles do not survive a restart, see set_handle_key
+    /// for that. See vfs::HandleCodec::key. Fails if the operating system
+    /// has no random numbers to give. Should be set before the first
+    /// client mounts. Defaults to false.
+    fn set_sign_handles(&mut self, enable: bool) -> io::Result<()>;
+
+    /// As set_sign_handles, with the given key. The key should be at least
+    /// 32 random bytes. Handles stay valid across restarts if the same key
+    /// is given each time (along with a persisted generation number, see
+    /// vfs::persist_generation_number).
+    fn set_handle_key(&mut self, key: &[u8]);
+
     /// Loops forever and never returns handling all incoming connections.
     async fn handle_forever(&self) -> io::Result<()>;
+
+    /// Handles all incoming connections until shutdown completes. Then
+    /// stops accepting connections, closes the open ones and returns once
+    /// their tasks have finished, so that the port can be bound again.
+    /// Requests in progress on the closed connections are abandoned, and
+    /// clients retransmit them when they reconnect.
+    async fn handle_until<F>(&self, shutdown: F) -> io::Result<()>
+    where
+        F: Future<Output = ()> + Send;
 }
 
 impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
@@ -106,6 +575,37 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
     /// "127.0.0.1:12000". fs is an instance of an implementation
     /// of NFSFileSystem.
     pub async fn bind(ipstr: &str, fs: T) -> io::Result<NFSTcpListener<T>> {
+        NFSTcpListener::bind_with_backlog(ipstr, fs, DEFAULT_LISTEN_BACKLOG).await
+    }
+
+    /// As bind, but with the given accept backlog instead of
+    /// DEFAULT_LISTEN_BACKLOG. A larger backlog avoids dropped connections
+    /// when many clients mount at once. The OS may cap the value
+    /// (net.core.somaxconn on Linux).
+    pub async fn bind_with_backlog(
+        ipstr: &str,
+        fs: T,
+        backlog: u32,
+    ) -> io::Result<NFSTcpListener<T>> {
+        let config = NFSServerConfig {
+            listen_backlog: backlog,
+            ..Default::default()
+        };
+        NFSTcpListener::bind_with_config(ipstr, fs, config).await
+    }
+
+    /// As bind, with the tunables of config rather than the defaults.
+    /// The config is checked with NFSServerConfig::validate_for against
+    /// the transfer sizes of fs first, and refused with InvalidInput.
+    pub async fn bind_with_config(
+        ipstr: &str,
+        fs: T,
+        config: NFSServerConfig,
+    ) -> io::Result<NFSTcpListener<T>> {
+        let fsinfo = fs.fsinfo_config();
+        config
+            .validate_for(fsinfo.rtmax, fsinfo.wtmax)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
         let (ip, port) = ipstr.split_once(':').ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::AddrNotAvailable,
@@ -121,15 +621,31 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
 
         let arcfs: Arc<T> = Arc::new(fs);
 
+        // A root which is not a directory makes the mount "succeed" and the
+        // client fail in confusing ways afterwards. Catch it here instead.
+        let root = arcfs.root_dir();
+        if let Ok(attr) = arcfs.getattr(root).await {
+            if !matches!(attr.ftype, ftype3::NF3DIR) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "root_dir() {} is not a directory (getattr reports {:?})",
+                        root, attr.ftype
+                    ),
+                ));
+            }
+        }
+
         if ip == "auto" {
             let mut num_tries_left = 32;
 
             for try_ip in 1u16.. {
                 let ip = generate_host_ip(try_ip);
 
-                let result = NFSTcpListener::bind_internal(&ip, port, arcfs.clone()).await;
+                let result =
+                    NFSTcpListener::bind_internal(&ip, port, arcfs.clone(), config.clone()).await;
 
-                match &result {
+                match result {
                     Err(_) => {
                         if num_tries_left == 0 {
                             return result;
@@ -138,22 +654,144 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
                             continue;
                         }
                     }
-                    Ok(_) => {
-                        return result;
+                    Ok(mut listener) => {
+                        listener.auto_ip = true;
+                        return Ok(listener);
                     }
                 }
             }
             unreachable!(); // Does not detect automatically that loop above never terminates.
         } else {
             // Otherwise, try this.
-            NFSTcpListener::bind_internal(ip, port, arcfs).await
+            NFSTcpListener::bind_internal(ip, port, arcfs, config).await
         }
     }
 
-    async fn bind_internal(ip: &str, port: u16, arcfs: Arc<T>) -> io::Result<NFSTcpListener<T>> {
+    /// Describes what a fileid refers to in the backing file system.
+    /// See NFSFileSystem::describe_fileid. Useful for debugging and tooling.
+    pub async fn describe_fileid(&self, id: fileid3) -> Option<String> {
+        self.arcfs.describe_fileid(id).await
+    }
+
+    /// Returns the fileids the file system currently resolves, with a
+    /// description of each, or None if it keeps no such table. For
+    /// inspecting which handles the server considers valid when clients
+    /// report stale handles. See NFSFileSystem::handle_table.
+    ///
+    /// This exposes the layout of the exported tree, so it is only built
+    /// with the diagnostics feature.
+    #[cfg(feature = "diagnostics")]
+    pub async fn dump_handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        self.arcfs.handle_table().await
+    }
+
+    /// Returns the configuration in effect, including the changes made
+    /// with the NFSTcp setters since bind.
+    pub fn config(&self) -> &NFSServerConfig {
+        &self.config
+    }
+
+    /// Returns the number of bytes currently held by requests in flight.
+    /// See NFSTcp::set_memory_budget.
+    pub fn memory_in_use(&self) -> usize {
+        self.memory_budget.used()
+    }
+
+    /// Returns the most bytes held by requests in flight at once since
+    /// the last reset_memory_peak (or bind).
+    pub fn memory_peak_in_use(&self) -> usize {
+        self.memory_budget.peak()
+    }
+
+    /// Restarts memory_peak_in_use from the bytes held now
+    pub fn reset_memory_peak(&self) {
+        self.memory_budget.reset_peak();
+    }
+
+    /// Captures the next n_requests RPC records received from client, on
+    /// existing as well as new connections, together with their replies,
+    /// into `<dir>/<client>.rpclog`. Capture disarms itself after that.
+    /// Arming again replaces the remaining count, and 0 disarms.
+    /// See the capture module for the format.
+    pub fn capture_next(
+        &self,
+        client: IpAddr,
+        n_requests: usize,
+        dir: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        self.capture.arm(client, n_requests, dir.as_ref())
+    }
+
+    /// Changes the filter of the subscriber installed with
+    /// logging::install_subscriber, i.e. to temporarily raise the verbosity
+    /// of a single subsystem. See the logging module for the targets.
+    #[cfg(feature = "log-reload")]
+    pub fn set_log_filter(&self, filter: &str) -> Result<(), anyhow::Error> {
+        crate::logging::set_log_filter(filter)
+    }
+
+    /// Registers a version of an RPC program as served by this listener.
+    /// This affects the version ranges reported in PROG_MISMATCH replies.
+    /// Returns false if the version has no implementation in this crate,
+    /// which is then not served. See ProgramRegistry.
+    pub fn register_program(&self, prog: u32, vers: u32) -> bool {
+        let registered = self.programs.write().unwrap().register(prog, vers);
+        if !registered {
+            warn!(
+                target: "nfsserve::rpc",
+                "Not registering version {} of program {}: not implemented",
+                vers,
+                prog
+            );
+        }
+        self.getport.clear();
+        registered
+    }
+
+    /// Deregisters a version of an RPC program. Calls to it will be
+    /// rejected with PROG_MISMATCH, or PROG_UNAVAIL if it was the last
+    /// served version of the program.
+    pub fn deregister_program(&self, prog: u32, vers: u32) -> bool {
+        let removed = self.programs.write().unwrap().deregister(prog, vers);
+        self.getport.clear();
+        removed
+    }
+
+    /// Returns a snapshot of the programs served by this listener
+    pub fn served_programs(&self) -> ProgramRegistry {
+        self.programs.read().unwrap().clone()
+    }
+
+    /// Returns how many PORTMAP GETPORT queries asked for each (program,
+    /// version) which is not served, e.g. (100003, 4) for NFSv4, 100021
+    /// for NLM (locking) or 100024 for NSM (lock recovery). Past 64
+    /// distinct pairs the rest are counted under (u32::MAX, u32::MAX).
+    pub fn getport_unserved_queries(&self) -> BTreeMap<(u32, u32), u64> {
+        self.getport.unserved()
+    }
+
+    async fn bind_internal(
+        ip: &str,
+        port: u16,
+        arcfs: Arc<T>,
+        config: NFSServerConfig,
+    ) -> io::Result<NFSTcpListener<T>> {
         let ipstr = format!("{ip}:{port}");
-        let listener = TcpListener::bind(&ipstr).await?;
-        info!("Listening on {:?}", &ipstr);
+        let listener = listen_with_backlog(&ipstr, config.listen_backlog).await?;
+        info!(
+            target: "nfsserve::tcp",
+            "Listening on {:?} with backlog {}",
+            &ipstr,
+            config.listen_backlog
+        );
+        let memory_budget = MemoryBudget::unlimited();
+        memory_budget.set_limit(config.memory_budget);
+        // a secret of this listener alone
+        let handle_key = if config.sign_handles {
+            Some(crate::vfs::random_handle_key()?)
+        } else {
+            None
+        };
 
         let port = match listener.local_addr().unwrap() {
             SocketAddr::V4(s) => s.port(),
@@ -164,10 +802,53 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
             port,
             arcfs,
             mount_signal: None,
+            programs: Arc::new(RwLock::new(ProgramRegistry::with_default_programs())),
+            getport: Arc::new(GetportCache::default()),
+            runtime: tokio::runtime::Handle::current(),
+            auto_ip: false,
+            memory_budget: Arc::new(memory_budget),
+            mounts: Arc::new(Mutex::new(BTreeMap::new())),
+            capture: Arc::new(CaptureRegistry::default()),
+            exports: Arc::new(vec![b"/".to_vec()]),
+            readdir_sizes: Arc::new(EntrySizeEstimator::default()),
+            mount_authorizer: None,
+            auth_handler: None,
+            symlink_rewriter: None,
+            silly_renames: Arc::new(SillyRenames::default()),
+            handle_key,
+            handle_key_kept: false,
+            config,
         })
     }
 }
 
+impl NFSTcpListener<ExportTable> {
+    /// Binds like bind, serving each file system under its own export
+    /// path. For instance
+    /// ```ignore
+    /// NFSTcpListener::bind_multi("127.0.0.1:12000", vec![("/data", fs1), ("/scratch", fs2)])
+    /// ```
+    /// See ExportTable.
+    pub async fn bind_multi(
+        ipstr: &str,
+        exports: Vec<(&str, Arc<dyn NFSFileSystem + Send + Sync>)>,
+    ) -> io::Result<NFSTcpListener<ExportTable>> {
+        NFSTcpListener::bind_exports(ipstr, ExportTable::new(exports)?).await
+    }
+
+    /// As bind_multi, with an ExportTable built (and configured, e.g. with
+    /// ExportTable::set_squash) beforehand.
+    pub async fn bind_exports(
+        ipstr: &str,
+        table: ExportTable,
+    ) -> io::Result<NFSTcpListener<ExportTable>> {
+        let paths = table.export_paths();
+        let mut listener = NFSTcpListener::bind(ipstr, table).await?;
+        listener.exports = Arc::new(paths);
+        Ok(listener)
+    }
+}
+
 #[async_trait]
 impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
     /// Gets the true listening port. Useful if the bound port number is 0
@@ -187,22 +868,224 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
         self.mount_signal = Some(signal);
     }
 
+    /// Sets a listener for the IP selected when binding with "auto".
+    fn set_auto_ip_listener(&mut self, signal: mpsc::Sender<IpAddr>) {
+        if self.auto_ip {
+            let ip = self.get_listen_ip();
+            info!(target: "nfsserve::tcp", "Automatically selected IP {}", ip);
+            // sent from a task so that the IP is not lost if the channel
+            // is full, as try_send would
+            self.runtime.spawn(async move {
+                if signal.send(ip).await.is_err() {
+                    warn!(target: "nfsserve::tcp", "Auto IP listener closed before receiving {}", ip);
+                }
+            });
+        }
+    }
+
+    /// Sets the runtime on which the per-connection and per-request tasks
+    /// are spawned.
+    fn set_runtime_handle(&mut self, handle: tokio::runtime::Handle) {
+        self.runtime = handle;
+    }
+
+    /// Sets whether Windows spellings of the MOUNT path are normalized.
+    fn set_windows_path_compat(&mut self, enable: bool) {
+        self.config.windows_path_compat = enable;
+    }
+
+    /// Sets a budget in bytes for the transient memory of requests in flight.
+    fn set_memory_budget(&mut self, bytes: usize) {
+        self.config.memory_budget = bytes;
+        self.memory_budget.set_limit(bytes);
+    }
+
+    /// Sets the maximum number of active mounts of a single client host.
+    fn set_max_mounts_per_client(&mut self, limit: usize) {
+        self.config.max_mounts_per_client = limit;
+    }
+
+    /// Sets the maximum number of requests in flight per connection.
+    fn set_max_requests_per_connection(&mut self, limit: usize) {
+        self.config.max_requests_per_connection = limit;
+    }
+
+    /// Sets the maximum number of connections served at a time.
+    fn set_max_connections(&mut self, limit: usize) {
+        self.config.max_connections = limit;
+    }
+
+    /// Makes the replies of the server reproducible.
+    fn set_deterministic(&mut self, seed: u64) {
+        if !crate::vfs::set_random_seed(seed) {
+            warn!(
+                target: "nfsserve::tcp",
+                "Seed {} set after file handles or write verifiers were produced", seed
+            );
+        }
+        // a random handle key is derived from seed too
+        if self.handle_key.is_some() && !self.handle_key_kept {
+            if let Ok(key) = crate::vfs::random_handle_key() {
+                self.handle_key = Some(key);
+            }
+        }
+        self.config.max_requests_per_connection = 1;
+        self.config.ordered_replies = true;
+    }
+
+    /// Sets how caller credentials are mapped.
+    fn set_squash(&mut self, mode: SquashMode) {
+        self.config.squash = mode;
+    }
+
+    /// Sets a policy consulted on every MNT once the path is resolved.
+    fn set_mount_authorizer(&mut self, authorizer: Arc<dyn MountAuthorizer>) {
+        self.mount_authorizer = Some(authorizer);
+    }
+
+    /// Sets whether silly renamed files are hidden from other clients.
+    fn set_hide_silly_renames(&mut self, enable: bool) {
+        self.config.hide_silly_renames = enable;
+    }
+
+    /// Sets whether tiny READDIRPLUS counts are replaced by dtpref.
+    fn set_readdir_count_compat(&mut self, enable: bool) {
+        self.config.readdir_count_compat = enable;
+    }
+
+    /// Sets whether READDIRPLUS is served, or replied NFS3ERR_NOTSUPP.
+    fn set_readdirplus(&mut self, enable: bool) {
+        self.config.readdirplus = enable;
+    }
+
+    /// Sets the handler of RPCSEC_GSS credentials.
+    fn set_auth_handler(&mut self, handler: Arc<dyn AuthHandler>) {
+        self.auth_handler = Some(handler);
+    }
+
+    /// Sets the policy applied to the targets READLINK replies.
+    fn set_symlink_rewriter(&mut self, rewriter: Arc<dyn SymlinkRewriter>) {
+        self.symlink_rewriter = Some(rewriter);
+    }
+
+    /// Sets whether replies are written in the order requests arrived.
+    fn set_ordered_replies(&mut self, enable: bool) {
+        self.config.ordered_replies = enable;
+    }
+
+    /// Sets the largest stable WRITE which is written UNSTABLE.
+    fn set_relaxed_durability(&mut self, max_write: u32) {
+        self.config.relaxed_durability = max_write;
+    }
+
+    /// Sets whether long READDIR and READDIRPLUS replies are streamed.
+    fn set_streamed_replies(&mut self, enable: bool) {
+        self.config.streamed_replies = enable;
+    }
+
+    /// Sets how long file handles stay valid.
+    fn set_handle_ttl(&mut self, ttl: Option<Duration>) {
+        self.config.handle_ttl = ttl;
+    }
+
+    /// Sets whether file handles are signed with a random key.
+    fn set_sign_handles(&mut self, enable: bool) -> io::Result<()> {
+        self.handle_key = if enable {
+            Some(crate::vfs::random_handle_key()?)
+        } else {
+            None
+        };
+        self.handle_key_kept = false;
+        self.config.sign_handles = enable;
+        Ok(())
+    }
+
+    /// Sets the key file handles are signed with.
+    fn set_handle_key(&mut self, key: &[u8]) {
+        self.handle_key = Some(Arc::from(key));
+        self.handle_key_kept = true;
+        self.config.sign_handles = true;
+    }
+
     /// Loops forever and never returns handling all incoming connections.
     async fn handle_forever(&self) -> io::Result<()> {
+        self.handle_until(std::future::pending()).await
+    }
+
+    /// Handles all incoming connections until shutdown completes.
+    async fn handle_until<F>(&self, shutdown: F) -> io::Result<()>
+    where
+        F: Future<Output = ()> + Send,
+    {
+        info!(target: "nfsserve::tcp", "Serving with {:?}", self.config);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
         loop {
-            let (socket, _) = self.listener.accept().await?;
-            let context = RPCContext {
-                local_port: self.port,
-                client_addr: socket.peer_addr().unwrap().to_string(),
-                auth: crate::rpc::auth_unix::default(),
-                vfs: self.arcfs.clone(),
-                mount_signal: self.mount_signal.clone(),
-            };
-            info!("Accepting connection from {}", context.client_addr);
-            debug!("Accepting socket {:?} {:?}", socket, context);
-            tokio::spawn(async move {
-                let _ = process_socket(socket, context).await;
-            });
+            tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = self.listener.accept(), if connections.len() < self.config.max_connections => {
+                    let (socket, _) = accepted?;
+                    let context = RPCContext {
+                        local_port: self.port,
+                        client_addr: socket.peer_addr().unwrap().to_string(),
+                        auth: crate::rpc::auth_unix::default(),
+                        vfs: self.arcfs.clone(),
+                        mount_signal: self.mount_signal.clone(),
+                        programs: self.programs.clone(),
+                        getport: self.getport.clone(),
+                        runtime: self.runtime.clone(),
+                        windows_path_compat: self.config.windows_path_compat,
+                        memory_budget: self.memory_budget.clone(),
+                        record_len: 0,
+                        mounts: self.mounts.clone(),
+                        max_mounts_per_client: self.config.max_mounts_per_client,
+                        max_requests_per_connection: self.config.max_requests_per_connection,
+                        capture: self.capture.clone(),
+                        squash: self.config.squash,
+                        squashed: false,
+                        exports: self.exports.clone(),
+                        read_buffers: Arc::new(Mutex::new(Vec::new())),
+                        readdir_sizes: self.readdir_sizes.clone(),
+                        mount_authorizer: self.mount_authorizer.clone(),
+                        auth_handler: self.auth_handler.clone(),
+                        symlink_rewriter: self.symlink_rewriter.clone(),
+                        attr_cache: Arc::default(),
+                        hide_silly_renames: self.config.hide_silly_renames,
+                        readdir_count_compat: self.config.readdir_count_compat,
+                        readdir_count_substituted: Arc::default(),
+                        readdirplus: self.config.readdirplus,
+                        ordered_replies: self.config.ordered_replies,
+                        relaxed_durability: self.config.relaxed_durability,
+                        streamed_replies: self.config.streamed_replies,
+                        handle_codec: HandleCodec {
+                            ttl: self.config.handle_ttl,
+                            key: self.handle_key.clone(),
+                        },
+                        reply_stream: None,
+                        silly_renames: self.silly_renames.clone(),
+                    };
+                    info!(target: "nfsserve::tcp", "Accepting connection from {}", context.client_addr);
+                    debug!(target: "nfsserve::tcp", "Accepting socket {:?} {:?}", socket, context);
+                    let stop_rx = stop_rx.clone();
+                    connections.spawn_on(
+                        async move {
+                            let _ = process_socket(socket, context, stop_rx).await;
+                        },
+                        &self.runtime,
+                    );
+                },
+                // reap the finished connections
+                Some(_) = connections.join_next(), if !connections.is_empty() => {},
+            }
         }
+        info!(
+            target: "nfsserve::tcp",
+            "Shutting down. Closing {} connections",
+            connections.len()
+        );
+        let _ = stop_tx.send(true);
+        while connections.join_next().await.is_some() {}
+        Ok(())
     }
 }
@@ -1,13 +1,21 @@
 use crate::nfs::*;
 use crate::nfs;
 use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::cmp::Ordering;
-use std::sync::Once;
-use std::time::SystemTime;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 #[derive(Default, Debug)]
 pub struct DirEntrySimple {
     pub fileid: fileid3,
     pub name: filename3,
+    /// The position of the entry in the listing. See DirEntry::cookie
+    pub cookie: cookie3,
 }
 #[derive(Default, Debug)]
 pub struct ReadDirSimpleResult {
@@ -20,6 +28,33 @@ pub struct DirEntry {
     pub fileid: fileid3,
     pub name: filename3,
     pub attr: fattr3,
+    /// The position of the entry in the listing, handed back to readdir
+    /// as start_after to resume after this entry. 0 (the default) stands
+    /// for the fileid, which is the cookie of file systems that do not
+    /// set one.
+    pub cookie: cookie3,
+}
+
+impl DirEntry {
+    /// The cookie sent to the client for this entry
+    pub fn effective_cookie(&self) -> cookie3 {
+        effective_cookie(self.cookie, self.fileid)
+    }
+}
+
+impl DirEntrySimple {
+    /// The cookie sent to the client for this entry
+    pub fn effective_cookie(&self) -> cookie3 {
+        effective_cookie(self.cookie, self.fileid)
+    }
+}
+
+fn effective_cookie(cookie: cookie3, fileid: fileid3) -> cookie3 {
+    if cookie == cookie3(0) {
+        cookie3(fileid.0)
+    } else {
+        cookie
+    }
 }
 #[derive(Default, Debug)]
 pub struct ReadDirResult {
@@ -27,14 +62,140 @@ pub struct ReadDirResult {
     pub end: bool,
 }
 
+/// A directory entry as READDIRPLUS lists it, with its file handle.
+/// See NFSFileSystem::readdirplus
+#[derive(Default, Debug)]
+pub struct DirEntryPlus {
+    pub fileid: fileid3,
+    pub name: filename3,
+    pub attr: fattr3,
+    pub handle: post_op_fh3,
+    /// The position of the entry in the listing. See DirEntry::cookie
+    pub cookie: cookie3,
+}
+
+impl DirEntryPlus {
+    /// The cookie sent to the client for this entry
+    pub fn effective_cookie(&self) -> cookie3 {
+        effective_cookie(self.cookie, self.fileid)
+    }
+
+    /// The size of the entry as counted against the dircount of a
+    /// READDIRPLUS call: its fileid, name and cookie
+    pub fn dircount_size(&self) -> usize {
+        8 + 4 + self.name.len() + 8
+    }
+
+    /// The size of the entry encoded in a READDIRPLUS reply
+    pub fn encoded_size(&self) -> usize {
+        let handle = match &self.handle {
+            post_op_fh3::handle(fh) => 4 + 4 + fh.data.len().next_multiple_of(4),
+            post_op_fh3::Void => 4,
+        };
+        // the list marker, fileid, name, cookie and attributes
+        4 + 8 + 4 + self.name.len().next_multiple_of(4) + 8 + 4 + FATTR3_SIZE + handle
+    }
+}
+
+/// The size of an encoded fattr3
+const FATTR3_SIZE: usize = 84;
+
+#[derive(Default, Debug)]
+pub struct ReadDirPlusResult {
+    pub entries: Vec<DirEntryPlus>,
+    pub end: bool,
+}
+
+/// Fills a ReadDirPlusResult with as many entries as fit in the dircount
+/// and maxcount of a READDIRPLUS call. See NFSFileSystem::readdirplus
+#[derive(Debug)]
+pub struct ReadDirPlusPage {
+    page: ReadDirPlusResult,
+    dircount_left: usize,
+    maxcount_left: usize,
+}
+
+impl ReadDirPlusPage {
+    pub fn new(dircount: count3, maxcount: count3) -> ReadDirPlusPage {
+        ReadDirPlusPage {
+            page: ReadDirPlusResult::default(),
+            dircount_left: dircount as usize,
+            maxcount_left: maxcount as usize,
+        }
+    }
+
+    /// Adds entry to the page. Returns false, leaving the page as is, if
+    /// it does not fit.
+    pub fn push(&mut self, entry: DirEntryPlus) -> bool {
+        let (dircount_size, encoded_size) = (entry.dircount_size(), entry.encoded_size());
+        if dircount_size > self.dircount_left || encoded_size > self.maxcount_left {
+            return false;
+        }
+        self.dircount_left -= dircount_size;
+        self.maxcount_left -= encoded_size;
+        self.page.entries.push(entry);
+        true
+    }
+
+    /// The page, where end is whether it reaches the end of the directory
+    pub fn finish(self, end: bool) -> ReadDirPlusResult {
+        ReadDirPlusResult {
+            entries: self.page.entries,
+            end,
+        }
+    }
+}
+
+/// What NFSFileSystem::read_ext returns besides the bytes read
+#[derive(Default, Debug)]
+pub struct ReadReply {
+    /// True if the read reached the end of the file
+    pub eof: bool,
+    /// The attributes of the file as of the read, if known. Saves the
+    /// READ handler a separate getattr.
+    pub attr: Option<fattr3>,
+}
+
+/// What NFSFileSystem::write_ext returns
+#[derive(Default, Debug)]
+pub struct WriteReply {
+    /// The attributes of the file immediately before the write, when they
+    /// were read in the same step as the write. See write_ext.
+    pub before: Option<wcc_attr>,
+    /// The attributes of the file after the write
+    pub attr: fattr3,
+    /// How stable the write actually is
+    pub committed: stable_how,
+}
+
+/// A page of directory entries which is already encoded in the
+/// READDIRPLUS wire format. See NFSFileSystem::readdir_raw.
+#[derive(Default, Debug)]
+pub struct RawDirPage {
+    /// The XDR encoded entries, concatenated. Each entry is the "true"
+    /// list marker followed by an entryplus3. The terminating "false"
+    /// marker and the eof flag must not be included.
+    pub entries: Vec<u8>,
+    /// The byte offset of the start of every entry in entries, in
+    /// increasing order, so starting with 0. Used to truncate the page at
+    /// an entry boundary. A page whose offsets do not match its entries is
+    /// replied NFS3ERR_SERVERFAULT.
+    pub entry_offsets: Vec<usize>,
+    /// True if the last entry in entries is the last entry in the directory
+    pub end: bool,
+    /// The cookie verifier to return with this page
+    pub cookieverf: cookieverf3,
+}
+
 impl ReadDirSimpleResult {
-    fn from_readdir_result(result: &ReadDirResult) -> ReadDirSimpleResult {
+    pub(crate) fn from_readdir_result(result: &ReadDirResult) -> ReadDirSimpleResult {
         let entries: Vec<DirEntrySimple> = result
             .entries
             .iter()
             .map(|e| DirEntrySimple {
                 fileid: e.fileid,
                 name: e.name.clone(),
+                cookie: e.cookie,
             })
             .collect();
         ReadDirSimpleResult {
@@ -44,18 +205,577 @@ impl ReadDirSimpleResult {
     }
 }
 
-static mut GENERATION_NUMBER: u64 = 0;
-static GENERATION_NUMBER_INIT: Once = Once::new();
+static GENERATION_NUMBER: OnceLock<u64> = OnceLock::new();
 
 fn get_generation_number() -> u64 {
-    unsafe {
-        GENERATION_NUMBER_INIT.call_once(|| {
-            GENERATION_NUMBER = SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64;
+    *GENERATION_NUMBER.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    })
+}
+
+/// When this process started serving. Unlike the generation number this
+/// is never persisted, as unstable writes do not survive a restart.
+static BOOT_TIME: OnceLock<u64> = OnceLock::new();
+
+/// The number of times unstable writes were reported lost
+static WRITE_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// The write verifier of the default NFSFileSystem::serverid
+pub fn default_write_verifier() -> writeverf3 {
+    let boot = *BOOT_TIME.get_or_init(now_millis);
+    boot.wrapping_add(WRITE_EPOCH.load(AtomicOrdering::Relaxed))
+        .to_le_bytes()
+}
+
+/// Reports that data written UNSTABLE and not yet committed may have
+/// been lost (for instance a flush of the page cache failed). Changes
+/// the write verifier of the default NFSFileSystem::serverid, which
+/// tells clients to send their uncommitted writes again.
+pub fn discard_unstable_writes() {
+    WRITE_EPOCH.fetch_add(1, AtomicOrdering::Relaxed);
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// How the default NFSFileSystem::id_to_fh and fh_to_id make and check
+/// file handles. Each listener has its own, which is in effect while it
+/// serves a request (see current_handle_codec), so that servers in one
+/// process may differ.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct HandleCodec {
+    /// Handles expire ttl after they are handed out, independently of
+    /// server restarts. The expiry time is part of the handle, and
+    /// decode replies NFS3ERR_STALE to a handle past it.
+    ///
+    /// Clients get fresh handles for objects they look up or list again,
+    /// but an expired root handle (from MNT) can only be replaced by
+    /// mounting again. Since the expiry is in the handle, a client can
+    /// forge a later one unless handles are signed (see key): on its own
+    /// this bounds how long well behaved clients keep using a handle, it
+    /// does not enforce access. See NFSTcp::set_handle_ttl
+    pub ttl: Option<Duration>,
+    /// Handles are signed with an HMAC under key, and decode refuses
+    /// handles whose MAC does not match with NFS3ERR_BADHANDLE.
+    ///
+    /// Unsigned handles carry the fileid in the clear, so a client can
+    /// make up a handle for any fileid without looking it up, i.e. without
+    /// the search permission on the directories leading to it. A signed
+    /// handle can only have been produced by a server holding the key: the
+    /// handles of one server are refused by another with a key of its
+    /// own. File systems overriding id_to_fh (e.g. with StableFh) are not
+    /// affected. See NFSTcp::set_sign_handles and NFSTcp::set_handle_key
+    pub key: Option<Arc<[u8]>>,
+}
+
+// the key is a secret, and is kept out of logs
+impl fmt::Debug for HandleCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HandleCodec")
+            .field("ttl", &self.ttl)
+            .field("signed", &self.key.is_some())
+            .finish()
+    }
+}
+
+tokio::task_local! {
+    static HANDLE_CODEC: HandleCodec;
+}
+
+/// Returns the handle codec of the listener serving the current request,
+/// or the default (handles which neither expire nor are signed) outside
+/// of a request. Only valid on the task running the request, as
+/// current_user.
+pub fn current_handle_codec() -> HandleCodec {
+    HANDLE_CODEC.try_with(|c| c.clone()).unwrap_or_default()
+}
+
+/// Runs fut with codec as the value of current_handle_codec
+pub(crate) async fn with_handle_codec<F: std::future::Future>(
+    codec: HandleCodec,
+    fut: F,
+) -> F::Output {
+    HANDLE_CODEC.scope(codec, fut).await
+}
+
+/// The length of the MAC of signed handles (HMAC-SHA256 truncated)
+const FH_MAC_LEN: usize = 16;
+
+/// A random 32 byte key for HandleCodec::key, which only this process
+/// knows, from the random number generator of the operating system (or
+/// derived from the seed of set_random_seed). Handles signed with it are
+/// NFS3ERR_BADHANDLE to any other server, and to this one once restarted.
+pub(crate) fn random_handle_key() -> io::Result<Arc<[u8]>> {
+    if let Some(seed) = RANDOM_SEED.get() {
+        return Ok(Arc::from(seeded(*seed, b"handle key")));
+    }
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key)
+        .map_err(|e| io::Error::other(format!("no random handle key: {e}")))?;
+    Ok(Arc::from(key))
+}
+
+/// HMAC-SHA256 of the handle bytes under key
+fn handle_mac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac
+}
+
+/// The first byte of the file handles of the default
+/// NFSFileSystem::id_to_fh: a handle holding the generation number and
+/// the fileid (8 bytes each, little endian).
+pub const FH_TAG_DEFAULT: u8 = 0x01;
+/// As FH_TAG_DEFAULT, followed by the expiry time (milliseconds since the
+/// epoch, 8 bytes little endian). See HandleCodec::ttl.
+pub const FH_TAG_EXPIRING: u8 = 0x02;
+/// As FH_TAG_DEFAULT, followed by a 16 byte MAC of all the bytes before
+/// it. See HandleCodec::key.
+pub const FH_TAG_SIGNED: u8 = 0x04;
+/// As FH_TAG_EXPIRING, followed by a 16 byte MAC of all the bytes before
+/// it. See HandleCodec::key.
+pub const FH_TAG_SIGNED_EXPIRING: u8 = 0x05;
+
+/// Encodes a fileid into the file handle format of the default
+/// NFSFileSystem::id_to_fh, under the handle codec of the current request
+/// (see HandleCodec::encode).
+///
+/// Implementations which override id_to_fh with a format of their own
+/// should start their handles with a byte other than the FH_TAG_ values
+/// (0x00 or 0x80 and up are never used by this crate), so that handles of
+/// either format held by clients across an upgrade are told apart and
+/// refused cleanly.
+pub fn default_id_to_fh(id: fileid3) -> nfs_fh3 {
+    current_handle_codec().encode(id)
+}
+
+/// Decodes a file handle made by the default NFSFileSystem::id_to_fh,
+/// under the handle codec of the current request (see
+/// HandleCodec::decode). For implementations which override fh_to_id to
+/// add their own checks on top of the default handle format.
+pub fn default_fh_to_id(id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+    current_handle_codec().decode(id)
+}
+
+impl HandleCodec {
+    /// Encodes a fileid: a tag byte (one of the FH_TAG_ values but
+    /// FH_TAG_STABLE), the generation number and the fileid, followed by
+    /// the expiry time if ttl is set, and by the MAC if key is.
+    pub fn encode(&self, id: fileid3) -> nfs_fh3 {
+        let gennum = get_generation_number();
+        let key = self.key.as_deref();
+        let mut ret: Vec<u8> = Vec::with_capacity(25 + FH_MAC_LEN);
+        ret.push(match (self.ttl.is_some(), key.is_some()) {
+            (false, false) => FH_TAG_DEFAULT,
+            (true, false) => FH_TAG_EXPIRING,
+            (false, true) => FH_TAG_SIGNED,
+            (true, true) => FH_TAG_SIGNED_EXPIRING,
         });
-        GENERATION_NUMBER
+        ret.extend_from_slice(&gennum.to_le_bytes());
+        ret.extend_from_slice(&id.0.to_le_bytes());
+        if let Some(ttl) = self.ttl {
+            let expiry = now_millis().saturating_add(ttl.as_millis() as u64);
+            ret.extend_from_slice(&expiry.to_le_bytes());
+        }
+        if let Some(key) = key {
+            let mac = handle_mac(key, &ret).finalize().into_bytes();
+            ret.extend_from_slice(&mac[..FH_MAC_LEN]);
+        }
+        nfs_fh3 { data: ret }
+    }
+
+    /// Decodes a file handle made by encode.
+    ///
+    /// The untagged 16 byte handles of earlier versions are still
+    /// accepted. Handles of an unknown tag or length, or whose MAC does
+    /// not match, are NFS3ERR_BADHANDLE. Handles of an earlier generation
+    /// (server instance) or past their expiry are NFS3ERR_STALE. While ttl
+    /// is set, handles without an expiry are NFS3ERR_STALE too, and
+    /// likewise unsigned handles while key is set, and signed ones while
+    /// it is not.
+    pub fn decode(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        let (body, expiring, signed) = match (id.data.len(), id.data.first()) {
+            (16, _) => (&id.data[..], false, false),
+            (17, Some(&FH_TAG_DEFAULT)) => (&id.data[1..], false, false),
+            (25, Some(&FH_TAG_EXPIRING)) => (&id.data[1..], true, false),
+            (33, Some(&FH_TAG_SIGNED)) => (&id.data[1..17], false, true),
+            (41, Some(&FH_TAG_SIGNED_EXPIRING)) => (&id.data[1..25], true, true),
+            _ => return Err(nfsstat3::NFS3ERR_BADHANDLE),
+        };
+        // nothing in the handle is trusted before its MAC is checked
+        match (self.key.as_deref(), signed) {
+            (Some(key), true) => {
+                let (data, mac) = id.data.split_at(id.data.len() - FH_MAC_LEN);
+                // in constant time, against the leading bytes of the MAC
+                if handle_mac(key, data).verify_truncated_left(mac).is_err() {
+                    return Err(nfsstat3::NFS3ERR_BADHANDLE);
+                }
+            }
+            // made before handles were signed, or while they were
+            (Some(_), false) | (None, true) => return Err(nfsstat3::NFS3ERR_STALE),
+            (None, false) => {}
+        }
+        let gen = u64::from_le_bytes(body[0..8].try_into().unwrap());
+        let fileid = u64::from_le_bytes(body[8..16].try_into().unwrap());
+        let gennum = get_generation_number();
+        match gen.cmp(&gennum) {
+            Ordering::Less => return Err(nfsstat3::NFS3ERR_STALE),
+            Ordering::Greater => return Err(nfsstat3::NFS3ERR_BADHANDLE),
+            Ordering::Equal => {}
+        }
+        if expiring {
+            let expiry = u64::from_le_bytes(body[16..24].try_into().unwrap());
+            if now_millis() > expiry {
+                return Err(nfsstat3::NFS3ERR_STALE);
+            }
+        } else if self.ttl.is_some() {
+            // made before handles expired, e.g. by an earlier run sharing
+            // the generation number
+            return Err(nfsstat3::NFS3ERR_STALE);
+        }
+        Ok(fileid3(fileid))
+    }
+}
+
+/// The first byte of the file handles made by StableFh
+pub const FH_TAG_STABLE: u8 = 0x03;
+
+/// A file handle which stays valid across server restarts: a tag byte
+/// (FH_TAG_STABLE), the fileid (8 bytes, little endian) and a key of up
+/// to MAX_KEY_LEN bytes chosen by the file system. Unlike the handles of
+/// default_id_to_fh it holds no generation number and no expiry.
+///
+/// This is for file systems which can name their objects durably, for
+/// instance by device and inode number, or by an object key and version.
+/// They override id_to_fh to encode a StableFh, and fh_to_id to decode
+/// it and map the key back to a fileid, replying NFS3ERR_STALE when the
+/// object the key names is gone. The fileid is only a hint after a
+/// restart: it is whatever the earlier run assigned. It is not signed
+/// (HandleCodec::key does not apply), so the key should not be guessable
+/// where that matters.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StableFh {
+    pub fileid: fileid3,
+    pub key: Vec<u8>,
+}
+
+impl StableFh {
+    /// The longest key. One byte of NFS3_FHSIZE is left for the export
+    /// index exports::ExportTable prefixes to the handles of its exports.
+    pub const MAX_KEY_LEN: usize = NFS3_FHSIZE as usize - 1 - 8 - 1;
+
+    /// Makes the file handle. Panics if the key is longer than MAX_KEY_LEN.
+    pub fn encode(&self) -> nfs_fh3 {
+        assert!(self.key.len() <= StableFh::MAX_KEY_LEN, "StableFh key too long");
+        let mut data = Vec::with_capacity(9 + self.key.len());
+        data.push(FH_TAG_STABLE);
+        data.extend_from_slice(&self.fileid.0.to_le_bytes());
+        data.extend_from_slice(&self.key);
+        nfs_fh3 { data }
+    }
+
+    /// Reads a file handle made by encode. Handles of the default format
+    /// (an earlier version of the file system, which did not override
+    /// id_to_fh) are NFS3ERR_STALE, anything else is NFS3ERR_BADHANDLE.
+    pub fn decode(fh: &nfs_fh3) -> Result<StableFh, nfsstat3> {
+        match fh.data.first() {
+            Some(&FH_TAG_STABLE) if fh.data.len() >= 9 => Ok(StableFh {
+                fileid: fileid3(u64::from_le_bytes(fh.data[1..9].try_into().unwrap())),
+                key: fh.data[9..].to_vec(),
+            }),
+            _ => match default_fh_to_id(fh) {
+                Err(nfsstat3::NFS3ERR_BADHANDLE) => Err(nfsstat3::NFS3ERR_BADHANDLE),
+                _ => Err(nfsstat3::NFS3ERR_STALE),
+            },
+        }
+    }
+}
+
+/// Fixes the generation number used to build file handles instead of
+/// deriving it from the startup time.
+///
+/// This is meant for debugging: with a fixed generation number, two runs
+/// of the server over the same file system produce byte-identical file
+/// handles, which makes replies reproducible. The cost is that
+/// clients are no longer told that their handles expired across a restart,
+/// so this should not be used in production.
+///
+/// This must be called before the first file handle is produced. Returns
+/// false if the generation number has already been set.
+pub fn set_generation_number(gen: u64) -> bool {
+    GENERATION_NUMBER.set(gen).is_ok()
+}
+
+/// The seed of set_random_seed
+static RANDOM_SEED: OnceLock<u64> = OnceLock::new();
+
+/// 32 bytes derived from seed for the use named by label
+fn seeded(seed: u64, label: &[u8]) -> [u8; 32] {
+    handle_mac(&seed.to_le_bytes(), label)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Derives what the server otherwise takes from the startup time or from
+/// the random number generator of the operating system from seed
+/// instead: the generation number, the boot time of the write verifier,
+/// and the random handle keys of listeners signing handles (see
+/// NFSTcp::set_sign_handles). Two runs with the same seed then
+/// hand out the same file handles and write verifiers.
+///
+/// This is meant for debugging, see NFSTcp::set_deterministic. As with
+/// set_generation_number, clients are not told that their handles
+/// expired across a restart, nor that their unstable writes were lost.
+///
+/// This must be called before the first file handle or write verifier is
+/// produced. Returns false if the seed, the generation number or the
+/// write verifier was already set; those already set are kept.
+pub fn set_random_seed(seed: u64) -> bool {
+    let gen = u64::from_le_bytes(seeded(seed, b"generation number")[..8].try_into().unwrap());
+    let boot = u64::from_le_bytes(seeded(seed, b"write verifier")[..8].try_into().unwrap());
+    // each is set even if an earlier one was not
+    RANDOM_SEED.set(seed).is_ok() & set_generation_number(gen) & BOOT_TIME.set(boot).is_ok()
+}
+
+/// Persists the generation number in a file so that file handles survive
+/// intended server restarts.
+///
+/// If the file exists, the generation number stored in it is used.
+/// Otherwise the current generation number is written to it. Either way the
+/// generation number in use is returned. Like set_generation_number, this
+/// must be called before the first file handle is produced.
+///
+/// This is only correct if the file system assigns the same fileid to the
+/// same object across restarts (see for instance fileid_alloc). Otherwise
+/// old handles will resolve to the wrong objects instead of being STALE.
+/// Delete the file to invalidate all outstanding handles.
+pub fn persist_generation_number(path: impl AsRef<Path>) -> io::Result<u64> {
+    let path = path.as_ref();
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            let gen = contents
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if !set_generation_number(gen) && get_generation_number() != gen {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "a different generation number is already in use",
+                ));
+            }
+            Ok(gen)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let gen = get_generation_number();
+            write_atomically(path, gen.to_string().as_bytes())?;
+            Ok(gen)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the file at path with contents, such that after a crash it
+/// holds either all of contents or what it held before: contents are
+/// written and synced to a temporary file in the same directory, which is
+/// then renamed over path.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    // make the rename itself durable. Directories cannot be opened (or
+    // synced) this way on every platform, so this is best effort.
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = std::fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Checks at compile time that a type can be served by the listener, i.e.
+/// that it implements [`NFSFileSystem`](crate::vfs::NFSFileSystem) and is
+/// `Send + Sync + 'static`.
+///
+/// ```ignore
+/// struct MyFS { .. }
+/// #[async_trait]
+/// impl NFSFileSystem for MyFS { .. }
+/// nfsserve::assert_vfs!(MyFS);
+/// ```
+#[macro_export]
+macro_rules! assert_vfs {
+    ($t:ty) => {
+        const _: fn() = || {
+            fn assert_vfs<T: $crate::vfs::NFSFileSystem + Send + Sync + 'static>() {}
+            assert_vfs::<$t>();
+        };
+    };
+}
+
+/// The credentials of the caller of a request, from its AUTH_UNIX
+/// credential. See current_user.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserContext {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups
+    pub gids: Vec<u32>,
+    /// The client's host name as it sent it
+    pub machinename: Vec<u8>,
+}
+
+tokio::task_local! {
+    static USER_CONTEXT: Option<UserContext>;
+}
+
+/// The ACCESS3 bits of requested which the mode bits of attr grant user,
+/// as a local file system would check them: by the owner, group or other
+/// bits, with uid 0 allowed everything but executing files no one may
+/// execute. Everything requested is granted without credentials (user
+/// None). This is the default of NFSFileSystem::access.
+pub fn default_access(attr: &fattr3, user: Option<&UserContext>, requested: u32) -> u32 {
+    let Some(user) = user else {
+        return requested;
+    };
+    let is_dir = matches!(attr.ftype, ftype3::NF3DIR);
+    let rwx = if user.uid == 0 {
+        if is_dir || attr.mode & 0o111 != 0 {
+            7
+        } else {
+            6
+        }
+    } else if user.uid == attr.uid {
+        (attr.mode >> 6) & 7
+    } else if user.gid == attr.gid || user.gids.contains(&attr.gid) {
+        (attr.mode >> 3) & 7
+    } else {
+        attr.mode & 7
+    };
+    let mut granted = 0;
+    if rwx & 4 != 0 {
+        granted |= ACCESS3_READ;
+    }
+    if rwx & 2 != 0 {
+        granted |= ACCESS3_MODIFY | ACCESS3_EXTEND | ACCESS3_DELETE;
+    }
+    if rwx & 1 != 0 {
+        granted |= ACCESS3_LOOKUP | ACCESS3_EXECUTE;
+    }
+    requested & granted
+}
+
+/// Returns the credentials of the caller of the request being served, or
+/// None if the request did not carry AUTH_UNIX credentials (or if called
+/// outside of a request). May be called from any NFSFileSystem method to
+/// enforce per-user permissions, squash uids and so on. Note that the
+/// server itself only checks NFSFileSystem::access on the directories a
+/// call changes the entries of: the rest is up to the file system.
+///
+/// Only valid on the task running the request: a task spawned by the
+/// file system has to capture the value first.
+pub fn current_user() -> Option<UserContext> {
+    USER_CONTEXT.try_with(|u| u.clone()).ok().flatten()
+}
+
+/// Runs fut with user as the value of current_user
+pub(crate) async fn with_user<F: std::future::Future>(
+    user: Option<UserContext>,
+    fut: F,
+) -> F::Output {
+    USER_CONTEXT.scope(user, fut).await
+}
+
+/// The transfer sizes and limits reported by the default fsinfo.
+/// See NFSFileSystem::fsinfo_config. All sizes are in bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct FsInfoConfig {
+    /// The largest READ the server serves. Larger READs are clamped
+    pub rtmax: u32,
+    /// The preferred READ size
+    pub rtpref: u32,
+    /// The suggested multiple for READ sizes
+    pub rtmult: u32,
+    /// The largest WRITE the server expects
+    pub wtmax: u32,
+    /// The preferred WRITE size
+    pub wtpref: u32,
+    /// The suggested multiple for WRITE sizes
+    pub wtmult: u32,
+    /// The preferred READDIR size
+    pub dtpref: u32,
+    /// The largest file size. WRITEs past it fail with NFS3ERR_FBIG
+    pub maxfilesize: u64,
+    /// The longest file name, advertised by PATHCONF. Calls naming a
+    /// longer one fail with NFS3ERR_NAMETOOLONG before reaching the file
+    /// system
+    pub name_max: u32,
+}
+
+impl Default for FsInfoConfig {
+    fn default() -> FsInfoConfig {
+        FsInfoConfig {
+            rtmax: 1024 * 1024,
+            rtpref: 1024 * 124,
+            rtmult: 1024 * 1024,
+            wtmax: 1024 * 1024,
+            wtpref: 1024 * 1024,
+            wtmult: 1024 * 1024,
+            dtpref: 1024 * 1024,
+            maxfilesize: 128 * 1024 * 1024 * 1024,
+            name_max: 255,
+        }
+    }
+}
+
+/// The dynamic file system information replied to FSSTAT.
+/// See NFSFileSystem::fs_stat.
+#[derive(Copy, Clone, Debug)]
+pub struct FsStat {
+    /// The size of the file system in bytes
+    pub total_bytes: u64,
+    /// The free space in bytes
+    pub free_bytes: u64,
+    /// The free space available to the caller in bytes
+    pub avail_bytes: u64,
+    /// The total number of file slots (inodes)
+    pub total_files: u64,
+    /// The number of free file slots
+    pub free_files: u64,
+    /// The number of free file slots available to the caller
+    pub avail_files: u64,
+    /// The number of seconds for which the above is not expected to change.
+    /// u32::MAX means it never does
+    pub invarsec: u32,
+}
+
+impl Default for FsStat {
+    /// A nominal 1TiB file system with 1G files, all of it free
+    fn default() -> FsStat {
+        FsStat {
+            total_bytes: 1024 * 1024 * 1024 * 1024,
+            free_bytes: 1024 * 1024 * 1024 * 1024,
+            avail_bytes: 1024 * 1024 * 1024 * 1024,
+            total_files: 1024 * 1024 * 1024,
+            free_files: 1024 * 1024 * 1024,
+            avail_files: 1024 * 1024 * 1024,
+            invarsec: u32::MAX,
+        }
     }
 }
 
@@ -74,10 +794,15 @@ pub enum VFSCapabilities {
 ///  - A 64-bit generation number derived from the server startup time
 ///   (i.e. so the opaque file handle expires when the NFS server restarts)
 ///  - The 64-bit file id
+///
+/// File systems which can name their objects durably can override
+/// id_to_fh and fh_to_id to keep handles valid across restarts instead.
+/// See StableFh. The default handles can be signed so that clients cannot
+/// forge them, see HandleCodec::key.
 //
 /// readdir pagination
 /// ------------------
-/// We do not use cookie verifier. We just use the start_after.  The
+/// We do not use cookie verifier. We just use the start_after cookie.  The
 /// implementation should allow startat to start at any position. That is,
 /// the next query to readdir may be the last entry in the previous readdir
 /// response.
@@ -92,8 +817,25 @@ pub enum VFSCapabilities {
 ///  getattr needs to be fast. NFS uses that a lot
 //
 ///  The 0 fileid is reserved and should not be used
+//
+/// Send and Sync
+/// -------------
+/// The listener shares one instance across all connections as an
+/// `Arc<dyn NFSFileSystem + Send + Sync>`, and every connection runs on its
+/// own task, so methods are called concurrently through `&self` from
+/// several threads. Hence the Sync supertrait, and the Send + 'static bound
+/// on the listener. The futures returned by the async methods must also be
+/// Send: do not hold a non-Send value (a `std::sync::MutexGuard`, an `Rc`,
+/// a `RefCell` borrow) across an await. The compiler reports the offending
+/// method on the impl. Use [`assert_vfs!`](crate::assert_vfs) next to the
+/// type to check all of the above where the type is defined rather than
+/// where it is first handed to the listener.
 ///
 #[async_trait]
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` does not implement `NFSFileSystem`",
+    note = "implement `nfsserve::vfs::NFSFileSystem` (with `#[async_trait]`) for `{Self}`"
+)]
 pub trait NFSFileSystem: Sync {
     /// Returns the set of capabilities supported
     fn capabilities(&self) -> VFSCapabilities;
@@ -123,13 +865,69 @@ pub trait NFSFileSystem: Sync {
     async fn read(&self, id: fileid3, offset: u64, count: u32)
         -> Result<(Vec<u8>, bool), nfsstat3>;
 
+    /// Reads as read does, appending the bytes read to buf, and also
+    /// returns the attributes of the file as of the read if they are known.
+    /// This is what the READ handler calls. It reuses buf across calls, so
+    /// implementations which read straight into buf save an allocation per
+    /// READ. Optional. The default calls read, copies the bytes into buf
+    /// and returns no attributes.
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        let (bytes, eof) = self.read(id, offset, count).await?;
+        buf.extend_from_slice(&bytes);
+        Ok(ReadReply { eof, attr: None })
+    }
+
     /// Writes the contents of a file returning (bytes, EOF)
     /// Note that offset/count may go past the end of the file and that
     /// in that case, the file is extended.
+    /// Any gap between the previous end of the file and offset must read
+    /// as zeros afterwards (as a sparse hole does on a local file system).
+    /// Implementations over storage which is not zeroed on allocation have
+    /// to zero-fill the gap explicitly. The same applies to a file
+    /// extended by setattr.
     /// If not supported due to readonly file system
     /// this should return Err(nfsstat3::NFS3ERR_ROFS)
     async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3>;
 
+    /// Writes the contents of a file as write does, where stable is how
+    /// much of the write the client requires to be on stable storage
+    /// before the reply. This is what the WRITE handler calls. Optional.
+    ///
+    /// committed is how stable the write actually is, which must be at
+    /// least as stable as requested. An UNSTABLE write must be made stable
+    /// by a later commit of its range.
+    ///
+    /// before are the attributes of the file immediately before the write
+    /// when they can be read in the same step as the write (e.g. under a
+    /// per file lock), so that no other change lands between them. These
+    /// become the wcc "before" attributes of the WRITE reply. A client
+    /// which sees "before" match its cached attributes keeps its cached
+    /// data, so "before" attributes read earlier, racing with the writes
+    /// of other clients, make clients discard their caches needlessly.
+    /// When None, the attributes read before calling this are used.
+    ///
+    /// The default calls write, reports FILE_SYNC, i.e. it assumes write
+    /// is synchronous, and returns no "before" attributes.
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        _stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        Ok(WriteReply {
+            before: None,
+            attr: self.write(id, offset, data).await?,
+            committed: stable_how::FILE_SYNC,
+        })
+    }
+
     /// Creates a file with the following attributes.
     /// If not supported due to readonly file system
     /// this should return Err(nfsstat3::NFS3ERR_ROFS)
@@ -180,23 +978,133 @@ pub trait NFSFileSystem: Sync {
     ///
     /// For instance if the directory has entry with ids [1,6,2,11,8,9]
     /// and start_after=6, readdir should returning 2,11,8,...
+    ///
+    /// start_after is the cookie of the entry to resume after, as the
+    /// client sent it back (0 to start from the beginning). The cookie of
+    /// each entry is DirEntry::cookie, or its fileid if that is left 0.
+    /// Set cookies when fileids do not identify a position in the listing,
+    /// e.g. with hard links (two entries with one fileid), or when the
+    /// listing is not ordered by fileid. Cookies must not be 0.
     //
     async fn readdir(
         &self,
         dirid: fileid3,
-        start_after: fileid3,
+        start_after: cookie3,
         max_entries: usize,
     ) -> Result<ReadDirResult, nfsstat3>;
 
+    /// Returns a page of directory entries already encoded in the
+    /// READDIRPLUS wire format. Optional.
+    ///
+    /// This is an escape hatch for file systems whose listings are themselves
+    /// obtained in XDR form (for instance when proxying another NFS server),
+    /// avoiding a decode / re-encode round trip. If this returns Some, the
+    /// result is spliced directly into the READDIRPLUS reply; only the
+    /// maxcount limit is enforced, by truncating at an entry boundary.
+    /// Respecting dircount is the responsibility of the implementation.
+    ///
+    /// The default returns None, in which case readdir() is used.
+    async fn readdir_raw(
+        &self,
+        _dirid: fileid3,
+        _cookie: cookie3,
+        _dircount: count3,
+        _maxcount: count3,
+    ) -> Option<Result<RawDirPage, nfsstat3>> {
+        None
+    }
+
+    /// Lists a directory as readdir does, handing the entries to sink one
+    /// at a time and in order, until sink returns false or the directory
+    /// ends. An entry sink returns false for is not taken, and the next
+    /// listing resumes after the entry before it. Optional.
+    ///
+    /// The default readdirplus lists with this so that no entries (and
+    /// attributes) are produced past what fits in its reply. File systems for which each
+    /// entry is costly, for instance a stat of the backing file, should
+    /// override it to produce entries only as sink takes them. max_entries
+    /// is how many entries are expected to be taken.
+    ///
+    /// Returns true if sink took every entry up to the end of the
+    /// directory. The default calls readdir for max_entries entries at a
+    /// time.
+    async fn readdir_each(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+        sink: &mut (dyn FnMut(DirEntry) -> bool + Send),
+    ) -> Result<bool, nfsstat3> {
+        let mut start_after = start_after;
+        loop {
+            let page = self.readdir(dirid, start_after, max_entries.max(1)).await?;
+            let Some(next) = page.entries.last().map(DirEntry::effective_cookie) else {
+                return Ok(page.end);
+            };
+            for entry in page.entries {
+                if !sink(entry) {
+                    return Ok(false);
+                }
+            }
+            if page.end {
+                return Ok(true);
+            }
+            start_after = next;
+        }
+    }
+
+    /// Lists a page of a directory for READDIRPLUS, starting after cookie:
+    /// the entries with their attributes and file handles, as many as fit
+    /// in dircount bytes of fileids, names and cookies and in maxcount
+    /// bytes of reply, which ReadDirPlusPage keeps track of.
+    /// end is true if the page reaches the end of the directory. Optional.
+    ///
+    /// max_entries is how many entries are expected to fit, from the sizes
+    /// of the entries of earlier pages of the directory (dircount / 16 for
+    /// a directory not listed yet). It is only a hint: the page still ends
+    /// where ReadDirPlusPage says it is full.
+    ///
+    /// File systems whose listings come with handles and attributes at
+    /// once (e.g. metadata loaded when listing the directory) can override
+    /// this to build the page in one pass.
+    ///
+    /// The default lists with readdir_each for max_entries entries,
+    /// taking entries until the page is full, with a handle from id_to_fh
+    /// for each.
+    async fn readdirplus(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+        max_entries: usize,
+    ) -> Result<ReadDirPlusResult, nfsstat3> {
+        let mut page = ReadDirPlusPage::new(dircount, maxcount);
+        let mut sink = |entry: DirEntry| {
+            page.push(DirEntryPlus {
+                handle: post_op_fh3::handle(self.id_to_fh(entry.fileid)),
+                fileid: entry.fileid,
+                name: entry.name,
+                attr: entry.attr,
+                cookie: entry.cookie,
+            })
+        };
+        let end = self
+            .readdir_each(dirid, cookie, max_entries, &mut sink)
+            .await?;
+        Ok(page.finish(end))
+    }
+
     /// Simple version of readdir.
-    /// Only need to return filename and id
+    /// Only need to return filename and id. start_after is as in readdir.
     async fn readdir_simple(
         &self,
         dirid: fileid3,
+        start_after: cookie3,
         count: usize,
     ) -> Result<ReadDirSimpleResult, nfsstat3> {
         Ok(ReadDirSimpleResult::from_readdir_result(
-            &self.readdir(dirid, 0, count).await?,
+            &self.readdir(dirid, start_after, count).await?,
         ))
     }
 
@@ -214,6 +1122,135 @@ pub trait NFSFileSystem: Sync {
     /// Reads a symlink
     async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3>;
 
+    /// Returns the directory holding id, if known. Optional.
+    /// NFSv3 calls name objects by handle alone, so this is how READLINK
+    /// finds the directory of a link for NFSTcp::set_symlink_rewriter.
+    /// The default returns None, on which targets are not rewritten.
+    async fn parent_dir(&self, _id: fileid3) -> Option<fileid3> {
+        None
+    }
+
+    /// Makes a special file: a character or block device (NF3CHR, NF3BLK),
+    /// a socket (NF3SOCK) or a named pipe (NF3FIFO). spec holds the major
+    /// and minor device numbers for devices and is zero otherwise.
+    /// Optional. The default returns Err(nfsstat3::NFS3ERR_NOTSUPP).
+    /// Types the file system cannot create should return
+    /// Err(nfsstat3::NFS3ERR_BADTYPE).
+    async fn mknod(
+        &self,
+        _dirid: fileid3,
+        _filename: &filename3,
+        _ftype: ftype3,
+        _attr: &sattr3,
+        _spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Makes a hard link named linkname in directory linkdirid to the
+    /// existing object id. Optional.
+    /// The default returns Err(nfsstat3::NFS3ERR_NOTSUPP). Implementations
+    /// should also return true from supports_hard_links so that FSINFO
+    /// advertises FSF_LINK.
+    async fn link(
+        &self,
+        _id: fileid3,
+        _linkdirid: fileid3,
+        _linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Returns true if link is implemented. Optional.
+    /// Used by the default fsinfo to set FSF_LINK.
+    fn supports_hard_links(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the contents of a directory never change. Optional.
+    ///
+    /// Clients cache negative LOOKUP results (ENOENT) for as long as the
+    /// attributes of the directory do not change. A failed lookup in an
+    /// immutable directory is replied with the directory's atime pinned to
+    /// its mtime, so that the attributes are identical on every reply and
+    /// the client keeps trusting its negative entries for the full
+    /// attribute cache timeout (acdirmin / acdirmax on Linux), instead of
+    /// repeating the lookup. This cuts down on the LOOKUP storms of PATH
+    /// searches for instance. Clients mounted with lookupcache=positive
+    /// or lookupcache=none never cache negative results regardless.
+    async fn is_immutable_dir(&self, _dirid: fileid3) -> bool {
+        false
+    }
+
+    /// Returns the ACCESS3 bits of requested which the caller
+    /// (current_user) is allowed on id, whose attributes are attr.
+    /// Optional.
+    ///
+    /// Clients refuse operations locally based on the reply (e.g. open
+    /// for writing), so that they fail up front rather than on the first
+    /// WRITE. The server only enforces it on directories: CREATE, MKDIR,
+    /// SYMLINK, MKNOD and LINK need ACCESS3_EXTEND and ACCESS3_LOOKUP on
+    /// the parent, REMOVE, RMDIR and the source of RENAME need
+    /// ACCESS3_DELETE and ACCESS3_LOOKUP, or NFS3ERR_ACCES is returned
+    /// without calling the file system. The default checks the mode
+    /// bits of attr with default_access. A file system which enforces
+    /// another policy (or none) should override it to match. The bits
+    /// which change data are cleared afterwards on a file system which is
+    /// not ReadWrite.
+    async fn access(&self, _id: fileid3, attr: &fattr3, requested: u32) -> u32 {
+        default_access(attr, current_user().as_ref(), requested)
+    }
+
+    /// Flushes previously written data in the range [offset, offset+count)
+    /// of a file to stable storage. A count of 0 means everything from
+    /// offset to the end of the file, and must cover every write not yet
+    /// committed in that range. Optional.
+    ///
+    /// The default does nothing, which is correct as long as every write
+    /// is stable by the time it returns.
+    async fn commit(&self, _id: fileid3, _offset: u64, _count: u32) -> Result<(), nfsstat3> {
+        Ok(())
+    }
+
+    /// The granularity of the times the file system stores, reported as
+    /// time_delta by the default fsinfo. Optional. Defaults to 1ms.
+    ///
+    /// Per RFC 1813 times set by SETATTR (touch, cp -p, tar) are only
+    /// guaranteed to be preserved to this accuracy, and clients may use it
+    /// as the precision of the file times. It also bounds how close two
+    /// changes can be and still be told apart by mtime / ctime alone.
+    ///  - {0, 1} says times are exact to the nanosecond. Only report it if
+    ///    getattr returns exactly what setattr stored.
+    ///  - {0, 1000000} (the default) is millisecond precision.
+    ///  - {1, 0} says times are only accurate to the second, which is what
+    ///    backends with coarse timestamps (such as object stores) should
+    ///    report. Clients then do not expect sub-second times to round
+    ///    trip, and should not rely on mtime alone to detect changes made
+    ///    within the same second.
+    ///
+    /// {0, 0} is allowed on the wire but carries no information, and
+    /// should not be used.
+    fn time_delta(&self) -> nfstime3 {
+        nfstime3 {
+            seconds: 0,
+            nseconds: 1000000,
+        }
+    }
+
+    /// The transfer sizes and limits reported by the default fsinfo.
+    /// Optional. The READ and WRITE handlers also enforce rtmax and
+    /// maxfilesize from here (it is cheaper than a full fsinfo), so an
+    /// implementation overriding fsinfo should keep the two consistent.
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        FsInfoConfig::default()
+    }
+
+    /// Returns the space and file slots used and available, for FSSTAT.
+    /// Optional. The default reports a nominal empty 1TiB file system.
+    async fn fs_stat(&self, _id: fileid3) -> Result<FsStat, nfsstat3> {
+        Ok(FsStat::default())
+    }
+
     /// Get static file system Information
     async fn fsinfo(
         &self,
@@ -225,46 +1262,35 @@ pub trait NFSFileSystem: Sync {
             Err(_) => nfs::post_op_attr::Void,
         };
 
-        let res = fsinfo3 {
+        let config = self.fsinfo_config();
+        let mut res = fsinfo3 {
             obj_attributes: dir_attr,
-            rtmax: 1024 * 1024,
-            rtpref: 1024 * 124,
-            rtmult: 1024 * 1024,
-            wtmax: 1024 * 1024,
-            wtpref: 1024 * 1024,
-            wtmult: 1024 * 1024,
-            dtpref: 1024 * 1024,
-            maxfilesize: 128 * 1024 * 1024 * 1024,
-            time_delta: nfs::nfstime3 {
-                seconds: 0,
-                nseconds: 1000000,
-            },
+            rtmax: config.rtmax,
+            rtpref: config.rtpref,
+            rtmult: config.rtmult,
+            wtmax: config.wtmax,
+            wtpref: config.wtpref,
+            wtmult: config.wtmult,
+            dtpref: config.dtpref,
+            maxfilesize: config.maxfilesize,
+            time_delta: self.time_delta(),
             properties: nfs::FSF_SYMLINK | nfs::FSF_HOMOGENEOUS | nfs::FSF_CANSETTIME,
         };
+        if self.supports_hard_links() {
+            res.properties |= nfs::FSF_LINK;
+        }
         Ok(res)
     }
 
     /// Converts the fileid to an opaque NFS file handle. Optional.
+    /// See default_id_to_fh for the default implementation.
     fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
-        let gennum = get_generation_number();
-        let mut ret: Vec<u8> = Vec::new();
-        ret.extend_from_slice(&gennum.to_le_bytes());
-        ret.extend_from_slice(&id.to_le_bytes());
-        nfs_fh3 { data: ret }
+        default_id_to_fh(id)
     }
     /// Converts an opaque NFS file handle to a fileid.  Optional.
+    /// See default_fh_to_id for the default implementation.
     fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
-        if id.data.len() != 16 {
-            return Err(nfsstat3::NFS3ERR_BADHANDLE);
-        }
-        let gen = u64::from_le_bytes(id.data[0..8].try_into().unwrap());
-        let id = u64::from_le_bytes(id.data[8..16].try_into().unwrap());
-        let gennum = get_generation_number();
-        match gen.cmp(&gennum) {
-            Ordering::Less => Err(nfsstat3::NFS3ERR_STALE),
-            Ordering::Greater => Err(nfsstat3::NFS3ERR_BADHANDLE),
-            Ordering::Equal => Ok(id),
-        }
+        default_fh_to_id(id)
     }
     /// Converts a complete path to a fileid.  Optional.
     /// The default implementation walks the directory structure with lookup()
@@ -280,8 +1306,40 @@ pub trait NFSFileSystem: Sync {
         Ok(fid)
     }
 
+    /// The write verifier returned by WRITE and COMMIT. It must change
+    /// whenever data written UNSTABLE and not yet committed may have been
+    /// lost, so that clients send it again: a restart of the server, or a
+    /// failed flush. Optional.
+    ///
+    /// The default is the time the server started, changed by every
+    /// discard_unstable_writes. See default_write_verifier.
     fn serverid(&self) -> cookieverf3 {
-        let gennum = get_generation_number();
-        gennum.to_le_bytes()
+        default_write_verifier()
+    }
+
+    /// Describes what a fileid refers to in the backing store
+    /// (i.e. a path, an object key, etc). Optional.
+    /// This is only used for debugging and tooling, for instance to map a
+    /// fileid seen in a packet capture back to a real object.
+    /// Returns None if the id is unknown or not describable.
+    async fn describe_fileid(&self, _id: fileid3) -> Option<String> {
+        None
+    }
+
+    /// Lists the fileids the VFS currently resolves, each with a
+    /// description as describe_fileid gives. Optional. Only used for
+    /// debugging stale handles (see NFSTcpListener::dump_handle_table).
+    /// Returns None if the VFS keeps no such table.
+    async fn handle_table(&self) -> Option<Vec<(fileid3, String)>> {
+        None
+    }
+
+    /// Returns a hash of the contents of a file, served through the
+    /// non-standard metadata program (metadata feature) so that a
+    /// co-designed client can verify or dedupe contents without reading
+    /// the whole file. Optional. What the hash is is up to the VFS, but
+    /// it must change whenever the contents change.
+    async fn content_hash(&self, _id: fileid3) -> Option<[u8; 32]> {
+        None
     }
 }
@@ -20,6 +20,10 @@ where
         self.inner
     }
 
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
     pub fn bytes_written(&self) -> usize {
         self.count
     }
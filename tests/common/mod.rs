@@ -0,0 +1,932 @@
+//! A minimal NFSv3 client speaking raw RPC over TCP, and a server to point
+//! it at. Only what the tests need: record marking, AUTH_NULL and AUTH_UNIX
+//! calls, MOUNT3 MNT and a handful of NFS3 procedures. Arguments and
+//! results are encoded with the crate's own XDR types where they are
+//! public.
+#![allow(dead_code)]
+
+use nfsserve::config::NFSServerConfig;
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{NFSFileSystem, ReadDirResult};
+use nfsserve::xdr::XDR;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MOUNT_PROGRAM: u32 = 100005;
+const MOUNT_VERSION: u32 = 3;
+const MOUNTPROC3_MNT: u32 = 1;
+const MOUNTPROC3_UMNT: u32 = 3;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC3_GETATTR: u32 = 1;
+const NFSPROC3_SETATTR: u32 = 2;
+const NFSPROC3_LOOKUP: u32 = 3;
+const NFSPROC3_READLINK: u32 = 5;
+const NFSPROC3_READ: u32 = 6;
+const NFSPROC3_WRITE: u32 = 7;
+const NFSPROC3_CREATE: u32 = 8;
+const NFSPROC3_MKDIR: u32 = 9;
+const NFSPROC3_SYMLINK: u32 = 10;
+const NFSPROC3_REMOVE: u32 = 12;
+const NFSPROC3_RMDIR: u32 = 13;
+const NFSPROC3_RENAME: u32 = 14;
+const NFSPROC3_READDIR: u32 = 16;
+const NFSPROC3_READDIRPLUS: u32 = 17;
+const NFSPROC3_COMMIT: u32 = 21;
+
+/// Appends one XDR encoded argument to a call
+type ArgWriter<'a> = &'a dyn Fn(&mut Vec<u8>);
+
+/// Serves fs on an ephemeral port of 127.0.0.1 from a thread of its own,
+/// and returns the port. The server lives until the test process exits.
+pub fn serve<T: NFSFileSystem + Send + Sync + 'static>(fs: T) -> u16 {
+    serve_with(fs, |_| {})
+}
+
+/// Like serve, with configure called on the listener before it serves
+pub fn serve_with<T, C>(fs: T, configure: C) -> u16
+where
+    T: NFSFileSystem + Send + Sync + 'static,
+    C: FnOnce(&mut NFSTcpListener<T>) + Send + 'static,
+{
+    serve_shared(fs, configure).get_listen_port()
+}
+
+/// Like serve_with, also returning the listener for the test to look at
+/// as it serves
+pub fn serve_shared<T, C>(fs: T, configure: C) -> Arc<NFSTcpListener<T>>
+where
+    T: NFSFileSystem + Send + Sync + 'static,
+    C: FnOnce(&mut NFSTcpListener<T>) + Send + 'static,
+{
+    serve_listener(fs, None, configure)
+}
+
+/// Like serve, binding with NFSTcpListener::bind_with_config
+pub fn serve_config<T: NFSFileSystem + Send + Sync + 'static>(
+    fs: T,
+    config: NFSServerConfig,
+) -> u16 {
+    serve_listener(fs, Some(config), |_| {}).get_listen_port()
+}
+
+fn serve_listener<T, C>(
+    fs: T,
+    config: Option<NFSServerConfig>,
+    configure: C,
+) -> Arc<NFSTcpListener<T>>
+where
+    T: NFSFileSystem + Send + Sync + 'static,
+    C: FnOnce(&mut NFSTcpListener<T>) + Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let mut listener = match config {
+                Some(config) => NFSTcpListener::bind_with_config("127.0.0.1:0", fs, config)
+                    .await
+                    .unwrap(),
+                None => NFSTcpListener::bind("127.0.0.1:0", fs).await.unwrap(),
+            };
+            configure(&mut listener);
+            let listener = Arc::new(listener);
+            tx.send(listener.clone()).unwrap();
+            listener.handle_forever().await.unwrap();
+        });
+    });
+    rx.recv().unwrap()
+}
+
+/// The required NFSFileSystem methods, as forward_to_memfs! implements
+/// them: each calls the MemFS of the struct, unless given in the hooks
+/// block of the macro
+#[async_trait::async_trait]
+pub trait MemFSHooks: Send + Sync {
+    fn memfs(&self) -> &MemFS;
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.memfs().lookup(dirid, filename).await
+    }
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.memfs().getattr(id).await
+    }
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.memfs().setattr(id, setattr).await
+    }
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.memfs().read(id, offset, count).await
+    }
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        self.memfs().write(id, offset, data).await
+    }
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.memfs().create(dirid, filename, attr).await
+    }
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.memfs().create_exclusive(dirid, filename).await
+    }
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.memfs().mkdir(dirid, dirname).await
+    }
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.memfs().remove(dirid, filename).await
+    }
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.memfs()
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        self.memfs().readdir(dirid, start_after, max_entries).await
+    }
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.memfs().symlink(dirid, linkname, symlink, attr).await
+    }
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.memfs().readlink(id).await
+    }
+}
+
+/// Implements NFSFileSystem for a struct with a field named inner which
+/// derefs to a MemFS. The required methods go through MemFSHooks, those
+/// given in a leading hooks block replacing its own, and the other
+/// methods given are added to the impl:
+///
+/// forward_to_memfs! {
+///     CountingFS,
+///     hooks {
+///         async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> { ... }
+///     }
+///     async fn commit(&self, ...) -> Result<(), nfsstat3> { ... }
+/// }
+#[allow(unused_macros)]
+macro_rules! forward_to_memfs {
+    ($fs:ty, hooks { $($hooks:tt)* } $($methods:tt)*) => {
+        #[async_trait::async_trait]
+        impl $crate::common::MemFSHooks for $fs {
+            fn memfs(&self) -> &nfsserve::memfs::MemFS {
+                &self.inner
+            }
+            $($hooks)*
+        }
+
+        #[async_trait::async_trait]
+        impl nfsserve::vfs::NFSFileSystem for $fs {
+            fn capabilities(&self) -> nfsserve::vfs::VFSCapabilities {
+                self.inner.capabilities()
+            }
+            fn root_dir(&self) -> fileid3 {
+                self.inner.root_dir()
+            }
+            async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+                $crate::common::MemFSHooks::lookup(self, dirid, filename).await
+            }
+            async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+                $crate::common::MemFSHooks::getattr(self, id).await
+            }
+            async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+                $crate::common::MemFSHooks::setattr(self, id, setattr).await
+            }
+            async fn read(
+                &self,
+                id: fileid3,
+                offset: u64,
+                count: u32,
+            ) -> Result<(Vec<u8>, bool), nfsstat3> {
+                $crate::common::MemFSHooks::read(self, id, offset, count).await
+            }
+            async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+                $crate::common::MemFSHooks::write(self, id, offset, data).await
+            }
+            async fn create(
+                &self,
+                dirid: fileid3,
+                filename: &filename3,
+                attr: sattr3,
+            ) -> Result<(fileid3, fattr3), nfsstat3> {
+                $crate::common::MemFSHooks::create(self, dirid, filename, attr).await
+            }
+            async fn create_exclusive(
+                &self,
+                dirid: fileid3,
+                filename: &filename3,
+            ) -> Result<fileid3, nfsstat3> {
+                $crate::common::MemFSHooks::create_exclusive(self, dirid, filename).await
+            }
+            async fn mkdir(
+                &self,
+                dirid: fileid3,
+                dirname: &filename3,
+            ) -> Result<(fileid3, fattr3), nfsstat3> {
+                $crate::common::MemFSHooks::mkdir(self, dirid, dirname).await
+            }
+            async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+                $crate::common::MemFSHooks::remove(self, dirid, filename).await
+            }
+            async fn rename(
+                &self,
+                from_dirid: fileid3,
+                from_filename: &filename3,
+                to_dirid: fileid3,
+                to_filename: &filename3,
+            ) -> Result<(), nfsstat3> {
+                $crate::common::MemFSHooks::rename(
+                    self,
+                    from_dirid,
+                    from_filename,
+                    to_dirid,
+                    to_filename,
+                )
+                .await
+            }
+            async fn readdir(
+                &self,
+                dirid: fileid3,
+                start_after: cookie3,
+                max_entries: usize,
+            ) -> Result<nfsserve::vfs::ReadDirResult, nfsstat3> {
+                $crate::common::MemFSHooks::readdir(self, dirid, start_after, max_entries).await
+            }
+            async fn symlink(
+                &self,
+                dirid: fileid3,
+                linkname: &filename3,
+                symlink: &nfspath3,
+                attr: &sattr3,
+            ) -> Result<(fileid3, fattr3), nfsstat3> {
+                $crate::common::MemFSHooks::symlink(self, dirid, linkname, symlink, attr).await
+            }
+            async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+                $crate::common::MemFSHooks::readlink(self, id).await
+            }
+            $($methods)*
+        }
+    };
+    ($fs:ty, $($methods:tt)*) => {
+        forward_to_memfs! { $fs, hooks {} $($methods)* }
+    };
+}
+#[allow(unused_imports)]
+pub(crate) use forward_to_memfs;
+
+/// An entry of a READDIRPLUS reply
+#[derive(Debug)]
+pub struct DirPlusEntry {
+    pub fileid: u64,
+    pub name: Vec<u8>,
+    pub cookie: u64,
+    pub attr: post_op_attr,
+    pub handle: post_op_fh3,
+}
+
+/// A READDIRPLUS reply
+#[derive(Debug)]
+pub struct DirPlusPage {
+    pub cookieverf: cookieverf3,
+    pub entries: Vec<DirPlusEntry>,
+    pub eof: bool,
+}
+
+pub struct Client {
+    stream: TcpStream,
+    xid: u32,
+    /// The uid and gid calls are made as, with AUTH_UNIX. AUTH_NULL if None.
+    user: Option<(u32, u32)>,
+}
+
+fn read_u32(src: &mut impl Read) -> u32 {
+    let mut v = 0u32;
+    v.deserialize(src).unwrap();
+    v
+}
+
+fn read_u64(src: &mut impl Read) -> u64 {
+    let mut v = 0u64;
+    v.deserialize(src).unwrap();
+    v
+}
+
+fn read<T: XDR + Default>(src: &mut impl Read) -> T {
+    let mut v = T::default();
+    v.deserialize(src).unwrap();
+    v
+}
+
+fn read_stat(src: &mut impl Read) -> nfsstat3 {
+    let mut stat = nfsstat3::NFS3_OK;
+    stat.deserialize(src).unwrap();
+    stat
+}
+
+/// Decodes the results of a READ: the data and the eof flag
+pub fn read_results(res: &mut impl Read) -> Result<(Vec<u8>, bool), nfsstat3> {
+    let stat = read_stat(res);
+    let _attr: post_op_attr = read(res);
+    match stat {
+        nfsstat3::NFS3_OK => {
+            let _count = read_u32(res);
+            let eof: bool = read(res);
+            let data: Vec<u8> = read(res);
+            Ok((data, eof))
+        }
+        stat => Err(stat),
+    }
+}
+
+/// Parses the results of a READDIRPLUS, as returned by readdirplus_bytes
+pub fn parse_readdirplus(results: &[u8]) -> Result<DirPlusPage, nfsstat3> {
+    let mut res = Cursor::new(results);
+    let stat = read_stat(&mut res);
+    let _dir_attr: post_op_attr = read(&mut res);
+    if !matches!(stat, nfsstat3::NFS3_OK) {
+        return Err(stat);
+    }
+    let cookieverf: cookieverf3 = read(&mut res);
+    let mut entries = Vec::new();
+    while read::<bool>(&mut res) {
+        entries.push(DirPlusEntry {
+            fileid: read_u64(&mut res),
+            name: read::<Vec<u8>>(&mut res),
+            cookie: read_u64(&mut res),
+            attr: read(&mut res),
+            handle: read(&mut res),
+        });
+    }
+    let eof = read(&mut res);
+    assert_eq!(
+        res.position() as usize,
+        res.get_ref().len(),
+        "trailing bytes in the READDIRPLUS reply"
+    );
+    Ok(DirPlusPage {
+        cookieverf,
+        entries,
+        eof,
+    })
+}
+
+fn diropargs(dir: &nfs_fh3, name: &[u8]) -> diropargs3 {
+    diropargs3 {
+        dir: dir.clone(),
+        name: name.into(),
+    }
+}
+
+impl Client {
+    pub fn connect(port: u16) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        Client {
+            stream,
+            xid: 0,
+            user: None,
+        }
+    }
+
+    /// Connects from local_ip, a loopback address other than 127.0.0.1,
+    /// which the server then sees as another client host
+    pub fn connect_from(port: u16, local_ip: [u8; 4]) -> Client {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let stream = rt.block_on(async {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind((local_ip, 0).into()).unwrap();
+            socket.connect(([127, 0, 0, 1], port).into()).await.unwrap()
+        });
+        let stream = stream.into_std().unwrap();
+        stream.set_nonblocking(false).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        Client {
+            stream,
+            xid: 0,
+            user: None,
+        }
+    }
+
+    /// Another handle on the same connection, for sending calls from one
+    /// thread while another receives the replies. Its xids continue from
+    /// xid_base, so that they do not collide with those of self.
+    pub fn try_clone(&self, xid_base: u32) -> Client {
+        Client {
+            stream: self.stream.try_clone().unwrap(),
+            xid: xid_base,
+            user: self.user,
+        }
+    }
+
+    /// Makes the calls that follow with AUTH_UNIX credentials of uid and
+    /// gid, without supplementary groups
+    pub fn set_user(&mut self, uid: u32, gid: u32) {
+        self.user = Some((uid, gid));
+    }
+
+    /// Makes a call and returns the results, past the
+    /// accepted reply header. Panics if the call is not accepted.
+    pub fn call(&mut self, prog: u32, vers: u32, proc: u32, args: &[u8]) -> Cursor<Vec<u8>> {
+        let xid = self.send_call(prog, vers, proc, args);
+        let (reply_xid, reply) = self.recv_reply();
+        assert_eq!(reply_xid, xid, "xid");
+        reply
+    }
+
+    /// Sends a call without waiting for the reply. Returns
+    /// its xid.
+    pub fn send_call(&mut self, prog: u32, vers: u32, proc: u32, args: &[u8]) -> u32 {
+        self.xid += 1;
+        // the record marking header is filled in below
+        let mut msg = vec![0u8; 4];
+        // xid, CALL, rpcvers 2, prog, vers, proc
+        for v in [self.xid, 0, 2, prog, vers, proc] {
+            v.serialize(&mut msg).unwrap();
+        }
+        match self.user {
+            Some((uid, gid)) => {
+                let mut cred = Vec::new();
+                // stamp, machinename, uid, gid and no gids
+                0u32.serialize(&mut cred).unwrap();
+                b"client".to_vec().serialize(&mut cred).unwrap();
+                for v in [uid, gid, 0] {
+                    v.serialize(&mut cred).unwrap();
+                }
+                1u32.serialize(&mut msg).unwrap();
+                cred.serialize(&mut msg).unwrap();
+            }
+            None => {
+                0u32.serialize(&mut msg).unwrap();
+                0u32.serialize(&mut msg).unwrap();
+            }
+        }
+        // AUTH_NULL verifier
+        for v in [0u32, 0] {
+            v.serialize(&mut msg).unwrap();
+        }
+        msg.extend_from_slice(args);
+        // a single fragment, so the last fragment bit is set. One write, or
+        // Nagle holds back the body until the header is acknowledged.
+        let header = 0x8000_0000u32 | (msg.len() - 4) as u32;
+        msg[..4].copy_from_slice(&header.to_be_bytes());
+        self.stream.write_all(&msg).unwrap();
+        self.xid
+    }
+
+    /// Receives the next reply. Returns its xid and the results, past the
+    /// accepted reply header. Panics if the call was not accepted.
+    pub fn recv_reply(&mut self) -> (u32, Cursor<Vec<u8>>) {
+        let (xid, stat, reply) = self.recv_accepted();
+        assert_eq!(stat, 0, "accept_stat SUCCESS");
+        (xid, reply)
+    }
+
+    /// Makes a call which may not succeed. Returns the accept_stat of the
+    /// reply and what follows it.
+    pub fn call_accepted(
+        &mut self,
+        prog: u32,
+        vers: u32,
+        proc: u32,
+        args: &[u8],
+    ) -> (u32, Cursor<Vec<u8>>) {
+        let xid = self.send_call(prog, vers, proc, args);
+        let (reply_xid, stat, reply) = self.recv_accepted();
+        assert_eq!(reply_xid, xid, "xid");
+        (stat, reply)
+    }
+
+    /// Receives the next reply. Returns its xid, its accept_stat and what
+    /// follows it. Panics if the call was denied.
+    fn recv_accepted(&mut self) -> (u32, u32, Cursor<Vec<u8>>) {
+        let mut record = Vec::new();
+        loop {
+            let mut header = [0u8; 4];
+            self.stream.read_exact(&mut header).unwrap();
+            let header = u32::from_be_bytes(header);
+            let mut fragment = vec![0u8; (header & 0x7fff_ffff) as usize];
+            self.stream.read_exact(&mut fragment).unwrap();
+            record.extend_from_slice(&fragment);
+            if header & 0x8000_0000 != 0 {
+                break;
+            }
+        }
+        let mut reply = Cursor::new(record);
+        let xid = read_u32(&mut reply);
+        assert_eq!(read_u32(&mut reply), 1, "msg_type REPLY");
+        assert_eq!(read_u32(&mut reply), 0, "reply_stat MSG_ACCEPTED");
+        let _verf_flavor = read_u32(&mut reply);
+        let _verf_body: Vec<u8> = read(&mut reply);
+        let stat = read_u32(&mut reply);
+        (xid, stat, reply)
+    }
+
+    fn nfs(&mut self, proc: u32, args: &[ArgWriter]) -> Cursor<Vec<u8>> {
+        let mut buf = Vec::new();
+        for arg in args {
+            arg(&mut buf);
+        }
+        self.call(NFS_PROGRAM, NFS_VERSION, proc, &buf)
+    }
+
+    /// MOUNT3 MNT of path. Returns the root handle.
+    pub fn mount(&mut self, path: &[u8]) -> nfs_fh3 {
+        self.try_mount(path).expect("MNT3_OK")
+    }
+
+    /// MOUNT3 MNT of path. Returns the root handle, or the mountstat3 of
+    /// the failure.
+    pub fn try_mount(&mut self, path: &[u8]) -> Result<nfs_fh3, u32> {
+        let mut args = Vec::new();
+        path.to_vec().serialize(&mut args).unwrap();
+        let mut res = self.call(MOUNT_PROGRAM, MOUNT_VERSION, MOUNTPROC3_MNT, &args);
+        match read_u32(&mut res) {
+            0 => Ok(read(&mut res)),
+            stat => Err(stat),
+        }
+    }
+
+    /// MOUNT3 UMNT of path
+    pub fn umount(&mut self, path: &[u8]) {
+        let mut args = Vec::new();
+        path.to_vec().serialize(&mut args).unwrap();
+        self.call(MOUNT_PROGRAM, MOUNT_VERSION, MOUNTPROC3_UMNT, &args);
+    }
+
+    pub fn getattr(&mut self, fh: &nfs_fh3) -> Result<fattr3, nfsstat3> {
+        let mut res = self.nfs(NFSPROC3_GETATTR, &[&|b| fh.serialize(b).unwrap()]);
+        match read_stat(&mut res) {
+            nfsstat3::NFS3_OK => Ok(read(&mut res)),
+            stat => Err(stat),
+        }
+    }
+
+    /// SETATTR of the mode alone, without a guard
+    pub fn chmod(&mut self, fh: &nfs_fh3, mode: u32) -> Result<(), nfsstat3> {
+        let attr = sattr3 {
+            mode: set_mode3::mode(mode),
+            ..Default::default()
+        };
+        self.setattr(fh, &attr)
+    }
+
+    /// SETATTR without a guard
+    pub fn setattr(&mut self, fh: &nfs_fh3, attr: &sattr3) -> Result<(), nfsstat3> {
+        let mut res = self.nfs(
+            NFSPROC3_SETATTR,
+            &[
+                &|b| fh.serialize(b).unwrap(),
+                &|b| attr.serialize(b).unwrap(),
+                &|b| false.serialize(b).unwrap(),
+            ],
+        );
+        match read_stat(&mut res) {
+            nfsstat3::NFS3_OK => Ok(()),
+            stat => Err(stat),
+        }
+    }
+
+    pub fn lookup(&mut self, dir: &nfs_fh3, name: &[u8]) -> Result<nfs_fh3, nfsstat3> {
+        let args = diropargs(dir, name);
+        let mut res = self.nfs(NFSPROC3_LOOKUP, &[&|b| args.serialize(b).unwrap()]);
+        match read_stat(&mut res) {
+            nfsstat3::NFS3_OK => Ok(read(&mut res)),
+            stat => Err(stat),
+        }
+    }
+
+    /// Returns the data and the eof flag
+    pub fn read(
+        &mut self,
+        fh: &nfs_fh3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let xid = self.send_read(fh, offset, count);
+        let (reply_xid, mut res) = self.recv_reply();
+        assert_eq!(reply_xid, xid, "xid");
+        read_results(&mut res)
+    }
+
+    /// Sends a READ without waiting for the reply. Returns its xid. See
+    /// read_results.
+    pub fn send_read(&mut self, fh: &nfs_fh3, offset: u64, count: u32) -> u32 {
+        let mut args = Vec::new();
+        fh.serialize(&mut args).unwrap();
+        offset.serialize(&mut args).unwrap();
+        count.serialize(&mut args).unwrap();
+        self.send_call(NFS_PROGRAM, NFS_VERSION, NFSPROC3_READ, &args)
+    }
+
+    /// A FILE_SYNC write. Returns the count written.
+    pub fn write(&mut self, fh: &nfs_fh3, offset: u64, data: &[u8]) -> Result<u32, nfsstat3> {
+        self.write_stable(fh, offset, data, stable_how::FILE_SYNC)
+            .map(|(count, _, _)| count)
+    }
+
+    /// A WRITE asking for stable. Returns the count written, how stable
+    /// the write was committed and the write verifier.
+    pub fn write_stable(
+        &mut self,
+        fh: &nfs_fh3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<(u32, stable_how, writeverf3), nfsstat3> {
+        let data = data.to_vec();
+        let mut res = self.nfs(
+            NFSPROC3_WRITE,
+            &[
+                &|b| fh.serialize(b).unwrap(),
+                &|b| offset.serialize(b).unwrap(),
+                &|b| (data.len() as u32).serialize(b).unwrap(),
+                &|b| stable.serialize(b).unwrap(),
+                &|b| data.serialize(b).unwrap(),
+            ],
+        );
+        let stat = read_stat(&mut res);
+        let _wcc: wcc_data = read(&mut res);
+        match stat {
+            nfsstat3::NFS3_OK => {
+                let count = read_u32(&mut res);
+                let committed: stable_how = read(&mut res);
+                Ok((count, committed, read(&mut res)))
+            }
+            stat => Err(stat),
+        }
+    }
+
+    /// COMMIT of the whole file. Returns the write verifier.
+    pub fn commit(&mut self, fh: &nfs_fh3) -> Result<writeverf3, nfsstat3> {
+        let mut res = self.nfs(
+            NFSPROC3_COMMIT,
+            &[
+                &|b| fh.serialize(b).unwrap(),
+                &|b| 0u64.serialize(b).unwrap(),
+                &|b| 0u32.serialize(b).unwrap(),
+            ],
+        );
+        let stat = read_stat(&mut res);
+        let _wcc: wcc_data = read(&mut res);
+        match stat {
+            nfsstat3::NFS3_OK => Ok(read(&mut res)),
+            stat => Err(stat),
+        }
+    }
+
+    fn created(res: &mut Cursor<Vec<u8>>) -> Result<nfs_fh3, nfsstat3> {
+        match read_stat(res) {
+            nfsstat3::NFS3_OK => match read(res) {
+                post_op_fh3::handle(fh) => Ok(fh),
+                post_op_fh3::Void => panic!("no handle for the created object"),
+            },
+            stat => Err(stat),
+        }
+    }
+
+    /// An UNCHECKED create with no attributes set
+    pub fn create(&mut self, dir: &nfs_fh3, name: &[u8]) -> Result<nfs_fh3, nfsstat3> {
+        let args = diropargs(dir, name);
+        let mut res = self.nfs(
+            NFSPROC3_CREATE,
+            &[
+                &|b| args.serialize(b).unwrap(),
+                // createmode3 UNCHECKED
+                &|b| 0u32.serialize(b).unwrap(),
+                &|b| sattr3::default().serialize(b).unwrap(),
+            ],
+        );
+        Client::created(&mut res)
+    }
+
+    pub fn mkdir(&mut self, dir: &nfs_fh3, name: &[u8]) -> Result<nfs_fh3, nfsstat3> {
+        self.mkdir_with(dir, name, &sattr3::default())
+    }
+
+    pub fn mkdir_with(
+        &mut self,
+        dir: &nfs_fh3,
+        name: &[u8],
+        attr: &sattr3,
+    ) -> Result<nfs_fh3, nfsstat3> {
+        let args = diropargs(dir, name);
+        let mut res = self.nfs(
+            NFSPROC3_MKDIR,
+            &[&|b| args.serialize(b).unwrap(), &|b| {
+                attr.serialize(b).unwrap()
+            }],
+        );
+        Client::created(&mut res)
+    }
+
+    pub fn symlink(
+        &mut self,
+        dir: &nfs_fh3,
+        name: &[u8],
+        target: &[u8],
+    ) -> Result<nfs_fh3, nfsstat3> {
+        let args = diropargs(dir, name);
+        let target = target.to_vec();
+        let mut res = self.nfs(
+            NFSPROC3_SYMLINK,
+            &[
+                &|b| args.serialize(b).unwrap(),
+                &|b| sattr3::default().serialize(b).unwrap(),
+                &|b| target.serialize(b).unwrap(),
+            ],
+        );
+        Client::created(&mut res)
+    }
+
+    pub fn readlink(&mut self, fh: &nfs_fh3) -> Result<Vec<u8>, nfsstat3> {
+        let mut res = self.nfs(NFSPROC3_READLINK, &[&|b| fh.serialize(b).unwrap()]);
+        let stat = read_stat(&mut res);
+        let _attr: post_op_attr = read(&mut res);
+        match stat {
+            nfsstat3::NFS3_OK => Ok(read(&mut res)),
+            stat => Err(stat),
+        }
+    }
+
+    pub fn remove(&mut self, dir: &nfs_fh3, name: &[u8]) -> Result<(), nfsstat3> {
+        let args = diropargs(dir, name);
+        let mut res = self.nfs(NFSPROC3_REMOVE, &[&|b| args.serialize(b).unwrap()]);
+        match read_stat(&mut res) {
+            nfsstat3::NFS3_OK => Ok(()),
+            stat => Err(stat),
+        }
+    }
+
+    pub fn rmdir(&mut self, dir: &nfs_fh3, name: &[u8]) -> Result<(), nfsstat3> {
+        let args = diropargs(dir, name);
+        let mut res = self.nfs(NFSPROC3_RMDIR, &[&|b| args.serialize(b).unwrap()]);
+        match read_stat(&mut res) {
+            nfsstat3::NFS3_OK => Ok(()),
+            stat => Err(stat),
+        }
+    }
+
+    pub fn rename(
+        &mut self,
+        from_dir: &nfs_fh3,
+        from_name: &[u8],
+        to_dir: &nfs_fh3,
+        to_name: &[u8],
+    ) -> Result<(), nfsstat3> {
+        let from = diropargs(from_dir, from_name);
+        let to = diropargs(to_dir, to_name);
+        let mut res = self.nfs(
+            NFSPROC3_RENAME,
+            &[&|b| from.serialize(b).unwrap(), &|b| {
+                to.serialize(b).unwrap()
+            }],
+        );
+        match read_stat(&mut res) {
+            nfsstat3::NFS3_OK => Ok(()),
+            stat => Err(stat),
+        }
+    }
+
+    /// Lists a directory with READDIR from the start to eof. Returns the
+    /// names of the entries.
+    pub fn readdir_all(&mut self, dir: &nfs_fh3, count: u32) -> Result<Vec<Vec<u8>>, nfsstat3> {
+        let mut names = Vec::new();
+        let mut cookie = 0u64;
+        let mut cookieverf = cookieverf3::default();
+        loop {
+            let mut res = self.nfs(
+                NFSPROC3_READDIR,
+                &[
+                    &|b| dir.serialize(b).unwrap(),
+                    &|b| cookie.serialize(b).unwrap(),
+                    &|b| cookieverf.serialize(b).unwrap(),
+                    &|b| count.serialize(b).unwrap(),
+                ],
+            );
+            let stat = read_stat(&mut res);
+            let _dir_attr: post_op_attr = read(&mut res);
+            if !matches!(stat, nfsstat3::NFS3_OK) {
+                return Err(stat);
+            }
+            cookieverf = read(&mut res);
+            let mut progress = false;
+            while read::<bool>(&mut res) {
+                let _fileid = read_u64(&mut res);
+                names.push(read::<Vec<u8>>(&mut res));
+                cookie = read_u64(&mut res);
+                progress = true;
+            }
+            if read::<bool>(&mut res) {
+                return Ok(names);
+            }
+            assert!(progress, "a page which is not the last must make progress");
+        }
+    }
+
+    /// READDIRPLUS, returning the results as they came, past the reply
+    /// header
+    pub fn readdirplus_bytes(
+        &mut self,
+        dir: &nfs_fh3,
+        cookie: u64,
+        cookieverf: cookieverf3,
+        dircount: u32,
+        maxcount: u32,
+    ) -> Vec<u8> {
+        let res = self.nfs(
+            NFSPROC3_READDIRPLUS,
+            &[
+                &|b| dir.serialize(b).unwrap(),
+                &|b| cookie.serialize(b).unwrap(),
+                &|b| cookieverf.serialize(b).unwrap(),
+                &|b| dircount.serialize(b).unwrap(),
+                &|b| maxcount.serialize(b).unwrap(),
+            ],
+        );
+        let pos = res.position() as usize;
+        res.into_inner().split_off(pos)
+    }
+
+    pub fn readdirplus(
+        &mut self,
+        dir: &nfs_fh3,
+        cookie: u64,
+        cookieverf: cookieverf3,
+        dircount: u32,
+        maxcount: u32,
+    ) -> Result<DirPlusPage, nfsstat3> {
+        parse_readdirplus(&self.readdirplus_bytes(dir, cookie, cookieverf, dircount, maxcount))
+    }
+
+    /// Lists a directory with READDIRPLUS from the start to eof. Returns
+    /// the entries and the number of calls it took.
+    pub fn readdirplus_all(
+        &mut self,
+        dir: &nfs_fh3,
+        dircount: u32,
+        maxcount: u32,
+    ) -> Result<(Vec<DirPlusEntry>, usize), nfsstat3> {
+        let mut entries: Vec<DirPlusEntry> = Vec::new();
+        let mut cookieverf = cookieverf3::default();
+        let mut calls = 0;
+        loop {
+            let cookie = entries.last().map(|e| e.cookie).unwrap_or(0);
+            let page = self.readdirplus(dir, cookie, cookieverf, dircount, maxcount)?;
+            calls += 1;
+            assert!(
+                page.eof || !page.entries.is_empty(),
+                "a page which is not the last must make progress"
+            );
+            cookieverf = page.cookieverf;
+            entries.extend(page.entries);
+            if page.eof {
+                return Ok((entries, calls));
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! A session captured from a server in deterministic mode, replayed on
+//! fresh servers with all its calls sent at once, gets the same replies
+//! byte for byte
+mod common;
+
+use common::{forward_to_memfs, serve_shared, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{NFSFileSystem, ReadDirResult};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SEED: u64 = 0x5eed;
+
+fn frozen(mut attr: fattr3) -> fattr3 {
+    let time = nfstime3 {
+        seconds: 1_000_000_000,
+        nseconds: 0,
+    };
+    attr.atime = time;
+    attr.mtime = time;
+    attr.ctime = time;
+    attr
+}
+
+/// A MemFS whose times are all the same, and whose writes are slow: the
+/// requests after a write would overtake it if handled concurrently
+struct FrozenFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    FrozenFS,
+    hooks {
+        async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+            self.inner.getattr(id).await.map(frozen)
+        }
+        async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+            self.inner.setattr(id, setattr).await.map(frozen)
+        }
+        async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.inner.write(id, offset, data).await.map(frozen)
+        }
+        async fn create(
+            &self,
+            dirid: fileid3,
+            filename: &filename3,
+            attr: sattr3,
+        ) -> Result<(fileid3, fattr3), nfsstat3> {
+            let (id, attr) = self.inner.create(dirid, filename, attr).await?;
+            Ok((id, frozen(attr)))
+        }
+        async fn mkdir(
+            &self,
+            dirid: fileid3,
+            dirname: &filename3,
+        ) -> Result<(fileid3, fattr3), nfsstat3> {
+            let (id, attr) = self.inner.mkdir(dirid, dirname).await?;
+            Ok((id, frozen(attr)))
+        }
+        async fn readdir(
+            &self,
+            dirid: fileid3,
+            start_after: cookie3,
+            max_entries: usize,
+        ) -> Result<ReadDirResult, nfsstat3> {
+            let mut result = self.inner.readdir(dirid, start_after, max_entries).await?;
+            for entry in &mut result.entries {
+                entry.attr = frozen(entry.attr);
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// A deterministic server of an empty FrozenFS
+fn deterministic_server() -> Arc<NFSTcpListener<FrozenFS>> {
+    let fs = FrozenFS {
+        inner: MemFS::new(),
+    };
+    serve_shared(fs, |listener| listener.set_deterministic(SEED))
+}
+
+/// Reads a record in the record marking format, with its fragment
+/// headers
+fn read_record(src: &mut impl Read) -> Vec<u8> {
+    let mut record = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        src.read_exact(&mut header).unwrap();
+        record.extend_from_slice(&header);
+        let header = u32::from_be_bytes(header);
+        let start = record.len();
+        record.resize(start + (header & 0x7fff_ffff) as usize, 0);
+        src.read_exact(&mut record[start..]).unwrap();
+        if header & 0x8000_0000 != 0 {
+            return record;
+        }
+    }
+}
+
+/// Makes a session of a client, which the server captures. Returns the
+/// calls and the replies of the capture.
+fn capture_session() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("deterministic_replay");
+    let _ = std::fs::remove_dir_all(&dir);
+    let listener = deterministic_server();
+    listener
+        .capture_next([127, 0, 0, 1].into(), 1000, &dir)
+        .unwrap();
+
+    let mut client = Client::connect(listener.get_listen_port());
+    let root = client.mount(b"/");
+    let sub = client.mkdir(&root, b"sub").unwrap();
+    for i in 0..5 {
+        let file = client.create(&sub, format!("f{i}").as_bytes()).unwrap();
+        client.write(&file, 0, &[i as u8; 3000]).unwrap();
+        client.commit(&file).unwrap();
+        client.chmod(&file, 0o600).unwrap();
+    }
+    let file = client.lookup(&sub, b"f3").unwrap();
+    client.read(&file, 1000, 100).unwrap();
+    client.readdirplus_all(&sub, 1024, 1024).unwrap();
+    client.readdir_all(&sub, 512).unwrap();
+    client.getattr(&root).unwrap();
+    listener
+        .capture_next([127, 0, 0, 1].into(), 0, &dir)
+        .unwrap();
+
+    let log = std::fs::read(dir.join("127.0.0.1.rpclog")).unwrap();
+    let mut src = log.as_slice();
+    let (mut calls, mut replies) = (Vec::new(), Vec::new());
+    while !src.is_empty() {
+        calls.push(read_record(&mut src));
+        replies.push(read_record(&mut src));
+    }
+    (calls, replies)
+}
+
+/// Sends all the calls to a fresh deterministic server before reading any
+/// reply. Returns the replies as they came.
+fn replay(calls: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let listener = deterministic_server();
+    let mut stream = TcpStream::connect(("127.0.0.1", listener.get_listen_port())).unwrap();
+    let mut sender = stream.try_clone().unwrap();
+    let all_calls = calls.concat();
+    let sending = std::thread::spawn(move || sender.write_all(&all_calls).unwrap());
+    let replies = calls.iter().map(|_| read_record(&mut stream)).collect();
+    sending.join().unwrap();
+    replies
+}
+
+#[test]
+fn replays_are_byte_identical() {
+    let (calls, captured) = capture_session();
+    assert!(calls.len() > 20, "{} calls captured", calls.len());
+    let first = replay(&calls);
+    let second = replay(&calls);
+    for i in 0..calls.len() {
+        assert!(first[i] == second[i], "the replies to call {i} differ");
+        assert!(
+            first[i] == captured[i],
+            "the reply to call {i} differs from the captured one"
+        );
+    }
+}
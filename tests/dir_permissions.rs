@@ -0,0 +1,85 @@
+//! Changes to the entries of a directory the caller may not write to
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{nfs_fh3, nfsstat3};
+
+/// A root owned directory "ro" of mode 0555 holding a file "kept", and a
+/// directory "rw" of mode 0777, made without credentials
+fn setup() -> (Client, nfs_fh3, nfs_fh3) {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let ro = client.mkdir(&root, b"ro").unwrap();
+    client.create(&ro, b"kept").unwrap();
+    client.chmod(&ro, 0o555).unwrap();
+    let rw = client.mkdir(&root, b"rw").unwrap();
+    client.chmod(&rw, 0o777).unwrap();
+    (client, ro, rw)
+}
+
+#[test]
+fn non_owner_cannot_change_read_only_directory() {
+    let (mut client, ro, rw) = setup();
+    client.set_user(1000, 1000);
+
+    assert!(matches!(
+        client.create(&ro, b"new"),
+        Err(nfsstat3::NFS3ERR_ACCES)
+    ));
+    assert!(matches!(
+        client.mkdir(&ro, b"sub"),
+        Err(nfsstat3::NFS3ERR_ACCES)
+    ));
+    assert!(matches!(
+        client.symlink(&ro, b"link", b"kept"),
+        Err(nfsstat3::NFS3ERR_ACCES)
+    ));
+    assert!(matches!(
+        client.remove(&ro, b"kept"),
+        Err(nfsstat3::NFS3ERR_ACCES)
+    ));
+    // out of, and into, the read only directory
+    assert!(matches!(
+        client.rename(&ro, b"kept", &rw, b"moved"),
+        Err(nfsstat3::NFS3ERR_ACCES)
+    ));
+    client.create(&rw, b"mine").unwrap();
+    assert!(matches!(
+        client.rename(&rw, b"mine", &ro, b"mine"),
+        Err(nfsstat3::NFS3ERR_ACCES)
+    ));
+
+    // nothing changed
+    client.lookup(&ro, b"kept").unwrap();
+    client.lookup(&rw, b"mine").unwrap();
+    for name in [&b"new"[..], b"sub", b"link", b"moved"] {
+        assert!(matches!(
+            client.lookup(&ro, name),
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+    }
+    assert!(matches!(
+        client.lookup(&rw, b"moved"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+}
+
+#[test]
+fn writable_directories_can_be_changed() {
+    let (mut client, ro, rw) = setup();
+    client.set_user(1000, 1000);
+    let sub = client.mkdir(&rw, b"sub").unwrap();
+    client.create(&sub, b"file").unwrap();
+    client.rename(&sub, b"file", &rw, b"file").unwrap();
+    client.remove(&rw, b"file").unwrap();
+
+    // by its owner, even with only owner write permission
+    client.chmod(&sub, 0o700).unwrap();
+    client.create(&sub, b"file").unwrap();
+
+    // root may change any directory
+    client.set_user(0, 0);
+    client.create(&ro, b"by_root").unwrap();
+    client.remove(&ro, b"kept").unwrap();
+}
@@ -0,0 +1,637 @@
+//! Every NFS procedure, driven through a file system scripted to fail at
+//! each point a handler reaches it (fh_to_id, each getattr, the operation
+//! itself) with each of a few errors, replies exactly one well-formed
+//! result: the resok or resfail arm of its RFC 1813 union, and nothing
+//! after it.
+//!
+//! New procedures get covered by adding a row to ROWS.
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::{FsStat, NFSFileSystem, ReadDirResult, VFSCapabilities};
+use nfsserve::xdr::XDR;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+
+/// Where the scripted file system fails
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Point {
+    FhToId,
+    /// The nth getattr of the call, from 0
+    Getattr(usize),
+    /// Every other method
+    Op,
+}
+
+#[derive(Default)]
+struct Script {
+    fail: Option<(Point, nfsstat3)>,
+    /// The getattrs made since the script was armed
+    getattrs: usize,
+    fired: bool,
+}
+
+impl Script {
+    fn arm(&mut self, fail: Option<(Point, nfsstat3)>) {
+        *self = Script {
+            fail,
+            ..Default::default()
+        };
+    }
+}
+
+/// A MemFS which fails as scripted
+struct ScriptedFS {
+    fs: MemFS,
+    script: Arc<Mutex<Script>>,
+}
+
+impl ScriptedFS {
+    fn check(&self, at: Point) -> Result<(), nfsstat3> {
+        let mut script = self.script.lock().unwrap();
+        let at = match at {
+            Point::Getattr(_) => {
+                script.getattrs += 1;
+                Point::Getattr(script.getattrs - 1)
+            }
+            at => at,
+        };
+        match script.fail {
+            Some((point, stat)) if point == at => {
+                script.fired = true;
+                Err(stat)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NFSFileSystem for ScriptedFS {
+    fn capabilities(&self) -> VFSCapabilities {
+        self.fs.capabilities()
+    }
+    fn root_dir(&self) -> fileid3 {
+        self.fs.root_dir()
+    }
+    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        self.check(Point::FhToId)?;
+        self.fs.fh_to_id(id)
+    }
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.lookup(dirid, filename).await
+    }
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.check(Point::Getattr(0))?;
+        self.fs.getattr(id).await
+    }
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.setattr(id, setattr).await
+    }
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.read(id, offset, count).await
+    }
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.write(id, offset, data).await
+    }
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.create(dirid, filename, attr).await
+    }
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.create_exclusive(dirid, filename).await
+    }
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.mkdir(dirid, dirname).await
+    }
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.remove(dirid, filename).await
+    }
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: cookie3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.readdir(dirid, start_after, max_entries).await
+    }
+    async fn symlink(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.symlink(dirid, linkname, symlink, attr).await
+    }
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.readlink(id).await
+    }
+    async fn mknod(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.mknod(dirid, filename, ftype, attr, spec).await
+    }
+    async fn link(
+        &self,
+        id: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.link(id, linkdirid, linkname).await
+    }
+    async fn commit(&self, id: fileid3, offset: u64, count: u32) -> Result<(), nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.commit(id, offset, count).await
+    }
+    async fn fs_stat(&self, id: fileid3) -> Result<FsStat, nfsstat3> {
+        self.check(Point::Op)?;
+        self.fs.fs_stat(id).await
+    }
+    async fn parent_dir(&self, id: fileid3) -> Option<fileid3> {
+        self.fs.parent_dir(id).await
+    }
+}
+
+/// A field of a result
+#[derive(Copy, Clone, Debug)]
+enum Field {
+    U32,
+    U64,
+    Bool,
+    /// Variable length opaque data, or a string
+    Opaque,
+    /// An 8 byte verifier
+    Verf,
+    Fattr,
+    Attr,
+    Wcc,
+    Fh,
+    PostFh,
+    /// The entries of a READDIR reply, up to the end of the list
+    Entries,
+    /// The entries of a READDIRPLUS reply, up to the end of the list
+    EntriesPlus,
+}
+use Field::*;
+
+fn read<T: XDR + Default>(res: &mut Cursor<Vec<u8>>) -> T {
+    let mut v = T::default();
+    v.deserialize(res).expect("truncated result");
+    v
+}
+
+fn decode(res: &mut Cursor<Vec<u8>>, fields: &[Field]) {
+    for field in fields {
+        match field {
+            U32 => {
+                read::<u32>(res);
+            }
+            U64 => {
+                read::<u64>(res);
+            }
+            Bool => {
+                read::<bool>(res);
+            }
+            Opaque => drop(read::<Vec<u8>>(res)),
+            Verf => {
+                read::<cookieverf3>(res);
+            }
+            Fattr => {
+                read::<fattr3>(res);
+            }
+            Attr => {
+                read::<post_op_attr>(res);
+            }
+            Wcc => {
+                read::<wcc_data>(res);
+            }
+            Fh => {
+                read::<nfs_fh3>(res);
+            }
+            PostFh => {
+                read::<post_op_fh3>(res);
+            }
+            Entries => {
+                while read::<bool>(res) {
+                    decode(res, &[U64, Opaque, U64]);
+                }
+            }
+            EntriesPlus => {
+                while read::<bool>(res) {
+                    decode(res, &[U64, Opaque, U64, Attr, PostFh]);
+                }
+            }
+        }
+    }
+}
+
+/// The objects the calls are made on
+struct Fixture {
+    root: nfs_fh3,
+    dir: nfs_fh3,
+    file: nfs_fh3,
+    link: nfs_fh3,
+}
+
+/// Encodes the arguments of a call, making what it needs first. n is
+/// unique to the call, for names.
+type Args = fn(&mut Client, &Fixture, usize) -> Vec<u8>;
+
+struct Row {
+    proc: u32,
+    name: &'static str,
+    args: Args,
+    ok: &'static [Field],
+    fail: &'static [Field],
+}
+
+/// Appends one XDR encoded argument to a call
+type ArgWriter<'a> = &'a dyn Fn(&mut Vec<u8>);
+
+fn encode(parts: &[ArgWriter]) -> Vec<u8> {
+    let mut args = Vec::new();
+    for part in parts {
+        part(&mut args);
+    }
+    args
+}
+
+fn fh(fh: &nfs_fh3) -> impl Fn(&mut Vec<u8>) + '_ {
+    move |b| fh.serialize(b).unwrap()
+}
+
+fn name(name: String) -> impl Fn(&mut Vec<u8>) {
+    move |b| name.as_bytes().to_vec().serialize(b).unwrap()
+}
+
+fn val<T: XDR>(v: T) -> impl Fn(&mut Vec<u8>) {
+    move |b| v.serialize(b).unwrap()
+}
+
+const CREATE_OK: &[Field] = &[PostFh, Attr, Wcc];
+
+const ROWS: &[Row] = &[
+    Row {
+        proc: 1,
+        name: "GETATTR",
+        args: |_, f, _| encode(&[&fh(&f.file)]),
+        ok: &[Fattr],
+        fail: &[],
+    },
+    Row {
+        proc: 2,
+        name: "SETATTR",
+        args: |_, f, _| {
+            let mode = sattr3 {
+                mode: set_mode3::mode(0o644),
+                ..Default::default()
+            };
+            // no guard
+            encode(&[&fh(&f.file), &val(mode), &val(false)])
+        },
+        ok: &[Wcc],
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 3,
+        name: "LOOKUP",
+        args: |_, f, _| encode(&[&fh(&f.dir), &name("file".into())]),
+        ok: &[Fh, Attr, Attr],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 4,
+        name: "ACCESS",
+        args: |_, f, _| encode(&[&fh(&f.file), &val(0x3fu32)]),
+        ok: &[Attr, U32],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 5,
+        name: "READLINK",
+        args: |_, f, _| encode(&[&fh(&f.link)]),
+        ok: &[Attr, Opaque],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 6,
+        name: "READ",
+        args: |_, f, _| encode(&[&fh(&f.file), &val(0u64), &val(16u32)]),
+        ok: &[Attr, U32, Bool, Opaque],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 7,
+        name: "WRITE",
+        args: |_, f, _| {
+            // FILE_SYNC
+            let data = b"data".to_vec();
+            encode(&[&fh(&f.file), &val(0u64), &val(4u32), &val(2u32), &val(data)])
+        },
+        ok: &[Wcc, U32, U32, Verf],
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 8,
+        name: "CREATE",
+        args: |_, f, n| {
+            // UNCHECKED
+            encode(&[
+                &fh(&f.dir),
+                &name(format!("create{n}")),
+                &val(0u32),
+                &val(sattr3::default()),
+            ])
+        },
+        ok: CREATE_OK,
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 8,
+        name: "CREATE EXCLUSIVE",
+        args: |_, f, n| {
+            encode(&[
+                &fh(&f.dir),
+                &name(format!("exclusive{n}")),
+                &val(2u32),
+                &val(*b"verifier"),
+            ])
+        },
+        ok: CREATE_OK,
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 9,
+        name: "MKDIR",
+        args: |_, f, n| {
+            encode(&[
+                &fh(&f.dir),
+                &name(format!("mkdir{n}")),
+                &val(sattr3::default()),
+            ])
+        },
+        ok: CREATE_OK,
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 10,
+        name: "SYMLINK",
+        args: |_, f, n| {
+            encode(&[
+                &fh(&f.dir),
+                &name(format!("symlink{n}")),
+                &val(sattr3::default()),
+                &name("file".into()),
+            ])
+        },
+        ok: CREATE_OK,
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 11,
+        name: "MKNOD",
+        args: |_, f, n| {
+            // NF3FIFO
+            encode(&[
+                &fh(&f.dir),
+                &name(format!("mknod{n}")),
+                &val(7u32),
+                &val(sattr3::default()),
+            ])
+        },
+        ok: CREATE_OK,
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 12,
+        name: "REMOVE",
+        args: |client, f, n| {
+            let name_ = format!("remove{n}");
+            client.create(&f.dir, name_.as_bytes()).unwrap();
+            encode(&[&fh(&f.dir), &name(name_)])
+        },
+        ok: &[Wcc],
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 13,
+        name: "RMDIR",
+        args: |client, f, n| {
+            let name_ = format!("rmdir{n}");
+            client.mkdir(&f.dir, name_.as_bytes()).unwrap();
+            encode(&[&fh(&f.dir), &name(name_)])
+        },
+        ok: &[Wcc],
+        fail: &[Wcc],
+    },
+    Row {
+        proc: 14,
+        name: "RENAME",
+        args: |client, f, n| {
+            let name_ = format!("rename{n}");
+            client.create(&f.dir, name_.as_bytes()).unwrap();
+            encode(&[
+                &fh(&f.dir),
+                &name(name_),
+                &fh(&f.root),
+                &name(format!("renamed{n}")),
+            ])
+        },
+        ok: &[Wcc, Wcc],
+        fail: &[Wcc, Wcc],
+    },
+    Row {
+        proc: 15,
+        name: "LINK",
+        args: |_, f, n| encode(&[&fh(&f.file), &fh(&f.dir), &name(format!("link{n}"))]),
+        ok: &[Attr, Wcc],
+        fail: &[Attr, Wcc],
+    },
+    Row {
+        proc: 16,
+        name: "READDIR",
+        args: |_, f, _| {
+            encode(&[
+                &fh(&f.dir),
+                &val(0u64),
+                &val(cookieverf3::default()),
+                &val(4096u32),
+            ])
+        },
+        ok: &[Attr, Verf, Entries, Bool],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 17,
+        name: "READDIRPLUS",
+        args: |_, f, _| {
+            encode(&[
+                &fh(&f.dir),
+                &val(0u64),
+                &val(cookieverf3::default()),
+                &val(4096u32),
+                &val(8192u32),
+            ])
+        },
+        ok: &[Attr, Verf, EntriesPlus, Bool],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 18,
+        name: "FSSTAT",
+        args: |_, f, _| encode(&[&fh(&f.root)]),
+        ok: &[Attr, U64, U64, U64, U64, U64, U64, U32],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 19,
+        name: "FSINFO",
+        args: |_, f, _| encode(&[&fh(&f.root)]),
+        ok: &[Attr, U32, U32, U32, U32, U32, U32, U32, U64, U32, U32, U32],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 20,
+        name: "PATHCONF",
+        args: |_, f, _| encode(&[&fh(&f.root)]),
+        ok: &[Attr, U32, U32, Bool, Bool, Bool, Bool],
+        fail: &[Attr],
+    },
+    Row {
+        proc: 21,
+        name: "COMMIT",
+        args: |_, f, _| encode(&[&fh(&f.file), &val(0u64), &val(0u32)]),
+        ok: &[Wcc, Verf],
+        fail: &[Wcc],
+    },
+];
+
+const ERRORS: [nfsstat3; 5] = [
+    nfsstat3::NFS3ERR_IO,
+    nfsstat3::NFS3ERR_NOENT,
+    nfsstat3::NFS3ERR_ACCES,
+    nfsstat3::NFS3ERR_STALE,
+    nfsstat3::NFS3ERR_JUKEBOX,
+];
+
+#[test]
+fn every_failure_replies_one_result() {
+    let script = Arc::new(Mutex::new(Script::default()));
+    let fs = ScriptedFS {
+        fs: MemFS::new(),
+        script: script.clone(),
+    };
+    let mut client = Client::connect(serve(fs));
+    let root = client.mount(b"/");
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    let file = client.create(&dir, b"file").unwrap();
+    client.write(&file, 0, b"contents").unwrap();
+    let link = client.symlink(&dir, b"link", b"file").unwrap();
+    let fixture = Fixture {
+        root,
+        dir,
+        file,
+        link,
+    };
+
+    let mut n = 0;
+    let mut call = |client: &mut Client, row: &Row, fail: Option<(Point, nfsstat3)>| {
+        n += 1;
+        let args = (row.args)(client, &fixture, n);
+        script.lock().unwrap().arm(fail);
+        let mut res = client.call(NFS_PROGRAM, NFS_VERSION, row.proc, &args);
+        let (fired, getattrs) = {
+            let mut script = script.lock().unwrap();
+            let done = (script.fired, script.getattrs);
+            script.arm(None);
+            done
+        };
+        let case = format!("{} failing {:?}", row.name, fail);
+
+        let mut stat = nfsstat3::NFS3_OK;
+        stat.deserialize(&mut res).expect("truncated result");
+        let fields = match stat {
+            nfsstat3::NFS3_OK => row.ok,
+            _ => row.fail,
+        };
+        decode(&mut res, fields);
+        assert_eq!(
+            res.position() as usize,
+            res.get_ref().len(),
+            "{case}: trailing bytes after {stat:?}"
+        );
+        // errors of the handle and of the operation itself are passed on
+        if let Some((point @ (Point::FhToId | Point::Op), err)) = fail {
+            if fired {
+                assert_eq!(stat as u32, err as u32, "{case} at {point:?}: {stat:?}");
+            }
+        }
+        // and nothing else was replied: the reply to a NULL call is next
+        client.call(NFS_PROGRAM, NFS_VERSION, 0, &[]);
+        getattrs
+    };
+
+    for row in ROWS {
+        let getattrs = call(&mut client, row, None);
+        let mut points = vec![Point::FhToId, Point::Op];
+        points.extend((0..getattrs).map(Point::Getattr));
+        for point in points {
+            for err in ERRORS {
+                call(&mut client, row, Some((point, err)));
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! EXCLUSIVE CREATE replies leave out the attributes of the new file, so
+//! that clients follow up with the SETATTR that commits the create
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::xdr::XDR;
+use std::io::Cursor;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC3_CREATE: u32 = 8;
+
+/// CREATE3args of an EXCLUSIVE create of name in dir
+fn exclusive_create(client: &mut Client, dir: &nfs_fh3, name: &[u8]) -> Cursor<Vec<u8>> {
+    let mut args = Vec::new();
+    dir.serialize(&mut args).unwrap();
+    name.to_vec().serialize(&mut args).unwrap();
+    // createmode3 EXCLUSIVE and the createverf3
+    2u32.serialize(&mut args).unwrap();
+    args.extend_from_slice(b"verifier");
+    client.call(NFS_PROGRAM, NFS_VERSION, NFSPROC3_CREATE, &args)
+}
+
+#[test]
+fn exclusive_create_replies_have_no_attributes() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+
+    let mut res = exclusive_create(&mut client, &root, b"file");
+    let mut stat = nfsstat3::NFS3ERR_IO;
+    stat.deserialize(&mut res).unwrap();
+    assert!(matches!(stat, nfsstat3::NFS3_OK), "{stat:?}");
+    let mut obj = post_op_fh3::Void;
+    obj.deserialize(&mut res).unwrap();
+    let post_op_fh3::handle(fh) = obj else {
+        panic!("no handle for the created file");
+    };
+    let mut obj_attributes = post_op_attr::Void;
+    obj_attributes.deserialize(&mut res).unwrap();
+    assert!(
+        matches!(obj_attributes, post_op_attr::Void),
+        "EXCLUSIVE create replied {obj_attributes:?}"
+    );
+    let mut dir_wcc = wcc_data::default();
+    dir_wcc.deserialize(&mut res).unwrap();
+    assert!(matches!(dir_wcc.after, post_op_attr::attributes(_)));
+    assert_eq!(
+        res.position() as usize,
+        res.get_ref().len(),
+        "trailing bytes"
+    );
+
+    // the handle is good for the SETATTR which commits the create
+    client.chmod(&fh, 0o644).unwrap();
+    assert_eq!(client.getattr(&fh).unwrap().mode & 0o777, 0o644);
+    assert_eq!(client.lookup(&root, b"file").unwrap().data, fh.data);
+}
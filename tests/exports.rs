@@ -0,0 +1,99 @@
+//! Serving several file systems under their own export paths with
+//! ExportTable
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::exports::ExportTable;
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::NFSFileSystem;
+use std::sync::Arc;
+
+const MNT3ERR_NOENT: u32 = 2;
+
+/// A MemFS whose file handles are NFS3_FHSIZE bytes long, leaving no room
+/// for the export index
+struct LongFhFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    LongFhFS,
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        let mut data = vec![0xff; NFS3_FHSIZE as usize - 8];
+        data.extend_from_slice(&id.0.to_le_bytes());
+        nfs_fh3 { data }
+    }
+    fn fh_to_id(&self, fh: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        let id = fh
+            .data
+            .get(NFS3_FHSIZE as usize - 8..)
+            .ok_or(nfsstat3::NFS3ERR_BADHANDLE)?;
+        Ok(fileid3(u64::from_le_bytes(
+            id.try_into().map_err(|_| nfsstat3::NFS3ERR_BADHANDLE)?,
+        )))
+    }
+}
+
+fn serve_exports(exports: Vec<(&str, Arc<dyn NFSFileSystem + Send + Sync>)>) -> Client {
+    Client::connect(serve(ExportTable::new(exports).unwrap()))
+}
+
+#[test]
+fn handles_route_to_their_export() {
+    let mut client = serve_exports(vec![
+        ("/a", Arc::new(MemFS::new())),
+        ("/b", Arc::new(MemFS::new())),
+    ]);
+    assert!(matches!(client.try_mount(b"/c"), Err(MNT3ERR_NOENT)));
+    assert!(matches!(client.try_mount(b"/ab"), Err(MNT3ERR_NOENT)));
+
+    let a = client.mount(b"/a");
+    let b = client.mount(b"/b");
+    let in_a = client.create(&a, b"file").unwrap();
+    client.write(&in_a, 0, b"in a").unwrap();
+    let in_b = client.create(&b, b"file").unwrap();
+    client.write(&in_b, 0, b"in b").unwrap();
+    client.mkdir(&b, b"only_in_b").unwrap();
+
+    assert_eq!(client.read(&in_a, 0, 100).unwrap().0, b"in a");
+    assert_eq!(client.read(&in_b, 0, 100).unwrap().0, b"in b");
+    let found = client.lookup(&a, b"file").unwrap();
+    assert_eq!(client.read(&found, 0, 100).unwrap().0, b"in a");
+    assert!(matches!(
+        client.lookup(&a, b"only_in_b"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    // the same object of either export has a distinct fileid
+    assert_ne!(
+        client.getattr(&a).unwrap().fileid,
+        client.getattr(&b).unwrap().fileid
+    );
+
+    // a path under an export mounts within it
+    let sub = client.mount(b"/b/only_in_b");
+    client.create(&sub, b"nested").unwrap();
+    let dir = client.lookup(&b, b"only_in_b").unwrap();
+    client.lookup(&dir, b"nested").unwrap();
+}
+
+#[test]
+fn handles_too_long_for_the_export_index_are_refused() {
+    let mut client = serve_exports(vec![
+        ("/a", Arc::new(MemFS::new())),
+        (
+            "/long",
+            Arc::new(LongFhFS {
+                inner: MemFS::new(),
+            }),
+        ),
+    ]);
+    let long = client.mount(b"/long");
+    assert!(long.data.is_empty());
+    assert!(matches!(
+        client.getattr(&long),
+        Err(nfsstat3::NFS3ERR_BADHANDLE)
+    ));
+    let a = client.mount(b"/a");
+    client.getattr(&a).unwrap();
+}
@@ -0,0 +1,88 @@
+//! FSINFO replies carry the transfer sizes of NFSFileSystem::fsinfo_config,
+//! and READs are clamped to its rtmax
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::{FsInfoConfig, NFSFileSystem};
+use nfsserve::xdr::XDR;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC3_FSINFO: u32 = 19;
+
+const RTMAX: u32 = 12 << 10;
+const WTMAX: u32 = 20 << 10;
+
+/// A MemFS advertising transfer sizes of its own
+struct SmallTransfersFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    SmallTransfersFS,
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        FsInfoConfig {
+            rtmax: RTMAX,
+            rtpref: RTMAX,
+            wtmax: WTMAX,
+            wtpref: WTMAX,
+            ..self.inner.fsinfo_config()
+        }
+    }
+}
+
+/// The FSINFO3resok of fh, checking the reply has nothing after it
+fn fsinfo(client: &mut Client, fh: &nfs_fh3) -> fsinfo3 {
+    let mut args = Vec::new();
+    fh.serialize(&mut args).unwrap();
+    let mut res = client.call(NFS_PROGRAM, NFS_VERSION, NFSPROC3_FSINFO, &args);
+    let mut stat = nfsstat3::NFS3ERR_IO;
+    stat.deserialize(&mut res).unwrap();
+    assert!(matches!(stat, nfsstat3::NFS3_OK), "{stat:?}");
+    let mut info = fsinfo3::default();
+    info.deserialize(&mut res).unwrap();
+    assert_eq!(
+        res.position() as usize,
+        res.get_ref().len(),
+        "trailing bytes"
+    );
+    info
+}
+
+#[test]
+fn fsinfo_sends_the_configured_sizes() {
+    let mut client = Client::connect(serve(SmallTransfersFS {
+        inner: MemFS::new(),
+    }));
+    let root = client.mount(b"/");
+    let info = fsinfo(&mut client, &root);
+    assert_eq!(info.rtmax, RTMAX);
+    assert_eq!(info.rtpref, RTMAX);
+    assert_eq!(info.wtmax, WTMAX);
+    assert_eq!(info.wtpref, WTMAX);
+    let defaults = MemFS::new().fsinfo_config();
+    assert_eq!(info.dtpref, defaults.dtpref);
+    assert_eq!(info.maxfilesize, defaults.maxfilesize);
+    assert!(matches!(info.obj_attributes, post_op_attr::attributes(_)));
+
+    // a READ of more than rtmax comes back short
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, &[7; 2 * RTMAX as usize]).unwrap();
+    let (data, eof) = client.read(&file, 0, 2 * RTMAX).unwrap();
+    assert_eq!(data.len(), RTMAX as usize);
+    assert!(!eof);
+}
+
+#[test]
+fn fsinfo_sends_the_default_sizes() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let info = fsinfo(&mut client, &root);
+    let defaults = MemFS::new().fsinfo_config();
+    assert_eq!(info.rtmax, defaults.rtmax);
+    assert_eq!(info.wtmax, defaults.wtmax);
+    assert_ne!(info.rtmax, RTMAX);
+    assert_ne!(info.wtmax, WTMAX);
+}
@@ -0,0 +1,39 @@
+//! Calls whose arguments do not decode are replied GARBAGE_ARGS, and the
+//! connection stays up for the calls after them
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::xdr::XDR;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC3_READ: u32 = 6;
+
+const GARBAGE_ARGS: u32 = 4;
+
+#[test]
+fn truncated_args_are_garbage() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, b"contents").unwrap();
+
+    // READ3args of the file, cut off within the handle and within the offset
+    let mut args = Vec::new();
+    file.serialize(&mut args).unwrap();
+    0u64.serialize(&mut args).unwrap();
+    8u32.serialize(&mut args).unwrap();
+    let cut = args.len() - 8;
+    for len in [0, 2, cut] {
+        let (stat, reply) =
+            client.call_accepted(NFS_PROGRAM, NFS_VERSION, NFSPROC3_READ, &args[..len]);
+        assert_eq!(stat, GARBAGE_ARGS, "{len} bytes of arguments");
+        assert!(reply.get_ref()[reply.position() as usize..].is_empty());
+    }
+
+    // and the next call on the connection is served
+    let (data, eof) = client.read(&file, 0, 8).unwrap();
+    assert_eq!(data, b"contents");
+    assert!(eof);
+}
@@ -0,0 +1,83 @@
+//! File handles signed with a key of the listener: NFSTcp::set_handle_key,
+//! NFSTcp::set_sign_handles or NFSServerConfig::sign_handles
+mod common;
+
+use common::{serve, serve_config, serve_with, Client};
+use nfsserve::config::NFSServerConfig;
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{nfs_fh3, nfsstat3};
+use nfsserve::tcp::NFSTcp;
+
+#[test]
+fn tampered_handles_are_refused() {
+    let mut client = Client::connect(serve_with(MemFS::new(), |listener| {
+        listener.set_handle_key(b"handle key")
+    }));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.getattr(&file).unwrap();
+
+    // flipping any bit of the handle, MAC included, invalidates it
+    for byte in 0..file.data.len() {
+        let mut forged = nfs_fh3 {
+            data: file.data.clone(),
+        };
+        forged.data[byte] ^= 1;
+        assert!(
+            matches!(client.getattr(&forged), Err(nfsstat3::NFS3ERR_BADHANDLE)),
+            "handle with byte {byte} flipped was accepted"
+        );
+    }
+    // as does cutting the MAC short
+    let truncated = nfs_fh3 {
+        data: file.data[..file.data.len() - 1].to_vec(),
+    };
+    assert!(matches!(
+        client.getattr(&truncated),
+        Err(nfsstat3::NFS3ERR_BADHANDLE)
+    ));
+    client.getattr(&file).unwrap();
+}
+
+/// Two servers of the same tree hand out handles which only differ by
+/// their MAC, and each refuses those of the other
+#[test]
+fn handles_do_not_replay_across_servers() {
+    let config = NFSServerConfig {
+        sign_handles: true,
+        ..Default::default()
+    };
+    let mut first = Client::connect(serve_config(MemFS::new(), config));
+    let mut second = Client::connect(serve_with(MemFS::new(), |listener| {
+        listener.set_sign_handles(true).unwrap()
+    }));
+    let first_root = first.mount(b"/");
+    let second_root = second.mount(b"/");
+    let first_file = first.create(&first_root, b"file").unwrap();
+    let second_file = second.create(&second_root, b"file").unwrap();
+    assert_eq!(first_file.data.len(), second_file.data.len());
+    assert_ne!(first_file.data, second_file.data);
+
+    for foreign in [&second_root, &second_file] {
+        assert!(matches!(
+            first.getattr(foreign),
+            Err(nfsstat3::NFS3ERR_BADHANDLE)
+        ));
+    }
+    for foreign in [&first_root, &first_file] {
+        assert!(matches!(
+            second.getattr(foreign),
+            Err(nfsstat3::NFS3ERR_BADHANDLE)
+        ));
+    }
+    first.getattr(&first_file).unwrap();
+    second.getattr(&second_file).unwrap();
+
+    // a server which does not sign takes signed handles for old ones
+    let mut unsigned = Client::connect(serve(MemFS::new()));
+    unsigned.mount(b"/");
+    assert!(matches!(
+        unsigned.getattr(&first_file),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+}
@@ -0,0 +1,63 @@
+//! File handles made to expire with NFSTcp::set_handle_ttl
+mod common;
+
+use common::{serve, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::nfsstat3;
+use nfsserve::tcp::NFSTcp;
+use std::time::Duration;
+
+#[test]
+fn expired_handles_are_stale() {
+    let mut client = Client::connect(serve_with(MemFS::new(), |listener| {
+        listener.set_handle_ttl(Some(Duration::from_millis(500)))
+    }));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.getattr(&file).unwrap();
+
+    std::thread::sleep(Duration::from_millis(600));
+    assert!(matches!(
+        client.getattr(&file),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+    assert!(matches!(
+        client.lookup(&root, b"file"),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+
+    // mounting and looking up again gives fresh handles
+    let root = client.mount(b"/");
+    let file = client.lookup(&root, b"file").unwrap();
+    client.getattr(&file).unwrap();
+}
+
+#[test]
+fn the_ttl_is_per_server() {
+    let mut expiring = Client::connect(serve_with(MemFS::new(), |listener| {
+        listener.set_handle_ttl(Some(Duration::from_millis(500)))
+    }));
+    let mut lasting = Client::connect(serve(MemFS::new()));
+    let expiring_root = expiring.mount(b"/");
+    let lasting_root = lasting.mount(b"/");
+    let file = lasting.create(&lasting_root, b"file").unwrap();
+
+    std::thread::sleep(Duration::from_millis(600));
+    assert!(matches!(
+        expiring.getattr(&expiring_root),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+    lasting.getattr(&lasting_root).unwrap();
+    lasting.getattr(&file).unwrap();
+
+    // the handles of each server are refused by the other, as one made
+    // without an expiry and one past it
+    assert!(matches!(
+        expiring.getattr(&file),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+    assert!(matches!(
+        lasting.getattr(&expiring_root),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+}
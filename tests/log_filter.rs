@@ -0,0 +1,62 @@
+//! The tracing targets of the subsystems, and changing the filter of the
+//! subscriber installed by the crate while the server runs
+#![cfg(feature = "log-reload")]
+mod common;
+
+use common::{serve, Client};
+use nfsserve::logging;
+use nfsserve::memfs::MemFS;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// Collects everything the subscriber writes
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+#[test]
+fn filter_changes_at_runtime() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    logging::install_subscriber_with_writer("nfsserve::mount=debug", move || writer.clone())
+        .unwrap();
+    // only one subscriber can be installed by the crate
+    assert!(logging::install_subscriber("debug").is_err());
+
+    let port = serve(MemFS::new());
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    client.readdir_all(&root, 4096).unwrap();
+    let logged = captured.take();
+    assert!(logged.contains("nfsserve::mount"));
+    assert!(!logged.contains("nfsserve::readdir"));
+    assert!(!logged.contains("nfsserve::rpc"));
+
+    logging::set_log_filter("nfsserve::readdir=debug,nfsserve::rpc=trace").unwrap();
+    client.mount(b"/");
+    client.readdir_all(&root, 4096).unwrap();
+    let logged = captured.take();
+    assert!(logged.contains("nfsserve::readdir"));
+    assert!(logged.contains("nfsserve::rpc"));
+    assert!(!logged.contains("nfsserve::mount"));
+
+    assert!(logging::set_log_filter("nfsserve::readdir=loud").is_err());
+    logging::set_log_filter("off").unwrap();
+    client.readdir_all(&root, 4096).unwrap();
+    assert!(captured.take().is_empty());
+}
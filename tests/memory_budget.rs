@@ -0,0 +1,126 @@
+//! READs and WRITEs against a memory budget smaller than a single READ
+mod common;
+
+use common::{forward_to_memfs, read_results, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::NFSTcp;
+use nfsserve::vfs::ReadReply;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+const BUDGET: usize = 48 * 1024;
+const COUNT: u32 = 64 * 1024;
+
+/// A MemFS whose READs wait for a permit of gate while gated is set
+struct GatedFS {
+    inner: MemFS,
+    gated: Arc<AtomicBool>,
+    waiting: Arc<AtomicBool>,
+    gate: Arc<Semaphore>,
+}
+
+forward_to_memfs! {
+    GatedFS,
+    async fn read_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<ReadReply, nfsstat3> {
+        if self.gated.load(Ordering::SeqCst) {
+            self.waiting.store(true, Ordering::SeqCst);
+            self.gate.acquire().await.unwrap().forget();
+        }
+        self.inner.read_ext(id, offset, count, buf).await
+    }
+}
+
+#[test]
+fn large_reads_wait_for_the_budget() {
+    let gated = Arc::new(AtomicBool::new(false));
+    let waiting = Arc::new(AtomicBool::new(false));
+    let gate = Arc::new(Semaphore::new(0));
+    let fs = GatedFS {
+        inner: MemFS::new(),
+        gated: gated.clone(),
+        waiting: waiting.clone(),
+        gate: gate.clone(),
+    };
+    let mut client = Client::connect(serve_with(fs, |listener| {
+        listener.set_memory_budget(BUDGET)
+    }));
+    let root = client.mount(b"/");
+    let contents = vec![7u8; COUNT as usize];
+    let files: Vec<nfs_fh3> = (0..3)
+        .map(|i| {
+            let file = client.create(&root, format!("file{i}").as_bytes()).unwrap();
+            // larger than the budget, admitted as nothing else is in flight
+            client.write(&file, 0, &contents).unwrap();
+            file
+        })
+        .collect();
+
+    // alone, a READ larger than the budget is served
+    assert_eq!(client.read(&files[0], 0, COUNT).unwrap().0, contents);
+
+    // while it is held up, the budget is spent and the others are deferred
+    gated.store(true, Ordering::SeqCst);
+    let held = client.send_read(&files[0], 0, COUNT);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !waiting.load(Ordering::SeqCst) {
+        assert!(Instant::now() < deadline, "the READ was not served");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    gated.store(false, Ordering::SeqCst);
+    for file in &files[1..] {
+        assert!(matches!(
+            client.read(file, 0, COUNT),
+            Err(nfsstat3::NFS3ERR_JUKEBOX)
+        ));
+    }
+    gate.add_permits(1);
+    let (xid, mut res) = client.recv_reply();
+    assert_eq!(xid, held);
+    assert_eq!(read_results(&mut res).unwrap().0, contents);
+
+    // and served once it is done, as a client retrying would
+    for file in &files[1..] {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            match client.read(file, 0, COUNT) {
+                Ok((data, _)) => {
+                    assert_eq!(data, contents);
+                    break;
+                }
+                Err(nfsstat3::NFS3ERR_JUKEBOX) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(stat) => panic!("READ failed with {stat:?}"),
+            }
+        }
+    }
+}
+
+#[test]
+fn oversized_fragments_close_the_connection() {
+    let port = serve_with(MemFS::new(), |listener| listener.set_memory_budget(BUDGET));
+    // a fragment header announcing 2GiB, and nothing else
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream.write_all(&0xffff_ffffu32.to_be_bytes()).unwrap();
+    // closed without a reply, rather than waiting for the data
+    let mut buf = [0u8; 4];
+    assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+
+    // the server still serves
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    client.create(&root, b"file").unwrap();
+}
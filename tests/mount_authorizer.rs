@@ -0,0 +1,62 @@
+//! MNT refused by a MountAuthorizer, which sees the resolved object
+mod common;
+
+use async_trait::async_trait;
+use common::{serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{fattr3, fileid3, ftype3};
+use nfsserve::tcp::{mountstat3, MountAuthorizer, NFSTcp};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const MNT3ERR_ACCES: u32 = 13;
+
+/// The sticky bit marks the directories which may be mounted
+const MARKER: u32 = 0o1000;
+
+/// Allows the mounts of the root, and of directories carrying the marker
+struct MarkedDirsOnly;
+
+#[async_trait]
+impl MountAuthorizer for MarkedDirsOnly {
+    async fn authorize_mount(
+        &self,
+        _client: SocketAddr,
+        path: &[u8],
+        _fileid: fileid3,
+        attrs: &fattr3,
+    ) -> Result<(), mountstat3> {
+        let is_dir = matches!(attrs.ftype, ftype3::NF3DIR);
+        if path == b"/" || (is_dir && attrs.mode & MARKER != 0) {
+            Ok(())
+        } else {
+            Err(mountstat3::MNT3ERR_ACCES)
+        }
+    }
+}
+
+#[test]
+fn authorizer_refuses_unmarked_objects() {
+    let port = serve_with(MemFS::new(), |listener| {
+        listener.set_mount_authorizer(Arc::new(MarkedDirsOnly));
+        // two mounts: the root and one more
+        listener.set_max_mounts_per_client(2);
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let marked = client.mkdir(&root, b"marked").unwrap();
+    client.chmod(&marked, 0o755 | MARKER).unwrap();
+    client.mkdir(&root, b"plain").unwrap();
+    let file = client.create(&root, b"file").unwrap();
+    // a file carrying the marker is still not a directory
+    client.chmod(&file, 0o644 | MARKER).unwrap();
+
+    assert!(matches!(client.try_mount(b"/plain"), Err(MNT3ERR_ACCES)));
+    // the policy decides for files too, before MNT3ERR_NOTDIR
+    assert!(matches!(client.try_mount(b"/file"), Err(MNT3ERR_ACCES)));
+
+    // the refused mounts were not counted against the client
+    let mounted = client.mount(b"/marked");
+    client.create(&mounted, b"inside").unwrap();
+    client.lookup(&marked, b"inside").unwrap();
+}
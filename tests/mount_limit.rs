@@ -0,0 +1,35 @@
+//! The limit on the number of paths one client host may have mounted
+mod common;
+
+use common::{serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::tcp::NFSTcp;
+
+const MNT3ERR_ACCES: u32 = 13;
+
+#[test]
+fn limit_applies_per_client_host() {
+    let port = serve_with(MemFS::new(), |listener| {
+        listener.set_max_mounts_per_client(2)
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    for dir in [&b"a"[..], b"b"] {
+        client.mkdir(&root, dir).unwrap();
+    }
+    client.mount(b"/a");
+    // mounting a path again is not another mount
+    client.mount(b"/a");
+    client.mount(b"/");
+    assert!(matches!(client.try_mount(b"/b"), Err(MNT3ERR_ACCES)));
+
+    // another host is not limited by the mounts of this one
+    let mut other = Client::connect_from(port, [127, 0, 0, 2]);
+    other.mount(b"/a");
+    other.mount(b"/b");
+
+    // and unmounting frees a place
+    client.umount(b"/a");
+    client.mount(b"/b");
+    assert!(matches!(client.try_mount(b"/a"), Err(MNT3ERR_ACCES)));
+}
@@ -0,0 +1,160 @@
+//! NFSv3 over a real TCP connection to a listener serving MemFS
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{ftype3, nfs_fh3, nfsstat3, post_op_attr, post_op_fh3};
+use std::collections::BTreeSet;
+
+fn mounted() -> (Client, nfs_fh3) {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    (client, root)
+}
+
+#[test]
+fn file_contents_round_trip() {
+    let (mut client, root) = mounted();
+    let fh = client.create(&root, b"data.bin").unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    for (i, chunk) in data.chunks(65536).enumerate() {
+        let written = client.write(&fh, (i * 65536) as u64, chunk).unwrap();
+        assert_eq!(written as usize, chunk.len());
+    }
+    let attr = client.getattr(&fh).unwrap();
+    assert!(matches!(attr.ftype, ftype3::NF3REG));
+    assert_eq!(attr.size, data.len() as u64);
+
+    let mut read_back = Vec::new();
+    loop {
+        let (chunk, eof) = client.read(&fh, read_back.len() as u64, 65536).unwrap();
+        read_back.extend_from_slice(&chunk);
+        if eof {
+            break;
+        }
+        assert!(!chunk.is_empty(), "short read without eof");
+    }
+    assert!(read_back == data, "contents differ");
+
+    // the handle from LOOKUP names the same file
+    let looked_up = client.lookup(&root, b"data.bin").unwrap();
+    let (head, _) = client.read(&looked_up, 0, 16).unwrap();
+    assert_eq!(head, data[..16]);
+}
+
+#[test]
+fn directory_listing_paginates() {
+    let (mut client, root) = mounted();
+    let dir = client.mkdir(&root, b"many").unwrap();
+    let expected: BTreeSet<Vec<u8>> = (0..300)
+        .map(|i| format!("a_fairly_long_file_name_so_that_pages_fill_up_{i:04}").into_bytes())
+        .collect();
+    for name in &expected {
+        client.create(&dir, name).unwrap();
+    }
+
+    let (entries, calls) = client.readdirplus_all(&dir, 1024, 4096).unwrap();
+    assert!(calls > 1, "expected several pages, got {calls}");
+    let names: Vec<Vec<u8>> = entries.iter().map(|e| e.name.clone()).collect();
+    let unique: BTreeSet<Vec<u8>> = names.iter().cloned().collect();
+    assert_eq!(unique.len(), names.len(), "an entry was listed twice");
+    assert_eq!(unique, expected);
+    for entry in &entries {
+        match &entry.attr {
+            post_op_attr::attributes(attr) => assert_eq!(attr.fileid.0, entry.fileid),
+            post_op_attr::Void => panic!("no attributes for {:?}", entry.name),
+        }
+        let post_op_fh3::handle(fh) = &entry.handle else {
+            panic!("no handle for {:?}", entry.name);
+        };
+        assert_eq!(client.getattr(fh).unwrap().fileid.0, entry.fileid);
+    }
+
+    // one large page holds everything
+    let (entries, calls) = client.readdirplus_all(&dir, 1 << 20, 1 << 20).unwrap();
+    assert_eq!(calls, 1);
+    assert_eq!(entries.len(), expected.len());
+}
+
+#[test]
+fn rename_and_remove() {
+    let (mut client, root) = mounted();
+    let sub = client.mkdir(&root, b"sub").unwrap();
+    let fh = client.create(&root, b"before").unwrap();
+    client.write(&fh, 0, b"hello").unwrap();
+
+    client.rename(&root, b"before", &sub, b"after").unwrap();
+    assert!(matches!(
+        client.lookup(&root, b"before"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    let moved = client.lookup(&sub, b"after").unwrap();
+    assert_eq!(
+        client.read(&moved, 0, 100).unwrap(),
+        (b"hello".to_vec(), true)
+    );
+
+    client.remove(&sub, b"after").unwrap();
+    assert!(matches!(
+        client.lookup(&sub, b"after"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    assert!(matches!(
+        client.remove(&sub, b"after"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    let (entries, _) = client.readdirplus_all(&sub, 4096, 16384).unwrap();
+    assert!(entries.is_empty());
+}
+
+#[test]
+fn error_statuses() {
+    let (mut client, root) = mounted();
+    assert!(matches!(
+        client.lookup(&root, b"missing"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    client.mkdir(&root, b"dir").unwrap();
+    assert!(matches!(
+        client.mkdir(&root, b"dir"),
+        Err(nfsstat3::NFS3ERR_EXIST)
+    ));
+    let file = client.create(&root, b"file").unwrap();
+    assert!(matches!(
+        client.lookup(&file, b"x"),
+        Err(nfsstat3::NFS3ERR_NOTDIR)
+    ));
+    assert!(matches!(
+        client.read(&root, 0, 10),
+        Err(nfsstat3::NFS3ERR_ISDIR)
+    ));
+}
+
+#[test]
+fn stale_and_bad_handles() {
+    let (mut client, root) = mounted();
+    let fh = client.create(&root, b"doomed").unwrap();
+    client.getattr(&fh).unwrap();
+    client.remove(&root, b"doomed").unwrap();
+    assert!(matches!(client.getattr(&fh), Err(nfsstat3::NFS3ERR_STALE)));
+
+    // a handle of an earlier server instance (generation 0)
+    let mut data = fh.data.clone();
+    data[1..9].copy_from_slice(&0u64.to_le_bytes());
+    assert!(matches!(
+        client.getattr(&nfs_fh3 { data }),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+
+    let garbage = nfs_fh3 {
+        data: vec![0xee; 5],
+    };
+    assert!(matches!(
+        client.getattr(&garbage),
+        Err(nfsstat3::NFS3ERR_BADHANDLE)
+    ));
+    assert!(matches!(
+        client.readdirplus(&garbage, 0, [0; 8], 1024, 4096),
+        Err(nfsstat3::NFS3ERR_BADHANDLE)
+    ));
+}
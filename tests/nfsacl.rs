@@ -0,0 +1,84 @@
+//! The NFSACL sideband program: with the nfsacl feature, GETACL and SETACL
+//! are answered NFS3ERR_NOTSUPP with the attributes of the object, and
+//! without it the program is unavailable
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::xdr::XDR;
+
+const NFS_ACL_PROGRAM: u32 = 100227;
+const NFS_ACL_VERSION: u32 = 3;
+const ACLPROC3_GETACL: u32 = 1;
+
+/// NFS_ACL | NFS_ACLCNT | NFS_DFACL | NFS_DFACLCNT, as getfacl asks
+const ALL_ACLS: u32 = 0xf;
+
+fn getacl_args(fh: &nfs_fh3) -> Vec<u8> {
+    let mut args = Vec::new();
+    fh.serialize(&mut args).unwrap();
+    ALL_ACLS.serialize(&mut args).unwrap();
+    args
+}
+
+/// The status and attributes of the GETACL3res for fh, checking the reply
+/// has nothing after them
+#[cfg(feature = "nfsacl")]
+fn getacl(client: &mut Client, fh: &nfs_fh3) -> (nfsstat3, post_op_attr) {
+    let mut res = client.call(
+        NFS_ACL_PROGRAM,
+        NFS_ACL_VERSION,
+        ACLPROC3_GETACL,
+        &getacl_args(fh),
+    );
+    let mut stat = nfsstat3::NFS3_OK;
+    stat.deserialize(&mut res).unwrap();
+    let mut attr = post_op_attr::Void;
+    attr.deserialize(&mut res).unwrap();
+    assert_eq!(
+        res.position() as usize,
+        res.get_ref().len(),
+        "trailing bytes"
+    );
+    (stat, attr)
+}
+
+#[cfg(feature = "nfsacl")]
+#[test]
+fn getacl_is_notsupp() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, b"contents").unwrap();
+
+    let (stat, attr) = getacl(&mut client, &file);
+    assert!(matches!(stat, nfsstat3::NFS3ERR_NOTSUPP), "{stat:?}");
+    let post_op_attr::attributes(attr) = attr else {
+        panic!("no attributes for the file");
+    };
+    let expected = client.getattr(&file).unwrap();
+    assert_eq!(attr.fileid, expected.fileid);
+    assert_eq!(attr.size, 8);
+
+    // a handle the server does not know still gets a well-formed reply
+    let (stat, attr) = getacl(&mut client, &nfs_fh3 { data: vec![1; 16] });
+    assert!(matches!(stat, nfsstat3::NFS3ERR_NOTSUPP), "{stat:?}");
+    assert!(matches!(attr, post_op_attr::Void), "{attr:?}");
+}
+
+#[cfg(not(feature = "nfsacl"))]
+#[test]
+fn getacl_is_prog_unavail() {
+    const PROG_UNAVAIL: u32 = 1;
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    let (stat, _) = client.call_accepted(
+        NFS_ACL_PROGRAM,
+        NFS_ACL_VERSION,
+        ACLPROC3_GETACL,
+        &getacl_args(&file),
+    );
+    assert_eq!(stat, PROG_UNAVAIL);
+}
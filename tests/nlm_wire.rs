@@ -0,0 +1,78 @@
+//! The lock manager over a real TCP connection: every lock is denied with
+//! NLM4_DENIED_NOLOCKS, echoing the cookie of the call
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::nfs_fh3;
+use nfsserve::xdr::XDR;
+use std::io::Read;
+
+const NLM_PROGRAM: u32 = 100021;
+const NLM_VERSION: u32 = 4;
+const NLMPROC4_TEST: u32 = 1;
+const NLMPROC4_LOCK: u32 = 2;
+const NLMPROC4_NM_LOCK: u32 = 22;
+
+const NLM4_DENIED_NOLOCKS: u32 = 2;
+
+/// Appends an nlm4_lock of the first 100 bytes of fh
+fn lock(args: &mut Vec<u8>, fh: &nfs_fh3) {
+    b"client".to_vec().serialize(args).unwrap();
+    fh.data.serialize(args).unwrap();
+    b"owner".to_vec().serialize(args).unwrap();
+    42i32.serialize(args).unwrap();
+    0u64.serialize(args).unwrap();
+    100u64.serialize(args).unwrap();
+}
+
+/// Decodes the cookie and stat of an nlm4_res, or of an nlm4_testres
+/// which is not NLM4_DENIED
+fn res(reply: &mut impl Read) -> (Vec<u8>, u32) {
+    let mut cookie = Vec::<u8>::new();
+    cookie.deserialize(reply).unwrap();
+    let mut stat = 0u32;
+    stat.deserialize(reply).unwrap();
+    (cookie, stat)
+}
+
+fn mounted() -> (Client, nfs_fh3) {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"locked").unwrap();
+    (client, file)
+}
+
+#[test]
+fn test_is_denied() {
+    let (mut client, file) = mounted();
+    let mut args = Vec::new();
+    b"test cookie".to_vec().serialize(&mut args).unwrap();
+    // exclusive
+    true.serialize(&mut args).unwrap();
+    lock(&mut args, &file);
+    let mut reply = client.call(NLM_PROGRAM, NLM_VERSION, NLMPROC4_TEST, &args);
+    assert_eq!(
+        res(&mut reply),
+        (b"test cookie".to_vec(), NLM4_DENIED_NOLOCKS)
+    );
+}
+
+#[test]
+fn lock_is_denied() {
+    let (mut client, file) = mounted();
+    for proc in [NLMPROC4_LOCK, NLMPROC4_NM_LOCK] {
+        let cookie = format!("cookie of {proc}").into_bytes();
+        let mut args = Vec::new();
+        cookie.serialize(&mut args).unwrap();
+        // block, exclusive
+        true.serialize(&mut args).unwrap();
+        true.serialize(&mut args).unwrap();
+        lock(&mut args, &file);
+        // reclaim, state
+        false.serialize(&mut args).unwrap();
+        1i32.serialize(&mut args).unwrap();
+        let mut reply = client.call(NLM_PROGRAM, NLM_VERSION, proc, &args);
+        assert_eq!(res(&mut reply), (cookie, NLM4_DENIED_NOLOCKS));
+    }
+}
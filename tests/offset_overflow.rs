@@ -0,0 +1,97 @@
+//! READs and WRITEs whose offset plus count wraps around u64 are
+//! NFS3ERR_INVAL, without reaching the file system
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::NFSFileSystem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The reads and writes made of a CountedFS
+#[derive(Default)]
+struct IoCounter {
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl IoCounter {
+    /// The reads and writes counted since the last take
+    fn take(&self) -> (usize, usize) {
+        (
+            self.reads.swap(0, Ordering::SeqCst),
+            self.writes.swap(0, Ordering::SeqCst),
+        )
+    }
+}
+
+/// A MemFS counting the reads and writes made of it
+struct CountedFS {
+    inner: MemFS,
+    counter: Arc<IoCounter>,
+}
+
+forward_to_memfs! {
+    CountedFS,
+    hooks {
+        async fn read(
+            &self,
+            id: fileid3,
+            offset: u64,
+            count: u32,
+        ) -> Result<(Vec<u8>, bool), nfsstat3> {
+            self.counter.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.read(id, offset, count).await
+        }
+        async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+            self.counter.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.write(id, offset, data).await
+        }
+    }
+}
+
+#[test]
+fn wrapping_ranges_are_invalid() {
+    let counter = Arc::new(IoCounter::default());
+    let mut client = Client::connect(serve(CountedFS {
+        inner: MemFS::new(),
+        counter: counter.clone(),
+    }));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, b"contents").unwrap();
+    counter.take();
+
+    // u64::MAX, and the first offset at which 16 bytes wrap around
+    for offset in [u64::MAX, u64::MAX - 15] {
+        assert!(matches!(
+            client.read(&file, offset, 16),
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+        assert!(matches!(
+            client.write(&file, offset, &[1; 16]),
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+    }
+    assert_eq!(counter.take(), (0, 0));
+
+    // ending at u64::MAX is fine, if past the file and maxfilesize
+    assert_eq!(
+        client.read(&file, u64::MAX - 16, 16).unwrap(),
+        (Vec::new(), true)
+    );
+    assert!(matches!(
+        client.write(&file, u64::MAX - 16, &[1; 16]),
+        Err(nfsstat3::NFS3ERR_FBIG)
+    ));
+    // as is reading from the end of the file
+    assert_eq!(client.read(&file, 8, 16).unwrap(), (Vec::new(), true));
+    assert_eq!(counter.take(), (2, 0));
+
+    assert_eq!(client.getattr(&file).unwrap().size, 8);
+    assert_eq!(
+        client.read(&file, 0, 16).unwrap(),
+        (b"contents".to_vec(), true)
+    );
+}
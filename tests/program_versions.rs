@@ -0,0 +1,104 @@
+//! PROG_MISMATCH and PROG_UNAVAIL replies follow the programs and versions
+//! registered on the listener
+mod common;
+
+use common::{serve_with, Client};
+use nfsserve::memfs::MemFS;
+use std::io::Read;
+use std::sync::mpsc;
+
+const MOUNT_PROGRAM: u32 = 100005;
+const NFS_PROGRAM: u32 = 100003;
+
+const PROG_UNAVAIL: u32 = 1;
+const PROG_MISMATCH: u32 = 2;
+
+/// Makes a NULL call. Returns the accept_stat, and the version bounds of a
+/// PROG_MISMATCH reply.
+fn null_call(client: &mut Client, prog: u32, vers: u32) -> (u32, Option<(u32, u32)>) {
+    let (stat, mut reply) = client.call_accepted(prog, vers, 0, &[]);
+    if stat != PROG_MISMATCH {
+        return (stat, None);
+    }
+    let mut bounds = [0u8; 8];
+    reply.read_exact(&mut bounds).unwrap();
+    let low = u32::from_be_bytes(bounds[..4].try_into().unwrap());
+    let high = u32::from_be_bytes(bounds[4..].try_into().unwrap());
+    (stat, Some((low, high)))
+}
+
+/// A change to the MOUNT versions served
+#[derive(Clone, Copy)]
+enum Change {
+    Register(u32),
+    Deregister(u32),
+}
+use Change::*;
+
+/// Serves MemFS with the changes applied to the listener in order. Returns
+/// the client, and what each change returned.
+fn serve_mount(changes: &[Change]) -> (Client, Vec<bool>) {
+    let changes = changes.to_vec();
+    let (tx, rx) = mpsc::channel();
+    let port = serve_with(MemFS::new(), move |listener| {
+        let results = changes
+            .into_iter()
+            .map(|change| match change {
+                Register(vers) => listener.register_program(MOUNT_PROGRAM, vers),
+                Deregister(vers) => listener.deregister_program(MOUNT_PROGRAM, vers),
+            })
+            .collect();
+        tx.send(results).unwrap();
+    });
+    (Client::connect(port), rx.recv().unwrap())
+}
+
+#[test]
+fn default_versions() {
+    let (mut client, _) = serve_mount(&[]);
+    assert_eq!(null_call(&mut client, MOUNT_PROGRAM, 3), (0, None));
+    assert_eq!(
+        null_call(&mut client, MOUNT_PROGRAM, 1),
+        (PROG_MISMATCH, Some((3, 3)))
+    );
+    assert_eq!(
+        null_call(&mut client, NFS_PROGRAM, 4),
+        (PROG_MISMATCH, Some((3, 3)))
+    );
+    assert_eq!(null_call(&mut client, 100099, 1), (PROG_UNAVAIL, None));
+}
+
+#[test]
+fn unimplemented_versions_are_not_registered() {
+    // there is no MOUNT v1 handler, so calls to it must not be accepted
+    // and decoded as v3 calls
+    let (mut client, results) = serve_mount(&[Register(1)]);
+    assert_eq!(results, [false]);
+    assert_eq!(
+        null_call(&mut client, MOUNT_PROGRAM, 1),
+        (PROG_MISMATCH, Some((3, 3)))
+    );
+    assert_eq!(null_call(&mut client, MOUNT_PROGRAM, 3), (0, None));
+}
+
+#[test]
+fn toggled_versions() {
+    let (mut client, results) = serve_mount(&[Deregister(3)]);
+    assert_eq!(results, [true]);
+    assert_eq!(
+        null_call(&mut client, MOUNT_PROGRAM, 3),
+        (PROG_UNAVAIL, None)
+    );
+    assert_eq!(
+        null_call(&mut client, MOUNT_PROGRAM, 1),
+        (PROG_UNAVAIL, None)
+    );
+
+    let (mut client, results) = serve_mount(&[Deregister(3), Register(3), Deregister(1)]);
+    assert_eq!(results, [true, true, false]);
+    assert_eq!(null_call(&mut client, MOUNT_PROGRAM, 3), (0, None));
+    assert_eq!(
+        null_call(&mut client, MOUNT_PROGRAM, 1),
+        (PROG_MISMATCH, Some((3, 3)))
+    );
+}
@@ -0,0 +1,148 @@
+//! The bytes of READ replies, whose data goes out from the read buffer
+//! rather than through the reply buffer
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::NFSFileSystem;
+use nfsserve::xdr::XDR;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFSPROC3_READ: u32 = 6;
+
+/// A MemFS whose files have the same attributes but for the size
+struct FixedFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    FixedFS,
+    hooks {
+        async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+            let attr = self.inner.getattr(id).await?;
+            if !matches!(attr.ftype, ftype3::NF3REG) {
+                return Ok(attr);
+            }
+            let size = attr.size;
+            let time = nfstime3 {
+                seconds: 1_000_000_000,
+                nseconds: 0,
+            };
+            Ok(fattr3 {
+                ftype: ftype3::NF3REG,
+                mode: 0o644,
+                nlink: 1,
+                uid: 1000,
+                gid: 1000,
+                size,
+                used: size,
+                rdev: specdata3::default(),
+                fsid: 0,
+                fileid: fileid3(42),
+                atime: time,
+                mtime: time,
+                ctime: time,
+            })
+        }
+    }
+}
+
+/// The reply record of a READ of count bytes at offset, as sent, and the
+/// xid of the call
+fn read_reply(client: &mut Client, fh: &nfs_fh3, offset: u64, count: u32) -> (u32, Vec<u8>) {
+    let mut args = Vec::new();
+    fh.serialize(&mut args).unwrap();
+    offset.serialize(&mut args).unwrap();
+    count.serialize(&mut args).unwrap();
+    let xid = client.send_call(NFS_PROGRAM, 3, NFSPROC3_READ, &args);
+    let (reply_xid, reply) = client.recv_reply();
+    assert_eq!(reply_xid, xid);
+    (xid, reply.into_inner())
+}
+
+fn hex(bytes: &str) -> Vec<u8> {
+    let digits: Vec<u8> = bytes.bytes().filter(u8::is_ascii_hexdigit).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+/// The reply up to the data of a READ of a file of size bytes, as
+/// nfs_handlers serialized it before the data was attached
+fn resok_head(xid: u32, size: u64, count: u32, eof: bool) -> Vec<u8> {
+    let mut head = xid.to_be_bytes().to_vec();
+    head.extend(hex("00000001 00000000 00000000 00000000 00000000
+         00000000 00000001
+         00000001 000001a4 00000001 000003e8 000003e8"));
+    size.serialize(&mut head).unwrap();
+    size.serialize(&mut head).unwrap();
+    head.extend(hex("00000000 00000000 0000000000000000 000000000000002a
+         3b9aca00 00000000 3b9aca00 00000000 3b9aca00 00000000"));
+    count.serialize(&mut head).unwrap();
+    eof.serialize(&mut head).unwrap();
+    head
+}
+
+#[test]
+fn small_read_reply_bytes() {
+    let mut client = Client::connect(serve(FixedFS {
+        inner: MemFS::new(),
+    }));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, b"hello").unwrap();
+
+    // an accepted reply, then the data<> opaque padded to 4 bytes
+    let (xid, reply) = read_reply(&mut client, &file, 0, 100);
+    let mut golden = xid.to_be_bytes().to_vec();
+    golden.extend(hex("00000001 00000000 00000000 00000000 00000000
+         00000000
+         00000001
+         00000001 000001a4 00000001 000003e8 000003e8
+         0000000000000005 0000000000000005
+         00000000 00000000 0000000000000000 000000000000002a
+         3b9aca00 00000000 3b9aca00 00000000 3b9aca00 00000000
+         00000005 00000001
+         00000005 68656c6c 6f000000"));
+    assert_eq!(reply, golden);
+    assert_eq!(resok_head(xid, 5, 5, true), golden[..golden.len() - 12]);
+
+    // a short read from the middle, and an empty one past the end
+    let (xid, reply) = read_reply(&mut client, &file, 1, 3);
+    let mut expected = resok_head(xid, 5, 3, false);
+    expected.extend(hex("00000003 656c6c00"));
+    assert_eq!(reply, expected);
+    let (xid, reply) = read_reply(&mut client, &file, 10, 3);
+    let mut expected = resok_head(xid, 5, 0, true);
+    expected.extend(hex("00000000"));
+    assert_eq!(reply, expected);
+}
+
+#[test]
+fn large_read_reply_bytes() {
+    let mut client = Client::connect(serve(FixedFS {
+        inner: MemFS::new(),
+    }));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    // more than a fragment, and not a multiple of 4
+    let data: Vec<u8> = (0..(1 << 20) + 3).map(|i| (i % 251) as u8).collect();
+    for (i, chunk) in data.chunks(1 << 16).enumerate() {
+        client.write(&file, (i << 16) as u64, chunk).unwrap();
+    }
+
+    let (xid, reply) = read_reply(&mut client, &file, 0, 1 << 20);
+    let mut expected = resok_head(xid, data.len() as u64, 1 << 20, false);
+    data[..1 << 20].to_vec().serialize(&mut expected).unwrap();
+    assert!(reply == expected);
+
+    let (xid, reply) = read_reply(&mut client, &file, (1 << 20) - 4, 1 << 20);
+    let mut expected = resok_head(xid, data.len() as u64, 7, true);
+    data[(1 << 20) - 4..]
+        .to_vec()
+        .serialize(&mut expected)
+        .unwrap();
+    assert!(reply == expected);
+}
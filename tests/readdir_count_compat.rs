@@ -0,0 +1,50 @@
+//! READDIRPLUS with a maxcount or dircount of zero is listed with the
+//! dtpref of the file system in its place, as set_readdir_count_compat
+//! is on by default
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::FsInfoConfig;
+
+const DTPREF: u32 = 2048;
+
+/// A MemFS preferring READDIR pages of DTPREF bytes
+struct SmallPagesFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    SmallPagesFS,
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        FsInfoConfig {
+            dtpref: DTPREF,
+            ..self.inner.fsinfo_config()
+        }
+    }
+}
+
+#[test]
+fn zero_counts_are_listed_with_dtpref() {
+    let fs = SmallPagesFS {
+        inner: MemFS::new(),
+    };
+    let mut client = Client::connect(serve(fs));
+    let root = client.mount(b"/");
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    let names: Vec<Vec<u8>> = (0..100)
+        .map(|i| format!("file {i:03}").into_bytes())
+        .collect();
+    for name in &names {
+        client.create(&dir, name).unwrap();
+    }
+
+    for (dircount, maxcount) in [(4096, 0), (0, 4096), (0, 0)] {
+        let (entries, calls) = client.readdirplus_all(&dir, dircount, maxcount).unwrap();
+        let listed: Vec<Vec<u8>> = entries.into_iter().map(|entry| entry.name).collect();
+        assert_eq!(listed, names, "dircount {dircount} maxcount {maxcount}");
+        // in pages of about dtpref bytes
+        assert!(calls > 1, "dircount {dircount} maxcount {maxcount}");
+    }
+}
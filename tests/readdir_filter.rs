@@ -0,0 +1,114 @@
+//! FilteredFS served over TCP: hidden entries are absent from listings
+//! and lookups
+mod common;
+
+use common::{serve, Client};
+use nfsserve::filter::{FilteredFS, HideDotfiles, HidePatterns, ReadDirFilter};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{fattr3, ftype3, nfs_fh3, nfsstat3};
+use std::collections::BTreeSet;
+
+fn mounted<F: ReadDirFilter + 'static>(filter: F) -> (Client, nfs_fh3) {
+    let mut client = Client::connect(serve(FilteredFS::new(MemFS::new(), filter)));
+    let root = client.mount(b"/");
+    (client, root)
+}
+
+fn names(list: &[&str]) -> BTreeSet<Vec<u8>> {
+    list.iter().map(|n| n.as_bytes().to_vec()).collect()
+}
+
+fn listing(client: &mut Client, dir: &nfs_fh3, dircount: u32, maxcount: u32) -> BTreeSet<Vec<u8>> {
+    let (entries, _) = client.readdirplus_all(dir, dircount, maxcount).unwrap();
+    let plus: BTreeSet<Vec<u8>> = entries.into_iter().map(|e| e.name).collect();
+    let plain: BTreeSet<Vec<u8>> = client
+        .readdir_all(dir, dircount)
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(plus, plain, "READDIR and READDIRPLUS disagree");
+    plus
+}
+
+#[test]
+fn dotfiles_are_hidden() {
+    let (mut client, root) = mounted(HideDotfiles);
+    client.create(&root, b"visible").unwrap();
+    client.create(&root, b".hidden").unwrap();
+    let git = client.mkdir(&root, b".git").unwrap();
+    client.create(&git, b"config").unwrap();
+    let sub = client.mkdir(&root, b"sub").unwrap();
+    client.create(&sub, b".profile").unwrap();
+
+    assert_eq!(
+        listing(&mut client, &root, 4096, 16384),
+        names(&["visible", "sub"])
+    );
+    assert!(listing(&mut client, &sub, 4096, 16384).is_empty());
+
+    for (dir, name) in [
+        (&root, &b".hidden"[..]),
+        (&root, b".git"),
+        (&sub, b".profile"),
+    ] {
+        assert!(matches!(
+            client.lookup(dir, name),
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+    }
+    client.lookup(&root, b"visible").unwrap();
+    // "." and ".." are never hidden
+    let parent = client.lookup(&sub, b"..").unwrap();
+    assert_eq!(
+        client.getattr(&parent).unwrap().fileid,
+        client.getattr(&root).unwrap().fileid
+    );
+    // the handle of a hidden directory, got before it was hidden, still works
+    assert_eq!(listing(&mut client, &git, 4096, 16384), names(&["config"]));
+
+    // hidden names can still be removed (as clients do with .nfsXXXX files)
+    client.remove(&root, b".hidden").unwrap();
+    assert!(matches!(
+        client.remove(&root, b".hidden"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+}
+
+#[test]
+fn pages_of_hidden_entries_are_skipped() {
+    let (mut client, root) = mounted(HidePatterns::new(&["*.tmp", "~*"]));
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    let mut expected = Vec::new();
+    for i in 0..200 {
+        client
+            .create(&dir, format!("scratch_file_number_{i:04}.tmp").as_bytes())
+            .unwrap();
+        client.create(&dir, format!("~lock{i}").as_bytes()).unwrap();
+        if i % 50 == 0 {
+            let name = format!("kept_{i}.tmp.txt");
+            client.create(&dir, name.as_bytes()).unwrap();
+            expected.push(name);
+        }
+    }
+    let expected: Vec<&str> = expected.iter().map(String::as_str).collect();
+    assert_eq!(listing(&mut client, &dir, 512, 2048), names(&expected));
+    assert!(matches!(
+        client.lookup(&dir, b"scratch_file_number_0007.tmp"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    client.lookup(&dir, b"kept_50.tmp.txt").unwrap();
+}
+
+#[test]
+fn filters_can_look_at_attributes() {
+    let hide_dirs = |_: &[u8], attr: &fattr3| matches!(attr.ftype, ftype3::NF3DIR);
+    let (mut client, root) = mounted(hide_dirs);
+    client.mkdir(&root, b"dir").unwrap();
+    client.create(&root, b"file").unwrap();
+    assert_eq!(listing(&mut client, &root, 4096, 16384), names(&["file"]));
+    assert!(matches!(
+        client.lookup(&root, b"dir"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    client.lookup(&root, b"file").unwrap();
+}
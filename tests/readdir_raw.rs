@@ -0,0 +1,102 @@
+//! READDIRPLUS replies spliced from readdir_raw pages, against the replies
+//! of the structured encoder for the same directory
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::{DirEntry, NFSFileSystem, RawDirPage};
+use nfsserve::xdr::XDR;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A MemFS which lists through readdir_raw while raw is set, encoding the
+/// entries itself
+struct RawFS {
+    inner: MemFS,
+    raw: Arc<AtomicBool>,
+}
+
+impl RawFS {
+    async fn raw_page(&self, dirid: fileid3, cookie: cookie3) -> Result<RawDirPage, nfsstat3> {
+        let attr = self.inner.getattr(dirid).await?;
+        let listing = self.inner.readdir(dirid, cookie, usize::MAX).await?;
+        let mut page = RawDirPage {
+            end: listing.end,
+            cookieverf: ((attr.mtime.seconds as u64) << 32 | attr.mtime.nseconds as u64)
+                .to_be_bytes(),
+            ..Default::default()
+        };
+        for entry in listing.entries {
+            page.entry_offsets.push(page.entries.len());
+            encode_entry(&mut page.entries, &entry, self.id_to_fh(entry.fileid));
+        }
+        Ok(page)
+    }
+}
+
+/// Appends entry as the list marker and entryplus3 of READDIRPLUS
+fn encode_entry(out: &mut Vec<u8>, entry: &DirEntry, fh: nfs_fh3) {
+    true.serialize(out).unwrap();
+    entry.fileid.serialize(out).unwrap();
+    entry.name.serialize(out).unwrap();
+    entry.effective_cookie().serialize(out).unwrap();
+    post_op_attr::attributes(entry.attr).serialize(out).unwrap();
+    post_op_fh3::handle(fh).serialize(out).unwrap();
+}
+
+forward_to_memfs!(
+    RawFS,
+    async fn readdir_raw(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        _dircount: count3,
+        _maxcount: count3,
+    ) -> Option<Result<RawDirPage, nfsstat3>> {
+        if !self.raw.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(self.raw_page(dirid, cookie).await)
+    }
+);
+
+#[test]
+fn raw_pages_match_the_structured_encoder() {
+    let raw = Arc::new(AtomicBool::new(false));
+    let fs = RawFS {
+        inner: MemFS::new(),
+        raw: raw.clone(),
+    };
+    let mut client = Client::connect(serve(fs));
+    let root = client.mount(b"/");
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    for i in 0..40 {
+        let name = format!("entry with a longer name {i}");
+        if i % 5 == 0 {
+            client.mkdir(&dir, name.as_bytes()).unwrap();
+        } else {
+            client.create(&dir, name.as_bytes()).unwrap();
+        }
+    }
+    let cookieverf = cookieverf3::default();
+    // the whole directory, a page cut at an entry boundary, a page with
+    // no room for an entry (TOOSMALL), and a listing resumed in the middle
+    let mut cases = vec![(0, 1 << 20), (0, 1500), (0, 200)];
+    let second = client
+        .readdirplus(&dir, 0, cookieverf, 1 << 20, 1 << 20)
+        .unwrap()
+        .entries[7]
+        .cookie;
+    cases.push((second, 2000));
+    for (cookie, maxcount) in cases {
+        raw.store(false, Ordering::SeqCst);
+        let structured = client.readdirplus_bytes(&dir, cookie, cookieverf, 1 << 20, maxcount);
+        raw.store(true, Ordering::SeqCst);
+        let spliced = client.readdirplus_bytes(&dir, cookie, cookieverf, 1 << 20, maxcount);
+        assert!(
+            structured == spliced,
+            "cookie {cookie} maxcount {maxcount}: replies differ"
+        );
+    }
+}
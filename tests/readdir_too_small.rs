@@ -0,0 +1,122 @@
+//! READDIR and READDIRPLUS with counts too small for a single entry are
+//! NFS3ERR_TOOSMALL, with the attributes of the directory, rather than an
+//! empty page the client would retry forever
+mod common;
+
+use common::{serve, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::NFSTcp;
+use nfsserve::xdr::XDR;
+use std::io::Cursor;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC3_READDIR: u32 = 16;
+const NFSPROC3_READDIRPLUS: u32 = 17;
+
+/// READDIR of dir from cookie with count, or READDIRPLUS with dircount
+/// and maxcount both count
+fn list(
+    client: &mut Client,
+    plus: bool,
+    dir: &nfs_fh3,
+    (cookie, cookieverf): (u64, cookieverf3),
+    count: u32,
+) -> Cursor<Vec<u8>> {
+    let mut args = Vec::new();
+    dir.serialize(&mut args).unwrap();
+    cookie.serialize(&mut args).unwrap();
+    cookieverf.serialize(&mut args).unwrap();
+    count.serialize(&mut args).unwrap();
+    if plus {
+        count.serialize(&mut args).unwrap();
+    }
+    let proc = if plus {
+        NFSPROC3_READDIRPLUS
+    } else {
+        NFSPROC3_READDIR
+    };
+    client.call(NFS_PROGRAM, NFS_VERSION, proc, &args)
+}
+
+/// Asserts res is NFS3ERR_TOOSMALL with the attributes of the directory
+/// fileid, and nothing else
+fn assert_too_small(mut res: Cursor<Vec<u8>>, fileid: fileid3) {
+    let mut stat = nfsstat3::NFS3_OK;
+    stat.deserialize(&mut res).unwrap();
+    assert!(matches!(stat, nfsstat3::NFS3ERR_TOOSMALL), "{stat:?}");
+    let mut dir_attr = post_op_attr::Void;
+    dir_attr.deserialize(&mut res).unwrap();
+    match dir_attr {
+        post_op_attr::attributes(attr) => {
+            assert!(matches!(attr.ftype, ftype3::NF3DIR));
+            assert_eq!(attr.fileid, fileid);
+        }
+        post_op_attr::Void => panic!("no directory attributes"),
+    }
+    assert_eq!(
+        res.position() as usize,
+        res.get_ref().len(),
+        "trailing bytes"
+    );
+}
+
+#[test]
+fn zero_counts_are_too_small() {
+    // without the substitution of dtpref for counts of zero
+    let port = serve_with(MemFS::new(), |listener| {
+        listener.set_readdir_count_compat(false)
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    client.create(&root, b"file").unwrap();
+    let fileid = client.getattr(&root).unwrap().fileid;
+
+    let start = (0, cookieverf3::default());
+    assert_too_small(list(&mut client, false, &root, start, 0), fileid);
+    assert_too_small(list(&mut client, true, &root, start, 0), fileid);
+    // the connection is still good
+    assert_eq!(
+        client.readdir_all(&root, 4096).unwrap(),
+        vec![b"file".to_vec()]
+    );
+}
+
+#[test]
+fn oversized_entries_are_too_small() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    let name = vec![b'n'; 255];
+    client.create(&dir, &name).unwrap();
+    let fileid = client.getattr(&dir).unwrap().fileid;
+
+    // the long name fits in no page of 450 bytes, even with the
+    // substitution for small counts on
+    let mut cookie = 0;
+    let mut cookieverf = cookieverf3::default();
+    loop {
+        match client.readdirplus(&dir, cookie, cookieverf, 450, 450) {
+            Ok(page) => {
+                assert!(!page.eof, "listed past the long name");
+                assert!(!page.entries.is_empty(), "empty page");
+                cookie = page.entries.last().unwrap().cookie;
+                cookieverf = page.cookieverf;
+            }
+            Err(stat) => {
+                assert!(matches!(stat, nfsstat3::NFS3ERR_TOOSMALL), "{stat:?}");
+                break;
+            }
+        }
+    }
+    let resume = (cookie, cookieverf);
+    assert_too_small(list(&mut client, false, &dir, resume, 450), fileid);
+    assert_too_small(list(&mut client, true, &dir, resume, 450), fileid);
+
+    // while counts with room for it list it
+    let names = client.readdir_all(&dir, 4096).unwrap();
+    assert!(names.contains(&name));
+    let (entries, _) = client.readdirplus_all(&dir, 4096, 4096).unwrap();
+    assert!(entries.iter().any(|entry| entry.name == name));
+}
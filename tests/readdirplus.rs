@@ -0,0 +1,165 @@
+//! READDIRPLUS lists through NFSFileSystem::readdirplus
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::{NFSFileSystem, ReadDirPlusResult, ReadDirResult};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A MemFS counting the pages listed by the default readdirplus
+struct CountingFS {
+    inner: MemFS,
+    pages: Arc<AtomicUsize>,
+}
+
+forward_to_memfs! {
+    CountingFS,
+    async fn readdirplus(
+        &self,
+        dirid: fileid3,
+        cookie: cookie3,
+        dircount: count3,
+        maxcount: count3,
+        max_entries: usize,
+    ) -> Result<ReadDirPlusResult, nfsstat3> {
+        self.pages.fetch_add(1, Ordering::SeqCst);
+        self.inner
+            .readdirplus(dirid, cookie, dircount, maxcount, max_entries)
+            .await
+    }
+}
+
+#[test]
+fn pages_come_from_readdirplus() {
+    let pages = Arc::new(AtomicUsize::new(0));
+    let port = serve(CountingFS {
+        inner: MemFS::new(),
+        pages: pages.clone(),
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    let mut created = BTreeSet::new();
+    for i in 0..300 {
+        let name = format!("file-with-a-longish-name-{i:04}").into_bytes();
+        client.create(&dir, &name).unwrap();
+        created.insert(name);
+    }
+
+    let before = pages.load(Ordering::SeqCst);
+    let (entries, calls) = client.readdirplus_all(&dir, 1024, 4096).unwrap();
+    assert!(calls > 1);
+    assert!(pages.load(Ordering::SeqCst) - before >= calls);
+
+    let mut listed = BTreeSet::new();
+    for entry in &entries {
+        if entry.name == b"." || entry.name == b".." {
+            continue;
+        }
+        assert!(listed.insert(entry.name.clone()), "listed twice");
+        // the entries carry their attributes and a usable handle
+        assert!(matches!(entry.attr, post_op_attr::attributes(a) if a.fileid.0 == entry.fileid));
+        let post_op_fh3::handle(fh) = &entry.handle else {
+            panic!("no handle for {:?}", entry.name);
+        };
+        assert_eq!(client.getattr(fh).unwrap().fileid.0, entry.fileid);
+    }
+    assert_eq!(listed, created);
+
+    // pages stay within maxcount
+    let page = client.readdirplus_bytes(&dir, 0, cookieverf3::default(), 1024, 4096);
+    assert!(page.len() <= 4096);
+}
+
+/// The calls to readdir of an EstimatedFS and the entries they return
+#[derive(Default)]
+struct ListingCounter {
+    calls: AtomicUsize,
+    entries: AtomicUsize,
+}
+
+impl ListingCounter {
+    /// The calls and entries counted since the last take
+    fn take(&self) -> (usize, usize) {
+        (
+            self.calls.swap(0, Ordering::SeqCst),
+            self.entries.swap(0, Ordering::SeqCst),
+        )
+    }
+}
+
+/// A MemFS counting its listings, which the default readdirplus makes
+/// through readdir
+struct EstimatedFS {
+    inner: MemFS,
+    counter: Arc<ListingCounter>,
+}
+
+forward_to_memfs! {
+    EstimatedFS,
+    hooks {
+        async fn readdir(
+            &self,
+            dirid: fileid3,
+            start_after: cookie3,
+            max_entries: usize,
+        ) -> Result<ReadDirResult, nfsstat3> {
+            let result = self.inner.readdir(dirid, start_after, max_entries).await?;
+            self.counter.calls.fetch_add(1, Ordering::SeqCst);
+            self.counter
+                .entries
+                .fetch_add(result.entries.len(), Ordering::SeqCst);
+            Ok(result)
+        }
+    }
+}
+
+#[test]
+fn pages_load_about_what_fits() {
+    let counter = Arc::new(ListingCounter::default());
+    let port = serve(EstimatedFS {
+        inner: MemFS::new(),
+        counter: counter.clone(),
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let dir = client.mkdir(&root, b"dir").unwrap();
+    for i in 0..200 {
+        let name = format!("{i:04}-{}", "n".repeat(200)).into_bytes();
+        client.create(&dir, &name).unwrap();
+    }
+
+    // dircount / 16 is 4096 entries, and about 20 fit in maxcount
+    let (dircount, maxcount) = (65536, 8192);
+    let mut cookie = 0;
+    let mut cookieverf = cookieverf3::default();
+    let mut pages = 0;
+    counter.take();
+    loop {
+        let page = client
+            .readdirplus(&dir, cookie, cookieverf, dircount, maxcount)
+            .unwrap();
+        let (calls, loaded) = counter.take();
+        assert!(!page.entries.is_empty());
+        if pages > 0 {
+            // once the entry size is known, each page loads about what it
+            // takes, in one call
+            assert_eq!(calls, 1, "page {pages}");
+            assert!(
+                loaded <= page.entries.len() + 2,
+                "page {pages} loaded {loaded} entries for {}",
+                page.entries.len()
+            );
+        }
+        pages += 1;
+        if page.eof {
+            break;
+        }
+        cookie = page.entries.last().unwrap().cookie;
+        cookieverf = page.cookieverf;
+    }
+    assert!(pages > 5);
+}
@@ -0,0 +1,39 @@
+//! With set_readdirplus(false), READDIRPLUS is NFS3ERR_NOTSUPP so that
+//! clients fall back to READDIR, which is still served
+mod common;
+
+use common::{serve, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::NFSTcp;
+use nfsserve::xdr::XDR;
+
+#[test]
+fn disabled_readdirplus_is_not_supported() {
+    let port = serve_with(MemFS::new(), |listener| listener.set_readdirplus(false));
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    client.create(&root, b"file").unwrap();
+    let cookieverf = cookieverf3::default();
+    let res = client.readdirplus_bytes(&root, 0, cookieverf, 4096, 4096);
+    // NFS3ERR_NOTSUPP and no directory attributes
+    let mut expected = Vec::new();
+    nfsstat3::NFS3ERR_NOTSUPP.serialize(&mut expected).unwrap();
+    post_op_attr::Void.serialize(&mut expected).unwrap();
+    assert_eq!(res, expected);
+
+    assert_eq!(
+        client.readdir_all(&root, 4096).unwrap(),
+        vec![b"file".to_vec()]
+    );
+}
+
+#[test]
+fn readdirplus_is_enabled_by_default() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    client.create(&root, b"file").unwrap();
+    let (entries, _) = client.readdirplus_all(&root, 4096, 4096).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, b"file");
+}
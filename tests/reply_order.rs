@@ -0,0 +1,108 @@
+//! The order replies are written in, with a slow request followed by a
+//! fast one on the same connection
+mod common;
+
+use common::{forward_to_memfs, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::NFSTcp;
+use nfsserve::vfs::NFSFileSystem;
+use nfsserve::xdr::XDR;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFSPROC3_LOOKUP: u32 = 3;
+const NFSPROC3_READ: u32 = 6;
+
+/// A MemFS whose LOOKUP of "gated" waits for a permit of gate, and which
+/// records that a READ was served
+struct GatedFS {
+    inner: MemFS,
+    gate: Arc<Semaphore>,
+    read_done: Arc<AtomicBool>,
+}
+
+forward_to_memfs! {
+    GatedFS,
+    hooks {
+        async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+            if filename[..] == b"gated"[..] {
+                self.gate.acquire().await.unwrap().forget();
+            }
+            self.inner.lookup(dirid, filename).await
+        }
+        async fn read(
+            &self,
+            id: fileid3,
+            offset: u64,
+            count: u32,
+        ) -> Result<(Vec<u8>, bool), nfsstat3> {
+            let res = self.inner.read(id, offset, count).await;
+            self.read_done.store(true, Ordering::SeqCst);
+            res
+        }
+    }
+}
+
+/// Sends a LOOKUP which waits on the gate, then a READ, and releases the
+/// gate once the READ has been served. Returns the xids of the LOOKUP and
+/// the READ, and the xids of the replies in the order they came.
+fn slow_then_fast(ordered: bool) -> ((u32, u32), Vec<u32>) {
+    let gate = Arc::new(Semaphore::new(0));
+    let read_done = Arc::new(AtomicBool::new(false));
+    let fs = GatedFS {
+        inner: MemFS::new(),
+        gate: gate.clone(),
+        read_done: read_done.clone(),
+    };
+    let port = serve_with(fs, move |listener| listener.set_ordered_replies(ordered));
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, b"contents").unwrap();
+
+    let mut args = Vec::new();
+    root.serialize(&mut args).unwrap();
+    b"gated".to_vec().serialize(&mut args).unwrap();
+    let slow = client.send_call(NFS_PROGRAM, 3, NFSPROC3_LOOKUP, &args);
+
+    let mut args = Vec::new();
+    file.serialize(&mut args).unwrap();
+    0u64.serialize(&mut args).unwrap();
+    100u32.serialize(&mut args).unwrap();
+    let fast = client.send_call(NFS_PROGRAM, 3, NFSPROC3_READ, &args);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !read_done.load(Ordering::SeqCst) {
+        assert!(Instant::now() < deadline, "the READ was not served");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let mut order = Vec::new();
+    if !ordered {
+        // the READ overtakes the LOOKUP, which cannot complete yet
+        order.push(client.recv_reply().0);
+    } else {
+        // give the READ reply time to be queued, were it not held back
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    gate.add_permits(1);
+    while order.len() < 2 {
+        order.push(client.recv_reply().0);
+    }
+    ((slow, fast), order)
+}
+
+#[test]
+fn replies_are_written_as_ready_by_default() {
+    let ((slow, fast), order) = slow_then_fast(false);
+    assert_eq!(order, [fast, slow]);
+}
+
+#[test]
+fn ordered_replies_follow_the_requests() {
+    let ((slow, fast), order) = slow_then_fast(true);
+    assert_eq!(order, [slow, fast]);
+}
@@ -0,0 +1,93 @@
+//! A flood of pipelined calls on one connection is served at most
+//! max_requests_per_connection at a time
+mod common;
+
+use common::{forward_to_memfs, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::NFSTcp;
+use nfsserve::xdr::XDR;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC3_ACCESS: u32 = 4;
+
+const LIMIT: usize = 16;
+const CALLS: u32 = 10_000;
+
+/// A MemFS whose access checks wait for a permit of gate, counting how
+/// many wait at once
+struct GatedFS {
+    inner: MemFS,
+    gate: Arc<Semaphore>,
+    active: Arc<AtomicUsize>,
+    max_active: Arc<AtomicUsize>,
+}
+
+forward_to_memfs! {
+    GatedFS,
+    async fn access(&self, id: fileid3, attr: &fattr3, requested: u32) -> u32 {
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_active.fetch_max(active, Ordering::SeqCst);
+        self.gate.acquire().await.unwrap().forget();
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        self.inner.access(id, attr, requested).await
+    }
+}
+
+#[test]
+fn pipelined_calls_are_capped() {
+    let gate = Arc::new(Semaphore::new(0));
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+    let fs = GatedFS {
+        inner: MemFS::new(),
+        gate: gate.clone(),
+        active: active.clone(),
+        max_active: max_active.clone(),
+    };
+    let port = serve_with(fs, |listener| {
+        listener.set_max_requests_per_connection(LIMIT)
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+
+    // the calls are sent from another thread, since the server stops
+    // reading them (and the socket buffers fill up) past the limit
+    let mut sender = client.try_clone(1 << 20);
+    let mut args = Vec::new();
+    root.serialize(&mut args).unwrap();
+    ACCESS3_READ.serialize(&mut args).unwrap();
+    let sending = std::thread::spawn(move || {
+        (0..CALLS)
+            .map(|_| sender.send_call(NFS_PROGRAM, NFS_VERSION, NFSPROC3_ACCESS, &args))
+            .collect::<HashSet<u32>>()
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while active.load(Ordering::SeqCst) < LIMIT {
+        assert!(Instant::now() < deadline, "the calls were not served");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    // give the server time to take on more if it would
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(max_active.load(Ordering::SeqCst), LIMIT);
+
+    // every call is served once the access checks complete
+    gate.add_permits(CALLS as usize);
+    let mut replied = HashSet::new();
+    for _ in 0..CALLS {
+        let (xid, mut res) = client.recv_reply();
+        let mut stat = nfsstat3::NFS3ERR_IO;
+        stat.deserialize(&mut res).unwrap();
+        assert!(matches!(stat, nfsstat3::NFS3_OK));
+        assert!(replied.insert(xid), "xid {xid} replied twice");
+    }
+    assert_eq!(replied, sending.join().unwrap());
+    assert!(max_active.load(Ordering::SeqCst) <= LIMIT);
+}
@@ -0,0 +1,74 @@
+//! Calls of an RPC version other than 2 are denied with RPC_MISMATCH,
+//! before their credentials are looked at
+mod common;
+
+use common::serve;
+use nfsserve::memfs::MemFS;
+use nfsserve::xdr::XDR;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+
+const AUTH_UNIX: u32 = 1;
+const MSG_ACCEPTED: u32 = 0;
+const MSG_DENIED: u32 = 1;
+const RPC_MISMATCH: u32 = 0;
+
+/// Makes a NULL call of rpcvers with the credentials cred. Returns the
+/// reply, past the xid and message type.
+fn null_call(
+    stream: &mut TcpStream,
+    xid: u32,
+    rpcvers: u32,
+    cred: (u32, &[u8]),
+) -> Cursor<Vec<u8>> {
+    let mut msg = Vec::new();
+    for v in [xid, 0, rpcvers, NFS_PROGRAM, NFS_VERSION, 0, cred.0] {
+        v.serialize(&mut msg).unwrap();
+    }
+    cred.1.to_vec().serialize(&mut msg).unwrap();
+    // AUTH_NULL verifier
+    for v in [0u32, 0] {
+        v.serialize(&mut msg).unwrap();
+    }
+    let mut record = (0x8000_0000u32 | msg.len() as u32).to_be_bytes().to_vec();
+    record.extend_from_slice(&msg);
+    stream.write_all(&record).unwrap();
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).unwrap();
+    let mut reply = vec![0u8; (u32::from_be_bytes(header) & 0x7fff_ffff) as usize];
+    stream.read_exact(&mut reply).unwrap();
+    let mut reply = Cursor::new(reply);
+    assert_eq!(read_u32(&mut reply), xid, "xid");
+    assert_eq!(read_u32(&mut reply), 1, "msg_type REPLY");
+    reply
+}
+
+fn read_u32(src: &mut impl Read) -> u32 {
+    let mut v = 0u32;
+    v.deserialize(src).unwrap();
+    v
+}
+
+#[test]
+fn other_rpc_versions_are_mismatched() {
+    let mut stream = TcpStream::connect(("127.0.0.1", serve(MemFS::new()))).unwrap();
+    // AUTH_UNIX credentials which do not decode
+    let garbage = (AUTH_UNIX, &[0xff; 6][..]);
+    for (xid, rpcvers) in [(1, 3), (2, 1), (3, u32::MAX)] {
+        let mut reply = null_call(&mut stream, xid, rpcvers, garbage);
+        assert_eq!(read_u32(&mut reply), MSG_DENIED);
+        assert_eq!(read_u32(&mut reply), RPC_MISMATCH);
+        // the lowest and highest versions supported
+        assert_eq!(read_u32(&mut reply), 2);
+        assert_eq!(read_u32(&mut reply), 2);
+        assert_eq!(reply.position() as usize, reply.get_ref().len());
+    }
+
+    // the connection is still good for version 2
+    let mut reply = null_call(&mut stream, 4, 2, (0, &[]));
+    assert_eq!(read_u32(&mut reply), MSG_ACCEPTED);
+}
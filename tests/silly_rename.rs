@@ -0,0 +1,123 @@
+//! Delete-while-open: a client renames a file it has open to ".nfs..."
+//! instead of removing it, keeps reading it, and removes it on close
+mod common;
+
+use common::{forward_to_memfs, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::NFSTcp;
+use nfsserve::vfs::{NFSFileSystem, ReadDirResult};
+
+const SILLY: &[u8] = b".nfs00000000000000020000001";
+
+fn names(client: &mut Client, dir: &nfs_fh3) -> Vec<Vec<u8>> {
+    let mut names = client.readdir_all(dir, 4096).unwrap();
+    names.retain(|name| name != b"." && name != b"..");
+    names.sort();
+    names
+}
+
+#[test]
+fn delete_while_open() {
+    let port = serve_with(MemFS::new(), |listener| {
+        listener.set_hide_silly_renames(true)
+    });
+    let mut owner = Client::connect(port);
+    let mut other = Client::connect_from(port, [127, 0, 0, 2]);
+    let root = owner.mount(b"/");
+    let other_root = other.mount(b"/");
+    let dir = owner.mkdir(&root, b"dir").unwrap();
+    let other_dir = other.lookup(&other_root, b"dir").unwrap();
+
+    let file = owner.create(&dir, b"doc").unwrap();
+    owner.write(&file, 0, b"still readable").unwrap();
+    // opened
+    owner.read(&file, 0, 5).unwrap();
+
+    // removed while open
+    owner.rename(&dir, b"doc", &dir, SILLY).unwrap();
+    assert_eq!(owner.read(&file, 0, 100).unwrap().0, b"still readable");
+    assert_eq!(names(&mut owner, &dir), [SILLY.to_vec()]);
+    // the other client sees the file gone
+    assert!(names(&mut other, &other_dir).is_empty());
+    assert!(matches!(
+        other.lookup(&other_dir, b"doc"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    owner.write(&file, 14, b", and writable").unwrap();
+    assert_eq!(
+        owner.read(&file, 0, 100).unwrap().0,
+        b"still readable, and writable"
+    );
+
+    // closed
+    owner.remove(&dir, SILLY).unwrap();
+    assert!(names(&mut owner, &dir).is_empty());
+    assert!(matches!(owner.getattr(&file), Err(nfsstat3::NFS3ERR_STALE)));
+
+    // a file of the same name renamed by the other client later is its own
+    let again = other.create(&other_dir, b"doc").unwrap();
+    other.rename(&other_dir, b"doc", &other_dir, SILLY).unwrap();
+    other.read(&again, 0, 1).unwrap();
+    assert_eq!(names(&mut other, &other_dir), [SILLY.to_vec()]);
+    assert!(names(&mut owner, &dir).is_empty());
+    other.remove(&other_dir, SILLY).unwrap();
+}
+
+/// A MemFS listing at most page_size entries per readdir
+struct PagedFS {
+    inner: MemFS,
+    page_size: usize,
+}
+
+forward_to_memfs! {
+    PagedFS,
+    hooks {
+        async fn readdir(
+            &self,
+            dirid: fileid3,
+            start_after: cookie3,
+            max_entries: usize,
+        ) -> Result<ReadDirResult, nfsstat3> {
+            let max_entries = max_entries.min(self.page_size);
+            self.inner.readdir(dirid, start_after, max_entries).await
+        }
+    }
+}
+
+#[test]
+fn hidden_at_a_page_boundary() {
+    // the silly renamed file ends a page of the file system for one of
+    // these page sizes
+    for page_size in 1..=6 {
+        let fs = PagedFS {
+            inner: MemFS::new(),
+            page_size,
+        };
+        let port = serve_with(fs, |listener| listener.set_hide_silly_renames(true));
+        let mut owner = Client::connect(port);
+        let mut other = Client::connect_from(port, [127, 0, 0, 2]);
+        let root = owner.mount(b"/");
+        let other_root = other.mount(b"/");
+        let dir = owner.mkdir(&root, b"dir").unwrap();
+        let other_dir = other.lookup(&other_root, b"dir").unwrap();
+
+        let mut expected = Vec::new();
+        for i in 0..6 {
+            let name = format!("f{i}").into_bytes();
+            owner.create(&dir, &name).unwrap();
+            expected.push(name);
+            if i == 2 {
+                let file = owner.create(&dir, b"doc").unwrap();
+                owner.read(&file, 0, 1).unwrap();
+                owner.rename(&dir, b"doc", &dir, SILLY).unwrap();
+            }
+        }
+        assert_eq!(names(&mut owner, &dir).len(), expected.len() + 1);
+        assert_eq!(
+            names(&mut other, &other_dir),
+            expected,
+            "pages of {page_size} entries"
+        );
+    }
+}
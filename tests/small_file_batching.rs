@@ -0,0 +1,270 @@
+//! Small UNSTABLE writes synced in batches by a SyncBatcher, small
+//! FILE_SYNC writes batched as well with set_relaxed_durability, and the
+//! COMMIT after a failed batched sync
+#![cfg(unix)]
+mod common;
+
+use common::{forward_to_memfs, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::sync_batch::{SyncBatchConfig, SyncBatcher};
+use nfsserve::tcp::NFSTcp;
+use nfsserve::vfs::{discard_unstable_writes, WriteReply};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const NEVER: Duration = Duration::from_secs(3600);
+
+/// The directory the data of a DiskFS is written to, a file per fileid
+struct Backing {
+    dir: PathBuf,
+    syncs: AtomicUsize,
+    /// Fails every sync while set
+    fail: AtomicBool,
+}
+
+impl Backing {
+    fn new(name: &str) -> Arc<Backing> {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Arc::new(Backing {
+            dir,
+            syncs: AtomicUsize::new(0),
+            fail: AtomicBool::new(false),
+        })
+    }
+
+    fn path(&self, id: fileid3) -> PathBuf {
+        self.dir.join(id.0.to_string())
+    }
+
+    fn sync(&self, id: fileid3) -> std::io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("injected sync failure"));
+        }
+        std::fs::File::open(self.path(id))?.sync_all()
+    }
+
+    /// Waits for the syncs to reach n
+    fn wait_for_syncs(&self, n: usize) {
+        let start = Instant::now();
+        while self.syncs.load(Ordering::SeqCst) < n {
+            assert!(start.elapsed() < Duration::from_secs(10), "syncs stuck");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+/// A MemFS which also writes the file data to files of a directory, and
+/// syncs those as the writes ask, or in batches
+struct DiskFS {
+    inner: MemFS,
+    backing: Arc<Backing>,
+    batcher: Option<SyncBatcher>,
+}
+
+impl DiskFS {
+    fn new(backing: &Arc<Backing>, batching: Option<SyncBatchConfig>) -> DiskFS {
+        let batcher = batching.map(|config| {
+            let backing = backing.clone();
+            SyncBatcher::new(config, move |id| {
+                let backing = backing.clone();
+                async move { backing.sync(id) }
+            })
+        });
+        DiskFS {
+            inner: MemFS::new(),
+            backing: backing.clone(),
+            batcher,
+        }
+    }
+}
+
+forward_to_memfs! {
+    DiskFS,
+    async fn write_ext(
+        &self,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WriteReply, nfsstat3> {
+        let attr = self.inner.write(id, offset, data).await?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.backing.path(id))
+            .or(Err(nfsstat3::NFS3ERR_IO))?;
+        file.write_all_at(data, offset)
+            .or(Err(nfsstat3::NFS3ERR_IO))?;
+        match stable {
+            stable_how::UNSTABLE => {
+                if let Some(batcher) = &self.batcher {
+                    batcher.add(id, data.len());
+                }
+            }
+            _ => self.backing.sync(id).or(Err(nfsstat3::NFS3ERR_IO))?,
+        }
+        Ok(WriteReply {
+            before: None,
+            attr,
+            committed: stable,
+        })
+    }
+    async fn commit(&self, id: fileid3, _offset: u64, _count: u32) -> Result<(), nfsstat3> {
+        let synced = match &self.batcher {
+            Some(batcher) => batcher.flush(id).await,
+            None => self.backing.sync(id),
+        };
+        synced.map_err(|_| {
+            discard_unstable_writes();
+            nfsstat3::NFS3ERR_IO
+        })
+    }
+}
+
+#[test]
+fn relaxed_small_writes_are_batched() {
+    let backing = Backing::new("relaxed_small_writes_are_batched");
+    let config = SyncBatchConfig {
+        max_write: 4096,
+        max_files: 20,
+        max_delay: NEVER,
+        ..Default::default()
+    };
+    let fs = DiskFS::new(&backing, Some(config));
+    let port = serve_with(fs, |listener| listener.set_relaxed_durability(4096));
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+
+    let files: Vec<nfs_fh3> = (0..20)
+        .map(|i| client.create(&root, format!("f{i}").as_bytes()).unwrap())
+        .collect();
+    for (i, file) in files.iter().enumerate() {
+        let (_, committed, _) = client
+            .write_stable(file, 0, &[1; 100], stable_how::FILE_SYNC)
+            .unwrap();
+        // replied as stable as asked
+        assert!(matches!(committed, stable_how::FILE_SYNC));
+        // the 20th file fills the batch
+        if i < 19 {
+            assert_eq!(backing.syncs.load(Ordering::SeqCst), 0);
+        }
+    }
+    backing.wait_for_syncs(20);
+
+    // larger writes are synced before the reply, as they ask
+    client
+        .write_stable(&files[0], 0, &[2; 8192], stable_how::FILE_SYNC)
+        .unwrap();
+    assert_eq!(backing.syncs.load(Ordering::SeqCst), 21);
+    // and so are UNSTABLE ones on COMMIT
+    client
+        .write_stable(&files[0], 0, &[3; 100], stable_how::UNSTABLE)
+        .unwrap();
+    client.commit(&files[0]).unwrap();
+    assert_eq!(backing.syncs.load(Ordering::SeqCst), 22);
+}
+
+#[test]
+fn failed_batched_syncs_fail_the_next_commit() {
+    let backing = Backing::new("failed_batched_syncs_fail_the_next_commit");
+    let config = SyncBatchConfig {
+        max_files: 2,
+        max_delay: NEVER,
+        ..Default::default()
+    };
+    let fs = DiskFS::new(&backing, Some(config));
+    let mut client = Client::connect(serve_with(fs, |_| {}));
+    let root = client.mount(b"/");
+    let a = client.create(&root, b"a").unwrap();
+    let b = client.create(&root, b"b").unwrap();
+
+    backing.fail.store(true, Ordering::SeqCst);
+    let (_, _, verf) = client
+        .write_stable(&a, 0, &[1; 100], stable_how::UNSTABLE)
+        .unwrap();
+    client
+        .write_stable(&b, 0, &[1; 100], stable_how::UNSTABLE)
+        .unwrap();
+    backing.wait_for_syncs(2);
+    backing.fail.store(false, Ordering::SeqCst);
+
+    // the syncs now succeed, but the writes of the batch may be lost
+    assert!(matches!(client.commit(&a), Err(nfsstat3::NFS3ERR_IO)));
+    assert!(matches!(client.commit(&b), Err(nfsstat3::NFS3ERR_IO)));
+    // the verifier changed, so the client sends them again
+    let resent = client.commit(&a).unwrap();
+    assert_ne!(resent, verf);
+    let (_, _, verf) = client
+        .write_stable(&a, 0, &[1; 100], stable_how::UNSTABLE)
+        .unwrap();
+    assert_eq!(verf, resent);
+    assert_eq!(client.commit(&a).unwrap(), verf);
+}
+
+#[test]
+fn empty_stable_writes_are_synced_by_default() {
+    let backing = Backing::new("empty_stable_writes_are_synced_by_default");
+    let mut client = Client::connect(serve_with(DiskFS::new(&backing, None), |_| {}));
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"f").unwrap();
+    // relaxed durability is off, so even a 0-byte write is as stable
+    // as asked
+    let (_, committed, _) = client
+        .write_stable(&file, 0, &[], stable_how::FILE_SYNC)
+        .unwrap();
+    assert!(matches!(committed, stable_how::FILE_SYNC));
+    assert_eq!(backing.syncs.load(Ordering::SeqCst), 1);
+}
+
+/// Unpacks files archive-style (CREATE, a FILE_SYNC WRITE, SETATTR of the
+/// times), returns the time taken
+fn unpack(client: &mut Client, dir: &nfs_fh3, files: usize) -> Duration {
+    let start = Instant::now();
+    let times = sattr3 {
+        mtime: set_mtime::SET_TO_CLIENT_TIME(nfstime3 {
+            seconds: 1_000_000_000,
+            nseconds: 0,
+        }),
+        ..Default::default()
+    };
+    for i in 0..files {
+        let file = client.create(dir, format!("f{i}").as_bytes()).unwrap();
+        client.write(&file, 0, &[7; 2048]).unwrap();
+        client.setattr(&file, &times).unwrap();
+    }
+    start.elapsed()
+}
+
+/// cargo test --release --test small_file_batching -- --ignored --nocapture
+#[test]
+#[ignore]
+fn unpack_many_small_files() {
+    const FILES: usize = 2000;
+    let backing = Backing::new("unpack_many_small_files_synced");
+    let mut client = Client::connect(serve_with(DiskFS::new(&backing, None), |_| {}));
+    let root = client.mount(b"/");
+    let synced = unpack(&mut client, &root, FILES);
+
+    let backing = Backing::new("unpack_many_small_files_batched");
+    let fs = DiskFS::new(&backing, Some(SyncBatchConfig::default()));
+    let port = serve_with(fs, |listener| listener.set_relaxed_durability(4096));
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let batched = unpack(&mut client, &root, FILES);
+    backing.wait_for_syncs(FILES);
+
+    println!(
+        "{FILES} files: {:?} syncing each write, {:?} batched ({:.1}x)",
+        synced,
+        batched,
+        synced.as_secs_f64() / batched.as_secs_f64()
+    );
+}
@@ -0,0 +1,122 @@
+//! Caller credentials mapped by the squash of the listener, as seen in the
+//! owners of the objects the caller creates, and the attributes MKDIR
+//! sets after making the directory
+mod common;
+
+use common::{forward_to_memfs, serve_with, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::{NFSTcp, SquashMode};
+
+const ANON: u32 = 65534;
+
+fn client(mode: SquashMode) -> Client {
+    Client::connect(serve_with(MemFS::new(), move |listener| {
+        listener.set_squash(mode)
+    }))
+}
+
+/// The (uid, gid) owning a file created by the client as uid and gid
+fn owner_of_new_file(client: &mut Client, name: &[u8], uid: u32, gid: u32) -> (u32, u32) {
+    client.set_user(uid, gid);
+    let root = client.mount(b"/");
+    let file = client.create(&root, name).unwrap();
+    let attr = client.getattr(&file).unwrap();
+    (attr.uid, attr.gid)
+}
+
+#[test]
+fn root_squash_maps_root_only() {
+    let mut client = client(SquashMode::RootSquash {
+        anon_uid: ANON,
+        anon_gid: ANON,
+    });
+    assert_eq!(
+        owner_of_new_file(&mut client, b"by_root", 0, 0),
+        (ANON, ANON)
+    );
+    assert_eq!(
+        owner_of_new_file(&mut client, b"by_user", 1000, 100),
+        (1000, 100)
+    );
+    // a user whose primary group is root's
+    assert_eq!(
+        owner_of_new_file(&mut client, b"by_wheel", 1000, 0),
+        (1000, ANON)
+    );
+}
+
+#[test]
+fn all_squash_maps_everyone() {
+    let mut client = client(SquashMode::AllSquash {
+        anon_uid: ANON,
+        anon_gid: ANON,
+    });
+    assert_eq!(
+        owner_of_new_file(&mut client, b"by_root", 0, 0),
+        (ANON, ANON)
+    );
+    assert_eq!(
+        owner_of_new_file(&mut client, b"by_user", 1000, 100),
+        (ANON, ANON)
+    );
+}
+
+#[test]
+fn no_squash_keeps_root() {
+    let mut client = client(SquashMode::NoSquash);
+    assert_eq!(owner_of_new_file(&mut client, b"by_root", 0, 0), (0, 0));
+}
+
+#[test]
+fn mkdir_sets_the_squashed_attributes() {
+    let mut client = client(SquashMode::RootSquash {
+        anon_uid: ANON,
+        anon_gid: ANON,
+    });
+    let root = client.mount(b"/");
+    let attr = sattr3 {
+        mode: set_mode3::mode(0o640),
+        uid: set_uid3::uid(0),
+        gid: set_gid3::gid(0),
+        ..Default::default()
+    };
+    let dir = client.mkdir_with(&root, b"dir", &attr).unwrap();
+    let attr = client.getattr(&dir).unwrap();
+    assert_eq!(attr.mode & 0o7777, 0o640);
+    assert_eq!((attr.uid, attr.gid), (ANON, ANON));
+}
+
+/// A MemFS which refuses every setattr
+struct FixedFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    FixedFS,
+    hooks {
+        async fn setattr(&self, _id: fileid3, _setattr: sattr3) -> Result<fattr3, nfsstat3> {
+            Err(nfsstat3::NFS3ERR_PERM)
+        }
+    }
+}
+
+#[test]
+fn mkdir_succeeds_when_its_attributes_cannot_be_set() {
+    let fs = FixedFS {
+        inner: MemFS::new(),
+    };
+    let mut client = Client::connect(serve_with(fs, |_| {}));
+    let root = client.mount(b"/");
+    let attr = sattr3 {
+        mode: set_mode3::mode(0o640),
+        ..Default::default()
+    };
+    // the directory is made, with the mode MemFS gives it
+    let dir = client.mkdir_with(&root, b"dir", &attr).unwrap();
+    assert!(matches!(
+        client.getattr(&dir).unwrap().ftype,
+        ftype3::NF3DIR
+    ));
+    assert_eq!(client.lookup(&root, b"dir").unwrap().data, dir.data);
+}
@@ -0,0 +1,126 @@
+//! A READDIRPLUS of a very large directory in pages of about 1MiB, which
+//! are streamed to the client a chunk at a time by default: the replies
+//! are the same as built whole, without holding more than a few chunks
+mod common;
+
+use common::{forward_to_memfs, parse_readdirplus, serve_shared, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{DirEntry, NFSFileSystem, ReadDirResult};
+use std::sync::Arc;
+
+const ENTRIES: u64 = 500_000;
+const MAXCOUNT: u32 = 1024 * 1024;
+/// The size of the chunks replies are streamed in
+const CHUNK: usize = 64 * 1024;
+
+/// A MemFS whose root lists ENTRIES generated files. Both servers list
+/// the one MemFS, so that their replies (with the attributes of the root)
+/// are alike.
+struct GeneratedFS {
+    inner: Arc<MemFS>,
+}
+
+forward_to_memfs! {
+    GeneratedFS,
+    hooks {
+        async fn readdir(
+            &self,
+            dirid: fileid3,
+            start_after: cookie3,
+            max_entries: usize,
+        ) -> Result<ReadDirResult, nfsstat3> {
+            if dirid != self.inner.root_dir() {
+                return self.inner.readdir(dirid, start_after, max_entries).await;
+            }
+            let start = start_after.0;
+            let end = ENTRIES.min(start.saturating_add(max_entries as u64));
+            let entries = (start..end)
+                .map(|i| DirEntry {
+                    fileid: fileid3(1000 + i),
+                    name: format!("generated file {i:06}").into_bytes().into(),
+                    attr: fattr3 {
+                        ftype: ftype3::NF3REG,
+                        mode: 0o644,
+                        nlink: 1,
+                        fileid: fileid3(1000 + i),
+                        ..Default::default()
+                    },
+                    cookie: cookie3(i + 1),
+                })
+                .collect();
+            Ok(ReadDirResult {
+                entries,
+                end: end == ENTRIES,
+            })
+        }
+    }
+}
+
+/// Lists the root. Returns the results of each page as they came, and
+/// the most memory the listener held while replying to a page.
+fn list_root(listener: &NFSTcpListener<GeneratedFS>) -> (Vec<Vec<u8>>, usize) {
+    let mut client = Client::connect(listener.get_listen_port());
+    let root = client.mount(b"/");
+    let mut pages = Vec::new();
+    let mut peak = 0;
+    let mut listed = 0;
+    let mut cookie = 0;
+    let mut cookieverf = cookieverf3::default();
+    loop {
+        listener.reset_memory_peak();
+        let bytes = client.readdirplus_bytes(&root, cookie, cookieverf, MAXCOUNT, MAXCOUNT);
+        peak = peak.max(listener.memory_peak_in_use());
+        let page = parse_readdirplus(&bytes).unwrap();
+        assert!(bytes.len() <= MAXCOUNT as usize);
+        for entry in &page.entries {
+            assert_eq!(
+                entry.name,
+                format!("generated file {listed:06}").into_bytes()
+            );
+            listed += 1;
+        }
+        pages.push(bytes);
+        if page.eof {
+            break;
+        }
+        cookie = page.entries.last().unwrap().cookie;
+        cookieverf = page.cookieverf;
+    }
+    assert_eq!(listed, ENTRIES);
+    (pages, peak)
+}
+
+#[test]
+fn long_listings_are_streamed() {
+    let memfs = Arc::new(MemFS::new());
+    let streamed = serve_shared(
+        GeneratedFS {
+            inner: memfs.clone(),
+        },
+        |_| {},
+    );
+    let whole = serve_shared(GeneratedFS { inner: memfs }, |listener| {
+        listener.set_streamed_replies(false)
+    });
+
+    let (streamed_pages, streamed_peak) = list_root(&streamed);
+    let (whole_pages, whole_peak) = list_root(&whole);
+    assert!(streamed_pages.len() > 10);
+    assert_eq!(streamed_pages.len(), whole_pages.len());
+    for (i, (streamed, whole)) in streamed_pages.iter().zip(&whole_pages).enumerate() {
+        assert!(streamed == whole, "page {i} differs");
+    }
+
+    // a few chunks queued, being written and being filled, against the
+    // whole reply
+    assert!(
+        streamed_peak <= 6 * CHUNK,
+        "streamed replies held {streamed_peak} bytes"
+    );
+    assert!(
+        whole_peak >= whole_pages[0].len(),
+        "whole replies held {whole_peak} bytes"
+    );
+}
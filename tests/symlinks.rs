@@ -0,0 +1,140 @@
+//! The symlink target cache of path backed file systems, and the rewrite
+//! of READLINK targets by the listener
+#![cfg(not(target_os = "windows"))]
+mod common;
+
+use common::{serve_with, Client};
+use nfsserve::fs_util::SymlinkCache;
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{fileid3, nfs_fh3, nfspath3};
+use nfsserve::tcp::{NFSTcp, SymlinkRewriter};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+fn target(s: &str) -> nfspath3 {
+    s.as_bytes().into()
+}
+
+#[test]
+fn cache_hits_and_invalidation() {
+    let mut cache = SymlinkCache::new(10);
+    assert!(cache.get(fileid3(1)).is_none());
+    cache.insert(fileid3(1), target("a"));
+    cache.insert(fileid3(2), target("b"));
+    assert_eq!(cache.get(fileid3(1)).unwrap().0, b"a");
+    assert_eq!(cache.get(fileid3(2)).unwrap().0, b"b");
+    assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+    // a changed link is read again, and cached anew
+    cache.invalidate(fileid3(1));
+    assert!(cache.get(fileid3(1)).is_none());
+    cache.insert(fileid3(1), target("a2"));
+    assert_eq!(cache.get(fileid3(1)).unwrap().0, b"a2");
+    assert_eq!(cache.len(), 2);
+    assert_eq!((cache.hits(), cache.misses()), (3, 2));
+
+    cache.invalidate(fileid3(99));
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn cache_evicts_least_recently_used() {
+    let mut cache = SymlinkCache::new(3);
+    for i in 1..=3 {
+        cache.insert(fileid3(i), target(&i.to_string()));
+    }
+    // 1 is now more recent than 2 and 3
+    cache.get(fileid3(1)).unwrap();
+    cache.insert(fileid3(4), target("4"));
+    assert_eq!(cache.len(), 3);
+    assert!(cache.get(fileid3(2)).is_none());
+    for i in [1, 3, 4] {
+        assert_eq!(cache.get(fileid3(i)).unwrap().0, i.to_string().as_bytes());
+    }
+    // replacing a cached target does not evict another link
+    cache.insert(fileid3(3), target("3b"));
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.get(fileid3(3)).unwrap().0, b"3b");
+
+    let mut disabled = SymlinkCache::new(0);
+    disabled.insert(fileid3(1), target("a"));
+    assert!(disabled.is_empty());
+}
+
+/// Rewrites absolute targets under root, the path the export is mounted
+/// at on clients, to targets relative to the link
+struct RelativeUnderRoot {
+    root: &'static [u8],
+    /// the path of each directory relative to the export root
+    dirs: Mutex<HashMap<fileid3, Vec<u8>>>,
+}
+
+impl SymlinkRewriter for RelativeUnderRoot {
+    fn rewrite_symlink_target(&self, target: &[u8], link_dir: fileid3) -> Option<Vec<u8>> {
+        let rest = target.strip_prefix(self.root)?;
+        let rest = match rest {
+            b"" => rest,
+            _ => rest.strip_prefix(b"/")?,
+        };
+        let dirs = self.dirs.lock().unwrap();
+        let depth = dirs
+            .get(&link_dir)?
+            .split(|&c| c == b'/')
+            .filter(|c| !c.is_empty())
+            .count();
+        let mut ret = b"../".repeat(depth);
+        ret.extend_from_slice(rest);
+        // the export root itself
+        if rest.is_empty() {
+            ret.pop();
+        }
+        if ret.is_empty() {
+            ret.push(b'.');
+        }
+        Some(ret)
+    }
+}
+
+#[test]
+fn readlink_targets_are_rewritten() {
+    let rewriter = Arc::new(RelativeUnderRoot {
+        root: b"/srv/export",
+        dirs: Mutex::new(HashMap::new()),
+    });
+    let configured = rewriter.clone();
+    let port = serve_with(MemFS::new(), move |listener| {
+        listener.set_symlink_rewriter(configured);
+    });
+    let mut client = Client::connect(port);
+    let root = client.mount(b"/");
+    let a = client.mkdir(&root, b"a").unwrap();
+    let b = client.mkdir(&a, b"b").unwrap();
+    {
+        let mut dirs = rewriter.dirs.lock().unwrap();
+        for (fh, path) in [(&root, ""), (&a, "a"), (&b, "a/b")] {
+            let id = client.getattr(fh).unwrap().fileid;
+            dirs.insert(id, path.as_bytes().to_vec());
+        }
+    }
+
+    let cases: [(&nfs_fh3, &str, &str); 7] = [
+        (&root, "/srv/export/x/y", "x/y"),
+        (&b, "/srv/export/a/file", "../../a/file"),
+        (&a, "/srv/export", ".."),
+        (&root, "/srv/export/", "."),
+        // outside the export, or already relative: left as is
+        (&a, "/etc/passwd", "/etc/passwd"),
+        (&b, "/srv/exported", "/srv/exported"),
+        (&b, "sibling", "sibling"),
+    ];
+    for (i, (dir, link_target, expected)) in cases.into_iter().enumerate() {
+        let link = client
+            .symlink(dir, format!("l{i}").as_bytes(), link_target.as_bytes())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(client.readlink(&link).unwrap()).unwrap(),
+            expected,
+            "target {link_target:?}"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! A session of ordinary file system use against a MemFS, over TCP from
+//! mount to removing what it made: directories, a 10MB file written and
+//! read back, a rename across directories and a directory of 1000 entries
+mod common;
+
+use common::{serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::{ftype3, nfs_fh3, nfsstat3};
+use sha2::{Digest, Sha256};
+
+const FILE_SIZE: usize = 10 << 20;
+const CHUNK: usize = 1 << 20;
+
+/// Lists dir without . and ..
+fn entries(client: &mut Client, dir: &nfs_fh3) -> Vec<Vec<u8>> {
+    let mut names = client.readdir_all(dir, 8192).unwrap();
+    names.retain(|name| name != b"." && name != b"..");
+    names.sort();
+    names
+}
+
+/// Removes the contents of dir, depth first
+fn remove_tree(client: &mut Client, dir: &nfs_fh3) {
+    for name in entries(client, dir) {
+        let fh = client.lookup(dir, &name).unwrap();
+        if matches!(client.getattr(&fh).unwrap().ftype, ftype3::NF3DIR) {
+            remove_tree(client, &fh);
+            client.rmdir(dir, &name).unwrap();
+        } else {
+            client.remove(dir, &name).unwrap();
+        }
+    }
+}
+
+#[test]
+fn memfs_workload() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    let root = client.mount(b"/");
+    let work = client.mkdir(&root, b"work").unwrap();
+    let moved = client.mkdir(&work, b"moved").unwrap();
+
+    // 10MB in wtmax sized writes, read back in rtmax sized reads
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i * 7 % 253) as u8).collect();
+    let file = client.create(&work, b"data").unwrap();
+    for (i, chunk) in data.chunks(CHUNK).enumerate() {
+        let written = client.write(&file, (i * CHUNK) as u64, chunk).unwrap();
+        assert_eq!(written as usize, chunk.len());
+    }
+    assert_eq!(client.getattr(&file).unwrap().size, FILE_SIZE as u64);
+    let mut read_back = Sha256::new();
+    let mut offset = 0;
+    loop {
+        let (bytes, eof) = client.read(&file, offset, CHUNK as u32).unwrap();
+        offset += bytes.len() as u64;
+        read_back.update(&bytes);
+        if eof {
+            break;
+        }
+        assert!(!bytes.is_empty(), "a read short of eof must make progress");
+    }
+    assert_eq!(offset, FILE_SIZE as u64);
+    assert_eq!(read_back.finalize(), Sha256::digest(&data));
+
+    // the handle follows the file to its new directory
+    client.rename(&work, b"data", &moved, b"renamed").unwrap();
+    assert!(matches!(
+        client.lookup(&work, b"data"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+    let renamed = client.lookup(&moved, b"renamed").unwrap();
+    assert_eq!(
+        client.getattr(&renamed).unwrap().fileid,
+        client.getattr(&file).unwrap().fileid
+    );
+    let (bytes, _) = client.read(&renamed, 5 << 20, 16).unwrap();
+    assert_eq!(bytes, data[5 << 20..(5 << 20) + 16]);
+
+    // 1000 entries, listed over many READDIR pages
+    let many = client.mkdir(&work, b"many").unwrap();
+    let mut names: Vec<Vec<u8>> = (0..1000)
+        .map(|i| format!("entry-{i:04}").into_bytes())
+        .collect();
+    for name in &names {
+        client.create(&many, name).unwrap();
+    }
+    names.sort();
+    assert_eq!(entries(&mut client, &many), names);
+
+    // a directory which is not empty stays, and the tree goes depth first
+    assert!(matches!(
+        client.rmdir(&root, b"work"),
+        Err(nfsstat3::NFS3ERR_NOTEMPTY)
+    ));
+    remove_tree(&mut client, &work);
+    client.rmdir(&root, b"work").unwrap();
+    assert!(entries(&mut client, &root).is_empty());
+    assert!(matches!(
+        client.lookup(&root, b"work"),
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+}
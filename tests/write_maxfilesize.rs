@@ -0,0 +1,63 @@
+//! WRITEs which would go past the maxfilesize of fsinfo_config are
+//! NFS3ERR_FBIG before any of the data is written
+mod common;
+
+use common::{forward_to_memfs, serve, Client};
+use nfsserve::memfs::MemFS;
+use nfsserve::nfs::*;
+use nfsserve::vfs::{FsInfoConfig, NFSFileSystem};
+
+const MAXFILESIZE: u64 = 4096;
+
+/// A MemFS advertising a maxfilesize of MAXFILESIZE, while the MemFS
+/// itself takes files of any size
+struct SmallFilesFS {
+    inner: MemFS,
+}
+
+forward_to_memfs! {
+    SmallFilesFS,
+    fn fsinfo_config(&self) -> FsInfoConfig {
+        FsInfoConfig {
+            maxfilesize: MAXFILESIZE,
+            ..self.inner.fsinfo_config()
+        }
+    }
+}
+
+/// Writes 100 bytes at 10 bytes before maxfilesize, and a write ending at
+/// maxfilesize, to a file of 8 bytes
+fn check_writes(client: &mut Client, maxfilesize: u64) {
+    let root = client.mount(b"/");
+    let file = client.create(&root, b"file").unwrap();
+    client.write(&file, 0, b"contents").unwrap();
+
+    assert!(matches!(
+        client.write(&file, maxfilesize - 10, &[1; 100]),
+        Err(nfsstat3::NFS3ERR_FBIG)
+    ));
+    assert_eq!(client.getattr(&file).unwrap().size, 8);
+    let (data, _) = client.read(&file, 0, 100).unwrap();
+    assert_eq!(data, b"contents");
+}
+
+#[test]
+fn writes_past_maxfilesize_are_refused() {
+    let mut client = Client::connect(serve(MemFS::new()));
+    check_writes(&mut client, MemFS::new().fsinfo_config().maxfilesize);
+}
+
+#[test]
+fn writes_past_an_overridden_maxfilesize_are_refused() {
+    let fs = SmallFilesFS {
+        inner: MemFS::new(),
+    };
+    let mut client = Client::connect(serve(fs));
+    check_writes(&mut client, MAXFILESIZE);
+
+    // up to maxfilesize is fine
+    let root = client.mount(b"/");
+    let file = client.lookup(&root, b"file").unwrap();
+    client.write(&file, MAXFILESIZE - 100, &[1; 100]).unwrap();
+    assert_eq!(client.getattr(&file).unwrap().size, MAXFILESIZE);
+}